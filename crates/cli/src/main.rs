@@ -1,5 +1,6 @@
 use clap::{Arg, ArgAction, Command};
 use common::{Logger, error, info};
+use core::config::{ConfigFormat, ObfuscatorConfig};
 use log::LevelFilter;
 use std::fs::File;
 use std::io::{Read, Write};
@@ -41,6 +42,34 @@ fn save_file(path: &Path, data: &[u8]) -> Result<(), Box<dyn std::error::Error>>
     Ok(())
 }
 
+fn load_config(path: &Path) -> Result<ObfuscatorConfig, Box<dyn std::error::Error>> {
+    let format = path
+        .extension()
+        .and_then(|extension| ConfigFormat::from_extension(&extension.to_string_lossy()))
+        .ok_or_else(|| {
+            format!(
+                "Config file '{}' must have a .toml or .json extension",
+                path.display()
+            )
+        })?;
+
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read config file '{}': {}", path.display(), e))?;
+
+    let config = ObfuscatorConfig::parse(&text, format)
+        .map_err(|e| format!("Invalid config file '{}': {}", path.display(), e))?;
+
+    Ok(config)
+}
+
+fn parse_seed(value: &str) -> Result<u64, String> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|e| format!("Invalid seed '{value}': {e}"))
+}
+
 fn validate_file_exists(path: &Path, file_type: &str) -> Result<(), String> {
     if !path.exists() {
         return Err(format!(
@@ -66,15 +95,11 @@ fn generate_output_path(input_path: &Path) -> PathBuf {
     let stem = input_path
         .file_stem()
         .unwrap_or(std::ffi::OsStr::new("output"));
-    let extension = input_path
-        .extension()
-        .unwrap_or(std::ffi::OsStr::new("exe"));
-
-    parent.join(format!(
-        "{}_obfuscated.{}",
-        stem.to_string_lossy(),
-        extension.to_string_lossy()
-    ))
+    let name = match input_path.extension() {
+        Some(extension) => format!("{}_obfuscated.{}", stem.to_string_lossy(), extension.to_string_lossy()),
+        None => format!("{}_obfuscated", stem.to_string_lossy()),
+    };
+    parent.join(name)
 }
 
 fn main() {
@@ -82,17 +107,19 @@ fn main() {
         .version("0.1.0")
         .author("vasie1337")
         .arg(Arg::new("binary")
-            .help("Path to the PE binary file to obfuscate")
-            .long_help("Path to the Windows PE executable file (.exe, .dll) that will be obfuscated.\n\
-                       The file must be a valid x86-64 PE binary.")
+            .help("Path to the PE or ELF binary file to obfuscate")
+            .long_help("Path to the binary that will be obfuscated: a Windows PE image (.exe, .dll)\n\
+                       or an ELF executable or shared object. It must be built for x86-64.")
             .required(true)
             .value_name("BINARY_PATH")
             .index(1))
         .arg(Arg::new("pdb")
             .help("Path to the corresponding PDB debug file")
             .long_help("Path to the Program Database (.pdb) file that contains debug information\n\
-                       for the binary. This file is essential for the obfuscation process.")
-            .required(true)
+                       for a PE binary. Without it, functions are found from the binary itself:\n\
+                       .pdata, exports, entry point, TLS callbacks and the calls between them for PE,\n\
+                       the symbol table or .eh_frame for ELF.")
+            .required(false)
             .value_name("PDB_PATH")
             .index(2))
         .arg(Arg::new("output")
@@ -100,8 +127,65 @@ fn main() {
             .long("output")
             .help("Output path for the obfuscated binary")
             .long_help("Specify the output path for the obfuscated binary.\n\
-                       If not provided, defaults to '<input_name>_obfuscated.<ext>' in the same directory.")
+                       If not provided, defaults to '<input_name>_obfuscated' with the input's extension, if any,\n\
+                       in the same directory.")
             .value_name("OUTPUT_PATH"))
+        .arg(Arg::new("config")
+            .short('c')
+            .long("config")
+            .help("Pipeline configuration file (.toml or .json)")
+            .long_help("Path to a TOML or JSON file describing the passes to run, their order,\n\
+                       the number of iterations and per-pass parameters.\n\
+                       If not provided, the built-in default pipeline is used.")
+            .value_name("CONFIG_PATH"))
+        .arg(Arg::new("include")
+            .short('i')
+            .long("include")
+            .help("Only obfuscate functions matching this pattern (repeatable)")
+            .long_help("Only obfuscate functions matching the pattern. Can be given multiple times\n\
+                       and is added to the include list of the config file. Patterns are globs over\n\
+                       the demangled name ('license::*'), 're:<regex>', 'rva:<rva>' for the function\n\
+                       containing an address or 'rva:<start>-<end>' for functions starting in a range.")
+            .value_name("PATTERN")
+            .action(ArgAction::Append))
+        .arg(Arg::new("exclude")
+            .short('x')
+            .long("exclude")
+            .help("Never obfuscate functions matching this pattern (repeatable)")
+            .long_help("Leave functions matching the pattern untouched. Can be given multiple times\n\
+                       and is added to the exclude list of the config file. Uses the same pattern\n\
+                       syntax as --include.")
+            .value_name("PATTERN")
+            .action(ArgAction::Append))
+        .arg(Arg::new("seed")
+            .short('s')
+            .long("seed")
+            .help("Seed for the random choices made by the passes")
+            .long_help("Seed for the random choices made by the passes (decimal or 0x-prefixed hex).\n\
+                       The same input, configuration and seed produce an identical binary.\n\
+                       Overrides the seed of the config file; a random seed is used if neither is set.")
+            .value_name("SEED")
+            .value_parser(parse_seed))
+        .arg(Arg::new("verify")
+            .long("verify")
+            .help("Check every pass by emulating the functions before and after it")
+            .long_help("Emulate every function before the passes and after each of them from random\n\
+                       states and report the passes that changed its behavior. Enables the\n\
+                       [verification] table of the config file; its trials and max_steps still apply.")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("report")
+            .short('r')
+            .long("report")
+            .help("Write a JSON report of the run, including the seed")
+            .value_name("REPORT_PATH"))
+        .arg(Arg::new("map")
+            .short('m')
+            .long("map")
+            .help("Write a JSON symbol map of the obfuscated binary")
+            .long_help("Write a JSON map from the relocated code of the obfuscated binary back to the\n\
+                       original functions, instruction RVAs and source lines, for symbolicating crashes.\n\
+                       Keep it private: it undoes much of the obfuscation.")
+            .value_name("MAP_PATH"))
         .arg(Arg::new("verbose")
             .short('v')
             .long("verbose")
@@ -135,7 +219,7 @@ fn main() {
     Logger::ensure_init_with_level(log_level);
 
     let binary_path = Path::new(matches.get_one::<String>("binary").unwrap());
-    let pdb_path = Path::new(matches.get_one::<String>("pdb").unwrap());
+    let pdb_path = matches.get_one::<String>("pdb").map(Path::new);
 
     let output_path = if let Some(output) = matches.get_one::<String>("output") {
         PathBuf::from(output)
@@ -143,24 +227,68 @@ fn main() {
         generate_output_path(binary_path)
     };
 
-    info!("x86-64 PE Binary Obfuscator v0.1.0");
+    info!("x86-64 PE/ELF Binary Obfuscator v0.1.0");
 
     if let Err(e) = validate_file_exists(binary_path, "Binary") {
         error!("{e}");
         process::exit(1);
     }
 
-    if let Err(e) = validate_file_exists(pdb_path, "PDB") {
+    if let Some(pdb_path) = pdb_path
+        && let Err(e) = validate_file_exists(pdb_path, "PDB")
+    {
         error!("{e}");
         process::exit(1);
     }
 
+    let config = match matches.get_one::<String>("config") {
+        Some(config_path) => {
+            let config_path = Path::new(config_path);
+            if let Err(e) = validate_file_exists(config_path, "Config") {
+                error!("{e}");
+                process::exit(1);
+            }
+            match load_config(config_path) {
+                Ok(config) => {
+                    info!("Config file: {}", config_path.display());
+                    config
+                }
+                Err(e) => {
+                    error!("Failed to load config: {e}");
+                    process::exit(1);
+                }
+            }
+        }
+        None => ObfuscatorConfig::default(),
+    };
+
+    let mut config = config;
+    if let Some(patterns) = matches.get_many::<String>("include") {
+        config.selection.include.extend(patterns.cloned());
+    }
+    if let Some(patterns) = matches.get_many::<String>("exclude") {
+        config.selection.exclude.extend(patterns.cloned());
+    }
+    if let Some(&seed) = matches.get_one::<u64>("seed") {
+        config.seed = Some(seed);
+    }
+    if matches.get_flag("verify") {
+        config.verification.enabled = true;
+    }
+    if let Err(e) = config.validate() {
+        error!("Invalid configuration: {e}");
+        process::exit(1);
+    }
+
     info!("Input binary: {}", binary_path.display());
-    info!("PDB file: {}", pdb_path.display());
+    match pdb_path {
+        Some(pdb_path) => info!("PDB file: {}", pdb_path.display()),
+        None => info!("No PDB file, discovering functions from the binary"),
+    }
 
     info!("Loading input files...");
 
-    let pe_data = match load_file(binary_path) {
+    let binary_data = match load_file(binary_path) {
         Ok(data) => {
             info!(
                 "Loaded binary: {:.2} MB",
@@ -174,7 +302,7 @@ fn main() {
         }
     };
 
-    let pdb_data = match load_file(pdb_path) {
+    let pdb_data = pdb_path.map(|pdb_path| match load_file(pdb_path) {
         Ok(data) => {
             info!("Loaded PDB: {:.2} MB", data.len() as f64 / 1024.0 / 1024.0);
             data
@@ -183,14 +311,18 @@ fn main() {
             error!("Failed to load PDB: {e}");
             process::exit(1);
         }
-    };
+    });
 
     info!("Starting obfuscation process...");
 
-    let obfuscated_data = match core::run(&pe_data, &pdb_data) {
-        Ok(data) => {
+    let result = match &pdb_data {
+        Some(pdb_data) => core::run_with_config(&binary_data, pdb_data, &config),
+        None => core::run_without_pdb(&binary_data, &config),
+    };
+    let output = match result {
+        Ok(output) => {
             info!("Obfuscation completed successfully");
-            data
+            output
         }
         Err(e) => {
             error!("Obfuscation failed: {e}");
@@ -200,18 +332,48 @@ fn main() {
 
     info!("Saving obfuscated binary...");
 
+    let obfuscated_data = output.binary;
     if let Err(e) = save_file(&output_path, &obfuscated_data) {
         error!("Failed to save output: {e}");
         process::exit(1);
     }
 
+    if let Some(report_path) = matches.get_one::<String>("report") {
+        let report_path = Path::new(report_path);
+        let saved = output
+            .report
+            .to_json()
+            .map_err(|e| e.into())
+            .and_then(|json| save_file(report_path, json.as_bytes()));
+        if let Err(e) = saved {
+            error!("Failed to save report: {e}");
+            process::exit(1);
+        }
+        info!("Report written to {}", report_path.display());
+    }
+
+    if let Some(map_path) = matches.get_one::<String>("map") {
+        let map_path = Path::new(map_path);
+        let saved = output
+            .symbol_map
+            .to_json()
+            .map_err(|e| e.into())
+            .and_then(|json| save_file(map_path, json.as_bytes()));
+        if let Err(e) = saved {
+            error!("Failed to save symbol map: {e}");
+            process::exit(1);
+        }
+        info!("Symbol map written to {}", map_path.display());
+    }
+
     info!(
         "Successfully created obfuscated binary: {}",
         output_path.display()
     );
+    info!("Seed: {}", output.report.seed);
     info!(
         "Original size: {:.2} MB, Obfuscated size: {:.2} MB",
-        pe_data.len() as f64 / 1024.0 / 1024.0,
+        binary_data.len() as f64 / 1024.0 / 1024.0,
         obfuscated_data.len() as f64 / 1024.0 / 1024.0
     );
 }
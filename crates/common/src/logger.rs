@@ -103,10 +103,10 @@ impl Logger {
     fn extract_crate_name(file_path: &str) -> &str {
         let path_parts: Vec<&str> = file_path.split(['/', '\\']).collect();
 
-        if let Some(crates_index) = path_parts.iter().position(|&part| part == "crates") {
-            if crates_index + 1 < path_parts.len() {
-                return path_parts[crates_index + 1];
-            }
+        if let Some(crates_index) = path_parts.iter().position(|&part| part == "crates")
+            && crates_index + 1 < path_parts.len()
+        {
+            return path_parts[crates_index + 1];
         }
 
         if path_parts.len() >= 2 {
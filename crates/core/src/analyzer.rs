@@ -1,27 +1,51 @@
+use crate::format::BinaryImage;
 use crate::pdb::{PDBContext, PDBFunction};
-use crate::pe::PEContext;
+use crate::selection::FunctionSelection;
 use crate::{
     CoreContext,
     function::{Decodable, ObfuscatorFunction, StateManaged},
 };
-use common::info;
+use common::{debug, info};
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 
 pub struct AnalyzerContext {
-    pe_context: Rc<RefCell<PEContext>>,
+    image: Rc<RefCell<dyn BinaryImage>>,
     pdb_context: Rc<RefCell<PDBContext>>,
+    selection: FunctionSelection,
 }
 
 impl AnalyzerContext {
+    #[must_use]
     pub fn new(core_context: &CoreContext) -> Self {
+        Self::with_selection(core_context, FunctionSelection::default())
+    }
+
+    #[must_use]
+    pub fn with_selection(core_context: &CoreContext, selection: FunctionSelection) -> Self {
         Self {
-            pe_context: core_context.pe_context.clone(),
+            image: core_context.image.clone(),
             pdb_context: core_context.pdb_context.clone(),
+            selection,
         }
     }
 
-    fn filter_by_size(&self, pdb_functions: &[PDBFunction]) -> Vec<PDBFunction> {
+    fn filter_by_selection(&self, pdb_functions: Vec<PDBFunction>) -> Vec<PDBFunction> {
+        let total = pdb_functions.len();
+        let selected: Vec<PDBFunction> = pdb_functions
+            .into_iter()
+            .filter(|f| self.selection.is_selected(&f.name, f.rva, f.size))
+            .collect();
+        info!(
+            "Selection filter: {} functions remaining (filtered out {} by include/exclude patterns)",
+            selected.len(),
+            total - selected.len()
+        );
+        selected
+    }
+
+    fn filter_by_size(pdb_functions: &[PDBFunction]) -> Vec<PDBFunction> {
         let total = pdb_functions.len();
         let size_filtered: Vec<PDBFunction> = pdb_functions
             .iter()
@@ -37,18 +61,17 @@ impl AnalyzerContext {
         size_filtered
     }
 
-    fn decode_functions(&self, pdb_functions: Vec<PDBFunction>) -> Vec<ObfuscatorFunction> {
+    fn decode_functions(&self, pdb_functions: &[PDBFunction]) -> Vec<ObfuscatorFunction> {
         let mut failed_decodes = 0;
         let functions: Vec<ObfuscatorFunction> = pdb_functions
             .iter()
             .filter_map(|f| {
                 let mut func = ObfuscatorFunction::new(f);
-                match func.decode(&self.pe_context.borrow()) {
-                    Ok(_) => Some(func),
-                    Err(_) => {
-                        failed_decodes += 1;
-                        None
-                    }
+                if func.decode(&*self.image.borrow()).is_ok() {
+                    Some(func)
+                } else {
+                    failed_decodes += 1;
+                    None
                 }
             })
             .collect();
@@ -62,62 +85,149 @@ impl AnalyzerContext {
     }
 
     fn analyze_functions(&self, functions: &mut [ObfuscatorFunction]) -> Result<(), String> {
+        let image = self.image.borrow();
         for func in functions.iter_mut() {
+            func.red_zone = image.format().red_zone();
+            func.call_scratch = image.format().call_scratch();
             func.capture_original_state();
             func.build_branch_map();
+            func.build_rip_references();
+            func.collect_import_calls(&*image)?;
+            func.build_cfg();
         }
         Ok(())
     }
 
-    fn filter_by_exception(
+    fn filter_by_indirect_branches(
+        &self,
+        mut functions: Vec<ObfuscatorFunction>,
+    ) -> Vec<ObfuscatorFunction> {
+        let image = self.image.borrow();
+        let before = functions.len();
+        functions.retain_mut(|f| match f.resolve_jump_tables(&*image) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Skipping function {}: {e}", f.name);
+                false
+            }
+        });
+
+        let filtered_count = before - functions.len();
+        let table_count: usize = functions.iter().map(|f| f.jump_tables.len()).sum();
+        info!(
+            "Indirect branch filter: {} functions remaining (filtered out {} with unresolved jump tables, resolved {} tables)",
+            functions.len(),
+            filtered_count,
+            table_count
+        );
+        functions
+    }
+
+    fn filter_by_unwind_info(
         &self,
         mut functions: Vec<ObfuscatorFunction>,
+        pdb_functions: &[PDBFunction],
     ) -> Result<Vec<ObfuscatorFunction>, String> {
-        let exception_functions = self.pe_context.borrow().get_exception_functions()?;
+        let image = self.image.borrow();
+        // Statically linked handlers are only known by their symbol.
+        let handler_symbols: HashMap<u32, String> = pdb_functions
+            .iter()
+            .map(|f| (f.rva, f.name.clone()))
+            .collect();
+        let collect = image.unwind_collector(&handler_symbols)?;
         let before = functions.len();
-        functions.retain(|f| {
-            !exception_functions
-                .iter()
-                .any(|ef| ef.begin_address == f.rva)
+        functions.retain_mut(|f| match collect(f) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Skipping function {}: {e}", f.name);
+                false
+            }
         });
 
         let filtered_count = before - functions.len();
+        let region_count: usize = functions.iter().map(|f| f.unwind.region_count()).sum();
+        let handler_count = functions.iter().filter(|f| f.unwind.has_handlers()).count();
         info!(
-            "Exception filter: {} functions remaining (filtered out {} with unwind info)",
+            "Unwind filter: {} functions remaining (filtered out {} with unsupported unwind info, tracking {} regions, {} with exception handlers)",
             functions.len(),
-            filtered_count
+            filtered_count,
+            region_count,
+            handler_count
+        );
+        Ok(functions)
+    }
+
+    fn filter_by_base_relocations(
+        &self,
+        mut functions: Vec<ObfuscatorFunction>,
+    ) -> Result<Vec<ObfuscatorFunction>, String> {
+        let image = self.image.borrow();
+        let relocations = image.get_base_relocations()?;
+        let before = functions.len();
+        functions.retain_mut(|f| match f.collect_base_relocations(&*image, &relocations) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Skipping function {}: {e}", f.name);
+                false
+            }
+        });
+
+        let filtered_count = before - functions.len();
+        let reference_count: usize = functions.iter().map(|f| f.absolute_references.len()).sum();
+        info!(
+            "Base relocation filter: {} functions remaining (filtered out {} with unsupported relocations, tracking {} absolute references)",
+            functions.len(),
+            filtered_count,
+            reference_count
         );
         Ok(functions)
     }
 
+    /// # Errors
+    ///
+    /// Fails when the debug information cannot be read or when no function is
+    /// left to analyze.
     pub fn analyze(&self) -> Result<Vec<ObfuscatorFunction>, String> {
         let pdb_functions = self
             .pdb_context
             .borrow()
-            .get_functions()
-            .map_err(|e| e.to_string())?;
+            .get_functions()?;
 
-        info!("Retrieved {} functions from PDB", pdb_functions.len());
+        info!("Retrieved {} functions", pdb_functions.len());
 
-        let size_filtered = self.filter_by_size(&pdb_functions);
+        let size_filtered = Self::filter_by_size(&pdb_functions);
         if size_filtered.is_empty() {
             return Err("No functions to analyze".to_string());
         }
 
-        let decoded_functions = self.decode_functions(size_filtered);
+        let selected = self.filter_by_selection(size_filtered);
+        if selected.is_empty() {
+            return Err("No functions match the selection".to_string());
+        }
+
+        let decoded_functions = self.decode_functions(&selected);
         if decoded_functions.is_empty() {
             return Err("No functions to analyze".to_string());
         }
 
-        let mut functions = self.filter_by_exception(decoded_functions)?;
+        let mut functions = decoded_functions;
+
+        self.analyze_functions(&mut functions)?;
+
+        let functions = self.filter_by_indirect_branches(functions);
         if functions.is_empty() {
             return Err("No functions to analyze".to_string());
         }
 
-        // DEBUG: only 1 function
-        //functions = functions.iter().filter(|f| f.name.contains("__scrt_fastfail")).cloned().collect();
+        let functions = self.filter_by_unwind_info(functions, &pdb_functions)?;
+        if functions.is_empty() {
+            return Err("No functions to analyze".to_string());
+        }
 
-        self.analyze_functions(&mut functions)?;
+        let functions = self.filter_by_base_relocations(functions)?;
+        if functions.is_empty() {
+            return Err("No functions to analyze".to_string());
+        }
 
         info!(
             "Analysis completed: {} functions ready for obfuscation",
@@ -1,6 +1,6 @@
 use crate::{function::ObfuscatorFunction, instruction::InstructionWithId};
 use common::{debug, warn};
-use iced_x86::*;
+use iced_x86::{FlowControl, Instruction, OpKind};
 
 #[derive(Clone, Debug)]
 pub struct BranchInfo {
@@ -12,25 +12,35 @@ pub struct BranchInfo {
 pub struct BranchManager;
 
 impl BranchManager {
-    pub fn new() -> Self {
+    #[must_use]
+    pub const fn new() -> Self {
         Self
     }
 
-    pub fn get_branch_target(instruction: &Instruction) -> u32 {
-        instruction.near_branch_target() as u32
+    /// Target of a near branch, `None` when it lies outside the 4 GiB an
+    /// image spans.
+    #[must_use]
+    pub fn get_branch_target(instruction: &Instruction) -> Option<u32> {
+        u32::try_from(instruction.near_branch_target()).ok()
     }
 
-    pub fn set_branch_target(instruction: &mut Instruction, target_rva: u32) -> Result<(), String> {
+    /// # Errors
+    ///
+    /// Fails when the instruction is not a near branch.
+    pub fn set_branch_target(instruction: &mut Instruction, target: u64) -> Result<(), String> {
         let op_kind = instruction.op0_kind();
         match op_kind {
-            OpKind::NearBranch16 => instruction.set_near_branch16(target_rva as u16),
-            OpKind::NearBranch32 => instruction.set_near_branch32(target_rva),
-            OpKind::NearBranch64 => instruction.set_near_branch64(target_rva as u64),
+            OpKind::NearBranch16 => instruction
+                .set_near_branch16(u16::try_from(target).map_err(|_| format!("Branch target {target:#x} out of range"))?),
+            OpKind::NearBranch32 => instruction
+                .set_near_branch32(u32::try_from(target).map_err(|_| format!("Branch target {target:#x} out of range"))?),
+            OpKind::NearBranch64 => instruction.set_near_branch64(target),
             _ => return Err(format!("Invalid branch operand kind: {op_kind:#?}")),
         }
         Ok(())
     }
 
+    #[must_use]
     pub fn build_branch_map(
         &self,
         instructions: &[InstructionWithId],
@@ -42,11 +52,14 @@ impl BranchManager {
         for inst_with_id in instructions {
             let instruction = &inst_with_id.instruction;
 
-            if !self.is_branch_instruction(instruction) {
+            if !Self::is_branch_instruction(instruction) {
                 continue;
             }
 
-            let target_rva = Self::get_branch_target(instruction);
+            let Some(target_rva) = Self::get_branch_target(instruction) else {
+                debug!("Branch at RVA {:#x} targets outside the image", instruction.ip());
+                continue;
+            };
             debug!("{instruction}");
             debug!("Target RVA: {target_rva:#x}");
 
@@ -61,7 +74,7 @@ impl BranchManager {
 
             if let Some(target_inst) = instructions
                 .iter()
-                .find(|inst| inst.instruction.ip() == target_rva as u64)
+                .find(|inst| inst.instruction.ip() == u64::from(target_rva))
             {
                 debug!("Target instruction: {:#?}", target_inst.instruction);
                 debug!("================================================");
@@ -69,7 +82,7 @@ impl BranchManager {
                 branch_map.push(BranchInfo {
                     source_id: inst_with_id.id,
                     target_id: target_inst.id,
-                    original_target: target_rva as u64,
+                    original_target: u64::from(target_rva),
                 });
             } else {
                 warn!(
@@ -83,6 +96,9 @@ impl BranchManager {
         branch_map
     }
 
+    /// # Errors
+    ///
+    /// Fails when a branch target no longer exists or cannot be set.
     pub fn fix_branches(
         &self,
         instructions: &mut [InstructionWithId],
@@ -99,7 +115,7 @@ impl BranchManager {
                     )
                 })?;
 
-            let target_ip = target_inst.instruction.ip() as u32;
+            let target_ip = target_inst.instruction.ip();
             let target_str = target_inst.instruction.to_string();
 
             let source_inst = instructions
@@ -127,7 +143,7 @@ impl BranchManager {
         Ok(())
     }
 
-    fn is_branch_instruction(&self, instruction: &Instruction) -> bool {
+    fn is_branch_instruction(instruction: &Instruction) -> bool {
         matches!(
             instruction.flow_control(),
             FlowControl::ConditionalBranch | FlowControl::UnconditionalBranch
@@ -142,16 +158,19 @@ impl Default for BranchManager {
 }
 
 impl ObfuscatorFunction {
-    pub fn get_branch_target(&self, instruction: &Instruction) -> u32 {
+    pub fn get_branch_target(&self, instruction: &Instruction) -> Option<u32> {
         BranchManager::get_branch_target(instruction)
     }
 
+    /// # Errors
+    ///
+    /// Fails when the instruction is not a near branch.
     pub fn set_branch_target(
         &self,
         instruction: &mut Instruction,
-        target_rva: u32,
+        target: u64,
     ) -> Result<(), String> {
-        BranchManager::set_branch_target(instruction, target_rva)
+        BranchManager::set_branch_target(instruction, target)
     }
 
     pub fn build_branch_map(&mut self) {
@@ -159,6 +178,9 @@ impl ObfuscatorFunction {
         self.branch_map = branch_manager.build_branch_map(&self.instructions, self.rva, self.size);
     }
 
+    /// # Errors
+    ///
+    /// Fails when a branch target no longer exists or cannot be set.
     pub fn fix_branches(&mut self) -> Result<(), String> {
         let branch_manager = BranchManager::new();
         branch_manager.fix_branches(&mut self.instructions, &self.branch_map)
@@ -0,0 +1,475 @@
+use crate::branches::BranchInfo;
+use crate::function::ObfuscatorFunction;
+use crate::instruction::InstructionWithId;
+use crate::jump_tables::JumpTable;
+use common::debug;
+use iced_x86::FlowControl;
+use std::collections::HashMap;
+use std::ops::Range;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Unconditional,
+    Conditional,
+    JumpTable,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Edge {
+    pub target: usize,
+    pub kind: EdgeKind,
+}
+
+#[derive(Clone, Debug)]
+pub struct BasicBlock {
+    pub id: usize,
+    pub range: Range<usize>,
+    pub successors: Vec<Edge>,
+    pub predecessors: Vec<usize>,
+}
+
+impl BasicBlock {
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.range.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.range.is_empty()
+    }
+
+    #[must_use]
+    pub const fn first_index(&self) -> usize {
+        self.range.start
+    }
+
+    #[must_use]
+    pub const fn last_index(&self) -> usize {
+        self.range.end - 1
+    }
+
+    #[must_use]
+    pub const fn is_exit(&self) -> bool {
+        self.successors.is_empty()
+    }
+
+    #[must_use]
+    pub fn fallthrough(&self) -> Option<usize> {
+        self.successors
+            .iter()
+            .find(|edge| edge.kind == EdgeKind::Fallthrough)
+            .map(|edge| edge.target)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DominatorTree {
+    idom: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+}
+
+impl DominatorTree {
+    fn build(blocks: &[BasicBlock], entry: usize, rpo: &[usize]) -> Self {
+        let mut idom: Vec<Option<usize>> = vec![None; blocks.len()];
+        if blocks.is_empty() {
+            return Self::default();
+        }
+
+        let mut rpo_index = vec![usize::MAX; blocks.len()];
+        for (index, &block) in rpo.iter().enumerate() {
+            rpo_index[block] = index;
+        }
+
+        idom[entry] = Some(entry);
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in rpo.iter().skip(1) {
+                let mut new_idom = None;
+                for &pred in &blocks[block].predecessors {
+                    if idom[pred].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(new_idom.map_or(pred, |current| Self::intersect(&idom, &rpo_index, pred, current)));
+                }
+
+                if new_idom.is_some() && idom[block] != new_idom {
+                    idom[block] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut children = vec![Vec::new(); blocks.len()];
+        for (block, dominator) in idom.iter().enumerate() {
+            if let Some(dominator) = *dominator
+                && dominator != block
+            {
+                children[dominator].push(block);
+            }
+        }
+
+        Self { idom, children }
+    }
+
+    fn intersect(idom: &[Option<usize>], rpo_index: &[usize], a: usize, b: usize) -> usize {
+        let mut finger1 = a;
+        let mut finger2 = b;
+        while finger1 != finger2 {
+            while rpo_index[finger1] > rpo_index[finger2] {
+                finger1 = idom[finger1].unwrap_or(finger2);
+            }
+            while rpo_index[finger2] > rpo_index[finger1] {
+                finger2 = idom[finger2].unwrap_or(finger1);
+            }
+        }
+        finger1
+    }
+
+    #[must_use]
+    pub fn immediate_dominator(&self, block: usize) -> Option<usize> {
+        match self.idom.get(block).copied().flatten() {
+            Some(dominator) if dominator != block => Some(dominator),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn children(&self, block: usize) -> &[usize] {
+        self.children.get(block).map_or(&[], |c| c.as_slice())
+    }
+
+    #[must_use]
+    pub fn is_reachable(&self, block: usize) -> bool {
+        self.idom.get(block).is_some_and(Option::is_some)
+    }
+
+    #[must_use]
+    pub fn dominates(&self, dominator: usize, block: usize) -> bool {
+        if !self.is_reachable(block) {
+            return false;
+        }
+
+        let mut current = block;
+        loop {
+            if current == dominator {
+                return true;
+            }
+            match self.immediate_dominator(current) {
+                Some(next) => current = next,
+                None => return false,
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ControlFlowGraph {
+    pub blocks: Vec<BasicBlock>,
+    pub entry: usize,
+    pub exits: Vec<usize>,
+    pub entry_points: Vec<usize>,
+    pub dominators: DominatorTree,
+}
+
+impl ControlFlowGraph {
+    #[must_use]
+    pub fn build(
+        instructions: &[InstructionWithId],
+        branch_map: &[BranchInfo],
+        jump_tables: &[JumpTable],
+        entry_point_ids: &[usize],
+    ) -> Self {
+        if instructions.is_empty() {
+            return Self::default();
+        }
+
+        let mut first_index_of_id: HashMap<usize, usize> = HashMap::new();
+        for (index, inst) in instructions.iter().enumerate() {
+            first_index_of_id.entry(inst.id).or_insert(index);
+        }
+
+        let branch_targets: HashMap<usize, usize> = branch_map
+            .iter()
+            .filter_map(|branch| {
+                first_index_of_id
+                    .get(&branch.target_id)
+                    .map(|&target| (branch.source_id, target))
+            })
+            .collect();
+
+        let table_targets: HashMap<usize, Vec<usize>> = jump_tables
+            .iter()
+            .map(|table| {
+                let mut targets: Vec<usize> = table
+                    .target_ids
+                    .iter()
+                    .filter_map(|id| first_index_of_id.get(id).copied())
+                    .collect();
+                targets.sort_unstable();
+                targets.dedup();
+                (table.jump_id, targets)
+            })
+            .collect();
+
+        let entry_point_indices: Vec<usize> = entry_point_ids
+            .iter()
+            .filter_map(|id| first_index_of_id.get(id).copied())
+            .collect();
+
+        let mut leaders = vec![false; instructions.len()];
+        leaders[0] = true;
+        for &target in branch_targets
+            .values()
+            .chain(table_targets.values().flatten())
+            .chain(&entry_point_indices)
+        {
+            leaders[target] = true;
+        }
+        for (index, inst) in instructions.iter().enumerate() {
+            if Self::is_terminator(inst) && index + 1 < instructions.len() {
+                leaders[index + 1] = true;
+            }
+        }
+
+        let (mut blocks, block_of_index) = Self::split_blocks(&leaders);
+
+        for block_id in 0..blocks.len() {
+            let last_index = blocks[block_id].last_index();
+            let last = &instructions[last_index];
+            let next_block = (last_index + 1 < instructions.len())
+                .then(|| block_of_index[last_index + 1]);
+            let taken = branch_targets
+                .get(&last.id)
+                .map(|&target| block_of_index[target]);
+
+            let successors =
+                Self::edges_from(last, taken, next_block, &table_targets, &block_of_index);
+
+            for edge in &successors {
+                if !blocks[edge.target].predecessors.contains(&block_id) {
+                    blocks[edge.target].predecessors.push(block_id);
+                }
+            }
+            blocks[block_id].successors = successors;
+        }
+
+        let exits = blocks
+            .iter()
+            .filter(|block| block.is_exit())
+            .map(|block| block.id)
+            .collect();
+
+        let mut entry_points: Vec<usize> = entry_point_indices
+            .iter()
+            .map(|&index| block_of_index[index])
+            .collect();
+        entry_points.sort_unstable();
+        entry_points.dedup();
+
+        let mut cfg = Self {
+            blocks,
+            entry: 0,
+            exits,
+            entry_points,
+            dominators: DominatorTree::default(),
+        };
+        let rpo = cfg.reverse_postorder();
+        cfg.dominators = DominatorTree::build(&cfg.blocks, cfg.entry, &rpo);
+        cfg
+    }
+
+    /// Cuts the instructions into blocks at the leaders. Returns the blocks
+    /// and the block of every instruction.
+    fn split_blocks(leaders: &[bool]) -> (Vec<BasicBlock>, Vec<usize>) {
+        let mut blocks: Vec<BasicBlock> = Vec::new();
+        let mut block_of_index = vec![0; leaders.len()];
+        let mut start = 0;
+        for index in 1..=leaders.len() {
+            if index == leaders.len() || leaders[index] {
+                let id = blocks.len();
+                block_of_index[start..index].fill(id);
+                blocks.push(BasicBlock {
+                    id,
+                    range: start..index,
+                    successors: Vec::new(),
+                    predecessors: Vec::new(),
+                });
+                start = index;
+            }
+        }
+        (blocks, block_of_index)
+    }
+
+    /// Edges leaving a block that ends in `last`, which branches to the
+    /// block `taken` and otherwise falls through to `next_block`.
+    fn edges_from(
+        last: &InstructionWithId,
+        taken: Option<usize>,
+        next_block: Option<usize>,
+        table_targets: &HashMap<usize, Vec<usize>>,
+        block_of_index: &[usize],
+    ) -> Vec<Edge> {
+        let mut successors = Vec::new();
+        match last.instruction.flow_control() {
+            FlowControl::ConditionalBranch => {
+                if let Some(target) = taken {
+                    successors.push(Edge {
+                        target,
+                        kind: EdgeKind::Conditional,
+                    });
+                }
+                if let Some(next) = next_block {
+                    successors.push(Edge {
+                        target: next,
+                        kind: EdgeKind::Fallthrough,
+                    });
+                }
+            }
+            FlowControl::UnconditionalBranch => {
+                if let Some(target) = taken {
+                    successors.push(Edge {
+                        target,
+                        kind: EdgeKind::Unconditional,
+                    });
+                }
+            }
+            FlowControl::IndirectBranch => {
+                for &target in table_targets.get(&last.id).into_iter().flatten() {
+                    let target = block_of_index[target];
+                    if !successors.iter().any(|edge: &Edge| edge.target == target) {
+                        successors.push(Edge {
+                            target,
+                            kind: EdgeKind::JumpTable,
+                        });
+                    }
+                }
+            }
+            FlowControl::Return
+            | FlowControl::Interrupt
+            | FlowControl::Exception => {}
+            _ => {
+                if let Some(next) = next_block {
+                    successors.push(Edge {
+                        target: next,
+                        kind: EdgeKind::Fallthrough,
+                    });
+                }
+            }
+        }
+        successors
+    }
+
+    fn is_terminator(instruction: &InstructionWithId) -> bool {
+        matches!(
+            instruction.instruction.flow_control(),
+            FlowControl::ConditionalBranch
+                | FlowControl::UnconditionalBranch
+                | FlowControl::IndirectBranch
+                | FlowControl::Return
+                | FlowControl::Interrupt
+                | FlowControl::Exception
+        )
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    #[must_use]
+    pub fn block(&self, id: usize) -> Option<&BasicBlock> {
+        self.blocks.get(id)
+    }
+
+    #[must_use]
+    pub fn block_containing(&self, instruction_index: usize) -> Option<&BasicBlock> {
+        let position = self
+            .blocks
+            .partition_point(|block| block.range.end <= instruction_index);
+        self.blocks
+            .get(position)
+            .filter(|block| block.range.contains(&instruction_index))
+    }
+
+    pub fn successors(&self, id: usize) -> impl Iterator<Item = usize> + '_ {
+        self.blocks
+            .get(id)
+            .into_iter()
+            .flat_map(|block| block.successors.iter().map(|edge| edge.target))
+    }
+
+    #[must_use]
+    pub fn predecessors(&self, id: usize) -> &[usize] {
+        self.blocks
+            .get(id)
+            .map_or(&[], |block| block.predecessors.as_slice())
+    }
+
+    #[must_use]
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        if self.blocks.is_empty() {
+            return Vec::new();
+        }
+
+        let mut visited = vec![false; self.blocks.len()];
+        let mut postorder = Vec::with_capacity(self.blocks.len());
+        let mut stack = vec![(self.entry, 0usize)];
+        visited[self.entry] = true;
+
+        while let Some((block, next_successor)) = stack.pop() {
+            let successors = &self.blocks[block].successors;
+            if let Some(edge) = successors.get(next_successor) {
+                stack.push((block, next_successor + 1));
+                if !visited[edge.target] {
+                    visited[edge.target] = true;
+                    stack.push((edge.target, 0));
+                }
+            } else {
+                postorder.push(block);
+            }
+        }
+
+        postorder.reverse();
+        postorder
+    }
+
+    #[must_use]
+    pub fn unreachable_blocks(&self) -> Vec<usize> {
+        (0..self.blocks.len())
+            .filter(|&block| !self.dominators.is_reachable(block))
+            .collect()
+    }
+}
+
+impl ObfuscatorFunction {
+    pub fn build_cfg(&mut self) {
+        let mut entry_points = self.unwind.entry_points();
+        entry_points.extend(
+            self.rip_references
+                .iter()
+                .filter_map(|reference| reference.target_id),
+        );
+        entry_points.extend(
+            self.absolute_references
+                .iter()
+                .filter_map(|reference| reference.target_id),
+        );
+        self.cfg = ControlFlowGraph::build(
+            &self.instructions,
+            &self.branch_map,
+            &self.jump_tables,
+            &entry_points,
+        );
+        debug!(
+            "Built CFG for function {}: {} blocks, {} exits, {} unreachable",
+            self.name,
+            self.cfg.blocks.len(),
+            self.cfg.exits.len(),
+            self.cfg.unreachable_blocks().len()
+        );
+    }
+}
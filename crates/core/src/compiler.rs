@@ -1,63 +1,376 @@
+use crate::config::LayoutConfig;
+use crate::format::{BinaryImage, SectionAccess};
 use crate::function::{AddressUpdatable, Encodable, ObfuscatorFunction, StateManaged};
-use crate::pe::PEContext;
+use crate::layout::CodeLayout;
+use crate::pe::relocation::BaseRelocation;
+use crate::references::ReferenceReport;
+use crate::unwind::{RelocatedFrame, RelocatedUnwind};
+use crate::vm::interpreter;
+use common::{debug, warn};
 use std::cell::RefCell;
+use std::ops::Range;
 use std::rc::Rc;
 
 pub struct CompilerContext {
-    pe_context: Rc<RefCell<PEContext>>,
+    image: Rc<RefCell<dyn BinaryImage>>,
+    reference_report: ReferenceReport,
+    layout: LayoutConfig,
+    seed: u64,
+}
+
+/// Encoded chunks of the functions that survived placement.
+struct PlacedCode {
+    bytes: Vec<u8>,
+    unwind_entries: Vec<RelocatedUnwind>,
+    frames: Vec<RelocatedFrame>,
+    base_relocations: Vec<BaseRelocation>,
+    report: ReferenceReport,
 }
 
 impl CompilerContext {
-    pub fn new(pe_context: Rc<RefCell<PEContext>>) -> Self {
-        Self { pe_context }
+    pub fn new(image: Rc<RefCell<dyn BinaryImage>>) -> Self {
+        Self::with_layout(image, LayoutConfig::default(), 0)
+    }
+
+    /// `seed` drives the order of the interleaved chunks.
+    pub fn with_layout(image: Rc<RefCell<dyn BinaryImage>>, layout: LayoutConfig, seed: u64) -> Self {
+        Self {
+            image,
+            reference_report: ReferenceReport::default(),
+            layout,
+            seed,
+        }
     }
 
+    /// # Errors
+    ///
+    /// Fails when the new section cannot be added or a function cannot be
+    /// encoded, relocated or bound to its data.
     pub fn compile_functions(
         &mut self,
         functions: &mut [ObfuscatorFunction],
     ) -> Result<Vec<u8>, String> {
+        self.emit_import_table(functions)?;
+        self.emit_virtual_machines(functions)?;
+
         let base_rva = self
-            .pe_context
+            .image
             .borrow()
-            .get_next_section_rva()
+            .next_section_rva()
             .map_err(|e| format!("Failed to get section RVA: {e}"))?;
 
-        let (merged_bytes, _) =
-            functions
-                .iter_mut()
-                .try_fold((Vec::new(), base_rva), |(mut bytes, rva), func| {
-                    let encoded = func
-                        .encode(rva)
-                        .map_err(|e| format!("Failed to encode {}: {e}", func.name))?;
+        let image_base = self.image.borrow().image_base()?;
 
-                    bytes.extend_from_slice(&encoded);
-                    func.update_rva(rva as u32);
-                    func.update_size(encoded.len() as u32);
+        let mut layout = CodeLayout::new(functions, &self.layout, self.seed);
+        let placed = loop {
+            if let Some(placed) = self.place_functions(functions, &mut layout, base_rva, image_base) {
+                break placed;
+            }
+        };
+        self.reference_report.merge(placed.report);
 
-                    Ok::<_, String>((bytes, rva + encoded.len() as u32))
-                })?;
+        let addresses = layout.addresses(base_rva);
+        for (index, func) in functions.iter_mut().enumerate() {
+            let chunks = layout.chunks_of(index);
+            let Some(&entry) = chunks.first() else {
+                continue;
+            };
+            func.update_rva(addresses[entry]);
+            func.update_size(chunks.iter().map(|&chunk| layout.chunks()[chunk].slot).sum());
+        }
 
+        self.write_vm_bytecode(functions)?;
         self.trash_old_function_bytes(functions)?;
         self.patch_function_redirects(functions)?;
+        self.rewrite_jump_tables(functions)?;
 
-        self.pe_context
+        let (code_rva, _) = self
+            .image
             .borrow_mut()
-            .create_executable_section(".vasie", &merged_bytes)
+            .add_section_with(".vasie", &placed.bytes, SectionAccess::Execute)
             .map_err(|e| format!("Failed to create section: {e}"))?;
+        if code_rva != base_rva {
+            return Err(format!("Code section placed at {code_rva:#x} instead of {base_rva:#x}"));
+        }
+
+        self.image
+            .borrow_mut()
+            .emit_unwind_info(&placed.unwind_entries, &placed.frames)?;
+        self.emit_base_relocations(functions, &placed.base_relocations)?;
 
-        Ok(merged_bytes)
+        Ok(placed.bytes)
+    }
+
+    /// Encodes every function at the addresses of its chunks. A chunk whose
+    /// encoding outgrew its slot enlarges the slot, and a function that
+    /// cannot be encoded or whose RIP-relative operands no longer reach their
+    /// data is left at its original location; both move the chunks behind
+    /// them, so `None` asks for another round. Chunks smaller than their
+    /// slot are padded with int3, as none of them falls through.
+    #[allow(clippy::cast_possible_truncation, reason = "a chunk holds at most a function's instructions")]
+    fn place_functions(
+        &mut self,
+        functions: &mut [ObfuscatorFunction],
+        layout: &mut CodeLayout,
+        base_rva: u32,
+        image_base: u64,
+    ) -> Option<PlacedCode> {
+        let addresses = layout.addresses(base_rva);
+        let mut encoded = vec![Vec::new(); layout.chunks().len()];
+        for (index, func) in functions.iter_mut().enumerate() {
+            let chunks = layout.chunks_of(index);
+            if chunks.is_empty() {
+                continue;
+            }
+            let placements: Vec<(Range<usize>, u32)> = chunks
+                .iter()
+                .map(|&chunk| (layout.chunks()[chunk].range.clone(), addresses[chunk]))
+                .collect();
+            match func.encode_chunks(&placements) {
+                Ok(code) => {
+                    for (&chunk, code) in chunks.iter().zip(code) {
+                        encoded[chunk] = code;
+                    }
+                }
+                Err(e) => {
+                    self.leave_in_place(func, layout, index, &e);
+                    return None;
+                }
+            }
+        }
+
+        let sizes: Vec<u32> = encoded.iter().map(|code| code.len() as u32).collect();
+        if !layout.fit(&sizes) {
+            debug!("Code layout grew to {} bytes, placing again", layout.size());
+            return None;
+        }
+
+        let mut bytes = vec![0xCC; layout.size()];
+        for (code, &address) in encoded.iter().zip(&addresses) {
+            let offset = (address - base_rva) as usize;
+            bytes[offset..offset + code.len()].copy_from_slice(code);
+        }
+
+        let mut placed = PlacedCode {
+            bytes: Vec::new(),
+            unwind_entries: Vec::new(),
+            frames: Vec::new(),
+            base_relocations: Vec::new(),
+            report: ReferenceReport::default(),
+        };
+        for (index, func) in functions.iter_mut().enumerate() {
+            let chunks = layout.chunks_of(index);
+            if chunks.is_empty() {
+                continue;
+            }
+
+            let report = func.validate_rip_references(&bytes, base_rva);
+            if !report.unresolved.is_empty() {
+                self.reference_report.merge(report);
+                self.leave_in_place(
+                    func,
+                    layout,
+                    index,
+                    "RIP-relative references could not be preserved",
+                );
+                return None;
+            }
+            placed.report.merge(report);
+
+            let relocations = match func.relocate_base_relocations(image_base, &mut bytes, base_rva) {
+                Ok(relocations) => relocations,
+                Err(e) => {
+                    self.leave_in_place(func, layout, index, &e);
+                    return None;
+                }
+            };
+
+            let ranges: Vec<Range<u32>> = chunks
+                .iter()
+                .map(|&chunk| addresses[chunk]..addresses[chunk] + sizes[chunk])
+                .collect();
+            let unwind = match func.relocate_unwind_info(&ranges) {
+                Ok(unwind) => unwind,
+                Err(e) => {
+                    self.leave_in_place(func, layout, index, &e);
+                    return None;
+                }
+            };
+            let frames = match func.relocate_call_frames(&ranges) {
+                Ok(frames) => frames,
+                Err(e) => {
+                    self.leave_in_place(func, layout, index, &e);
+                    return None;
+                }
+            };
+            placed.frames.extend(frames);
+            let chain_base = placed.unwind_entries.len();
+            placed.unwind_entries.extend(unwind.into_iter().map(|mut entry| {
+                entry.chained_parent = entry.chained_parent.map(|parent| parent + chain_base);
+                entry
+            }));
+            placed.base_relocations.extend(relocations);
+        }
+
+        placed.bytes = bytes;
+        Some(placed)
+    }
+
+    fn leave_in_place(
+        &mut self,
+        func: &ObfuscatorFunction,
+        layout: &mut CodeLayout,
+        index: usize,
+        reason: &str,
+    ) {
+        warn!("Leaving function {} in place: {reason}", func.name);
+        self.reference_report.skipped_functions.push(func.name.clone());
+        layout.remove_function(index);
+    }
+
+    /// Gives every rewritten import call an entry in a writable table, so
+    /// that decompilers cannot fold the encoded offsets back into the IAT
+    /// slot, and points its `lea` at the entry.
+    fn emit_import_table(&self, functions: &mut [ObfuscatorFunction]) -> Result<(), String> {
+        let count: usize = functions.iter().map(|f| f.import_thunks.len()).sum();
+        if count == 0 {
+            return Ok(());
+        }
+
+        let size = u32::try_from(count * 8).map_err(|_| "Too many import calls".to_string())?;
+        let (table_rva, _) = self
+            .image
+            .borrow_mut()
+            .add_section(".vimp", size, SectionAccess::ReadWrite)
+            .map_err(|e| format!("Failed to create import table: {e}"))?;
+
+        let mut table = Vec::with_capacity(count * 8);
+        let mut entry_rva = table_rva;
+        for func in functions.iter_mut() {
+            for thunk in std::mem::take(&mut func.import_thunks) {
+                table.extend_from_slice(&thunk.encode_entry(entry_rva).to_le_bytes());
+                func.retarget_import_thunk(&thunk, entry_rva);
+                entry_rva += 8;
+            }
+        }
+
+        self.image.borrow_mut().write_data_at_rva(table_rva, &table)?;
+        debug!("Wrote import table with {count} entries at {table_rva:#x}");
+        Ok(())
+    }
+
+    /// Lays out the interpreter and bytecode of every virtualized function
+    /// in the `.vmp` section, writes the interpreters and points the entry
+    /// stubs at them. The bytecode follows once the exit targets are known.
+    fn emit_virtual_machines(&self, functions: &mut [ObfuscatorFunction]) -> Result<(), String> {
+        let mut sizes = Vec::new();
+        for func in functions.iter() {
+            if let Some(program) = &func.vm {
+                let interpreter = interpreter::assemble(&program.layout, 0)?;
+                let code_size = u32::try_from(interpreter.code.len()).map_err(|_| "VM interpreter too large")?;
+                let bytecode_size = u32::try_from(program.bytecode_size()).map_err(|_| "VM bytecode too large")?;
+                sizes.push((code_size, bytecode_size));
+            }
+        }
+        if sizes.is_empty() {
+            return Ok(());
+        }
+
+        let total = sizes
+            .iter()
+            .try_fold(0u32, |total, (code, bytecode)| {
+                code.checked_add(*bytecode)?.checked_next_multiple_of(16)?.checked_add(total)
+            })
+            .ok_or("VM section too large")?;
+        let (section_rva, _) = self
+            .image
+            .borrow_mut()
+            .add_section(".vmp", total, SectionAccess::Execute)
+            .map_err(|e| format!("Failed to create VM section: {e}"))?;
+
+        let mut rva = section_rva;
+        let programs = functions.iter_mut().filter(|func| func.vm.is_some());
+        for (func, (code_size, bytecode_size)) in programs.zip(sizes) {
+            let layout = &func.vm.as_ref().expect("filtered on programs").layout;
+            let interpreter = interpreter::assemble(layout, rva)?;
+            if interpreter.code.len() != code_size as usize {
+                return Err(format!("VM interpreter of {} changed size with its RVA", func.name));
+            }
+            self.image.borrow_mut().write_data_at_rva(rva, &interpreter.code)?;
+            func.vm.as_mut().expect("filtered on programs").interpreter_rva = rva;
+            func.bind_vm_stubs(rva + interpreter.entry, rva + code_size)?;
+            debug!(
+                "Wrote VM interpreter of {} at {rva:#x} ({code_size} bytes, {bytecode_size} bytes of bytecode)",
+                func.name
+            );
+            rva += (code_size + bytecode_size).next_multiple_of(16);
+        }
+        Ok(())
+    }
+
+    /// Bytecode of functions left in place is never run and stays zeroed.
+    fn write_vm_bytecode(&self, functions: &[ObfuscatorFunction]) -> Result<(), String> {
+        for func in functions.iter().filter(|f| f.is_relocated()) {
+            if let Some(bytecode) = func.encode_vm_bytecode()? {
+                let rva = func.vm.as_ref().map_or(0, |program| program.bytecode_rva);
+                self.image.borrow_mut().write_data_at_rva(rva, &bytecode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the base relocation table without the entries that covered
+    /// the original bodies, which now hold the redirect and 0xCC filler, and
+    /// with the entries of the relocated code. Images without relocations
+    /// are loaded at their preferred base and need nothing.
+    fn emit_base_relocations(
+        &self,
+        functions: &[ObfuscatorFunction],
+        relocated: &[BaseRelocation],
+    ) -> Result<(), String> {
+        let mut image = self.image.borrow_mut();
+        let original = image.get_base_relocations()?;
+        if original.is_empty() {
+            return Ok(());
+        }
+
+        let original_count = original.len();
+        let moved_ranges: Vec<(u32, u32)> = functions
+            .iter()
+            .filter(|f| f.is_relocated())
+            .map(|f| (f.get_original_rva(), f.get_original_rva() + f.get_original_size()))
+            .collect();
+        let mut relocations: Vec<BaseRelocation> = original
+            .into_iter()
+            .filter(|relocation| {
+                !moved_ranges
+                    .iter()
+                    .any(|&(begin, end)| (begin..end).contains(&relocation.rva))
+            })
+            .collect();
+        let dropped = original_count - relocations.len();
+        relocations.extend_from_slice(relocated);
+
+        image.set_base_relocations(&relocations)?;
+
+        debug!(
+            "Rebuilt base relocations with {} entries ({dropped} dropped, {} added)",
+            relocations.len(),
+            relocated.len()
+        );
+        Ok(())
     }
 
     fn trash_old_function_bytes(&self, functions: &[ObfuscatorFunction]) -> Result<(), String> {
         functions
             .iter()
-            .filter(|f| f.get_original_size() > 5)
+            .filter(|f| f.is_relocated() && f.get_original_size() > 5)
             .try_for_each(|func| {
                 let rva = func.get_original_rva() + 5;
                 let size = func.get_original_size() - 5;
                 let bytes = vec![0xCC; size as usize];
 
-                self.pe_context
+                self.image
                     .borrow_mut()
                     .write_data_at_rva(rva, &bytes)
                     .map_err(|e| format!("Failed to zero bytes at {rva:#x}: {e}"))
@@ -65,21 +378,35 @@ impl CompilerContext {
     }
 
     fn patch_function_redirects(&self, functions: &[ObfuscatorFunction]) -> Result<(), String> {
-        functions.iter().try_for_each(|func| {
+        functions.iter().filter(|f| f.is_relocated()).try_for_each(|func| {
             let src_rva = func.get_original_rva();
-            let rel_offset = (func.rva as i64) - ((src_rva + 5) as i64);
+            let rel_offset = i32::try_from(i64::from(func.rva) - i64::from(src_rva + 5))
+                .map_err(|_| format!("{} was relocated out of reach of a JMP at {src_rva:#x}", func.name))?;
 
             let mut jmp_bytes = [0xE9u8; 5];
-            jmp_bytes[1..].copy_from_slice(&(rel_offset as i32).to_le_bytes());
+            jmp_bytes[1..].copy_from_slice(&rel_offset.to_le_bytes());
 
-            self.pe_context
+            self.image
                 .borrow_mut()
                 .write_data_at_rva(src_rva, &jmp_bytes)
                 .map_err(|e| format!("Failed to patch JMP at {src_rva:#x}: {e}"))
         })
     }
 
+    fn rewrite_jump_tables(&self, functions: &[ObfuscatorFunction]) -> Result<(), String> {
+        functions
+            .iter()
+            .filter(|f| f.is_relocated())
+            .try_for_each(|func| func.rewrite_jump_tables(&mut *self.image.borrow_mut()))
+    }
+
+    #[must_use]
+    pub const fn get_reference_report(&self) -> &ReferenceReport {
+        &self.reference_report
+    }
+
+    #[must_use]
     pub fn get_binary_data(self) -> Vec<u8> {
-        self.pe_context.borrow().pe_data.clone()
+        self.image.borrow().data().to_vec()
     }
 }
@@ -0,0 +1,435 @@
+use crate::selection::{FunctionSelection, parse_patterns};
+use serde::{Deserialize, Serialize};
+
+/// Deeper expressions grow the code quickly, each level adds up to five terms.
+pub const MAX_MBA_DEPTH: usize = 8;
+
+pub const MAX_CONSTANT_ROUNDS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    #[must_use]
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_ascii_lowercase().as_str() {
+            "toml" => Some(Self::Toml),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Describes the obfuscation pipeline: the passes to run in order and how
+/// many times the whole list is applied to every function. Without a seed
+/// a random one is picked and reported.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ObfuscatorConfig {
+    pub seed: Option<u64>,
+    pub iterations: usize,
+    pub passes: Vec<PassConfig>,
+    pub selection: SelectionConfig,
+    pub overrides: Vec<FunctionOverride>,
+    pub strings: StringEncryptionConfig,
+    pub layout: LayoutConfig,
+    pub verification: VerificationConfig,
+}
+
+/// Include and exclude patterns, see [`crate::selection::FunctionPattern`]
+/// for the syntax.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelectionConfig {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+/// Settings of string encryption, which is configured once instead of as a
+/// pass.
+///
+/// It works across functions and adds a section to the image. Strings
+/// shorter than `min_length` characters are left alone; with
+/// `erase_plaintext` the original bytes are cleared when nothing else can
+/// still read them.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StringEncryptionConfig {
+    pub enabled: bool,
+    pub min_length: usize,
+    pub erase_plaintext: bool,
+}
+
+impl Default for StringEncryptionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_length: 4,
+            erase_plaintext: true,
+        }
+    }
+}
+
+/// Placement of the relocated code. With `interleave` the functions are
+/// cut after their unconditional jumps and returns, and the pieces of all
+/// functions are shuffled across the code section.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LayoutConfig {
+    pub interleave: bool,
+}
+
+/// Settings of the emulator-based verification of every pass.
+///
+/// Every function is emulated before and after each pass from `trials`
+/// random states, and the passes that changed what it does are reported.
+/// Runs of the original code longer than `max_steps` instructions are given
+/// up.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VerificationConfig {
+    pub enabled: bool,
+    pub trials: usize,
+    pub max_steps: u64,
+}
+
+impl Default for VerificationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            trials: 32,
+            max_steps: 100_000,
+        }
+    }
+}
+
+/// Replaces the pipeline for the functions matching `functions`. Settings
+/// left out are taken from the top level; the first matching override wins.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FunctionOverride {
+    pub functions: Vec<String>,
+    pub iterations: Option<usize>,
+    pub passes: Option<Vec<PassConfig>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PassConfig {
+    Mutation(MutationConfig),
+    ControlFlowFlattening(FlatteningConfig),
+    OpaquePredicate(OpaquePredicateConfig),
+    Mba(MbaConfig),
+    ConstantEncryption(ConstantEncryptionConfig),
+    Virtualization(VirtualizationConfig),
+    BlockReordering(BlockReorderingConfig),
+    JunkCode(JunkCodeConfig),
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MutationConfig {
+    pub probability: MutationProbabilities,
+}
+
+/// Functions with fewer flattenable blocks than `min_blocks` are left as is.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlatteningConfig {
+    pub min_blocks: usize,
+}
+
+impl Default for FlatteningConfig {
+    fn default() -> Self {
+        Self { min_blocks: 2 }
+    }
+}
+
+/// `probability` is the chance that a predicate is inserted before an
+/// eligible instruction; bogus blocks hold up to `junk_instructions`
+/// instructions before jumping back into the function.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpaquePredicateConfig {
+    pub probability: f64,
+    pub junk_instructions: usize,
+}
+
+impl Default for OpaquePredicateConfig {
+    fn default() -> Self {
+        Self {
+            probability: 0.1,
+            junk_instructions: 6,
+        }
+    }
+}
+
+/// `probability` is the chance that a supported instruction is rewritten and
+/// `depth` the number of zero identities folded into each expression.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MbaConfig {
+    pub probability: f64,
+    pub depth: usize,
+}
+
+impl Default for MbaConfig {
+    fn default() -> Self {
+        Self {
+            probability: 0.5,
+            depth: 2,
+        }
+    }
+}
+
+/// Immediates whose signed value is smaller in magnitude than
+/// `min_magnitude` are left alone; the others are rebuilt by a chain of
+/// `rounds` operations with the given `probability`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConstantEncryptionConfig {
+    pub probability: f64,
+    pub rounds: usize,
+    pub min_magnitude: u64,
+}
+
+impl Default for ConstantEncryptionConfig {
+    fn default() -> Self {
+        Self {
+            probability: 1.0,
+            rounds: 3,
+            min_magnitude: 0x10,
+        }
+    }
+}
+
+/// Runs of fewer than `min_instructions` supported instructions are left
+/// native, as entering and leaving the VM costs more than they do.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VirtualizationConfig {
+    pub min_instructions: usize,
+}
+
+impl Default for VirtualizationConfig {
+    fn default() -> Self {
+        Self { min_instructions: 2 }
+    }
+}
+
+/// Functions with fewer than `min_blocks` blocks that can leave their
+/// position keep their layout.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlockReorderingConfig {
+    pub min_blocks: usize,
+}
+
+impl Default for BlockReorderingConfig {
+    fn default() -> Self {
+        Self { min_blocks: 2 }
+    }
+}
+
+/// `density` is the chance that junk is inserted before an eligible
+/// instruction, up to `max_instructions` instructions at a time. Function
+/// overrides give hot functions a lower density.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JunkCodeConfig {
+    pub density: f64,
+    pub max_instructions: usize,
+}
+
+impl Default for JunkCodeConfig {
+    fn default() -> Self {
+        Self {
+            density: 0.2,
+            max_instructions: 3,
+        }
+    }
+}
+
+/// Chance that an instruction of each class is rewritten when visited.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MutationProbabilities {
+    pub lea: f64,
+    pub call: f64,
+    pub add: f64,
+    pub or: f64,
+    pub inc: f64,
+    pub dec: f64,
+    pub push: f64,
+}
+
+impl Default for MutationProbabilities {
+    fn default() -> Self {
+        Self {
+            lea: 1.0,
+            call: 1.0,
+            add: 1.0,
+            or: 1.0,
+            inc: 1.0,
+            dec: 1.0,
+            push: 1.0,
+        }
+    }
+}
+
+impl Default for ObfuscatorConfig {
+    fn default() -> Self {
+        Self {
+            seed: None,
+            iterations: 2,
+            passes: vec![PassConfig::Mutation(MutationConfig::default())],
+            selection: SelectionConfig::default(),
+            overrides: Vec::new(),
+            strings: StringEncryptionConfig::default(),
+            layout: LayoutConfig::default(),
+            verification: VerificationConfig::default(),
+        }
+    }
+}
+
+impl ObfuscatorConfig {
+    /// # Errors
+    ///
+    /// Fails when the text is not valid for `format` or the configuration does
+    /// not validate.
+    pub fn parse(text: &str, format: ConfigFormat) -> Result<Self, String> {
+        let config: Self = match format {
+            ConfigFormat::Toml => toml::from_str(text).map_err(|e| e.to_string())?,
+            ConfigFormat::Json => serde_json::from_str(text).map_err(|e| e.to_string())?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// # Errors
+    ///
+    /// Fails on the first setting that is out of range or names an unknown
+    /// pass, pattern or option.
+    pub fn validate(&self) -> Result<(), String> {
+        validate_passes(&self.passes)?;
+        FunctionSelection::new(&self.selection)?;
+        if self.strings.min_length == 0 {
+            return Err("String encryption min_length must be at least 1".to_string());
+        }
+        if self.verification.trials == 0 {
+            return Err("Verification trials must be at least 1".to_string());
+        }
+        if self.verification.max_steps == 0 {
+            return Err("Verification max_steps must be at least 1".to_string());
+        }
+        for function_override in &self.overrides {
+            if function_override.functions.is_empty() {
+                return Err("Function override without any function pattern".to_string());
+            }
+            parse_patterns(&function_override.functions)?;
+            if let Some(passes) = &function_override.passes {
+                validate_passes(passes)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn validate_passes(passes: &[PassConfig]) -> Result<(), String> {
+    for pass in passes {
+        match pass {
+            PassConfig::Mutation(config) => config.probability.validate()?,
+            PassConfig::ControlFlowFlattening(config) => {
+                if config.min_blocks == 0 {
+                    return Err("Flattening min_blocks must be at least 1".to_string());
+                }
+            }
+            PassConfig::OpaquePredicate(config) => {
+                if !(0.0..=1.0).contains(&config.probability) {
+                    return Err(format!(
+                        "Opaque predicate probability must be between 0 and 1, got {}",
+                        config.probability
+                    ));
+                }
+                if config.junk_instructions == 0 {
+                    return Err("Opaque predicate junk_instructions must be at least 1".to_string());
+                }
+            }
+            PassConfig::Mba(config) => {
+                if !(0.0..=1.0).contains(&config.probability) {
+                    return Err(format!(
+                        "MBA probability must be between 0 and 1, got {}",
+                        config.probability
+                    ));
+                }
+                if !(1..=MAX_MBA_DEPTH).contains(&config.depth) {
+                    return Err(format!(
+                        "MBA depth must be between 1 and {MAX_MBA_DEPTH}, got {}",
+                        config.depth
+                    ));
+                }
+            }
+            PassConfig::ConstantEncryption(config) => {
+                if !(0.0..=1.0).contains(&config.probability) {
+                    return Err(format!(
+                        "Constant encryption probability must be between 0 and 1, got {}",
+                        config.probability
+                    ));
+                }
+                if !(1..=MAX_CONSTANT_ROUNDS).contains(&config.rounds) {
+                    return Err(format!(
+                        "Constant encryption rounds must be between 1 and {MAX_CONSTANT_ROUNDS}, got {}",
+                        config.rounds
+                    ));
+                }
+            }
+            PassConfig::Virtualization(config) => {
+                if config.min_instructions == 0 {
+                    return Err("Virtualization min_instructions must be at least 1".to_string());
+                }
+            }
+            PassConfig::BlockReordering(config) => {
+                if config.min_blocks == 0 {
+                    return Err("Block reordering min_blocks must be at least 1".to_string());
+                }
+            }
+            PassConfig::JunkCode(config) => {
+                if !(0.0..=1.0).contains(&config.density) {
+                    return Err(format!(
+                        "Junk code density must be between 0 and 1, got {}",
+                        config.density
+                    ));
+                }
+                if config.max_instructions == 0 {
+                    return Err("Junk code max_instructions must be at least 1".to_string());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+impl MutationProbabilities {
+    fn validate(&self) -> Result<(), String> {
+        let classes = [
+            ("lea", self.lea),
+            ("call", self.call),
+            ("add", self.add),
+            ("or", self.or),
+            ("inc", self.inc),
+            ("dec", self.dec),
+            ("push", self.push),
+        ];
+        for (class, probability) in classes {
+            if !(0.0..=1.0).contains(&probability) {
+                return Err(format!(
+                    "Mutation probability for {class} must be between 0 and 1, got {probability}"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
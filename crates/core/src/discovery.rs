@@ -0,0 +1,310 @@
+use crate::pdb::PDBFunction;
+use crate::pe::PEContext;
+use crate::pe::exception::{UNW_FLAG_CHAININFO, UnwindInfo};
+use common::{debug, info};
+use iced_x86::{Decoder, DecoderOptions, FlowControl, OpKind};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
+
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+/// Instructions traced per function before giving up on it.
+const MAX_TRACED_INSTRUCTIONS: usize = 100_000;
+/// Chained unwind infos followed before giving up on an entry.
+const MAX_CHAIN_DEPTH: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Source {
+    RuntimeFunction,
+    Export,
+    EntryPoint,
+    TlsCallback,
+    CallTarget,
+}
+
+struct Seed {
+    name: Option<String>,
+    /// End given by the function's `RUNTIME_FUNCTION`.
+    end: Option<u32>,
+    source: Source,
+}
+
+/// Code reachable from a function start without following calls.
+struct Trace {
+    end: u32,
+    /// Targets of direct calls and of jumps that leave the function.
+    targets: Vec<u32>,
+}
+
+/// Finds the functions of an image without debug information.
+///
+/// Function starts come from the `RUNTIME_FUNCTION` entries, the exports,
+/// the entry point and the TLS callbacks, and then from the direct calls
+/// and tail jumps found by following the control flow of every known
+/// function.
+///
+/// A `RUNTIME_FUNCTION` gives the exact extent of its function. Leaf
+/// functions have none, so theirs ends after the last instruction their
+/// control flow reaches, and never past the next known function. Chained
+/// entries describe further parts of a function: those right after it
+/// extend it, and the others are never taken for functions of their own.
+pub struct FunctionDiscovery<'a> {
+    pe_context: &'a PEContext,
+    /// Executable sections and their raw bytes.
+    code: Vec<(Range<u32>, &'a [u8])>,
+}
+
+impl<'a> FunctionDiscovery<'a> {
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed or an executable section lies
+    /// outside the file.
+    pub fn new(pe_context: &'a PEContext) -> Result<Self, String> {
+        let pe = pe_context.parse()?;
+        let mut code = Vec::new();
+        for section in pe.sections.iter().filter(|section| section.characteristics & IMAGE_SCN_MEM_EXECUTE != 0) {
+            let size = section.virtual_size.min(section.size_of_raw_data);
+            let offset = section.pointer_to_raw_data as usize;
+            let bytes = pe_context
+                .pe_data
+                .get(offset..offset + size as usize)
+                .ok_or_else(|| format!("Section {} is outside the file", section.name().unwrap_or("?")))?;
+            code.push((section.virtual_address..section.virtual_address + size, bytes));
+        }
+        Ok(Self { pe_context, code })
+    }
+
+    /// # Errors
+    ///
+    /// Fails when the image or its exception directory cannot be parsed.
+    pub fn discover(&self) -> Result<Vec<PDBFunction>, String> {
+        let mut seeds = BTreeMap::new();
+        let runtime_functions = self.seed_runtime_functions(&mut seeds)?;
+        let inside_runtime_function = |rva: u32| {
+            let index = runtime_functions.partition_point(|(range, _)| range.start <= rva);
+            index > 0 && {
+                let (range, primary) = &runtime_functions[index - 1];
+                range.contains(&rva) && !(*primary && range.start == rva)
+            }
+        };
+
+        let pe = self.pe_context.parse()?;
+        let mut entries = Vec::new();
+        for export in pe.exports.iter().filter(|export| export.reexport.is_none()) {
+            if let Ok(rva) = u32::try_from(export.rva) {
+                entries.push((rva, export.name.map(str::to_string), Source::Export));
+            }
+        }
+        if pe.entry != 0 {
+            entries.push((pe.entry, Some("entry".to_string()), Source::EntryPoint));
+        }
+        if let Some(tls) = &pe.tls_data {
+            for (index, &callback) in tls.callbacks.iter().enumerate() {
+                if let Ok(rva) = u32::try_from(callback.wrapping_sub(pe.image_base)) {
+                    entries.push((rva, Some(format!("tls_callback_{index}")), Source::TlsCallback));
+                }
+            }
+        }
+        for (rva, name, source) in entries {
+            if !self.is_code(rva) || inside_runtime_function(rva) {
+                debug!("Ignoring {source:?} at {rva:#x}, which is not the start of a function");
+                continue;
+            }
+            let seed = seeds.entry(rva).or_insert(Seed {
+                name: None,
+                end: None,
+                source,
+            });
+            if seed.name.is_none() {
+                seed.name = name;
+            }
+        }
+
+        let mut pending: Vec<u32> = seeds.keys().copied().collect();
+        let mut traced = HashSet::new();
+        let mut extents = HashMap::new();
+        while let Some(start) = pending.pop() {
+            if !traced.insert(start) {
+                continue;
+            }
+            let Some(trace) = self.trace(start, &seeds) else {
+                if seeds[&start].source == Source::CallTarget {
+                    debug!("Dropping call target {start:#x}, which does not decode");
+                    seeds.remove(&start);
+                }
+                continue;
+            };
+            extents.insert(start, trace.end);
+            for target in trace.targets {
+                if self.is_code(target) && !seeds.contains_key(&target) && !inside_runtime_function(target) {
+                    seeds.insert(
+                        target,
+                        Seed {
+                            name: None,
+                            end: None,
+                            source: Source::CallTarget,
+                        },
+                    );
+                    pending.push(target);
+                }
+            }
+        }
+
+        let starts: Vec<u32> = seeds.keys().copied().collect();
+        let mut functions = Vec::with_capacity(seeds.len());
+        let mut counts: HashMap<Source, usize> = HashMap::new();
+        for (index, (&start, seed)) in seeds.iter().enumerate() {
+            let Some(end) = seed.end.or_else(|| extents.get(&start).copied()) else {
+                continue;
+            };
+            let end = starts.get(index + 1).map_or(end, |&next| end.min(next));
+            *counts.entry(seed.source).or_default() += 1;
+            functions.push(PDBFunction {
+                name: seed.name.clone().unwrap_or_else(|| format!("sub_{start:x}")),
+                rva: start,
+                size: end - start,
+            });
+        }
+
+        let count = |source| counts.get(&source).copied().unwrap_or(0);
+        info!(
+            "Discovery: {} functions ({} from RUNTIME_FUNCTIONs, {} exports, {} entry points and TLS callbacks, {} call targets)",
+            functions.len(),
+            count(Source::RuntimeFunction),
+            count(Source::Export),
+            count(Source::EntryPoint) + count(Source::TlsCallback),
+            count(Source::CallTarget)
+        );
+        Ok(functions)
+    }
+
+    /// Seeds the primary `RUNTIME_FUNCTION` entries, extended by the chained
+    /// entries that directly follow them. Returns every covered range,
+    /// sorted, and whether it starts a function.
+    fn seed_runtime_functions(&self, seeds: &mut BTreeMap<u32, Seed>) -> Result<Vec<(Range<u32>, bool)>, String> {
+        let mut functions = self.pe_context.get_runtime_functions()?;
+        functions.retain(|function| self.is_code(function.begin_address) && function.end_address > function.begin_address);
+        functions.sort_by_key(|function| function.begin_address);
+
+        let mut ranges: Vec<(Range<u32>, bool)> = Vec::new();
+        for function in functions {
+            let range = function.begin_address..function.end_address;
+            let Some(primary) = self.primary_of(function.unwind_info_address) else {
+                seeds.insert(
+                    range.start,
+                    Seed {
+                        name: None,
+                        end: Some(range.end),
+                        source: Source::RuntimeFunction,
+                    },
+                );
+                ranges.push((range, true));
+                continue;
+            };
+            if let Some(seed) = seeds.get_mut(&primary)
+                && seed.end == Some(range.start)
+            {
+                seed.end = Some(range.end);
+                if let Some((last, _)) = ranges.last_mut().filter(|(last, _)| last.start == primary) {
+                    last.end = range.end;
+                }
+                continue;
+            }
+            ranges.push((range, false));
+        }
+        Ok(ranges)
+    }
+
+    /// Start of the primary entry a chained unwind info belongs to, or
+    /// `None` when the unwind info is not chained.
+    fn primary_of(&self, mut unwind_info_address: u32) -> Option<u32> {
+        let mut primary = None;
+        for _ in 0..MAX_CHAIN_DEPTH {
+            let Ok(info) = UnwindInfo::parse(self.pe_context, unwind_info_address) else {
+                break;
+            };
+            match info.chained.filter(|_| info.flags & UNW_FLAG_CHAININFO != 0) {
+                Some(chained) => {
+                    primary = Some(chained.begin_address);
+                    unwind_info_address = chained.unwind_info_address;
+                }
+                None => break,
+            }
+        }
+        primary
+    }
+
+    fn is_code(&self, rva: u32) -> bool {
+        self.code.iter().any(|(range, _)| range.contains(&rva))
+    }
+
+    fn decode(&self, rva: u32) -> Option<iced_x86::Instruction> {
+        let (range, bytes) = self.code.iter().find(|(range, _)| range.contains(&rva))?;
+        let mut decoder = Decoder::with_ip(
+            64,
+            &bytes[(rva - range.start) as usize..],
+            u64::from(rva),
+            DecoderOptions::NONE,
+        );
+        let instruction = decoder.decode();
+        (!instruction.is_invalid()).then_some(instruction)
+    }
+
+    /// Follows the control flow from `start`. A jump stays inside the
+    /// function when it lands within its `RUNTIME_FUNCTION` or, without one,
+    /// after the start and before the next known function; any other jump
+    /// is a tail call. Returns `None` when reachable bytes do not decode.
+    fn trace(&self, start: u32, seeds: &BTreeMap<u32, Seed>) -> Option<Trace> {
+        let own_end = seeds.get(&start).and_then(|seed| seed.end);
+        let internal = |target: u32| {
+            own_end.map_or_else(
+                || target > start && seeds.range(start + 1..=target).next().is_none(),
+                |end| (start..end).contains(&target),
+            )
+        };
+
+        let mut pending = vec![start];
+        let mut visited = HashSet::new();
+        let mut end = start;
+        let mut targets = Vec::new();
+        while let Some(rva) = pending.pop() {
+            if !visited.insert(rva) {
+                continue;
+            }
+            if visited.len() > MAX_TRACED_INSTRUCTIONS {
+                return None;
+            }
+            let instruction = self.decode(rva)?;
+            let next = u32::try_from(instruction.next_ip()).ok()?;
+            end = end.max(next);
+
+            // A branch out of the image says as little as an indirect one.
+            let direct_target = matches!(instruction.op0_kind(), OpKind::NearBranch64)
+                .then(|| u32::try_from(instruction.near_branch_target()).ok())
+                .flatten();
+            match instruction.flow_control() {
+                FlowControl::Call => {
+                    targets.extend(direct_target);
+                    pending.push(next);
+                }
+                FlowControl::ConditionalBranch | FlowControl::UnconditionalBranch => {
+                    if let Some(target) = direct_target {
+                        if internal(target) {
+                            pending.push(target);
+                        } else {
+                            targets.push(target);
+                        }
+                    }
+                    if instruction.flow_control() == FlowControl::ConditionalBranch {
+                        pending.push(next);
+                    }
+                }
+                FlowControl::IndirectBranch
+                | FlowControl::Return
+                | FlowControl::Interrupt
+                | FlowControl::Exception => {}
+                _ => pending.push(next),
+            }
+        }
+        Some(Trace { end, targets })
+    }
+}
@@ -0,0 +1,328 @@
+use crate::elf::eh_frame::{CommonInformation, Reader};
+use std::collections::BTreeMap;
+
+/// DWARF number of RSP, which holds the CFA on entry.
+pub const RSP: u16 = 7;
+/// DWARF number of the return address column.
+pub const RETURN_ADDRESS: u16 = 16;
+/// Data alignment factor of the CIE written for relocated code.
+pub const DATA_ALIGNMENT: i64 = -8;
+
+const DW_CFA_ADVANCE_LOC: u8 = 0x40;
+const DW_CFA_OFFSET: u8 = 0x80;
+const DW_CFA_RESTORE: u8 = 0xC0;
+const DW_CFA_NOP: u8 = 0x00;
+const DW_CFA_SET_LOC: u8 = 0x01;
+const DW_CFA_ADVANCE_LOC1: u8 = 0x02;
+const DW_CFA_ADVANCE_LOC2: u8 = 0x03;
+const DW_CFA_ADVANCE_LOC4: u8 = 0x04;
+const DW_CFA_OFFSET_EXTENDED: u8 = 0x05;
+const DW_CFA_RESTORE_EXTENDED: u8 = 0x06;
+const DW_CFA_UNDEFINED: u8 = 0x07;
+const DW_CFA_SAME_VALUE: u8 = 0x08;
+const DW_CFA_REGISTER: u8 = 0x09;
+const DW_CFA_REMEMBER_STATE: u8 = 0x0A;
+const DW_CFA_RESTORE_STATE: u8 = 0x0B;
+const DW_CFA_DEF_CFA: u8 = 0x0C;
+const DW_CFA_DEF_CFA_REGISTER: u8 = 0x0D;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0E;
+const DW_CFA_OFFSET_EXTENDED_SF: u8 = 0x11;
+const DW_CFA_DEF_CFA_SF: u8 = 0x12;
+const DW_CFA_DEF_CFA_OFFSET_SF: u8 = 0x13;
+const DW_CFA_GNU_ARGS_SIZE: u8 = 0x2E;
+
+/// Where the caller's value of a register is found. Registers without a
+/// rule keep their value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RegisterRule {
+    /// Saved at this offset from the CFA.
+    Offset(i64),
+    /// Held in another register.
+    Register(u16),
+    Undefined,
+}
+
+/// A row of the call frame table: how to find the CFA and the caller's
+/// registers at an address.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FrameState {
+    pub cfa_register: u16,
+    pub cfa_offset: i64,
+    pub registers: BTreeMap<u16, RegisterRule>,
+}
+
+impl FrameState {
+    /// The state at a function entry: the CFA just above the return
+    /// address, which RSP points at.
+    #[must_use]
+    pub fn entry() -> Self {
+        Self {
+            cfa_register: RSP,
+            cfa_offset: 8,
+            registers: BTreeMap::from([(RETURN_ADDRESS, RegisterRule::Offset(-8))]),
+        }
+    }
+
+    /// The state before a CIE's initial instructions.
+    pub(super) const fn unset() -> Self {
+        Self {
+            cfa_register: RSP,
+            cfa_offset: 0,
+            registers: BTreeMap::new(),
+        }
+    }
+
+    /// Appends the instructions that turn this state into `target`, for a
+    /// CIE with [`DATA_ALIGNMENT`].
+    ///
+    /// # Errors
+    ///
+    /// Fails when an offset is not a multiple of the data alignment.
+    pub fn encode_transition(&self, target: &Self, program: &mut Vec<u8>) -> Result<(), String> {
+        if (self.cfa_register, self.cfa_offset) != (target.cfa_register, target.cfa_offset) {
+            if target.cfa_offset >= 0 {
+                program.push(DW_CFA_DEF_CFA);
+                write_uleb128(program, u64::from(target.cfa_register));
+                write_uleb128(program, target.cfa_offset.cast_unsigned());
+            } else {
+                program.push(DW_CFA_DEF_CFA_SF);
+                write_uleb128(program, u64::from(target.cfa_register));
+                write_sleb128(program, factored(target.cfa_offset)?);
+            }
+        }
+        let registers = self.registers.keys().chain(target.registers.keys());
+        let mut changed: Vec<u16> = registers
+            .filter(|register| self.registers.get(register) != target.registers.get(register))
+            .copied()
+            .collect();
+        changed.sort_unstable();
+        changed.dedup();
+        for register in changed {
+            match target.registers.get(&register) {
+                Some(&RegisterRule::Offset(offset)) => {
+                    let offset = factored(offset)?;
+                    if offset < 0 {
+                        program.push(DW_CFA_OFFSET_EXTENDED_SF);
+                        write_uleb128(program, u64::from(register));
+                        write_sleb128(program, offset);
+                    } else if let Ok(low) = u8::try_from(register)
+                        && low < 0x40
+                    {
+                        program.push(DW_CFA_OFFSET | low);
+                        write_uleb128(program, offset.cast_unsigned());
+                    } else {
+                        program.push(DW_CFA_OFFSET_EXTENDED);
+                        write_uleb128(program, u64::from(register));
+                        write_uleb128(program, offset.cast_unsigned());
+                    }
+                }
+                Some(&RegisterRule::Register(other)) => {
+                    program.push(DW_CFA_REGISTER);
+                    write_uleb128(program, u64::from(register));
+                    write_uleb128(program, u64::from(other));
+                }
+                Some(RegisterRule::Undefined) => {
+                    program.push(DW_CFA_UNDEFINED);
+                    write_uleb128(program, u64::from(register));
+                }
+                None => {
+                    program.push(DW_CFA_SAME_VALUE);
+                    write_uleb128(program, u64::from(register));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Appends an advance of the location by `delta` bytes, for a CIE with a
+/// code alignment of 1.
+#[allow(clippy::cast_possible_truncation, reason = "each arm only takes deltas its operand holds")]
+pub fn encode_advance(delta: u32, program: &mut Vec<u8>) {
+    match delta {
+        0 => {}
+        1..0x40 => program.push(DW_CFA_ADVANCE_LOC | delta as u8),
+        0x40..0x100 => program.extend_from_slice(&[DW_CFA_ADVANCE_LOC1, delta as u8]),
+        0x100..0x1_0000 => {
+            program.push(DW_CFA_ADVANCE_LOC2);
+            program.extend_from_slice(&(delta as u16).to_le_bytes());
+        }
+        _ => {
+            program.push(DW_CFA_ADVANCE_LOC4);
+            program.extend_from_slice(&delta.to_le_bytes());
+        }
+    }
+}
+
+fn factored(offset: i64) -> Result<i64, String> {
+    if offset % DATA_ALIGNMENT == 0 {
+        Ok(offset / DATA_ALIGNMENT)
+    } else {
+        Err(format!("Frame offset {offset} is not a multiple of {}", DATA_ALIGNMENT.abs()))
+    }
+}
+
+pub fn write_uleb128(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+pub fn write_sleb128(bytes: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = value.to_le_bytes()[0] & 0x7F;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Runs call frame programs, collecting the rows of the table.
+pub(super) struct Interpreter<'a> {
+    cie: &'a CommonInformation,
+    /// The state after the CIE's initial instructions, which the restore
+    /// instructions go back to.
+    initial: Option<&'a FrameState>,
+    pub(super) state: FrameState,
+    location: u64,
+    /// Each row applies from its address to the next one.
+    pub(super) rows: Vec<(u64, FrameState)>,
+    remembered: Vec<FrameState>,
+}
+
+impl<'a> Interpreter<'a> {
+    pub(super) const fn new(
+        cie: &'a CommonInformation,
+        initial: Option<&'a FrameState>,
+        state: FrameState,
+        location: u64,
+    ) -> Self {
+        Self {
+            cie,
+            initial,
+            state,
+            location,
+            rows: Vec::new(),
+            remembered: Vec::new(),
+        }
+    }
+
+    /// Runs the instructions up to `end`. Fails on instructions whose rules
+    /// cannot be tracked, DWARF expressions among them.
+    pub(super) fn run(&mut self, program: &mut Reader, end: usize) -> Result<(), String> {
+        while program.offset < end {
+            let opcode = program.u8()?;
+            let low = opcode & 0x3F;
+            match opcode & 0xC0 {
+                DW_CFA_ADVANCE_LOC => self.advance(u64::from(low) * self.cie.code_alignment)?,
+                DW_CFA_OFFSET => {
+                    let offset = program.uleb128()?.cast_signed() * self.cie.data_alignment;
+                    self.state.registers.insert(u16::from(low), RegisterRule::Offset(offset));
+                }
+                DW_CFA_RESTORE => self.restore(u16::from(low))?,
+                _ => self.run_extended(opcode, program)?,
+            }
+        }
+        self.rows.push((self.location, self.state.clone()));
+        Ok(())
+    }
+
+    fn run_extended(&mut self, opcode: u8, program: &mut Reader) -> Result<(), String> {
+        match opcode {
+            DW_CFA_NOP => {}
+            // Only matters to the personality routines of functions with
+            // language specific data, which are not relocated.
+            DW_CFA_GNU_ARGS_SIZE => {
+                program.uleb128()?;
+            }
+            DW_CFA_SET_LOC => {
+                let location = program.pointer(self.cie.fde_encoding)?;
+                self.rows.push((self.location, self.state.clone()));
+                self.location = location;
+            }
+            DW_CFA_ADVANCE_LOC1 => self.advance(u64::from(program.u8()?) * self.cie.code_alignment)?,
+            DW_CFA_ADVANCE_LOC2 => self.advance(u64::from(program.u16()?) * self.cie.code_alignment)?,
+            DW_CFA_ADVANCE_LOC4 => self.advance(u64::from(program.u32()?) * self.cie.code_alignment)?,
+            DW_CFA_OFFSET_EXTENDED | DW_CFA_OFFSET_EXTENDED_SF => {
+                let register = register_operand(program)?;
+                let factor = if opcode == DW_CFA_OFFSET_EXTENDED {
+                    program.uleb128()?.cast_signed()
+                } else {
+                    program.sleb128()?
+                };
+                let offset = factor * self.cie.data_alignment;
+                self.state.registers.insert(register, RegisterRule::Offset(offset));
+            }
+            DW_CFA_RESTORE_EXTENDED => {
+                let register = register_operand(program)?;
+                self.restore(register)?;
+            }
+            DW_CFA_UNDEFINED => {
+                let register = register_operand(program)?;
+                self.state.registers.insert(register, RegisterRule::Undefined);
+            }
+            DW_CFA_SAME_VALUE => {
+                let register = register_operand(program)?;
+                self.state.registers.remove(&register);
+            }
+            DW_CFA_REGISTER => {
+                let register = register_operand(program)?;
+                let other = register_operand(program)?;
+                self.state.registers.insert(register, RegisterRule::Register(other));
+            }
+            DW_CFA_REMEMBER_STATE => self.remembered.push(self.state.clone()),
+            DW_CFA_RESTORE_STATE => {
+                self.state = self
+                    .remembered
+                    .pop()
+                    .ok_or_else(|| "DW_CFA_restore_state without a remembered state".to_string())?;
+            }
+            DW_CFA_DEF_CFA => {
+                self.state.cfa_register = register_operand(program)?;
+                self.state.cfa_offset = program.uleb128()?.cast_signed();
+            }
+            DW_CFA_DEF_CFA_SF => {
+                self.state.cfa_register = register_operand(program)?;
+                self.state.cfa_offset = program.sleb128()? * self.cie.data_alignment;
+            }
+            DW_CFA_DEF_CFA_REGISTER => self.state.cfa_register = register_operand(program)?,
+            DW_CFA_DEF_CFA_OFFSET => self.state.cfa_offset = program.uleb128()?.cast_signed(),
+            DW_CFA_DEF_CFA_OFFSET_SF => self.state.cfa_offset = program.sleb128()? * self.cie.data_alignment,
+            opcode => return Err(format!("Unsupported call frame instruction {opcode:#x}")),
+        }
+        Ok(())
+    }
+
+    fn advance(&mut self, delta: u64) -> Result<(), String> {
+        if self.initial.is_none() {
+            return Err("CIE initial instructions advance the location".to_string());
+        }
+        self.rows.push((self.location, self.state.clone()));
+        self.location += delta;
+        Ok(())
+    }
+
+    fn restore(&mut self, register: u16) -> Result<(), String> {
+        let initial = self
+            .initial
+            .ok_or_else(|| "CIE initial instructions restore a register".to_string())?;
+        match initial.registers.get(&register) {
+            Some(&rule) => self.state.registers.insert(register, rule),
+            None => self.state.registers.remove(&register),
+        };
+        Ok(())
+    }
+}
+
+fn register_operand(program: &mut Reader) -> Result<u16, String> {
+    u16::try_from(program.uleb128()?).map_err(|_| "Call frame register out of range".to_string())
+}
@@ -0,0 +1,513 @@
+use crate::elf::{ElfContext, file_range, to_offset, to_rva, to_size};
+use crate::elf::cfi::{DATA_ALIGNMENT, FrameState, Interpreter, RETURN_ADDRESS, write_sleb128, write_uleb128};
+use crate::unwind::RelocatedFrame;
+use goblin::elf::program_header::{PT_GNU_EH_FRAME, PT_LOAD};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::ops::Range;
+
+const DW_EH_PE_OMIT: u8 = 0xFF;
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+const DW_EH_PE_ULEB128: u8 = 0x01;
+const DW_EH_PE_UDATA2: u8 = 0x02;
+const DW_EH_PE_UDATA4: u8 = 0x03;
+const DW_EH_PE_UDATA8: u8 = 0x04;
+const DW_EH_PE_SLEB128: u8 = 0x09;
+const DW_EH_PE_SDATA2: u8 = 0x0A;
+const DW_EH_PE_SDATA4: u8 = 0x0B;
+const DW_EH_PE_SDATA8: u8 = 0x0C;
+const DW_EH_PE_PCREL: u8 = 0x10;
+const DW_EH_PE_DATAREL: u8 = 0x30;
+/// `DW_EH_PE_pcrel | DW_EH_PE_sdata4`, what compilers emit for x86-64.
+const FDE_ENCODING: u8 = DW_EH_PE_PCREL | DW_EH_PE_SDATA4;
+/// `DW_EH_PE_datarel | DW_EH_PE_sdata4`, relative to `.eh_frame_hdr`.
+const TABLE_ENCODING: u8 = DW_EH_PE_DATAREL | DW_EH_PE_SDATA4;
+
+/// The code range of a frame description entry, the ELF counterpart of a
+/// `RUNTIME_FUNCTION`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FrameDescription {
+    pub begin_address: u32,
+    pub end_address: u32,
+    /// Whether the entry points at language specific data, the call site
+    /// tables of C++ exception handling.
+    pub has_lsda: bool,
+}
+
+/// Addresses of the code and of the record of an FDE, which
+/// `.eh_frame_hdr` looks the record up by.
+pub type TableEntry = (u64, u64);
+
+/// A frame description entry with the rows of its call frame table.
+#[derive(Clone, Debug)]
+pub struct FrameEntry {
+    /// Address of the record, which `.eh_frame_hdr` lists.
+    pub record: u64,
+    pub description: FrameDescription,
+    /// Whether the entry describes a signal handler, whose caller was
+    /// interrupted rather than made a call.
+    pub signal_frame: bool,
+    /// Rows by RVA, each applying up to the next one, or why the
+    /// instructions cannot be followed.
+    pub rows: Result<Vec<(u32, FrameState)>, String>,
+}
+
+/// What a CIE tells about its FDEs.
+pub(super) struct CommonInformation {
+    augmented: bool,
+    pub(super) fde_encoding: u8,
+    lsda_encoding: u8,
+    signal_frame: bool,
+    pub(super) code_alignment: u64,
+    pub(super) data_alignment: i64,
+    /// The state after the initial instructions, or why they cannot be
+    /// followed.
+    initial: Result<FrameState, String>,
+}
+
+/// Reads the `.eh_frame` records of a section loaded at `address`.
+pub(super) struct Reader<'a> {
+    bytes: &'a [u8],
+    address: u64,
+    pub(super) offset: usize,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let bytes = self
+            .bytes
+            .get(self.offset..self.offset + N)
+            .ok_or_else(|| format!(".eh_frame ends inside a record at {:#x}", self.offset))?;
+        self.offset += N;
+        Ok(bytes.try_into().expect("slice of N bytes"))
+    }
+
+    pub(super) fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    pub(super) fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.bytes()?))
+    }
+
+    pub(super) fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    pub(super) fn uleb128(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= u64::from(byte & 0x7F) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    pub(super) fn sleb128(&mut self) -> Result<i64, String> {
+        let mut value = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= i64::from(byte & 0x7F) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<&[u8], String> {
+        let rest = &self.bytes[self.offset.min(self.bytes.len())..];
+        let length = rest
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or_else(|| "Unterminated .eh_frame augmentation string".to_string())?;
+        self.offset += length + 1;
+        Ok(&rest[..length])
+    }
+
+    /// Reads a pointer in `encoding`. Indirect pointers are left as the
+    /// address of the slot holding them.
+    pub(super) fn pointer(&mut self, encoding: u8) -> Result<u64, String> {
+        let field = self.address + self.offset as u64;
+        let value = match encoding & 0x0F {
+            DW_EH_PE_ABSPTR | DW_EH_PE_UDATA8 | DW_EH_PE_SDATA8 => u64::from_le_bytes(self.bytes()?),
+            DW_EH_PE_ULEB128 => self.uleb128()?,
+            DW_EH_PE_SLEB128 => self.sleb128()?.cast_unsigned(),
+            DW_EH_PE_UDATA2 => u64::from(u16::from_le_bytes(self.bytes()?)),
+            DW_EH_PE_SDATA2 => i64::from(i16::from_le_bytes(self.bytes()?)).cast_unsigned(),
+            DW_EH_PE_UDATA4 => u64::from(self.u32()?),
+            DW_EH_PE_SDATA4 => i64::from(self.u32()?.cast_signed()).cast_unsigned(),
+            format => return Err(format!("Unsupported .eh_frame pointer format {format:#x}")),
+        };
+        match encoding & 0x70 {
+            0 => Ok(value),
+            DW_EH_PE_PCREL => Ok(field.wrapping_add(value)),
+            application => Err(format!("Unsupported .eh_frame pointer application {application:#x}")),
+        }
+    }
+}
+
+impl ElfContext {
+    /// Frame description entries of `.eh_frame`, sorted by address. Images
+    /// without the section have none.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed or `.eh_frame` is truncated or
+    /// uses an encoding that is not supported.
+    pub fn get_frame_descriptions(&self) -> Result<Vec<FrameDescription>, String> {
+        Ok(self
+            .get_frame_entries()?
+            .into_iter()
+            .map(|entry| entry.description)
+            .collect())
+    }
+
+    /// Address and file range of `.eh_frame`.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed.
+    pub fn eh_frame_section(&self) -> Result<Option<(u64, Range<usize>)>, String> {
+        let elf = self.parse()?;
+        elf.section_headers
+            .iter()
+            .find(|header| elf.shdr_strtab.get_at(header.sh_name) == Some(".eh_frame"))
+            .map(|header| Ok((header.sh_addr, file_range(header.sh_offset, header.sh_size)?)))
+            .transpose()
+    }
+
+    /// The entries of [`ElfContext::get_frame_descriptions`] with their call
+    /// frame tables: those of `.eh_frame`, then those the lookup table of
+    /// `.eh_frame_hdr` lists elsewhere, as in images this crate rewrote.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed or `.eh_frame` is truncated or
+    /// uses an encoding that is not supported.
+    pub fn get_frame_entries(&self) -> Result<Vec<FrameEntry>, String> {
+        let image_base = self.image_base()?;
+        let mut entries = Vec::new();
+        let mut section_addresses = 0..0;
+        if let Some((address, range)) = self.eh_frame_section()? {
+            let bytes = self
+                .elf_data
+                .get(range)
+                .ok_or_else(|| ".eh_frame is outside the file".to_string())?;
+            section_addresses = address..address + bytes.len() as u64;
+            let mut reader = Reader {
+                bytes,
+                address,
+                offset: 0,
+            };
+            let mut cies = HashMap::new();
+            while reader.offset + 4 <= bytes.len() {
+                match read_record(&mut reader, &mut cies, image_base)? {
+                    Record::Terminator => break,
+                    Record::Information => {}
+                    Record::Description(entry) => entries.extend(entry),
+                }
+            }
+        }
+
+        let elf = self.parse()?;
+        for record in self.get_frame_header_records()? {
+            if section_addresses.contains(&record) {
+                continue;
+            }
+            // Records outside `.eh_frame` are read along with the rest of
+            // their segment, as their CIE may lie anywhere in it.
+            let Some(segment) = elf.program_headers.iter().find(|header| {
+                header.p_type == PT_LOAD && (header.p_vaddr..header.p_vaddr + header.p_filesz).contains(&record)
+            }) else {
+                return Err(format!("Frame description at {record:#x} is not backed by the file"));
+            };
+            let mut reader = Reader {
+                bytes: self
+                    .elf_data
+                    .get(file_range(segment.p_offset, segment.p_filesz)?)
+                    .ok_or_else(|| format!("Segment at {:#x} is outside the file", segment.p_vaddr))?,
+                address: segment.p_vaddr,
+                offset: to_offset(record - segment.p_vaddr)?,
+            };
+            if let Record::Description(entry) = read_record(&mut reader, &mut HashMap::new(), image_base)? {
+                entries.extend(entry);
+            }
+        }
+        entries.sort_by_key(|entry| entry.description.begin_address);
+        Ok(entries)
+    }
+
+    /// Addresses of the records the lookup table of `.eh_frame_hdr` lists.
+    /// Headers without a table in the usual encodings list none.
+    fn get_frame_header_records(&self) -> Result<Vec<u64>, String> {
+        let elf = self.parse()?;
+        let Some(segment) = elf.program_headers.iter().find(|header| header.p_type == PT_GNU_EH_FRAME) else {
+            return Ok(Vec::new());
+        };
+        let bytes = self
+            .elf_data
+            .get(file_range(segment.p_offset, segment.p_filesz)?)
+            .ok_or_else(|| ".eh_frame_hdr is outside the file".to_string())?;
+        let mut reader = Reader {
+            bytes,
+            address: segment.p_vaddr,
+            offset: 0,
+        };
+        let [version, pointer_encoding, count_encoding, table_encoding] = reader.bytes()?;
+        if version != 1 || count_encoding != DW_EH_PE_UDATA4 || table_encoding != TABLE_ENCODING {
+            return Ok(Vec::new());
+        }
+        reader.pointer(pointer_encoding)?;
+        let count = reader.u32()?;
+        let mut records = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            reader.u32()?;
+            records.push(segment.p_vaddr.wrapping_add_signed(i64::from(reader.u32()?.cast_signed())));
+        }
+        Ok(records)
+    }
+}
+
+enum Record {
+    Terminator,
+    Information,
+    /// An FDE, unless its code was discarded.
+    Description(Option<FrameEntry>),
+}
+
+/// Reads the record at the reader, leaving the reader past it. The CIEs of
+/// FDEs are read when first referred to.
+fn read_record(
+    reader: &mut Reader,
+    cies: &mut HashMap<usize, CommonInformation>,
+    image_base: u64,
+) -> Result<Record, String> {
+    let record = reader.offset;
+    let length = reader.u32()? as usize;
+    if length == 0 {
+        return Ok(Record::Terminator);
+    }
+    if length == 0xFFFF_FFFF {
+        return Err("64-bit .eh_frame records are not supported".to_string());
+    }
+    let end = reader.offset + length;
+    let id_offset = reader.offset;
+    let id = reader.u32()?;
+    if id == 0 {
+        if let Entry::Vacant(vacant) = cies.entry(record) {
+            vacant.insert(read_cie(reader, end)?);
+        }
+        reader.offset = end;
+        return Ok(Record::Information);
+    }
+
+    let cie_offset = id_offset
+        .checked_sub(id as usize)
+        .ok_or_else(|| format!("FDE at {record:#x} points before .eh_frame"))?;
+    if !cies.contains_key(&cie_offset) {
+        let mut cie_reader = Reader {
+            bytes: reader.bytes,
+            address: reader.address,
+            offset: cie_offset,
+        };
+        if !matches!(read_record(&mut cie_reader, cies, image_base)?, Record::Information) {
+            return Err(format!("FDE at {record:#x} refers to an unknown CIE"));
+        }
+    }
+    let cie = &cies[&cie_offset];
+    let begin = reader.pointer(cie.fde_encoding)?;
+    let size = reader.pointer(cie.fde_encoding & 0x0F)?;
+    let mut has_lsda = false;
+    if cie.augmented {
+        let augmentation_end = to_offset(reader.uleb128()?)? + reader.offset;
+        // A null pointer stays null whatever its application.
+        if cie.lsda_encoding != DW_EH_PE_OMIT {
+            has_lsda = reader.pointer(cie.lsda_encoding & 0x0F)? != 0;
+        }
+        reader.offset = augmentation_end;
+    }
+    // Entries of discarded code are left with a zero address.
+    let entry = if begin >= image_base && size > 0 {
+        let begin_address = to_rva(begin, image_base)?;
+        Some(FrameEntry {
+            record: reader.address + record as u64,
+            description: FrameDescription {
+                begin_address,
+                end_address: begin_address + to_size(size)?,
+                has_lsda,
+            },
+            signal_frame: cie.signal_frame,
+            rows: read_rows(reader, end, cie, begin, image_base),
+        })
+    } else {
+        None
+    };
+    reader.offset = end;
+    Ok(Record::Description(entry))
+}
+
+fn read_cie(reader: &mut Reader, end: usize) -> Result<CommonInformation, String> {
+    let version = reader.u8()?;
+    let augmentation = reader.string()?.to_vec();
+    if augmentation.windows(2).any(|pair| pair == b"eh") {
+        return Err("CIEs with the old \"eh\" augmentation are not supported".to_string());
+    }
+    let code_alignment = reader.uleb128()?;
+    let data_alignment = reader.sleb128()?;
+    let return_address = if version == 1 {
+        u64::from(reader.u8()?)
+    } else {
+        reader.uleb128()?
+    };
+
+    let mut cie = CommonInformation {
+        augmented: augmentation.first() == Some(&b'z'),
+        fde_encoding: DW_EH_PE_ABSPTR,
+        lsda_encoding: DW_EH_PE_OMIT,
+        signal_frame: false,
+        code_alignment,
+        data_alignment,
+        initial: Err(String::new()),
+    };
+    if cie.augmented {
+        let augmentation_end = to_offset(reader.uleb128()?)? + reader.offset;
+        for &letter in &augmentation[1..] {
+            match letter {
+                b'R' => cie.fde_encoding = reader.u8()?,
+                b'L' => cie.lsda_encoding = reader.u8()?,
+                b'P' => {
+                    let encoding = reader.u8()?;
+                    reader.pointer(encoding)?;
+                }
+                b'S' => cie.signal_frame = true,
+                b'B' => {}
+                letter => return Err(format!("Unknown CIE augmentation {:?}", letter as char)),
+            }
+        }
+        reader.offset = augmentation_end;
+    }
+
+    // The rows only use the return address column every x86-64 producer
+    // picks, so other CIEs are read but their frames not followed.
+    cie.initial = if return_address == u64::from(RETURN_ADDRESS) {
+        let mut interpreter = Interpreter::new(&cie, None, FrameState::unset(), 0);
+        interpreter.run(reader, end).map(|()| interpreter.state)
+    } else {
+        Err(format!("Return address in register {return_address}"))
+    };
+    Ok(cie)
+}
+
+/// The rows of the FDE whose instructions the reader is at, by RVA.
+fn read_rows(
+    reader: &mut Reader,
+    end: usize,
+    cie: &CommonInformation,
+    begin: u64,
+    image_base: u64,
+) -> Result<Vec<(u32, FrameState)>, String> {
+    let initial = cie.initial.as_ref().map_err(Clone::clone)?;
+    let mut interpreter = Interpreter::new(cie, Some(initial), initial.clone(), begin);
+    interpreter.run(reader, end)?;
+    let mut rows: Vec<(u32, FrameState)> = Vec::with_capacity(interpreter.rows.len());
+    for (address, state) in interpreter.rows {
+        let rva = to_rva(address, image_base)?;
+        match rows.last_mut() {
+            Some(last) if last.0 == rva => last.1 = state,
+            Some(last) if last.1 == state => {}
+            _ => rows.push((rva, state)),
+        }
+    }
+    Ok(rows)
+}
+
+/// Encodes `.eh_frame` records for `frames`, to be loaded at `address`.
+///
+/// The records are a CIE with the usual x86-64 entry state, then an FDE
+/// per frame and the terminator. Returns them with the table entry of
+/// every FDE.
+///
+/// # Errors
+///
+/// Fails when the entry state cannot be encoded.
+pub fn encode_frames(
+    address: u64,
+    image_base: u64,
+    frames: &[RelocatedFrame],
+) -> Result<(Vec<u8>, Vec<TableEntry>), String> {
+    let mut cie = vec![0, 0, 0, 0, 1];
+    cie.extend_from_slice(b"zR\0");
+    write_uleb128(&mut cie, 1);
+    write_sleb128(&mut cie, DATA_ALIGNMENT);
+    write_uleb128(&mut cie, u64::from(RETURN_ADDRESS));
+    cie.extend_from_slice(&[1, FDE_ENCODING]);
+    FrameState::unset().encode_transition(&FrameState::entry(), &mut cie)?;
+    let mut section = record(cie)?;
+
+    let mut table = Vec::with_capacity(frames.len());
+    for frame in frames {
+        let offset = section.len();
+        let mut fde = Vec::new();
+        let cie_pointer = u32::try_from(offset + 4).map_err(|_| ".eh_frame grew past 4 GiB".to_string())?;
+        fde.extend_from_slice(&cie_pointer.to_le_bytes());
+        let begin = image_base + u64::from(frame.begin_address);
+        let field = address + offset as u64 + 8;
+        let begin_pointer = i32::try_from(begin.wrapping_sub(field).cast_signed())
+            .map_err(|_| format!("{begin:#x} is too far from .eh_frame"))?;
+        fde.extend_from_slice(&begin_pointer.to_le_bytes());
+        fde.extend_from_slice(&(frame.end_address - frame.begin_address).to_le_bytes());
+        fde.push(0);
+        fde.extend_from_slice(&frame.program);
+        table.push((begin, address + offset as u64));
+        section.extend_from_slice(&record(fde)?);
+    }
+    section.extend_from_slice(&[0; 4]);
+    Ok((section, table))
+}
+
+/// Encodes an `.eh_frame_hdr` to be loaded at `address`, pointing at the
+/// `.eh_frame` at `eh_frame` and with a lookup table of `fdes`.
+///
+/// # Errors
+///
+/// Fails when an address is too far from the header to be encoded.
+pub fn encode_frame_header(address: u64, eh_frame: u64, fdes: &[TableEntry]) -> Result<Vec<u8>, String> {
+    let relative = |target: u64, from: u64| -> Result<[u8; 4], String> {
+        i32::try_from(target.wrapping_sub(from).cast_signed())
+            .map(i32::to_le_bytes)
+            .map_err(|_| format!("{target:#x} is too far from .eh_frame_hdr"))
+    };
+    let mut fdes = fdes.to_vec();
+    fdes.sort_by_key(|&(begin, _)| begin);
+    let mut header = vec![1, FDE_ENCODING, DW_EH_PE_UDATA4, TABLE_ENCODING];
+    header.extend_from_slice(&relative(eh_frame, address + 4)?);
+    let count = u32::try_from(fdes.len()).map_err(|_| "Too many frame descriptions".to_string())?;
+    header.extend_from_slice(&count.to_le_bytes());
+    for (begin, record) in fdes {
+        header.extend_from_slice(&relative(begin, address)?);
+        header.extend_from_slice(&relative(record, address)?);
+    }
+    Ok(header)
+}
+
+/// Prefixes `body` with its length, padded with `DW_CFA_nop`.
+fn record(mut body: Vec<u8>) -> Result<Vec<u8>, String> {
+    body.resize((body.len() + 4).next_multiple_of(8) - 4, 0);
+    let length = u32::try_from(body.len()).map_err(|_| ".eh_frame record grew past 4 GiB".to_string())?;
+    let mut record = length.to_le_bytes().to_vec();
+    record.extend_from_slice(&body);
+    Ok(record)
+}
@@ -0,0 +1,266 @@
+use crate::elf::{ElfContext, file_range, to_rva, to_size};
+use crate::elf::eh_frame::{FrameEntry, encode_frame_header, encode_frames};
+use crate::format::{BinaryFormat, BinaryImage, ImageRegion, SectionAccess, UnwindCollector};
+use crate::pe::relocation::BaseRelocation;
+use crate::unwind::{RelocatedFrame, RelocatedUnwind};
+use common::debug;
+use goblin::elf::ProgramHeader;
+use goblin::elf::header::ET_EXEC;
+use goblin::elf::program_header::{
+    PF_R, PF_W, PF_X, PT_DYNAMIC, PT_GNU_EH_FRAME, PT_INTERP, PT_LOAD, PT_NOTE, PT_PHDR,
+};
+use goblin::elf::reloc::{R_X86_64_64, R_X86_64_GLOB_DAT, R_X86_64_IRELATIVE, R_X86_64_JUMP_SLOT, R_X86_64_RELATIVE};
+use goblin::elf::section_header::{SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHT_NOBITS, SHT_PROGBITS};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Allocated sections only the loader and the unwinder read.
+const LOADER_SECTIONS: [&str; 4] = [".interp", ".eh_frame", ".eh_frame_hdr", ".gcc_except_table"];
+
+impl BinaryImage for ElfContext {
+    fn format(&self) -> BinaryFormat {
+        BinaryFormat::Elf
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.elf_data
+    }
+
+    fn image_base(&self) -> Result<u64, String> {
+        Self::image_base(self)
+    }
+
+    fn read_data_at_rva(&self, rva: u32, size: usize) -> Result<Vec<u8>, String> {
+        Self::read_data_at_rva(self, rva, size)
+    }
+
+    fn write_data_at_rva(&mut self, rva: u32, data: &[u8]) -> Result<(), String> {
+        Self::write_data_at_rva(self, rva, data)
+    }
+
+    /// The allocated sections, then the loadable segments no section lies
+    /// in, such as the ones added by the compiler.
+    fn regions(&self) -> Result<Vec<ImageRegion>, String> {
+        let elf = self.parse()?;
+        let image_base = self.image_base()?;
+        let mut regions = Vec::new();
+        for section in elf.section_headers.iter().filter(|section| section.sh_flags & u64::from(SHF_ALLOC) != 0) {
+            let name = elf.shdr_strtab.get_at(section.sh_name).unwrap_or("?");
+            let file_size = if section.sh_type == SHT_NOBITS { 0 } else { section.sh_size };
+            let executable = section.sh_flags & u64::from(SHF_EXECINSTR) != 0;
+            let writable = section.sh_flags & u64::from(SHF_WRITE) != 0;
+            regions.push(ImageRegion {
+                name: name.to_string(),
+                rva: to_rva(section.sh_addr, image_base)?,
+                size: to_size(section.sh_size)?,
+                file: file_range(section.sh_offset, file_size)?,
+                executable,
+                writable,
+                constant_data: section.sh_type == SHT_PROGBITS
+                    && !executable
+                    && !writable
+                    && !LOADER_SECTIONS.contains(&name),
+            });
+        }
+        for (index, segment) in elf.program_headers.iter().enumerate() {
+            let addresses = segment.p_vaddr..segment.p_vaddr + segment.p_memsz;
+            let covered = elf
+                .section_headers
+                .iter()
+                .filter(|section| section.sh_flags & u64::from(SHF_ALLOC) != 0)
+                .any(|section| addresses.contains(&section.sh_addr));
+            if segment.p_type != PT_LOAD || covered {
+                continue;
+            }
+            regions.push(ImageRegion {
+                name: format!("segment{index}"),
+                rva: to_rva(segment.p_vaddr, image_base)?,
+                size: to_size(segment.p_memsz)?,
+                file: file_range(segment.p_offset, segment.p_filesz)?,
+                executable: segment.p_flags & PF_X != 0,
+                writable: segment.p_flags & PF_W != 0,
+                constant_data: false,
+            });
+        }
+        Ok(regions)
+    }
+
+    /// The segments the loader reads besides the loadable ones.
+    fn loader_data(&self) -> Result<Vec<Range<u32>>, String> {
+        let elf = self.parse()?;
+        let image_base = self.image_base()?;
+        elf.program_headers
+            .iter()
+            .filter(|segment| {
+                matches!(segment.p_type, PT_DYNAMIC | PT_INTERP | PT_NOTE | PT_PHDR | PT_GNU_EH_FRAME)
+            })
+            .map(|segment| {
+                let start = to_rva(segment.p_vaddr, image_base)?;
+                Ok(start..start + to_size(segment.p_memsz)?)
+            })
+            .collect()
+    }
+
+    fn function_ranges(&self) -> Result<Vec<Range<u32>>, String> {
+        Ok(self
+            .get_frame_descriptions()?
+            .iter()
+            .map(|description| description.begin_address..description.end_address)
+            .collect())
+    }
+
+    /// Targets of the dynamic relocations. Executables are not relocated,
+    /// so their data is searched for addresses in the image instead.
+    fn pointer_targets(&self) -> Result<Vec<u32>, String> {
+        let elf = self.parse()?;
+        let image_base = self.image_base()?;
+        let mut targets = Vec::new();
+        for relocation in elf.dynrelas.iter().chain(elf.pltrelocs.iter()) {
+            let addend = relocation.r_addend.unwrap_or(0).cast_unsigned();
+            let target = match relocation.r_type {
+                R_X86_64_RELATIVE | R_X86_64_IRELATIVE => addend,
+                R_X86_64_64 => match elf.dynsyms.get(relocation.r_sym) {
+                    Some(symbol) if symbol.st_shndx != 0 => symbol.st_value.wrapping_add(addend),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            // Relocations of data the image does not hold say nothing of its code.
+            if let Ok(target) = to_rva(target, image_base) {
+                targets.push(target);
+            }
+        }
+        if elf.header.e_type == ET_EXEC {
+            let regions = self.regions()?;
+            let image_end = regions.iter().map(|region| region.rva + region.size).max().unwrap_or(0);
+            for region in regions.iter().filter(|region| !region.executable) {
+                let Some(bytes) = self.elf_data.get(region.file.clone()) else {
+                    continue;
+                };
+                let skip = (region.rva.next_multiple_of(8) - region.rva) as usize;
+                targets.extend(
+                    bytes
+                        .get(skip..)
+                        .unwrap_or_default()
+                        .chunks_exact(8)
+                        .map(|chunk| u64::from_le_bytes(chunk.try_into().expect("chunk of 8 bytes")))
+                        .filter_map(|value| to_rva(value, image_base).ok())
+                        .filter(|&rva| rva < image_end),
+                );
+            }
+        }
+        Ok(targets)
+    }
+
+    /// The GOT entries the loader binds to symbols.
+    fn get_import_slots(&self) -> Result<HashMap<u32, String>, String> {
+        let elf = self.parse()?;
+        let image_base = self.image_base()?;
+        let mut slots = HashMap::new();
+        for relocation in elf.dynrelas.iter().chain(elf.dynrels.iter()).chain(elf.pltrelocs.iter()) {
+            if !matches!(relocation.r_type, R_X86_64_JUMP_SLOT | R_X86_64_GLOB_DAT) {
+                continue;
+            }
+            let Some(name) = elf
+                .dynsyms
+                .get(relocation.r_sym)
+                .and_then(|symbol| elf.dynstrtab.get_at(symbol.st_name))
+                .filter(|name| !name.is_empty())
+            else {
+                continue;
+            };
+            slots.insert(to_rva(relocation.r_offset, image_base)?, name.to_string());
+        }
+        Ok(slots)
+    }
+
+    /// Images with text relocations are not supported, so code holds no
+    /// address the loader adjusts.
+    fn get_base_relocations(&self) -> Result<Vec<BaseRelocation>, String> {
+        Ok(Vec::new())
+    }
+
+    fn set_base_relocations(&mut self, relocations: &[BaseRelocation]) -> Result<(), String> {
+        if relocations.is_empty() {
+            Ok(())
+        } else {
+            Err("ELF images cannot take text relocations".to_string())
+        }
+    }
+
+    fn next_section_rva(&self) -> Result<u32, String> {
+        self.get_next_segment_rva()
+    }
+
+    /// Adds a loadable segment. Section headers are not added, as the
+    /// loader does not read them.
+    fn add_section(&mut self, name: &str, size: u32, access: SectionAccess) -> Result<(u32, u32), String> {
+        let flags = match access {
+            SectionAccess::Execute => PF_R | PF_X,
+            SectionAccess::Read => PF_R,
+            SectionAccess::ReadWrite => PF_R | PF_W,
+        };
+        let rva = self.create_segment(size, flags)?;
+        debug!("Added segment for {name} at {rva:#x} ({size} bytes)");
+        Ok((rva, size))
+    }
+
+    fn unwind_collector<'a>(&'a self, _handler_symbols: &'a HashMap<u32, String>) -> Result<UnwindCollector<'a>, String> {
+        let entries = self.get_frame_entries()?;
+        Ok(Box::new(move |function| {
+            let range = function.rva..function.rva + function.size;
+            let overlapping: Vec<&FrameEntry> = entries
+                .iter()
+                .filter(|entry| {
+                    entry.description.begin_address < range.end && entry.description.end_address > range.start
+                })
+                .collect();
+            function.collect_call_frames(&overlapping)
+        }))
+    }
+
+    /// Adds a segment holding `.eh_frame` records for the relocated code and
+    /// a new `.eh_frame_hdr` whose table lists them along with the original
+    /// records, and points `PT_GNU_EH_FRAME` at it.
+    fn emit_unwind_info(&mut self, _entries: &[RelocatedUnwind], frames: &[RelocatedFrame]) -> Result<(), String> {
+        if frames.is_empty() {
+            return Ok(());
+        }
+        let image_base = self.image_base()?;
+        let address = image_base + u64::from(self.get_next_segment_rva()?);
+        let (mut section, mut table) = encode_frames(address, image_base, frames)?;
+        let new_records = table.len();
+        table.extend(
+            self.get_frame_entries()?
+                .iter()
+                .map(|entry| (image_base + u64::from(entry.description.begin_address), entry.record)),
+        );
+        let eh_frame = self.eh_frame_section()?.map_or(address, |(eh_frame, _)| eh_frame);
+
+        let header_offset = section.len();
+        let header = encode_frame_header(address + header_offset as u64, eh_frame, &table)?;
+        section.extend_from_slice(&header);
+        let (rva, _) = self.add_section_with(".eh_frame", &section, SectionAccess::Read)?;
+        if image_base + u64::from(rva) != address {
+            return Err(format!("Unwind segment placed at {rva:#x} instead of {:#x}", address - image_base));
+        }
+
+        let header_address = address + header_offset as u64;
+        let offset = self.rva_to_file_offset(rva)? as u64 + header_offset as u64;
+        self.set_program_header(&ProgramHeader {
+            p_type: PT_GNU_EH_FRAME,
+            p_flags: PF_R,
+            p_offset: offset,
+            p_vaddr: header_address,
+            p_paddr: header_address,
+            p_filesz: header.len() as u64,
+            p_memsz: header.len() as u64,
+            p_align: 4,
+        })?;
+        debug!(
+            "Wrote {new_records} frame descriptions and a lookup table of {} at {header_address:#x}",
+            table.len()
+        );
+        Ok(())
+    }
+}
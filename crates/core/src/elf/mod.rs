@@ -0,0 +1,39 @@
+pub mod cfi;
+pub mod eh_frame;
+pub mod image;
+pub mod parser;
+pub mod segments;
+
+use std::ops::Range;
+
+/// An x86-64 ELF executable or shared object. RVAs are relative to the
+/// first loadable segment, as symbolic reports the symbol and DWARF
+/// addresses, so they read like the RVAs of a PE image.
+#[derive(Clone)]
+pub struct ElfContext {
+    pub elf_data: Vec<u8>,
+}
+
+/// RVA of an address the image gives.
+pub(crate) fn to_rva(address: u64, image_base: u64) -> Result<u32, String> {
+    address
+        .checked_sub(image_base)
+        .and_then(|rva| u32::try_from(rva).ok())
+        .ok_or_else(|| format!("Address {address:#x} is outside the image"))
+}
+
+/// A size the image gives, which RVAs have to be able to span.
+pub(crate) fn to_size(size: u64) -> Result<u32, String> {
+    u32::try_from(size).map_err(|_| format!("Size {size:#x} does not fit the image"))
+}
+
+/// A file offset or size the image gives.
+pub(crate) fn to_offset(value: u64) -> Result<usize, String> {
+    usize::try_from(value).map_err(|_| format!("File offset {value:#x} is out of range"))
+}
+
+/// The bytes of the file at the offset and of the size a header gives.
+pub(crate) fn file_range(offset: u64, size: u64) -> Result<Range<usize>, String> {
+    let start = to_offset(offset)?;
+    Ok(start..start + to_offset(size)?)
+}
@@ -0,0 +1,190 @@
+use crate::elf::{ElfContext, to_offset, to_rva, to_size};
+use crate::pdb::{PDBFunction, SourceLine};
+use goblin::elf::Elf;
+use goblin::elf::dynamic::DF_TEXTREL;
+use goblin::elf::header::{ELFCLASS64, ELFDATA2LSB, EM_X86_64, ET_DYN, ET_EXEC};
+use goblin::elf::program_header::{PF_X, PT_LOAD};
+use std::ops::Range;
+use symbolic::common::Name;
+use symbolic::debuginfo::elf::ElfObject;
+use symbolic::demangle::{Demangle, DemangleOptions};
+
+impl ElfContext {
+    #[must_use]
+    pub const fn new(elf_data: Vec<u8>) -> Self {
+        Self { elf_data }
+    }
+
+    /// # Errors
+    ///
+    /// Fails when the data is not an ELF image goblin can parse.
+    pub fn parse(&self) -> Result<Elf<'_>, String> {
+        Elf::parse(&self.elf_data).map_err(|e| e.to_string())
+    }
+
+    #[must_use]
+    pub fn is_supported(&self) -> bool {
+        let Ok(elf) = self.parse() else {
+            return false;
+        };
+        let ident = &elf.header.e_ident;
+        ident[goblin::elf::header::EI_CLASS] == ELFCLASS64
+            && ident[goblin::elf::header::EI_DATA] == ELFDATA2LSB
+            && elf.header.e_machine == EM_X86_64
+            && matches!(elf.header.e_type, ET_EXEC | ET_DYN)
+            && elf.program_headers.iter().any(|header| header.p_type == PT_LOAD)
+            && !has_text_relocations(&elf)
+    }
+
+    /// Address of the first loadable segment, which RVAs are relative to.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed or has no loadable segment.
+    pub fn image_base(&self) -> Result<u64, String> {
+        self.parse()?
+            .program_headers
+            .iter()
+            .find(|header| header.p_type == PT_LOAD)
+            .map(|header| header.p_vaddr)
+            .ok_or_else(|| "ELF has no loadable segment".to_string())
+    }
+
+    /// RVA ranges of the executable segments.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed.
+    pub fn executable_ranges(&self) -> Result<Vec<Range<u32>>, String> {
+        let elf = self.parse()?;
+        let image_base = self.image_base()?;
+        elf.program_headers
+            .iter()
+            .filter(|header| header.p_type == PT_LOAD && header.p_flags & PF_X != 0)
+            .map(|header| {
+                let start = to_rva(header.p_vaddr, image_base)?;
+                Ok(start..start + to_size(header.p_memsz)?)
+            })
+            .collect()
+    }
+
+    /// # Errors
+    ///
+    /// Fails when no loadable segment holds `rva` in the file.
+    pub fn rva_to_file_offset(&self, rva: u32) -> Result<usize, String> {
+        let elf = self.parse()?;
+        let address = self.image_base()? + u64::from(rva);
+        elf.program_headers
+            .iter()
+            .filter(|header| header.p_type == PT_LOAD)
+            .find(|header| (header.p_vaddr..header.p_vaddr + header.p_filesz).contains(&address))
+            .ok_or_else(|| format!("RVA {rva:#x} is not backed by the file"))
+            .and_then(|header| to_offset(header.p_offset + address - header.p_vaddr))
+    }
+
+    /// # Errors
+    ///
+    /// Fails when the range is not backed by the file.
+    pub fn read_data_at_rva(&self, rva: u32, size: usize) -> Result<Vec<u8>, String> {
+        let offset = self.rva_to_file_offset(rva)?;
+        self.elf_data
+            .get(offset..offset + size)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| "Read would exceed file bounds".to_string())
+    }
+
+    /// # Errors
+    ///
+    /// Fails when the range is not backed by the file.
+    pub fn write_data_at_rva(&mut self, rva: u32, data: &[u8]) -> Result<(), String> {
+        let offset = self.rva_to_file_offset(rva)?;
+        self.elf_data
+            .get_mut(offset..offset + data.len())
+            .ok_or_else(|| "Write would exceed file bounds".to_string())?
+            .copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Functions from the DWARF debug information, then from the symbol
+    /// tables, then from the `.eh_frame` entries that neither covers, which
+    /// are named `sub_<rva>`. Stripped shared objects keep their dynamic
+    /// symbols and `.eh_frame`, so most of their code is still found.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image, its debug information or `.eh_frame` cannot be
+    /// read.
+    pub fn get_functions(&self) -> Result<Vec<PDBFunction>, String> {
+        let object = ElfObject::parse(&self.elf_data).map_err(|e| e.to_string())?;
+        // symbolic gives addresses relative to the image base already.
+        let mut functions = Vec::new();
+        if object.has_debug_info() {
+            let session = object.debug_session().map_err(|e| e.to_string())?;
+            for func in session.functions().flatten().filter(|func| func.size > 0) {
+                functions.push(PDBFunction {
+                    name: demangle(func.name.as_ref()),
+                    rva: to_rva(func.address, 0)?,
+                    size: to_size(func.size)?,
+                });
+            }
+        }
+        for symbol in object.symbols().filter(|symbol| symbol.size > 0) {
+            functions.push(PDBFunction {
+                name: symbol
+                    .name
+                    .map_or_else(|| format!("sub_{:x}", symbol.address), |name| demangle(&name)),
+                rva: to_rva(symbol.address, 0)?,
+                size: to_size(symbol.size)?,
+            });
+        }
+        for description in self.get_frame_descriptions()? {
+            functions.push(PDBFunction {
+                name: format!("sub_{:x}", description.begin_address),
+                rva: description.begin_address,
+                size: description.end_address - description.begin_address,
+            });
+        }
+
+        // Stable, so the first source of every RVA wins.
+        functions.sort_by_key(|f| f.rva);
+        functions.dedup_by(|a, b| a.rva == b.rva);
+        Ok(functions)
+    }
+
+    /// Source lines from the DWARF line programs, sorted by RVA.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image or its debug information cannot be read.
+    pub fn get_lines(&self) -> Result<Vec<SourceLine>, String> {
+        let object = ElfObject::parse(&self.elf_data).map_err(|e| e.to_string())?;
+        if !object.has_debug_info() {
+            return Ok(Vec::new());
+        }
+        let session = object.debug_session().map_err(|e| e.to_string())?;
+        let mut lines = Vec::new();
+        for func in session.functions().flatten() {
+            for line in &func.lines {
+                lines.push(SourceLine {
+                    rva: to_rva(line.address, 0)?,
+                    size: to_size(line.size.unwrap_or(0))?,
+                    file: line.file.path_str(),
+                    line: u32::try_from(line.line).unwrap_or(u32::MAX),
+                });
+            }
+        }
+        lines.sort_by_key(|line| line.rva);
+        Ok(lines)
+    }
+}
+
+/// Relocations the loader applies to code would have to move with it.
+fn has_text_relocations(elf: &Elf) -> bool {
+    elf.dynamic
+        .as_ref()
+        .is_some_and(|dynamic| dynamic.info.textrel || dynamic.info.flags & DF_TEXTREL != 0)
+}
+
+fn demangle(name: &str) -> String {
+    Name::from(name).try_demangle(DemangleOptions::complete()).to_string()
+}
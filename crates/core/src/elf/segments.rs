@@ -0,0 +1,175 @@
+use crate::elf::{ElfContext, to_offset, to_rva};
+use goblin::elf::ProgramHeader;
+use goblin::elf::program_header::{PF_R, PT_LOAD, PT_NULL, PT_PHDR};
+
+pub const PAGE_SIZE: u64 = 0x1000;
+/// Free entries left in a moved program header table for later segments.
+const SPARE_PROGRAM_HEADERS: usize = 8;
+const PHDR_SIZE: usize = 56;
+const E_PHOFF: usize = 32;
+const E_PHNUM: usize = 56;
+
+/// Where the next segment goes.
+struct Placement {
+    /// Index of a free program header, `None` when the table must move.
+    slot: Option<usize>,
+    /// Where the table goes when it moves.
+    table_offset: u64,
+    table_address: u64,
+    address: u64,
+}
+
+impl ElfContext {
+    /// RVA of the segment the next [`ElfContext::create_segment`] adds.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed.
+    pub fn get_next_segment_rva(&self) -> Result<u32, String> {
+        to_rva(self.placement()?.address, self.image_base()?)
+    }
+
+    /// Appends a loadable segment of `size` zero bytes to the file, moving
+    /// the program header table behind the loaded image when it has no free
+    /// entry. Returns the RVA of the segment.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed.
+    pub fn create_segment(&mut self, size: u32, flags: u32) -> Result<u32, String> {
+        let placement = self.placement()?;
+        let slot = self.free_program_header(&placement)?;
+        let offset = (self.elf_data.len() as u64).next_multiple_of(PAGE_SIZE);
+        self.elf_data.resize(to_offset(offset + u64::from(size))?, 0);
+        self.write_program_header(
+            slot,
+            &ProgramHeader {
+                p_type: PT_LOAD,
+                p_flags: flags,
+                p_offset: offset,
+                p_vaddr: placement.address,
+                p_paddr: placement.address,
+                p_filesz: u64::from(size),
+                p_memsz: u64::from(size),
+                p_align: PAGE_SIZE,
+            },
+        )?;
+        to_rva(placement.address, self.image_base()?)
+    }
+
+    /// Replaces the program header of type `header.p_type`, or adds it when
+    /// the image has none.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed.
+    pub fn set_program_header(&mut self, header: &ProgramHeader) -> Result<(), String> {
+        let existing = self
+            .parse()?
+            .program_headers
+            .iter()
+            .position(|existing| existing.p_type == header.p_type);
+        let slot = if let Some(slot) = existing {
+            slot
+        } else {
+            let placement = self.placement()?;
+            self.free_program_header(&placement)?
+        };
+        self.write_program_header(slot, header)
+    }
+
+    fn free_program_header(&mut self, placement: &Placement) -> Result<usize, String> {
+        placement.slot.map_or_else(|| self.move_program_headers(placement), Ok)
+    }
+
+    /// Loadable segments must stay sorted by address, so only entries past
+    /// the last of them are free.
+    fn placement(&self) -> Result<Placement, String> {
+        let elf = self.parse()?;
+        let headers = &elf.program_headers;
+        let last_load = headers
+            .iter()
+            .rposition(|header| header.p_type == PT_LOAD)
+            .ok_or_else(|| "ELF has no loadable segment".to_string())?;
+        let slot = (last_load + 1..headers.len()).find(|&index| headers[index].p_type == PT_NULL);
+        let loads = headers.iter().filter(|header| header.p_type == PT_LOAD);
+        let loaded_end = loads.map(|header| header.p_vaddr + header.p_memsz).max().unwrap_or(0);
+
+        // The kernel finds the table at the address its offset maps to
+        // through the first loadable segment.
+        let first = &headers[headers.iter().position(|header| header.p_type == PT_LOAD).unwrap_or(0)];
+        let delta = first.p_vaddr - first.p_offset;
+        let table_offset = (self.elf_data.len() as u64)
+            .max(loaded_end.saturating_sub(delta))
+            .next_multiple_of(PAGE_SIZE);
+        let table_address = delta + table_offset;
+        let table_size = ((headers.len() + SPARE_PROGRAM_HEADERS) * PHDR_SIZE) as u64;
+        let address = if slot.is_some() {
+            loaded_end.next_multiple_of(PAGE_SIZE)
+        } else {
+            (table_address + table_size).next_multiple_of(PAGE_SIZE)
+        };
+        Ok(Placement {
+            slot,
+            table_offset,
+            table_address,
+            address,
+        })
+    }
+
+    /// Copies the program header table to the end of the file with room for
+    /// more entries, in a segment of its own. Returns the first free entry.
+    fn move_program_headers(&mut self, placement: &Placement) -> Result<usize, String> {
+        let (offset, count) = {
+            let elf = self.parse()?;
+            (to_offset(elf.header.e_phoff)?, elf.program_headers.len())
+        };
+        let mut table = self
+            .elf_data
+            .get(offset..offset + count * PHDR_SIZE)
+            .ok_or_else(|| "Program header table is outside the file".to_string())?
+            .to_vec();
+        let total = count + SPARE_PROGRAM_HEADERS;
+        table.resize(total * PHDR_SIZE, 0);
+
+        let phnum = u16::try_from(total).map_err(|_| "Too many program headers".to_string())?;
+        self.elf_data.resize(to_offset(placement.table_offset)?, 0);
+        self.elf_data.extend_from_slice(&table);
+        self.elf_data[E_PHOFF..E_PHOFF + 8].copy_from_slice(&placement.table_offset.to_le_bytes());
+        self.elf_data[E_PHNUM..E_PHNUM + 2].copy_from_slice(&phnum.to_le_bytes());
+
+        let table_size = (total * PHDR_SIZE) as u64;
+        let segment = |p_type| ProgramHeader {
+            p_type,
+            p_flags: PF_R,
+            p_offset: placement.table_offset,
+            p_vaddr: placement.table_address,
+            p_paddr: placement.table_address,
+            p_filesz: table_size,
+            p_memsz: table_size,
+            p_align: if p_type == PT_LOAD { PAGE_SIZE } else { 8 },
+        };
+        self.write_program_header(count, &segment(PT_LOAD))?;
+        if let Some(phdr) = self.parse()?.program_headers.iter().position(|header| header.p_type == PT_PHDR) {
+            self.write_program_header(phdr, &segment(PT_PHDR))?;
+        }
+        Ok(count + 1)
+    }
+
+    fn write_program_header(&mut self, index: usize, header: &ProgramHeader) -> Result<(), String> {
+        let offset = to_offset(self.parse()?.header.e_phoff)? + index * PHDR_SIZE;
+        let entry = self
+            .elf_data
+            .get_mut(offset..offset + PHDR_SIZE)
+            .ok_or_else(|| format!("Program header {index} is outside the file"))?;
+        entry[0..4].copy_from_slice(&header.p_type.to_le_bytes());
+        entry[4..8].copy_from_slice(&header.p_flags.to_le_bytes());
+        entry[8..16].copy_from_slice(&header.p_offset.to_le_bytes());
+        entry[16..24].copy_from_slice(&header.p_vaddr.to_le_bytes());
+        entry[24..32].copy_from_slice(&header.p_paddr.to_le_bytes());
+        entry[32..40].copy_from_slice(&header.p_filesz.to_le_bytes());
+        entry[40..48].copy_from_slice(&header.p_memsz.to_le_bytes());
+        entry[48..56].copy_from_slice(&header.p_align.to_le_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,811 @@
+use crate::pe::PEContext;
+use crate::unwind::Anchor;
+
+const FH3_MAGIC_MIN: u32 = 0x1993_0520;
+const FH3_MAGIC_MAX: u32 = 0x1993_0522;
+
+const FH4_IS_CATCH: u8 = 0x01;
+const FH4_IS_SEPARATED: u8 = 0x02;
+const FH4_BBT: u8 = 0x04;
+const FH4_UNWIND_MAP: u8 = 0x08;
+const FH4_TRY_BLOCK_MAP: u8 = 0x10;
+
+const FH4_HANDLER_ADJECTIVES: u8 = 0x01;
+const FH4_HANDLER_DISP_TYPE: u8 = 0x02;
+const FH4_HANDLER_CATCH_OBJ: u8 = 0x04;
+const FH4_HANDLER_CONT_IS_RVA: u8 = 0x08;
+const FH4_HANDLER_CONT_MASK: u8 = 0x30;
+
+const GS_HAS_ALIGNMENT: u32 = 0x4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandlerKind {
+    CSpecific,
+    GsCheck,
+    GsCheckSeh,
+    Cxx3,
+    GsCheckEh,
+    Cxx4,
+    GsCheckEh4,
+}
+
+impl HandlerKind {
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "__C_specific_handler" => Some(Self::CSpecific),
+            "__GSHandlerCheck" => Some(Self::GsCheck),
+            "__GSHandlerCheck_SEH" => Some(Self::GsCheckSeh),
+            "__CxxFrameHandler3" => Some(Self::Cxx3),
+            "__GSHandlerCheck_EH" => Some(Self::GsCheckEh),
+            "__CxxFrameHandler4" => Some(Self::Cxx4),
+            "__GSHandlerCheck_EH4" => Some(Self::GsCheckEh4),
+            _ => None,
+        }
+    }
+
+    const fn has_gs_data(self) -> bool {
+        matches!(
+            self,
+            Self::GsCheck | Self::GsCheckSeh | Self::GsCheckEh | Self::GsCheckEh4
+        )
+    }
+}
+
+/// An address referenced by exception data.
+///
+/// Addresses inside the function move with the instruction they name,
+/// `bias` bytes into it (LLVM places IP-to-state boundaries one byte past
+/// the start of a call); everything else is kept as is.
+#[derive(Clone, Copy, Debug)]
+pub enum CodeAddress {
+    Inside { anchor: Anchor, bias: u32 },
+    Outside(u32),
+}
+
+impl CodeAddress {
+    const fn entry_point(&self) -> Option<usize> {
+        match self {
+            Self::Inside { anchor, bias: 0 } => *anchor,
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ScopeRecord {
+    pub begin: CodeAddress,
+    pub end: CodeAddress,
+    pub handler: CodeAddress,
+    pub target: CodeAddress,
+}
+
+#[derive(Clone, Debug)]
+pub struct CatchHandler3 {
+    pub adjectives: u32,
+    pub type_descriptor: u32,
+    pub catch_object: i32,
+    pub handler: CodeAddress,
+    pub frame: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct TryBlock3 {
+    pub try_low: i32,
+    pub try_high: i32,
+    pub catch_high: i32,
+    pub handlers: Vec<CatchHandler3>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FuncInfo3 {
+    pub magic: u32,
+    pub max_state: i32,
+    pub unwind_map: Vec<(i32, CodeAddress)>,
+    pub try_blocks: Vec<TryBlock3>,
+    pub ip_map: Vec<(CodeAddress, i32)>,
+    pub unwind_help: i32,
+    pub es_type_list: u32,
+    pub eh_flags: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct CatchHandler4 {
+    pub header: u8,
+    pub adjectives: Option<u32>,
+    pub type_descriptor: Option<u32>,
+    pub catch_object: Option<u32>,
+    pub handler: CodeAddress,
+    pub continuations: Vec<CodeAddress>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TryBlock4 {
+    pub try_low: u32,
+    pub try_high: u32,
+    pub catch_high: u32,
+    pub handlers: Vec<CatchHandler4>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FuncInfo4 {
+    pub header: u8,
+    pub bbt_flags: Option<u32>,
+    pub unwind_map: Option<u32>,
+    pub try_blocks: Option<Vec<TryBlock4>>,
+    pub ip_map: Vec<(CodeAddress, i32)>,
+    pub frame: Option<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub enum LanguageData {
+    None,
+    ScopeTable(Vec<ScopeRecord>),
+    FuncInfo3(FuncInfo3),
+    FuncInfo4(FuncInfo4),
+}
+
+#[derive(Clone, Debug)]
+pub struct ExceptionHandler {
+    pub kind: HandlerKind,
+    pub data: LanguageData,
+    pub gs_data: Vec<u8>,
+}
+
+/// A block of data emitted next to the unwind info. `pointers` are RVA
+/// slots inside `bytes` that must receive the address of another blob.
+#[derive(Clone, Debug, Default)]
+pub struct DataBlob {
+    pub bytes: Vec<u8>,
+    pub pointers: Vec<BlobPointer>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BlobPointer {
+    pub offset: usize,
+    pub blob: usize,
+}
+
+impl DataBlob {
+    fn push_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn push_i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Entry count of a table, which the format holds in 32 bits.
+    fn count(length: usize) -> Result<u32, String> {
+        u32::try_from(length).map_err(|_| format!("Exception table of {length} entries is too large"))
+    }
+
+    fn push_pointer(&mut self, blob: Option<usize>) {
+        if let Some(blob) = blob {
+            self.pointers.push(BlobPointer {
+                offset: self.bytes.len(),
+                blob,
+            });
+        }
+        self.push_u32(0);
+    }
+
+    /// FH4 compressed unsigned integer: the low bits of the first byte give
+    /// the length, the remaining bits hold the value.
+    fn push_compressed(&mut self, value: u32) {
+        if value < 1 << 7 {
+            self.bytes.extend_from_slice(&(value << 1).to_le_bytes()[..1]);
+        } else if value < 1 << 14 {
+            self.bytes
+                .extend_from_slice(&((value << 2) | 0x1).to_le_bytes()[..2]);
+        } else if value < 1 << 21 {
+            self.bytes
+                .extend_from_slice(&((value << 3) | 0x3).to_le_bytes()[..3]);
+        } else if value < 1 << 28 {
+            self.bytes
+                .extend_from_slice(&((value << 4) | 0x7).to_le_bytes());
+        } else {
+            self.bytes.push(0xF);
+            self.push_u32(value);
+        }
+    }
+}
+
+struct DataReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> DataReader<'a> {
+    fn new(pe_context: &'a PEContext, rva: u32) -> Result<Self, String> {
+        Ok(Self {
+            bytes: pe_context.data_at_rva(rva)?,
+            position: 0,
+        })
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + count)
+            .ok_or_else(|| "Exception data is truncated".to_string())?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        Ok(self.u32()?.cast_signed())
+    }
+
+    fn compressed(&mut self) -> Result<u32, String> {
+        let first = *self
+            .bytes
+            .get(self.position)
+            .ok_or_else(|| "Exception data is truncated".to_string())?;
+        let length = match first & 0xF {
+            bits if bits & 0x1 == 0 => 1,
+            bits if bits & 0x3 == 0x1 => 2,
+            bits if bits & 0x7 == 0x3 => 3,
+            0x7 => 4,
+            _ => {
+                self.take(1)?;
+                return self.u32();
+            }
+        };
+
+        let bytes = self.take(length)?;
+        let value = bytes
+            .iter()
+            .enumerate()
+            .fold(0u32, |value, (index, &byte)| value | u32::from(byte) << (8 * index));
+        Ok(value >> length)
+    }
+}
+
+impl ExceptionHandler {
+    /// Parses the language specific data that follows the handler RVA of an
+    /// `UNWIND_INFO`. `address` classifies code addresses against the function
+    /// being analyzed.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the handler data is truncated or its handler is not
+    /// supported.
+    pub fn parse(
+        kind: HandlerKind,
+        pe_context: &PEContext,
+        data_rva: u32,
+        region_begin: u32,
+        address: &dyn Fn(u32) -> Result<CodeAddress, String>,
+    ) -> Result<Self, String> {
+        let mut reader = DataReader::new(pe_context, data_rva)?;
+        let data = match kind {
+            HandlerKind::CSpecific | HandlerKind::GsCheckSeh => {
+                LanguageData::ScopeTable(Self::parse_scope_table(&mut reader, address)?)
+            }
+            HandlerKind::Cxx3 | HandlerKind::GsCheckEh => {
+                let func_info = reader.u32()?;
+                LanguageData::FuncInfo3(Self::parse_func_info3(pe_context, func_info, address)?)
+            }
+            HandlerKind::Cxx4 | HandlerKind::GsCheckEh4 => {
+                let func_info = reader.u32()?;
+                LanguageData::FuncInfo4(Self::parse_func_info4(
+                    pe_context,
+                    func_info,
+                    region_begin,
+                    address,
+                )?)
+            }
+            HandlerKind::GsCheck => LanguageData::None,
+        };
+
+        let mut gs_data = Vec::new();
+        if kind.has_gs_data() {
+            let cookie = reader.u32()?;
+            gs_data.extend_from_slice(&cookie.to_le_bytes());
+            if cookie & GS_HAS_ALIGNMENT != 0 {
+                gs_data.extend_from_slice(reader.take(8)?);
+            }
+        }
+
+        Ok(Self {
+            kind,
+            data,
+            gs_data,
+        })
+    }
+
+    fn parse_scope_table(
+        reader: &mut DataReader,
+        address: &dyn Fn(u32) -> Result<CodeAddress, String>,
+    ) -> Result<Vec<ScopeRecord>, String> {
+        let count = reader.u32()?;
+        (0..count)
+            .map(|_| {
+                Ok(ScopeRecord {
+                    begin: address(reader.u32()?)?,
+                    end: address(reader.u32()?)?,
+                    handler: address(reader.u32()?)?,
+                    target: address(reader.u32()?)?,
+                })
+            })
+            .collect()
+    }
+
+    fn parse_func_info3(
+        pe_context: &PEContext,
+        rva: u32,
+        address: &dyn Fn(u32) -> Result<CodeAddress, String>,
+    ) -> Result<FuncInfo3, String> {
+        let mut reader = DataReader::new(pe_context, rva)?;
+        let magic = reader.u32()?;
+        if !(FH3_MAGIC_MIN..=FH3_MAGIC_MAX).contains(&magic) {
+            return Err(format!("Unknown FuncInfo magic {magic:#x} at {rva:#x}"));
+        }
+
+        let max_state = reader.i32()?;
+        let unwind_map_rva = reader.u32()?;
+        let try_block_count = reader.u32()?;
+        let try_block_rva = reader.u32()?;
+        let ip_map_count = reader.u32()?;
+        let ip_map_rva = reader.u32()?;
+        let unwind_help = reader.i32()?;
+        let es_type_list = if magic >= 0x1993_0521 { reader.u32()? } else { 0 };
+        let eh_flags = if magic >= 0x1993_0522 { reader.u32()? } else { 0 };
+
+        let mut unwind_map = Vec::new();
+        if max_state > 0 {
+            let mut reader = DataReader::new(pe_context, unwind_map_rva)?;
+            for _ in 0..max_state {
+                let to_state = reader.i32()?;
+                unwind_map.push((to_state, address(reader.u32()?)?));
+            }
+        }
+
+        let mut try_blocks = Vec::new();
+        if try_block_count > 0 {
+            let mut reader = DataReader::new(pe_context, try_block_rva)?;
+            for _ in 0..try_block_count {
+                let try_low = reader.i32()?;
+                let try_high = reader.i32()?;
+                let catch_high = reader.i32()?;
+                let handler_count = reader.u32()?;
+                let handler_rva = reader.u32()?;
+
+                let mut handler_reader = DataReader::new(pe_context, handler_rva)?;
+                let handlers = (0..handler_count)
+                    .map(|_| {
+                        Ok(CatchHandler3 {
+                            adjectives: handler_reader.u32()?,
+                            type_descriptor: handler_reader.u32()?,
+                            catch_object: handler_reader.i32()?,
+                            handler: address(handler_reader.u32()?)?,
+                            frame: handler_reader.u32()?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+
+                try_blocks.push(TryBlock3 {
+                    try_low,
+                    try_high,
+                    catch_high,
+                    handlers,
+                });
+            }
+        }
+
+        let mut ip_map = Vec::new();
+        if ip_map_count > 0 {
+            let mut reader = DataReader::new(pe_context, ip_map_rva)?;
+            for _ in 0..ip_map_count {
+                let ip = address(reader.u32()?)?;
+                ip_map.push((ip, reader.i32()?));
+            }
+        }
+
+        Ok(FuncInfo3 {
+            magic,
+            max_state,
+            unwind_map,
+            try_blocks,
+            ip_map,
+            unwind_help,
+            es_type_list,
+            eh_flags,
+        })
+    }
+
+    fn parse_func_info4(
+        pe_context: &PEContext,
+        rva: u32,
+        region_begin: u32,
+        address: &dyn Fn(u32) -> Result<CodeAddress, String>,
+    ) -> Result<FuncInfo4, String> {
+        let mut reader = DataReader::new(pe_context, rva)?;
+        let header = reader.u8()?;
+        if header & FH4_IS_SEPARATED != 0 {
+            return Err("Separated FH4 functions are not supported".to_string());
+        }
+
+        let bbt_flags = (header & FH4_BBT != 0)
+            .then(|| reader.compressed())
+            .transpose()?;
+        let unwind_map = (header & FH4_UNWIND_MAP != 0)
+            .then(|| reader.u32())
+            .transpose()?;
+        let try_block_rva = (header & FH4_TRY_BLOCK_MAP != 0)
+            .then(|| reader.u32())
+            .transpose()?;
+        let ip_map_rva = reader.u32()?;
+        let frame = (header & FH4_IS_CATCH != 0)
+            .then(|| reader.compressed())
+            .transpose()?;
+
+        // Unwind actions are referenced by RVA and stay valid as long as they
+        // live outside the function, which is where MSVC puts its funclets.
+        if let Some(unwind_map_rva) = unwind_map {
+            let mut reader = DataReader::new(pe_context, unwind_map_rva)?;
+            for _ in 0..reader.compressed()? {
+                let next = reader.compressed()?;
+                let action = match next & 0x3 {
+                    0 => None,
+                    3 => Some(reader.u32()?),
+                    _ => {
+                        let action = reader.u32()?;
+                        reader.compressed()?;
+                        Some(action)
+                    }
+                };
+                if let Some(action) = action
+                    && matches!(address(action)?, CodeAddress::Inside { .. })
+                {
+                    return Err("FH4 unwind action inside the function is not supported".to_string());
+                }
+            }
+        }
+
+        let try_blocks = try_block_rva
+            .map(|try_block_rva| {
+                let mut reader = DataReader::new(pe_context, try_block_rva)?;
+                (0..reader.compressed()?)
+                    .map(|_| {
+                        let try_low = reader.compressed()?;
+                        let try_high = reader.compressed()?;
+                        let catch_high = reader.compressed()?;
+                        let handler_rva = reader.u32()?;
+                        Ok(TryBlock4 {
+                            try_low,
+                            try_high,
+                            catch_high,
+                            handlers: Self::parse_handlers4(
+                                pe_context,
+                                handler_rva,
+                                region_begin,
+                                address,
+                            )?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, String>>()
+            })
+            .transpose()?;
+
+        let mut ip_map = Vec::new();
+        if ip_map_rva != 0 {
+            let mut reader = DataReader::new(pe_context, ip_map_rva)?;
+            let mut ip = region_begin;
+            for _ in 0..reader.compressed()? {
+                ip += reader.compressed()?;
+                let state = reader.compressed()?.cast_signed() - 1;
+                ip_map.push((address(ip)?, state));
+            }
+        }
+
+        Ok(FuncInfo4 {
+            header,
+            bbt_flags,
+            unwind_map,
+            try_blocks,
+            ip_map,
+            frame,
+        })
+    }
+
+    fn parse_handlers4(
+        pe_context: &PEContext,
+        rva: u32,
+        region_begin: u32,
+        address: &dyn Fn(u32) -> Result<CodeAddress, String>,
+    ) -> Result<Vec<CatchHandler4>, String> {
+        let mut reader = DataReader::new(pe_context, rva)?;
+        (0..reader.compressed()?)
+            .map(|_| {
+                let header = reader.u8()?;
+                let adjectives = (header & FH4_HANDLER_ADJECTIVES != 0)
+                    .then(|| reader.compressed())
+                    .transpose()?;
+                let type_descriptor = (header & FH4_HANDLER_DISP_TYPE != 0)
+                    .then(|| reader.u32())
+                    .transpose()?;
+                let catch_object = (header & FH4_HANDLER_CATCH_OBJ != 0)
+                    .then(|| reader.compressed())
+                    .transpose()?;
+                let handler = address(reader.u32()?)?;
+
+                let continuation_count = (header & FH4_HANDLER_CONT_MASK) >> 4;
+                if continuation_count > 2 {
+                    return Err("Invalid FH4 continuation count".to_string());
+                }
+                let continuations = (0..continuation_count)
+                    .map(|_| {
+                        let continuation = if header & FH4_HANDLER_CONT_IS_RVA != 0 {
+                            reader.u32()?
+                        } else {
+                            region_begin + reader.compressed()?
+                        };
+                        address(continuation)
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+
+                Ok(CatchHandler4 {
+                    header,
+                    adjectives,
+                    type_descriptor,
+                    catch_object,
+                    handler,
+                    continuations,
+                })
+            })
+            .collect()
+    }
+
+    /// Instructions the runtime may transfer control to directly.
+    pub fn entry_points(&self) -> Vec<usize> {
+        let addresses: Vec<CodeAddress> = match &self.data {
+            LanguageData::None => Vec::new(),
+            LanguageData::ScopeTable(records) => records
+                .iter()
+                .flat_map(|record| [record.handler, record.target])
+                .collect(),
+            LanguageData::FuncInfo3(func_info) => func_info
+                .unwind_map
+                .iter()
+                .map(|(_, action)| *action)
+                .chain(
+                    func_info
+                        .try_blocks
+                        .iter()
+                        .flat_map(|block| block.handlers.iter().map(|handler| handler.handler)),
+                )
+                .collect(),
+            LanguageData::FuncInfo4(func_info) => func_info
+                .try_blocks
+                .iter()
+                .flatten()
+                .flat_map(|block| &block.handlers)
+                .flat_map(|handler| {
+                    std::iter::once(handler.handler).chain(handler.continuations.iter().copied())
+                })
+                .collect(),
+        };
+
+        addresses
+            .iter()
+            .filter_map(CodeAddress::entry_point)
+            .collect()
+    }
+
+    /// Re-emits the handler data for the relocated function. The returned
+    /// blob is the inline handler data; its pointers index into the list of
+    /// out-of-line blobs returned alongside it.
+    ///
+    /// # Errors
+    ///
+    /// Fails when an address the handler data refers to no longer exists in the
+    /// function.
+    pub fn relocate(
+        &self,
+        resolve: &dyn Fn(CodeAddress) -> Result<u32, String>,
+        region_begin: u32,
+    ) -> Result<(DataBlob, Vec<DataBlob>), String> {
+        let mut inline = DataBlob::default();
+        let mut blobs = Vec::new();
+
+        match &self.data {
+            LanguageData::None => {}
+            LanguageData::ScopeTable(records) => {
+                inline.push_u32(DataBlob::count(records.len())?);
+                for record in records {
+                    inline.push_u32(resolve(record.begin)?);
+                    inline.push_u32(resolve(record.end)?);
+                    inline.push_u32(resolve(record.handler)?);
+                    inline.push_u32(resolve(record.target)?);
+                }
+            }
+            LanguageData::FuncInfo3(func_info) => {
+                let index = Self::emit_func_info3(func_info, resolve, &mut blobs)?;
+                inline.push_pointer(Some(index));
+            }
+            LanguageData::FuncInfo4(func_info) => {
+                let index = Self::emit_func_info4(func_info, resolve, region_begin, &mut blobs)?;
+                inline.push_pointer(Some(index));
+            }
+        }
+
+        inline.bytes.extend_from_slice(&self.gs_data);
+        Ok((inline, blobs))
+    }
+
+    fn emit_func_info3(
+        func_info: &FuncInfo3,
+        resolve: &dyn Fn(CodeAddress) -> Result<u32, String>,
+        blobs: &mut Vec<DataBlob>,
+    ) -> Result<usize, String> {
+        let unwind_map = (!func_info.unwind_map.is_empty())
+            .then(|| -> Result<usize, String> {
+                let mut blob = DataBlob::default();
+                for &(to_state, action) in &func_info.unwind_map {
+                    blob.push_i32(to_state);
+                    blob.push_u32(resolve(action)?);
+                }
+                blobs.push(blob);
+                Ok(blobs.len() - 1)
+            })
+            .transpose()?;
+
+        let try_blocks = (!func_info.try_blocks.is_empty())
+            .then(|| -> Result<usize, String> {
+                let mut blob = DataBlob::default();
+                for block in &func_info.try_blocks {
+                    let mut handlers = DataBlob::default();
+                    for handler in &block.handlers {
+                        handlers.push_u32(handler.adjectives);
+                        handlers.push_u32(handler.type_descriptor);
+                        handlers.push_i32(handler.catch_object);
+                        handlers.push_u32(resolve(handler.handler)?);
+                        handlers.push_u32(handler.frame);
+                    }
+                    blobs.push(handlers);
+
+                    blob.push_i32(block.try_low);
+                    blob.push_i32(block.try_high);
+                    blob.push_i32(block.catch_high);
+                    blob.push_u32(DataBlob::count(block.handlers.len())?);
+                    blob.push_pointer(Some(blobs.len() - 1));
+                }
+                blobs.push(blob);
+                Ok(blobs.len() - 1)
+            })
+            .transpose()?;
+
+        let ip_map = Self::resolve_ip_map(&func_info.ip_map, resolve)?;
+        let ip_map_blob = (!ip_map.is_empty()).then(|| {
+            let mut blob = DataBlob::default();
+            for &(ip, state) in &ip_map {
+                blob.push_u32(ip);
+                blob.push_i32(state);
+            }
+            blobs.push(blob);
+            blobs.len() - 1
+        });
+
+        let mut blob = DataBlob::default();
+        blob.push_u32(func_info.magic);
+        blob.push_i32(func_info.max_state);
+        blob.push_pointer(unwind_map);
+        blob.push_u32(DataBlob::count(func_info.try_blocks.len())?);
+        blob.push_pointer(try_blocks);
+        blob.push_u32(DataBlob::count(ip_map.len())?);
+        blob.push_pointer(ip_map_blob);
+        blob.push_i32(func_info.unwind_help);
+        blob.push_u32(func_info.es_type_list);
+        blob.push_u32(func_info.eh_flags);
+        blobs.push(blob);
+        Ok(blobs.len() - 1)
+    }
+
+    fn emit_func_info4(
+        func_info: &FuncInfo4,
+        resolve: &dyn Fn(CodeAddress) -> Result<u32, String>,
+        region_begin: u32,
+        blobs: &mut Vec<DataBlob>,
+    ) -> Result<usize, String> {
+        let try_blocks = func_info
+            .try_blocks
+            .as_ref()
+            .map(|try_blocks| -> Result<usize, String> {
+                let mut blob = DataBlob::default();
+                blob.push_compressed(DataBlob::count(try_blocks.len())?);
+                for block in try_blocks {
+                    // Continuations are always written as RVAs so that they do
+                    // not depend on where the function starts.
+                    let mut handlers = DataBlob::default();
+                    handlers.push_compressed(DataBlob::count(block.handlers.len())?);
+                    for handler in &block.handlers {
+                        handlers.bytes.push(handler.header | FH4_HANDLER_CONT_IS_RVA);
+                        if let Some(adjectives) = handler.adjectives {
+                            handlers.push_compressed(adjectives);
+                        }
+                        if let Some(type_descriptor) = handler.type_descriptor {
+                            handlers.push_u32(type_descriptor);
+                        }
+                        if let Some(catch_object) = handler.catch_object {
+                            handlers.push_compressed(catch_object);
+                        }
+                        handlers.push_u32(resolve(handler.handler)?);
+                        for &continuation in &handler.continuations {
+                            handlers.push_u32(resolve(continuation)?);
+                        }
+                    }
+                    blobs.push(handlers);
+
+                    blob.push_compressed(block.try_low);
+                    blob.push_compressed(block.try_high);
+                    blob.push_compressed(block.catch_high);
+                    blob.push_pointer(Some(blobs.len() - 1));
+                }
+                blobs.push(blob);
+                Ok(blobs.len() - 1)
+            })
+            .transpose()?;
+
+        let ip_map = Self::resolve_ip_map(&func_info.ip_map, resolve)?;
+        let ip_map_blob = (!ip_map.is_empty())
+            .then(|| -> Result<usize, String> {
+                let mut blob = DataBlob::default();
+                blob.push_compressed(DataBlob::count(ip_map.len())?);
+                let mut previous = region_begin;
+                for &(ip, state) in &ip_map {
+                    let delta = ip
+                        .checked_sub(previous)
+                        .ok_or_else(|| "FH4 IP-to-state entry before its function".to_string())?;
+                    blob.push_compressed(delta);
+                    blob.push_compressed((state + 1).cast_unsigned());
+                    previous = ip;
+                }
+                blobs.push(blob);
+                Ok(blobs.len() - 1)
+            })
+            .transpose()?;
+
+        let mut blob = DataBlob::default();
+        blob.bytes.push(func_info.header);
+        if let Some(bbt_flags) = func_info.bbt_flags {
+            blob.push_compressed(bbt_flags);
+        }
+        if let Some(unwind_map) = func_info.unwind_map {
+            blob.push_u32(unwind_map);
+        }
+        if func_info.try_blocks.is_some() {
+            blob.push_pointer(try_blocks);
+        }
+        blob.push_pointer(ip_map_blob);
+        if let Some(frame) = func_info.frame {
+            blob.push_compressed(frame);
+        }
+        blobs.push(blob);
+        Ok(blobs.len() - 1)
+    }
+
+    /// The runtime looks states up by address, so the map has to stay sorted
+    /// once entries of the relocated function move past everything else.
+    fn resolve_ip_map(
+        ip_map: &[(CodeAddress, i32)],
+        resolve: &dyn Fn(CodeAddress) -> Result<u32, String>,
+    ) -> Result<Vec<(u32, i32)>, String> {
+        let mut resolved = ip_map
+            .iter()
+            .map(|&(ip, state)| Ok((resolve(ip)?, state)))
+            .collect::<Result<Vec<_>, String>>()?;
+        resolved.sort_by_key(|&(ip, _)| ip);
+        Ok(resolved)
+    }
+}
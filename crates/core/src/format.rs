@@ -0,0 +1,203 @@
+use crate::function::ObfuscatorFunction;
+use crate::pe::relocation::BaseRelocation;
+use crate::unwind::{RelocatedFrame, RelocatedUnwind};
+use iced_x86::Register;
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Container formats the obfuscator recognizes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryFormat {
+    Pe,
+    Elf,
+}
+
+impl BinaryFormat {
+    #[must_use]
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        match data {
+            [b'M', b'Z', ..] => Some(Self::Pe),
+            [0x7F, b'E', b'L', b'F', ..] => Some(Self::Elf),
+            _ => None,
+        }
+    }
+
+    /// Bytes below RSP a function may use without reserving them: the
+    /// System V ABI leaves 128 bytes to them, Windows x64 none. Code the
+    /// passes insert has to step over them before it pushes anything.
+    #[must_use]
+    pub const fn red_zone(self) -> u16 {
+        match self {
+            Self::Pe => 0,
+            Self::Elf => 128,
+        }
+    }
+
+    /// Registers that pass nothing into a called function and that it may
+    /// clobber. Windows x64 leaves RAX, R10 and R11 to the caller. Under
+    /// System V, AL passes the number of vector registers to a varargs
+    /// function and R10 its static chain, leaving only R11.
+    #[must_use]
+    pub const fn call_scratch(self) -> &'static [Register] {
+        match self {
+            Self::Pe => &[Register::RAX, Register::R10, Register::R11],
+            Self::Elf => &[Register::R11],
+        }
+    }
+}
+
+/// What the code in a new section may do with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SectionAccess {
+    Execute,
+    Read,
+    ReadWrite,
+}
+
+/// A range of the loaded image: a section of a PE image, a section or
+/// loadable segment of an ELF image.
+#[derive(Clone, Debug)]
+pub struct ImageRegion {
+    pub name: String,
+    pub rva: u32,
+    /// Size in memory, past the file data when the rest is zero-filled.
+    pub size: u32,
+    /// File bytes loaded at `rva`.
+    pub file: Range<usize>,
+    pub executable: bool,
+    pub writable: bool,
+    /// Read-only data the program reads itself rather than the loader,
+    /// where string literals live.
+    pub constant_data: bool,
+}
+
+impl ImageRegion {
+    /// Size of the part of the region the file holds.
+    #[must_use]
+    pub fn loaded_size(&self) -> u32 {
+        u32::try_from(self.file.len()).map_or(self.size, |file| self.size.min(file))
+    }
+}
+
+/// Builds the unwind information of an analyzed function, failing when
+/// the function's information cannot be tracked through the passes.
+pub type UnwindCollector<'a> = Box<dyn Fn(&mut ObfuscatorFunction) -> Result<(), String> + 'a>;
+
+/// An executable image the pipeline reads and rewrites. The analyzer,
+/// passes and compiler only see this, so every format runs through the
+/// same pipeline.
+pub trait BinaryImage {
+    fn format(&self) -> BinaryFormat;
+
+    /// The image file as rewritten so far.
+    fn data(&self) -> &[u8];
+
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed.
+    fn image_base(&self) -> Result<u64, String>;
+
+    /// # Errors
+    ///
+    /// Fails when the range is not backed by the file.
+    fn read_data_at_rva(&self, rva: u32, size: usize) -> Result<Vec<u8>, String>;
+
+    /// # Errors
+    ///
+    /// Fails when the range is not backed by the file.
+    fn write_data_at_rva(&mut self, rva: u32, data: &[u8]) -> Result<(), String>;
+
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed.
+    fn regions(&self) -> Result<Vec<ImageRegion>, String>;
+
+    /// Ranges the loader reads, which must stay as they are.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed.
+    fn loader_data(&self) -> Result<Vec<Range<u32>>, String>;
+
+    /// Code the image describes as functions for its unwinder, which may
+    /// lie outside the executable regions.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the unwind information cannot be read.
+    fn function_ranges(&self) -> Result<Vec<Range<u32>>, String>;
+
+    /// RVAs the loader or the data of the image point at, besides what the
+    /// code loads RIP-relative.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the relocations cannot be read.
+    fn pointer_targets(&self) -> Result<Vec<u32>, String>;
+
+    /// Every slot the loader fills with the address of an imported
+    /// function, with the name of the function.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed.
+    fn get_import_slots(&self) -> Result<HashMap<u32, String>, String>;
+
+    /// Absolute addresses in the image the loader adjusts in place.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the relocations cannot be read.
+    fn get_base_relocations(&self) -> Result<Vec<BaseRelocation>, String>;
+
+    /// Replaces the relocations of [`BinaryImage::get_base_relocations`].
+    ///
+    /// # Errors
+    ///
+    /// Fails when the relocations cannot be written.
+    fn set_base_relocations(&mut self, relocations: &[BaseRelocation]) -> Result<(), String>;
+
+    /// Where the next [`BinaryImage::add_section`] places its section.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed.
+    fn next_section_rva(&self) -> Result<u32, String>;
+
+    /// Appends a zeroed section of `size` bytes and returns its RVA and
+    /// size in memory.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image has no room for another section.
+    fn add_section(&mut self, name: &str, size: u32, access: SectionAccess) -> Result<(u32, u32), String>;
+
+    /// Appends a section holding `bytes`.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image has no room for another section.
+    fn add_section_with(&mut self, name: &str, bytes: &[u8], access: SectionAccess) -> Result<(u32, u32), String> {
+        let size = u32::try_from(bytes.len()).map_err(|_| format!("Section {name} is too large"))?;
+        let (rva, size) = self.add_section(name, size, access)?;
+        self.write_data_at_rva(rva, bytes)?;
+        Ok((rva, size))
+    }
+
+    /// Reads the unwind information of the image once for every function
+    /// the returned collector is called with. `handler_symbols` names the
+    /// functions linked into the image.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the unwind information cannot be read.
+    fn unwind_collector<'a>(&'a self, handler_symbols: &'a HashMap<u32, String>) -> Result<UnwindCollector<'a>, String>;
+
+    /// Adds the unwind information of the relocated code: `entries` for PE
+    /// images, `frames` for ELF images.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the information cannot be written.
+    fn emit_unwind_info(&mut self, entries: &[RelocatedUnwind], frames: &[RelocatedFrame]) -> Result<(), String>;
+}
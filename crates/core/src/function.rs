@@ -1,23 +1,45 @@
 use crate::branches::BranchInfo;
+use crate::cfg::ControlFlowGraph;
+use crate::format::BinaryImage;
+use crate::imports::{ImportCall, ImportThunk};
 use crate::instruction::{InstructionContext, InstructionWithId};
+use crate::jump_tables::JumpTable;
 use crate::pdb::PDBFunction;
-use crate::pe::PEContext;
+use crate::references::RipReference;
+use crate::relocations::AbsoluteReference;
+use crate::unwind::FunctionUnwind;
+use crate::vm::VmProgram;
 use common::{debug, warn};
-use iced_x86::*;
+use iced_x86::{BlockEncoder, BlockEncoderOptions, Code, Decoder, Instruction, InstructionBlock, Register};
+use std::ops::Range;
 
 pub trait Decodable {
-    fn decode(&mut self, pe_context: &PEContext) -> Result<(), String>;
+    /// # Errors
+    ///
+    /// Fails when the function's bytes cannot be read from the image.
+    fn decode(&mut self, image: &dyn BinaryImage) -> Result<(), String>;
 }
 
 pub trait Encodable {
+    /// # Errors
+    ///
+    /// Fails when an instruction cannot be encoded at its new address.
     fn encode(&mut self, rva: u32) -> Result<Vec<u8>, String>;
+    /// # Errors
+    ///
+    /// Fails when an instruction cannot be encoded at its new address.
+    fn encode_chunks(&mut self, chunks: &[(Range<usize>, u32)]) -> Result<Vec<Vec<u8>>, String>;
 }
 
 pub trait StateManaged {
     fn capture_original_state(&mut self);
     fn get_original_rva(&self) -> u32;
     fn get_original_size(&self) -> u32;
+    /// # Errors
+    ///
+    /// Fails when the original state has not been captured yet.
     fn get_original_instructions(&self) -> Result<&[Instruction], String>;
+    fn is_relocated(&self) -> bool;
 }
 
 pub trait AddressUpdatable {
@@ -40,10 +62,25 @@ pub struct ObfuscatorFunction {
     pub instructions: Vec<InstructionWithId>,
     pub original: Option<OriginalFunctionState>,
     pub branch_map: Vec<BranchInfo>,
+    pub jump_tables: Vec<JumpTable>,
+    pub rip_references: Vec<RipReference>,
+    pub absolute_references: Vec<AbsoluteReference>,
+    pub import_calls: Vec<ImportCall>,
+    pub import_thunks: Vec<ImportThunk>,
+    pub vm: Option<VmProgram>,
+    pub unwind: FunctionUnwind,
+    pub cfg: ControlFlowGraph,
     pub instruction_context: InstructionContext,
+    /// Bytes below RSP the function may keep data in, see
+    /// [`BinaryFormat::red_zone`](crate::format::BinaryFormat::red_zone).
+    pub red_zone: u16,
+    /// Registers free at calls the function makes, see
+    /// [`BinaryFormat::call_scratch`](crate::format::BinaryFormat::call_scratch).
+    pub call_scratch: &'static [Register],
 }
 
 impl ObfuscatorFunction {
+    #[must_use]
     pub fn new(pdb_function: &PDBFunction) -> Self {
         Self {
             name: pdb_function.name.clone(),
@@ -52,11 +89,21 @@ impl ObfuscatorFunction {
             instructions: vec![],
             original: None,
             branch_map: vec![],
+            jump_tables: vec![],
+            rip_references: vec![],
+            absolute_references: vec![],
+            import_calls: vec![],
+            import_thunks: vec![],
+            vm: None,
+            unwind: FunctionUnwind::default(),
+            cfg: ControlFlowGraph::default(),
             instruction_context: InstructionContext::new(),
+            red_zone: 0,
+            call_scratch: &[],
         }
     }
 
-    pub fn get_original(&self) -> Option<&OriginalFunctionState> {
+    pub const fn get_original(&self) -> Option<&OriginalFunctionState> {
         self.original.as_ref()
     }
 }
@@ -104,34 +151,35 @@ impl StateManaged for ObfuscatorFunction {
     fn get_original_rva(&self) -> u32 {
         self.original
             .as_ref()
-            .map(|orig| orig.rva)
-            .unwrap_or(self.rva)
+            .map_or(self.rva, |orig| orig.rva)
     }
 
     fn get_original_size(&self) -> u32 {
         self.original
             .as_ref()
-            .map(|orig| orig.size)
-            .unwrap_or(self.size)
+            .map_or(self.size, |orig| orig.size)
     }
 
     fn get_original_instructions(&self) -> Result<&[Instruction], String> {
-        if let Some(orig) = &self.original {
-            Ok(&orig.instructions)
-        } else {
-            Err("Cannot get original instructions when original state is not captured".to_string())
-        }
+        self.original
+            .as_ref()
+            .map(|orig| orig.instructions.as_slice())
+            .ok_or_else(|| "Cannot get original instructions when original state is not captured".to_string())
+    }
+
+    fn is_relocated(&self) -> bool {
+        self.original.as_ref().is_some_and(|orig| orig.rva != self.rva)
     }
 }
 
 impl Decodable for ObfuscatorFunction {
-    fn decode(&mut self, pe_context: &PEContext) -> Result<(), String> {
+    fn decode(&mut self, image: &dyn BinaryImage) -> Result<(), String> {
         debug!(
             "Decoding function {} at RVA {:#x} with size {}",
             self.name, self.rva, self.size
         );
 
-        let bytes = pe_context
+        let bytes = image
             .read_data_at_rva(self.rva, self.size as usize)
             .map_err(|e| {
                 format!(
@@ -144,7 +192,7 @@ impl Decodable for ObfuscatorFunction {
 
         let mut instructions = Vec::new();
         let mut decoder =
-            Decoder::with_ip(64, &bytes, self.rva as u64, iced_x86::DecoderOptions::NONE);
+            Decoder::with_ip(64, &bytes, u64::from(self.rva), iced_x86::DecoderOptions::NONE);
 
         let mut invalid_instruction_found = false;
 
@@ -179,24 +227,39 @@ impl Decodable for ObfuscatorFunction {
     }
 }
 
-fn adjust_instruction_addrs(code: &mut [InstructionWithId], start_addr: u32) {
+/// Instructions are laid out at this address while encoding so that no
+/// RIP-relative data target can be mistaken by the block encoder for an
+/// instruction of the block being encoded.
+const ENCODING_BASE: u64 = 0x7fff_0000_0000;
+
+fn adjust_instruction_addrs(code: &mut [InstructionWithId], start_addr: u64) {
     let mut new_ip = start_addr;
     for inst_with_id in code.iter_mut() {
-        inst_with_id.instruction.set_ip(new_ip as u64);
-        new_ip = inst_with_id.instruction.next_ip() as u32;
+        inst_with_id.instruction.set_ip(new_ip);
+        new_ip = inst_with_id.instruction.next_ip();
     }
 }
 
 impl Encodable for ObfuscatorFunction {
     fn encode(&mut self, rva: u32) -> Result<Vec<u8>, String> {
+        let mut chunks = self.encode_chunks(&[(0..self.instructions.len(), rva)])?;
+        Ok(chunks.remove(0))
+    }
+
+    /// Encodes each range of instructions at its own address, which must be
+    /// distinct. Branches between the chunks are resolved against their
+    /// final addresses.
+    fn encode_chunks(&mut self, chunks: &[(Range<usize>, u32)]) -> Result<Vec<Vec<u8>>, String> {
         debug!(
-            "Encoding function {} with {} instructions at RVA {:#x}",
+            "Encoding function {} with {} instructions in {} chunks at RVA {:#x}",
             self.name,
             self.instructions.len(),
-            rva
+            chunks.len(),
+            chunks.first().map_or(0, |(_, rva)| *rva)
         );
 
-        adjust_instruction_addrs(&mut self.instructions, rva);
+        adjust_instruction_addrs(&mut self.instructions, ENCODING_BASE);
+        self.retarget_code_references();
 
         self.fix_branches()?;
 
@@ -206,21 +269,49 @@ impl Encodable for ObfuscatorFunction {
             .map(|inst| inst.instruction)
             .collect();
 
-        let block = InstructionBlock::new(&instructions, rva as u64);
+        let blocks: Vec<InstructionBlock> = chunks
+            .iter()
+            .map(|(range, rva)| InstructionBlock::new(&instructions[range.clone()], u64::from(*rva)))
+            .collect();
 
-        let result = match BlockEncoder::encode(64, block, BlockEncoderOptions::NONE) {
-            Ok(result) => result,
+        let results = match BlockEncoder::encode_slice(
+            64,
+            &blocks,
+            BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS,
+        ) {
+            Ok(results) => results,
             Err(e) => {
                 return Err(format!("Failed to encode function {}: {e}", self.name));
             }
         };
 
+        // The block encoder may pick different branch sizes than the estimate
+        // above, so record where every instruction really ended up. Its
+        // results come back sorted by address.
+        let mut code = vec![Vec::new(); chunks.len()];
+        for result in results {
+            let index = chunks
+                .iter()
+                .position(|(_, rva)| u64::from(*rva) == result.rip)
+                .ok_or_else(|| format!("Encoded chunk at unexpected address {:#x}", result.rip))?;
+            let (range, rva) = &chunks[index];
+            for (inst_with_id, &offset) in self.instructions[range.clone()]
+                .iter_mut()
+                .zip(result.new_instruction_offsets.iter())
+            {
+                if offset != u32::MAX {
+                    inst_with_id.instruction.set_ip(u64::from(*rva) + u64::from(offset));
+                }
+            }
+            code[index] = result.code_buffer;
+        }
+
         debug!(
             "Successfully encoded function {} into {} bytes",
             self.name,
-            result.code_buffer.len()
+            code.iter().map(Vec::len).sum::<usize>()
         );
 
-        Ok(result.code_buffer)
+        Ok(code)
     }
 }
@@ -0,0 +1,82 @@
+use crate::format::BinaryImage;
+use crate::function::ObfuscatorFunction;
+use common::debug;
+use iced_x86::Code;
+
+/// A `call [rip+slot]` through the import address table.
+#[derive(Clone, Debug)]
+pub struct ImportCall {
+    pub instruction_id: usize,
+    pub slot_rva: u32,
+    pub name: String,
+}
+
+/// A rewritten import call whose `lea` still points at the IAT slot. The
+/// compiler moves it to an entry of the import table holding
+/// `(slot - entry) ^ key`.
+#[derive(Clone, Debug)]
+pub struct ImportThunk {
+    pub instruction_id: usize,
+    pub slot_rva: u32,
+    pub key: i32,
+}
+
+impl ImportThunk {
+    #[must_use]
+    pub fn encode_entry(&self, entry_rva: u32) -> u64 {
+        ((i64::from(self.slot_rva) - i64::from(entry_rva)) ^ i64::from(self.key)).cast_unsigned()
+    }
+}
+
+impl ObfuscatorFunction {
+    /// # Errors
+    ///
+    /// Fails when the import table cannot be read.
+    pub fn collect_import_calls(&mut self, image: &dyn BinaryImage) -> Result<(), String> {
+        let slots = image.get_import_slots()?;
+        self.import_calls = self
+            .instructions
+            .iter()
+            .filter(|inst| {
+                inst.instruction.code() == Code::Call_rm64 && inst.instruction.is_ip_rel_memory_operand()
+            })
+            .filter_map(|inst| {
+                let slot_rva = u32::try_from(inst.instruction.ip_rel_memory_address()).ok()?;
+                let name = slots.get(&slot_rva)?;
+                debug!(
+                    "Import call at {:#x} in {} to {name}",
+                    inst.instruction.ip(),
+                    self.name
+                );
+                Some(ImportCall {
+                    instruction_id: inst.id,
+                    slot_rva,
+                    name: name.clone(),
+                })
+            })
+            .collect();
+        Ok(())
+    }
+
+    pub fn import_call(&self, instruction_id: usize) -> Option<&ImportCall> {
+        self.import_calls
+            .iter()
+            .find(|call| call.instruction_id == instruction_id)
+    }
+
+    /// Points the `lea` of a rewritten import call at its table entry,
+    /// keeping any displacement a later pass added to it.
+    pub fn retarget_import_thunk(&mut self, thunk: &ImportThunk, entry_rva: u32) {
+        let delta = i64::from(entry_rva) - i64::from(thunk.slot_rva);
+        if let Some(inst) = self
+            .instructions
+            .iter_mut()
+            .find(|inst| inst.id == thunk.instruction_id)
+        {
+            let displacement = inst.instruction.memory_displacement64();
+            inst.instruction
+                .set_memory_displacement64(displacement.wrapping_add(delta.cast_unsigned()));
+            self.shift_rip_reference(thunk.instruction_id, delta);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use iced_x86::*;
+use iced_x86::{Decoder, DecoderOptions, Encoder, Instruction, MemoryOperand};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub struct InstructionContext {
@@ -14,7 +14,8 @@ impl Clone for InstructionContext {
 }
 
 impl InstructionContext {
-    pub fn new() -> Self {
+    #[must_use]
+    pub const fn new() -> Self {
         Self {
             next_id: AtomicUsize::new(0),
         }
@@ -27,6 +28,15 @@ impl InstructionContext {
     pub fn create_instruction(&self, instruction: Instruction) -> InstructionWithId {
         InstructionWithId::new(self.next_id(), instruction)
     }
+
+    /// [`InstructionWithId::encoded`] with a fresh id.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the instruction cannot be encoded.
+    pub fn create_encoded(&self, instruction: Instruction) -> Result<InstructionWithId, String> {
+        InstructionWithId::encoded(self.next_id(), instruction)
+    }
 }
 
 impl Default for InstructionContext {
@@ -42,39 +52,74 @@ pub struct InstructionWithId {
 }
 
 impl InstructionWithId {
-    pub fn new(id: usize, instruction: Instruction) -> Self {
+    #[must_use]
+    pub const fn new(id: usize, instruction: Instruction) -> Self {
         Self { id, instruction }
     }
 
-    pub fn get_id(&self) -> usize {
+    /// An instruction built by a pass, put through the encoder and decoder
+    /// so that its length and operands are those of its encoding, as they
+    /// are for decoded instructions.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the instruction cannot be encoded.
+    pub fn encoded(id: usize, instruction: Instruction) -> Result<Self, String> {
+        let instruction = Self::new(id, instruction).re_encode(0)?;
+        Ok(Self::new(id, instruction))
+    }
+
+    #[must_use]
+    pub const fn get_id(&self) -> usize {
         self.id
     }
 
-    pub fn set_id(&mut self, id: usize) {
+    pub const fn set_id(&mut self, id: usize) {
         self.id = id;
     }
 
+    /// RVA the instruction was decoded or laid out at.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, reason = "functions are decoded and laid out at their RVAs")]
+    pub const fn rva(&self) -> u32 {
+        self.instruction.ip() as u32
+    }
+
+    /// Length of the instruction's encoding.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, reason = "an x86 instruction is at most 15 bytes long")]
+    pub const fn size(&self) -> u32 {
+        self.instruction.len() as u32
+    }
+
+    #[must_use]
     pub fn get_memory_operand(&self) -> MemoryOperand {
         let instr = &self.instruction;
         MemoryOperand::new(
             instr.memory_base(),
             instr.memory_index(),
             instr.memory_index_scale(),
-            instr.memory_displacement64() as i64,
+            instr.memory_displacement64().cast_signed(),
             instr.memory_displ_size(),
             instr.is_broadcast(),
             instr.segment_prefix(),
         )
     }
 
+    /// # Errors
+    ///
+    /// Fails when the instruction cannot be encoded.
     pub fn get_bytes(&self) -> Result<Vec<u8>, String> {
         let mut encoder = Encoder::new(64);
         encoder
             .encode(&self.instruction, self.instruction.ip())
-            .map_err(|e| format!("Encoding failed: {}", e))?;
+            .map_err(|e| format!("Encoding failed: {e}"))?;
         Ok(encoder.take_buffer())
     }
 
+    /// # Errors
+    ///
+    /// Fails when the instruction cannot be encoded at `rip`.
     pub fn re_encode(&self, rip: u64) -> Result<Instruction, String> {
         let bytes = self.get_bytes()?;
         let mut decoder = Decoder::new(64, &bytes, DecoderOptions::NONE);
@@ -0,0 +1,548 @@
+use crate::cfg::ControlFlowGraph;
+use crate::format::BinaryImage;
+use crate::function::ObfuscatorFunction;
+use crate::instruction::InstructionWithId;
+use common::debug;
+use iced_x86::{Code, ConditionCode, FlowControl, Instruction, InstructionInfoFactory, Mnemonic, OpAccess, OpKind, Register};
+use std::collections::HashMap;
+
+const MAX_TABLE_ENTRIES: usize = 4096;
+const MAX_SLICE_INSTRUCTIONS: usize = 64;
+const VOLATILE_REGISTERS: [Register; 7] = [
+    Register::RAX,
+    Register::RCX,
+    Register::RDX,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JumpTableEntry {
+    /// 32-bit entries holding `target - base`. MSVC uses the image base as
+    /// `base`, LLVM uses the address of the table itself.
+    Relative { base: u32 },
+    /// 64-bit entries holding absolute virtual addresses.
+    Absolute { image_base: u64 },
+}
+
+impl JumpTableEntry {
+    #[must_use]
+    pub const fn size(&self) -> usize {
+        match self {
+            Self::Relative { .. } => 4,
+            Self::Absolute { .. } => 8,
+        }
+    }
+
+    /// RVA an entry points at, `None` when it lies outside the image.
+    fn decode(&self, bytes: &[u8]) -> Option<u32> {
+        match *self {
+            Self::Relative { base } => {
+                let value = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                Some(base.wrapping_add(value.cast_unsigned()))
+            }
+            Self::Absolute { image_base } => {
+                let mut value = [0u8; 8];
+                value.copy_from_slice(&bytes[..8]);
+                u32::try_from(u64::from_le_bytes(value).wrapping_sub(image_base)).ok()
+            }
+        }
+    }
+
+    fn encode(&self, target_rva: u32) -> Vec<u8> {
+        match *self {
+            Self::Relative { base } => target_rva.wrapping_sub(base).to_le_bytes().to_vec(),
+            Self::Absolute { image_base } => (image_base + u64::from(target_rva)).to_le_bytes().to_vec(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct JumpTable {
+    pub jump_id: usize,
+    pub table_rva: u32,
+    pub entry: JumpTableEntry,
+    pub target_ids: Vec<usize>,
+    pub original_targets: Vec<u32>,
+}
+
+impl JumpTable {
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.target_ids.len()
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.target_ids.is_empty()
+    }
+
+    #[must_use]
+    pub const fn byte_size(&self) -> usize {
+        self.len() * self.entry.size()
+    }
+
+    #[must_use]
+    pub fn encode_entries(&self, targets: &[u32]) -> Vec<u8> {
+        targets
+            .iter()
+            .flat_map(|&target| self.entry.encode(target))
+            .collect()
+    }
+}
+
+struct TableLoad {
+    index: usize,
+    base: Register,
+    index_register: Register,
+    displacement: u64,
+    entry_size: usize,
+}
+
+impl TableLoad {
+    /// Displacement from a base register, which x86-64 encodes in 32 bits.
+    #[allow(clippy::cast_possible_truncation, reason = "x86-64 displacements are sign-extended 32-bit values")]
+    const fn offset(&self) -> u32 {
+        self.displacement as u32
+    }
+}
+
+pub struct JumpTableResolver<'a> {
+    image: &'a dyn BinaryImage,
+    image_base: u64,
+}
+
+impl<'a> JumpTableResolver<'a> {
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed.
+    pub fn new(image: &'a dyn BinaryImage) -> Result<Self, String> {
+        let image_base = image.image_base()?;
+        Ok(Self {
+            image,
+            image_base,
+        })
+    }
+
+    /// Resolves every indirect jump of the function that dispatches through a
+    /// jump table. Indirect jumps that do not index memory are left alone as
+    /// tail calls; ones that do but cannot be resolved make the function
+    /// unsafe to move and are reported as an error.
+    ///
+    /// # Errors
+    ///
+    /// Fails when a table's base register or entries cannot be resolved.
+    pub fn resolve(
+        &self,
+        instructions: &[InstructionWithId],
+        cfg: &ControlFlowGraph,
+        function_rva: u32,
+        function_size: u32,
+    ) -> Result<Vec<JumpTable>, String> {
+        let mut ip_to_id: HashMap<u64, usize> = HashMap::new();
+        for inst in instructions {
+            ip_to_id.entry(inst.instruction.ip()).or_insert(inst.id);
+        }
+
+        let mut tables = Vec::new();
+        for (index, inst) in instructions.iter().enumerate() {
+            let instruction = &inst.instruction;
+            if instruction.flow_control() != FlowControl::IndirectBranch {
+                continue;
+            }
+
+            let Some(load) = Self::find_table_load(instructions, cfg, index) else {
+                if Self::looks_like_table_dispatch(instructions, index) {
+                    return Err(format!(
+                        "Unresolved jump table dispatch at RVA {:#x}",
+                        instruction.ip()
+                    ));
+                }
+                debug!(
+                    "Indirect jump at RVA {:#x} treated as tail call",
+                    instruction.ip()
+                );
+                continue;
+            };
+
+            let entry = match load.entry_size {
+                4 => {
+                    let base = Self::resolve_base(instructions, cfg, load.index, load.base)
+                        .ok_or_else(|| {
+                            format!(
+                                "Unresolved jump table base register at RVA {:#x}",
+                                instruction.ip()
+                            )
+                        })?;
+                    JumpTableEntry::Relative { base }
+                }
+                _ => JumpTableEntry::Absolute {
+                    image_base: self.image_base,
+                },
+            };
+
+            let table_rva = match (entry, load.base) {
+                (JumpTableEntry::Relative { base }, _) => {
+                    base.wrapping_add(load.offset())
+                }
+                (JumpTableEntry::Absolute { .. }, Register::None) => {
+                    u32::try_from(load.displacement.wrapping_sub(self.image_base))
+                        .map_err(|_| format!("Jump table at {:#x} is outside the image", load.displacement))?
+                }
+                (JumpTableEntry::Absolute { .. }, base_register) => {
+                    let base = Self::resolve_base(instructions, cfg, load.index, base_register)
+                        .ok_or_else(|| {
+                            format!(
+                                "Unresolved jump table base register at RVA {:#x}",
+                                instruction.ip()
+                            )
+                        })?;
+                    base.wrapping_add(load.offset())
+                }
+            };
+
+            let count = Self::find_index_bound(instructions, cfg, load.index, load.index_register)
+                .ok_or_else(|| {
+                    format!(
+                        "Unbounded jump table index at RVA {:#x}",
+                        instruction.ip()
+                    )
+                })?;
+
+            let bytes = self
+                .image
+                .read_data_at_rva(table_rva, count * entry.size())
+                .map_err(|e| format!("Failed to read jump table at RVA {table_rva:#x}: {e}"))?;
+
+            let mut target_ids = Vec::with_capacity(count);
+            let mut original_targets = Vec::with_capacity(count);
+            for chunk in bytes.chunks_exact(entry.size()) {
+                let target = entry.decode(chunk).ok_or_else(|| {
+                    format!("Jump table at RVA {table_rva:#x} targets outside the image")
+                })?;
+                if target < function_rva || target >= function_rva + function_size {
+                    return Err(format!(
+                        "Jump table at RVA {table_rva:#x} targets {target:#x} outside function"
+                    ));
+                }
+                let target_id = ip_to_id.get(&u64::from(target)).ok_or_else(|| {
+                    format!(
+                        "Jump table at RVA {table_rva:#x} targets {target:#x} which is not an instruction boundary"
+                    )
+                })?;
+                target_ids.push(*target_id);
+                original_targets.push(target);
+            }
+
+            debug!(
+                "Resolved jump table at RVA {table_rva:#x} for jump at {:#x} with {count} entries",
+                instruction.ip()
+            );
+
+            tables.push(JumpTable {
+                jump_id: inst.id,
+                table_rva,
+                entry,
+                target_ids,
+                original_targets,
+            });
+        }
+
+        Ok(tables)
+    }
+
+    fn find_table_load(
+        instructions: &[InstructionWithId],
+        cfg: &ControlFlowGraph,
+        jump_index: usize,
+    ) -> Option<TableLoad> {
+        let jump = &instructions[jump_index].instruction;
+
+        // jmp qword ptr [base + index*8 + disp]
+        if jump.op0_kind() == OpKind::Memory {
+            return Self::as_table_load(jump, jump_index, 8);
+        }
+
+        let target_register = jump.op0_register().full_register();
+        let def_index = Self::find_definition(instructions, cfg, jump_index, target_register)?;
+        let def = &instructions[def_index].instruction;
+
+        // mov reg, qword ptr [base + index*8 + disp]; jmp reg
+        if def.code() == Code::Mov_r64_rm64 && def.op1_kind() == OpKind::Memory {
+            return Self::as_table_load(def, def_index, 8);
+        }
+
+        // load reg, dword ptr [base + index*4 + disp]; add reg, base; jmp reg
+        if !matches!(def.code(), Code::Add_r64_rm64 | Code::Add_rm64_r64)
+            || def.op1_kind() != OpKind::Register
+        {
+            return None;
+        }
+
+        let operands = [
+            def.op0_register().full_register(),
+            def.op1_register().full_register(),
+        ];
+        for [loaded, base] in [operands, [operands[1], operands[0]]] {
+            let Some(load_index) = Self::find_definition(instructions, cfg, def_index, loaded)
+            else {
+                continue;
+            };
+            let load = &instructions[load_index].instruction;
+            if !matches!(load.code(), Code::Movsxd_r64_rm32 | Code::Mov_r32_rm32) {
+                continue;
+            }
+            if let Some(table_load) = Self::as_table_load(load, load_index, 4)
+                && table_load.base == base
+            {
+                return Some(table_load);
+            }
+        }
+
+        None
+    }
+
+    fn as_table_load(instruction: &Instruction, index: usize, entry_size: usize) -> Option<TableLoad> {
+        let has_memory = (0..instruction.op_count()).any(|op| instruction.op_kind(op) == OpKind::Memory);
+        if !has_memory
+            || instruction.memory_index() == Register::None
+            || instruction.memory_index_scale() as usize != entry_size
+            || instruction.memory_base() == Register::RIP
+        {
+            return None;
+        }
+
+        Some(TableLoad {
+            index,
+            base: instruction.memory_base().full_register(),
+            index_register: instruction.memory_index().full_register(),
+            displacement: instruction.memory_displacement64(),
+            entry_size,
+        })
+    }
+
+    fn resolve_base(
+        instructions: &[InstructionWithId],
+        cfg: &ControlFlowGraph,
+        from_index: usize,
+        register: Register,
+    ) -> Option<u32> {
+        let def_index = Self::find_definition(instructions, cfg, from_index, register)?;
+        let def = &instructions[def_index].instruction;
+        if def.code() == Code::Lea_r64_m && def.memory_base() == Register::RIP {
+            u32::try_from(def.memory_displacement64()).ok()
+        } else {
+            None
+        }
+    }
+
+    /// Finds the upper bound of a table index from the `and reg, mask` or
+    /// `cmp reg, imm` + `ja`/`jae` guard that dominates the table load.
+    fn find_index_bound(
+        instructions: &[InstructionWithId],
+        cfg: &ControlFlowGraph,
+        load_index: usize,
+        index_register: Register,
+    ) -> Option<usize> {
+        let mut register = index_register;
+
+        for index in Self::dominating_indices(cfg, load_index) {
+            let instruction = &instructions[index].instruction;
+
+            if instruction.mnemonic() == Mnemonic::Cmp
+                && instruction.op0_kind() == OpKind::Register
+                && instruction.op0_register().full_register() == register
+                && Self::has_immediate_operand(instruction, 1)
+                && let Some(guard) = instructions.get(index + 1)
+                && let Ok(limit) = usize::try_from(instruction.immediate(1))
+            {
+                let count = match guard.instruction.condition_code() {
+                    ConditionCode::a | ConditionCode::be => limit.checked_add(1),
+                    ConditionCode::ae | ConditionCode::b => Some(limit),
+                    _ => None,
+                };
+                if let Some(count) = count.filter(|&c| c > 0 && c <= MAX_TABLE_ENTRIES) {
+                    return Some(count);
+                }
+            }
+
+            if !Self::writes_register(instruction, register) {
+                continue;
+            }
+
+            match instruction.mnemonic() {
+                Mnemonic::And if Self::has_immediate_operand(instruction, 1) => {
+                    return usize::try_from(instruction.immediate(1))
+                        .ok()
+                        .and_then(|mask| mask.checked_add(1))
+                        .filter(|&c| c <= MAX_TABLE_ENTRIES);
+                }
+                Mnemonic::Mov | Mnemonic::Movzx | Mnemonic::Movsxd
+                    if instruction.op1_kind() == OpKind::Register =>
+                {
+                    register = instruction.op1_register().full_register();
+                }
+                _ => return None,
+            }
+        }
+
+        None
+    }
+
+    fn has_immediate_operand(instruction: &Instruction, operand: u32) -> bool {
+        matches!(
+            instruction.op_kind(operand),
+            OpKind::Immediate8
+                | OpKind::Immediate16
+                | OpKind::Immediate32
+                | OpKind::Immediate8to16
+                | OpKind::Immediate8to32
+                | OpKind::Immediate8to64
+                | OpKind::Immediate32to64
+        )
+    }
+
+    fn find_definition(
+        instructions: &[InstructionWithId],
+        cfg: &ControlFlowGraph,
+        from_index: usize,
+        register: Register,
+    ) -> Option<usize> {
+        Self::dominating_indices(cfg, from_index)
+            .find(|&index| Self::writes_register(&instructions[index].instruction, register))
+    }
+
+    /// Walks backwards from `from_index` (exclusive) through its block and
+    /// then through the blocks on its dominator chain.
+    fn dominating_indices(cfg: &ControlFlowGraph, from_index: usize) -> impl Iterator<Item = usize> + '_ {
+        let mut block = cfg.block_containing(from_index).map(|b| b.id);
+        let mut next = from_index;
+
+        std::iter::from_fn(move || {
+            loop {
+                let current = cfg.block(block?)?;
+                if next > current.first_index() {
+                    next -= 1;
+                    return Some(next);
+                }
+                block = cfg.dominators.immediate_dominator(current.id);
+                next = block.and_then(|b| cfg.block(b)).map(|b| b.range.end)?;
+            }
+        })
+        .take(MAX_SLICE_INSTRUCTIONS)
+    }
+
+    fn writes_register(instruction: &Instruction, register: Register) -> bool {
+        if matches!(
+            instruction.flow_control(),
+            FlowControl::Call | FlowControl::IndirectCall
+        ) {
+            return VOLATILE_REGISTERS.contains(&register);
+        }
+
+        let mut factory = InstructionInfoFactory::new();
+        let info = factory.info(instruction);
+        info.used_registers().iter().any(|used| {
+            used.register().is_gpr()
+                && used.register().full_register() == register
+                && matches!(
+                    used.access(),
+                    OpAccess::Write | OpAccess::ReadWrite | OpAccess::CondWrite | OpAccess::ReadCondWrite
+                )
+        })
+    }
+
+    fn looks_like_table_dispatch(instructions: &[InstructionWithId], jump_index: usize) -> bool {
+        let jump = &instructions[jump_index].instruction;
+        if jump.op0_kind() == OpKind::Memory {
+            return jump.memory_index() != Register::None;
+        }
+
+        let target = jump.op0_register().full_register();
+        instructions[..jump_index]
+            .iter()
+            .rev()
+            .take(4)
+            .any(|inst| {
+                let instruction = &inst.instruction;
+                matches!(instruction.code(), Code::Add_r64_rm64 | Code::Add_rm64_r64)
+                    && instruction.op0_kind() == OpKind::Register
+                    && instruction.op1_kind() == OpKind::Register
+                    && instruction.op0_register().full_register() == target
+            })
+    }
+}
+
+impl ObfuscatorFunction {
+    /// # Errors
+    ///
+    /// Fails when a table cannot be resolved.
+    pub fn resolve_jump_tables(&mut self, image: &dyn BinaryImage) -> Result<(), String> {
+        let resolver = JumpTableResolver::new(image)?;
+
+        // Switches nested inside a case are unreachable until the outer
+        // table has been turned into CFG edges, so resolve until stable.
+        loop {
+            let tables = resolver.resolve(&self.instructions, &self.cfg, self.rva, self.size)?;
+            let resolved_more = tables.len() > self.jump_tables.len();
+            self.jump_tables = tables;
+            self.build_cfg();
+            if !resolved_more {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Entries of every jump table pointing at the current addresses of
+    /// their targets, with the RVA of each table.
+    ///
+    /// # Errors
+    ///
+    /// Fails when a table entry targets an instruction that no longer exists.
+    pub fn encode_jump_tables(&self) -> Result<Vec<(u32, Vec<u8>)>, String> {
+        self.jump_tables
+            .iter()
+            .map(|table| {
+                let targets = table
+                    .target_ids
+                    .iter()
+                    .map(|&id| {
+                        self.instructions
+                            .iter()
+                            .find(|inst| inst.id == id)
+                            .map(InstructionWithId::rva)
+                            .ok_or_else(|| format!("Jump table target with ID {id} not found"))
+                    })
+                    .collect::<Result<Vec<u32>, String>>()?;
+                Ok((table.table_rva, table.encode_entries(&targets)))
+            })
+            .collect()
+    }
+
+    /// Rewrites the entries of every jump table in place so they point at the
+    /// relocated targets. Must run after `encode` has assigned final IPs.
+    ///
+    /// # Errors
+    ///
+    /// Fails when a table cannot be encoded or written back into the image.
+    pub fn rewrite_jump_tables(&self, image: &mut dyn BinaryImage) -> Result<(), String> {
+        for (table_rva, entries) in self.encode_jump_tables()? {
+            image
+                .write_data_at_rva(table_rva, &entries)
+                .map_err(|e| format!("Failed to rewrite jump table at RVA {table_rva:#x}: {e}"))?;
+
+            debug!(
+                "Rewrote jump table at RVA {:#x} for function {} ({} bytes)",
+                table_rva,
+                self.name,
+                entries.len()
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use crate::config::LayoutConfig;
+use crate::function::ObfuscatorFunction;
+use crate::instruction::InstructionWithId;
+use crate::passes::PassRng;
+use iced_x86::FlowControl;
+use rand::SeedableRng;
+use rand::seq::SliceRandom;
+use std::ops::Range;
+
+/// A range of a function's instructions placed as one piece in the code
+/// section.
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    pub function: usize,
+    pub range: Range<usize>,
+    /// Bytes reserved for the chunk, grown until its encoding fits.
+    pub slot: u32,
+}
+
+/// Order and sizes of the chunks of the code section. Without interleaving
+/// every function is a single chunk and the functions keep their order.
+#[derive(Clone, Debug, Default)]
+pub struct CodeLayout {
+    chunks: Vec<Chunk>,
+}
+
+impl CodeLayout {
+    pub fn new(functions: &[ObfuscatorFunction], config: &LayoutConfig, seed: u64) -> Self {
+        let mut chunks = Vec::new();
+        for (index, function) in functions.iter().enumerate() {
+            // Starting from the current size keeps the chunk addresses
+            // distinct from the first round on.
+            let chunk = |range: Range<usize>| Chunk {
+                function: index,
+                slot: function.instructions[range.clone()]
+                    .iter()
+                    .map(InstructionWithId::size)
+                    .sum::<u32>()
+                    .max(1),
+                range,
+            };
+            if config.interleave {
+                chunks.extend(function.chunk_ranges().into_iter().map(chunk));
+            } else {
+                chunks.push(chunk(0..function.instructions.len()));
+            }
+        }
+        if config.interleave {
+            chunks.shuffle(&mut PassRng::seed_from_u64(seed));
+        }
+        Self { chunks }
+    }
+
+    #[must_use]
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    /// Address of every chunk when the section starts at `base_rva`.
+    #[must_use]
+    pub fn addresses(&self, base_rva: u32) -> Vec<u32> {
+        self.chunks
+            .iter()
+            .scan(base_rva, |rva, chunk| {
+                let address = *rva;
+                *rva += chunk.slot;
+                Some(address)
+            })
+            .collect()
+    }
+
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.slot as usize).sum()
+    }
+
+    /// Indices of the chunks of `function`, the one holding its entry first.
+    #[must_use]
+    pub fn chunks_of(&self, function: usize) -> Vec<usize> {
+        let mut indices: Vec<usize> = (0..self.chunks.len())
+            .filter(|&index| self.chunks[index].function == function)
+            .collect();
+        indices.sort_by_key(|&index| self.chunks[index].range.start);
+        indices
+    }
+
+    /// Grows the slots that are too small for the encoded `sizes` and tells
+    /// whether every chunk fit. Slots never shrink, so the layout settles.
+    pub fn fit(&mut self, sizes: &[u32]) -> bool {
+        let mut fits = true;
+        for (chunk, &size) in self.chunks.iter_mut().zip(sizes) {
+            if size > chunk.slot {
+                chunk.slot = size;
+                fits = false;
+            }
+        }
+        fits
+    }
+
+    pub fn remove_function(&mut self, function: usize) {
+        self.chunks.retain(|chunk| chunk.function != function);
+    }
+}
+
+impl ObfuscatorFunction {
+    /// Cuts the function after its unconditional transfers of control, so
+    /// that no chunk falls through into another. A jump right after a call
+    /// stays with it: the unwinder would take it for the end of an epilogue
+    /// when it finds the return address pointing at a jump out of the chunk.
+    pub fn chunk_ranges(&self) -> Vec<Range<usize>> {
+        let length = self.instructions.len();
+        let movable_from = self.unwind.movable_from(&self.instructions).unwrap_or(length);
+
+        let flow = |index: usize| self.instructions[index].instruction.flow_control();
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        for index in movable_from.max(1)..length {
+            let ends_chunk = matches!(
+                flow(index - 1),
+                FlowControl::Return
+                    | FlowControl::UnconditionalBranch
+                    | FlowControl::IndirectBranch
+                    | FlowControl::Interrupt
+                    | FlowControl::Exception
+            );
+            let after_call =
+                index >= 2 && matches!(flow(index - 2), FlowControl::Call | FlowControl::IndirectCall);
+            if ends_chunk && !after_call {
+                ranges.push(start..index);
+                start = index;
+            }
+        }
+        ranges.push(start..length);
+        ranges
+    }
+}
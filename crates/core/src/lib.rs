@@ -1,61 +1,157 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery, clippy::cargo)]
+// symbolic depends on older goblin, scroll and syn releases than ours.
+#![allow(clippy::multiple_crate_versions)]
 
 use analyzer::AnalyzerContext;
 use common::{Logger, debug, info, warn};
 use compiler::CompilerContext;
+use config::ObfuscatorConfig;
+use discovery::FunctionDiscovery;
+use elf::ElfContext;
+use format::{BinaryFormat, BinaryImage};
 use function::ObfuscatorFunction;
 use instant::Instant;
 use obfuscator::Obfuscator;
-use pdb::PDBContext;
+use pdb::{PDBContext, PDBFunction};
 use pe::PEContext;
+use passes::PassRng;
+use rand::SeedableRng;
+use references::ReferenceReport;
+use report::{ObfuscationOutput, ObfuscationReport};
+use selection::FunctionSelection;
 use std::cell::RefCell;
 use std::rc::Rc;
+use strings::StringEncryptionContext;
+use symbol_map::SymbolMap;
+use verify::{VerificationReport, Verifier};
 
 pub mod analyzer;
 pub mod branches;
+pub mod cfg;
 pub mod compiler;
+pub mod config;
+pub mod discovery;
+pub mod elf;
+pub mod exceptions;
+pub mod format;
 pub mod function;
+pub mod imports;
 pub mod instruction;
+pub mod jump_tables;
+pub mod layout;
+pub mod liveness;
 pub mod obfuscator;
 pub mod passes;
 pub mod pdb;
 pub mod pe;
+pub mod references;
+pub mod relocations;
+pub mod report;
+pub mod selection;
+pub mod strings;
+pub mod symbol_map;
+pub mod unwind;
+pub mod verify;
+pub mod vm;
 
 pub struct CoreContext {
-    pub pe_context: Rc<RefCell<PEContext>>,
+    pub image: Rc<RefCell<dyn BinaryImage>>,
     pub pdb_context: Rc<RefCell<PDBContext>>,
 }
 
 impl CoreContext {
-    pub fn new(pe_context: Rc<RefCell<PEContext>>, pdb_context: Rc<RefCell<PDBContext>>) -> Self {
-        Self {
-            pe_context,
-            pdb_context,
-        }
+    pub fn new(image: Rc<RefCell<dyn BinaryImage>>, pdb_context: Rc<RefCell<PDBContext>>) -> Self {
+        Self { image, pdb_context }
     }
 }
 
+/// # Errors
+///
+/// Fails when the image or PDB is not supported or the pipeline fails.
 pub fn run(binary_data: &[u8], pdb_data: &[u8]) -> Result<Vec<u8>, String> {
+    run_with_config(binary_data, pdb_data, &ObfuscatorConfig::default()).map(|output| output.binary)
+}
+
+/// # Errors
+///
+/// Fails when the image or PDB is not supported or the pipeline fails.
+pub fn run_with_config(
+    binary_data: &[u8],
+    pdb_data: &[u8],
+    config: &ObfuscatorConfig,
+) -> Result<ObfuscationOutput, String> {
     Logger::ensure_init();
+    if BinaryFormat::detect(binary_data) == Some(BinaryFormat::Elf) {
+        return Err("ELF images carry their own symbols and are read without a PDB".to_string());
+    }
+    debug!("PDB size: {} bytes", pdb_data.len());
+    let pdb_context = parse_and_validate_pdb(pdb_data)?;
+    obfuscate(binary_data, pdb_context, config)
+}
 
+/// Obfuscates an image whose functions are already known, e.g. one built
+/// in memory along with its symbol list.
+///
+/// # Errors
+///
+/// Fails when the image is not supported or the pipeline fails.
+pub fn run_with_functions(
+    binary_data: &[u8],
+    functions: Vec<PDBFunction>,
+    config: &ObfuscatorConfig,
+) -> Result<ObfuscationOutput, String> {
+    Logger::ensure_init();
+    let pdb_context = Rc::new(RefCell::new(PDBContext::from_functions(functions)));
+    obfuscate(binary_data, pdb_context, config)
+}
+
+/// Obfuscates an image without a PDB, finding its functions from the image
+/// itself: from its exception directory and code for PE images, from the
+/// symbols, DWARF and `.eh_frame` of ELF images.
+///
+/// # Errors
+///
+/// Fails when the image is not supported, no function is found or the pipeline
+/// fails.
+pub fn run_without_pdb(binary_data: &[u8], config: &ObfuscatorConfig) -> Result<ObfuscationOutput, String> {
+    Logger::ensure_init();
+    let pdb_context = if BinaryFormat::detect(binary_data) == Some(BinaryFormat::Elf) {
+        let elf_context = ElfContext::new(binary_data.to_vec());
+        let functions = elf_context.get_functions()?;
+        let lines = elf_context.get_lines().unwrap_or_else(|e| {
+            warn!("Failed to read source lines from DWARF: {e}");
+            Vec::new()
+        });
+        PDBContext::from_functions(functions).with_lines(lines)
+    } else {
+        let pe_context = PEContext::new(binary_data.to_vec());
+        PDBContext::from_functions(FunctionDiscovery::new(&pe_context)?.discover()?)
+    };
+    obfuscate(binary_data, Rc::new(RefCell::new(pdb_context)), config)
+}
+
+fn obfuscate(
+    binary_data: &[u8],
+    pdb_context: Rc<RefCell<PDBContext>>,
+    config: &ObfuscatorConfig,
+) -> Result<ObfuscationOutput, String> {
     let start_time = Instant::now();
     info!("Starting binary obfuscation process");
-    debug!(
-        "PE binary size: {} bytes, PDB size: {} bytes",
-        binary_data.len(),
-        pdb_data.len()
-    );
+    debug!("Binary size: {} bytes", binary_data.len());
 
-    let pe_context = parse_and_validate_pe(binary_data)?;
-    let pdb_context = parse_and_validate_pdb(pdb_data)?;
+    let image = parse_and_validate_image(binary_data)?;
 
-    let core_context = CoreContext::new(pe_context, pdb_context);
+    let core_context = CoreContext::new(image, pdb_context);
 
-    let mut obfuscator_functions = analyze_binary(&core_context)?;
+    let mut obfuscator_functions = analyze_binary(&core_context, config)?;
 
-    obfuscate_binary(&mut obfuscator_functions)?;
+    let mut strings = StringEncryptionContext::new(core_context.image.clone(), config.strings.clone());
+    let (seed, verification) =
+        obfuscate_binary(&core_context, &mut obfuscator_functions, &mut strings, config)?;
 
-    let binary_data = compile_binary(&core_context, &mut obfuscator_functions)?;
+    let (binary_data, references, strings_erased) =
+        compile_binary(&core_context, &mut obfuscator_functions, &strings, config, seed)?;
+    let symbol_map = map_symbols(&core_context, &obfuscator_functions)?;
 
     let elapsed = start_time.elapsed();
     info!(
@@ -64,7 +160,30 @@ pub fn run(binary_data: &[u8], pdb_data: &[u8]) -> Result<Vec<u8>, String> {
         elapsed.as_secs_f64() * 1000.0,
     );
 
-    Ok(binary_data)
+    Ok(ObfuscationOutput {
+        binary: binary_data,
+        report: ObfuscationReport {
+            strings_encrypted: strings.encrypted_count(),
+            strings_erased,
+            verification,
+            ..ObfuscationReport::new(seed, obfuscator_functions.len(), &references)
+        },
+        symbol_map,
+    })
+}
+
+fn parse_and_validate_image(binary_data: &[u8]) -> Result<Rc<RefCell<dyn BinaryImage>>, String> {
+    if BinaryFormat::detect(binary_data) != Some(BinaryFormat::Elf) {
+        return Ok(parse_and_validate_pe(binary_data)?);
+    }
+    debug!("Parsing and validating ELF binary");
+    let elf_context = ElfContext::new(binary_data.to_vec());
+    if !elf_context.is_supported() {
+        warn!("ELF binary is not supported");
+        return Err("ELF is not supported".to_string());
+    }
+    debug!("ELF binary successfully parsed and validated");
+    Ok(Rc::new(RefCell::new(elf_context)))
 }
 
 fn parse_and_validate_pe(binary_data: &[u8]) -> Result<Rc<RefCell<PEContext>>, String> {
@@ -89,9 +208,13 @@ fn parse_and_validate_pdb(pdb_data: &[u8]) -> Result<Rc<RefCell<PDBContext>>, St
     Ok(Rc::new(RefCell::new(pdb_context)))
 }
 
-fn analyze_binary(core_context: &CoreContext) -> Result<Vec<ObfuscatorFunction>, String> {
+fn analyze_binary(
+    core_context: &CoreContext,
+    config: &ObfuscatorConfig,
+) -> Result<Vec<ObfuscatorFunction>, String> {
     info!("Starting binary analysis phase");
-    let analyzer_context = AnalyzerContext::new(core_context);
+    let selection = FunctionSelection::new(&config.selection)?;
+    let analyzer_context = AnalyzerContext::with_selection(core_context, selection);
     let obfuscator_functions = analyzer_context.analyze()?;
     info!(
         "Binary analysis completed, found {} functions",
@@ -100,31 +223,113 @@ fn analyze_binary(core_context: &CoreContext) -> Result<Vec<ObfuscatorFunction>,
     Ok(obfuscator_functions)
 }
 
-fn obfuscate_binary(functions: &mut [ObfuscatorFunction]) -> Result<(), String> {
+fn obfuscate_binary(
+    core_context: &CoreContext,
+    functions: &mut [ObfuscatorFunction],
+    strings: &mut StringEncryptionContext,
+    config: &ObfuscatorConfig,
+) -> Result<(u64, Option<VerificationReport>), String> {
     info!(
         "Starting obfuscation phase for {} functions",
         functions.len()
     );
-    let obfuscator = Obfuscator::new();
-    obfuscator.obfuscate(functions)?;
+    config.validate()?;
+    let obfuscator = Obfuscator::with_config(config)?;
+    info!("Using seed {}", obfuscator.seed());
+    // Runs first so the passes also obfuscate the decryption stubs.
+    if config.strings.enabled {
+        strings.encrypt(functions, &mut PassRng::seed_from_u64(obfuscator.seed()))?;
+    }
+    // Built after string encryption so the emulated image holds the
+    // encrypted strings the stubs decrypt.
+    let verifier = if config.verification.enabled {
+        Some(Verifier::new(
+            &*core_context.image.borrow(),
+            config.verification.clone(),
+            obfuscator.seed(),
+        )?)
+    } else {
+        None
+    };
+    obfuscator.obfuscate(functions, verifier.as_ref())?;
     info!("Obfuscation phase completed successfully");
-    Ok(())
+
+    let verification = verifier.map(|verifier| verifier.report());
+    if let Some(report) = &verification {
+        info!(
+            "Verification: {} functions verified, {} inconclusive, {} of {} trials inconclusive, {} mismatches",
+            report.functions_verified,
+            report.functions_inconclusive.len(),
+            report.inconclusive_trials,
+            report.trials,
+            report.mismatches.len()
+        );
+    }
+    Ok((obfuscator.seed(), verification))
 }
 
 fn compile_binary(
     core_context: &CoreContext,
     functions: &mut [ObfuscatorFunction],
-) -> Result<Vec<u8>, String> {
+    strings: &StringEncryptionContext,
+    config: &ObfuscatorConfig,
+    seed: u64,
+) -> Result<(Vec<u8>, ReferenceReport, usize), String> {
     info!(
         "Starting compilation phase for {} functions",
         functions.len()
     );
-    let mut compiler_context = CompilerContext::new(core_context.pe_context.clone());
+    let mut compiler_context =
+        CompilerContext::with_layout(core_context.image.clone(), config.layout.clone(), seed);
     compiler_context.compile_functions(functions)?;
+
+    let report = compiler_context.get_reference_report().clone();
+    if report.is_clean() {
+        info!("RIP-relative references: {} checked, all preserved", report.checked);
+    } else {
+        warn!(
+            "RIP-relative references: {} checked, {} unresolved, {} functions left in place",
+            report.checked,
+            report.unresolved.len(),
+            report.skipped_functions.len()
+        );
+    }
+    for reference in &report.unresolved {
+        warn!(
+            "Unresolved RIP-relative reference in {} at {:#x}: expected {:#x}, got {} ({})",
+            reference.function,
+            reference.original_ip,
+            reference.expected_target,
+            reference
+                .actual_target
+                .map_or_else(|| "nothing".to_string(), |target| format!("{target:#x}")),
+            reference.reason
+        );
+    }
+
+    let strings_erased = strings.erase_plaintext(functions)?;
+
     let binary_data = compiler_context.get_binary_data();
     info!(
         "Compilation phase completed, generated {} bytes",
         binary_data.len()
     );
-    Ok(binary_data)
+    Ok((binary_data, report, strings_erased))
+}
+
+/// Source lines are a nicety, so a PDB without a readable line program
+/// still gets a map of the functions.
+fn map_symbols(core_context: &CoreContext, functions: &[ObfuscatorFunction]) -> Result<SymbolMap, String> {
+    let lines = core_context.pdb_context.borrow().get_lines().unwrap_or_else(|e| {
+        warn!("Failed to read source lines from PDB: {e}");
+        Vec::new()
+    });
+    let symbol_map = SymbolMap::new(&*core_context.image.borrow(), functions, &lines)?;
+    debug!(
+        "Symbol map: {} functions, {} ranges, {} source files",
+        symbol_map.functions.len(),
+        symbol_map.functions.iter().map(|f| f.ranges.len()).sum::<usize>(),
+        symbol_map.files.len()
+    );
+    Ok(symbol_map)
 }
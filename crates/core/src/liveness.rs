@@ -0,0 +1,409 @@
+use crate::cfg::{BasicBlock, ControlFlowGraph, EdgeKind};
+use crate::function::ObfuscatorFunction;
+use crate::instruction::InstructionWithId;
+use iced_x86::{
+    Code, FlowControl, Instruction, InstructionInfoFactory, Mnemonic, OpAccess, OpKind, Register,
+    RflagsBits,
+};
+
+pub const STATUS_FLAGS: u32 = RflagsBits::OF
+    | RflagsBits::SF
+    | RflagsBits::ZF
+    | RflagsBits::AF
+    | RflagsBits::CF
+    | RflagsBits::PF;
+
+pub const TRACKED_FLAGS: u32 = STATUS_FLAGS | RflagsBits::DF;
+
+const ALL_GPRS: u16 = u16::MAX;
+
+pub const GPRS: [Register; 16] = [
+    Register::RAX,
+    Register::RCX,
+    Register::RDX,
+    Register::RBX,
+    Register::RSP,
+    Register::RBP,
+    Register::RSI,
+    Register::RDI,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+    Register::R15,
+];
+
+const GPRS16: [Register; 16] = [
+    Register::AX,
+    Register::CX,
+    Register::DX,
+    Register::BX,
+    Register::SP,
+    Register::BP,
+    Register::SI,
+    Register::DI,
+    Register::R8W,
+    Register::R9W,
+    Register::R10W,
+    Register::R11W,
+    Register::R12W,
+    Register::R13W,
+    Register::R14W,
+    Register::R15W,
+];
+
+const GPRS8: [Register; 16] = [
+    Register::AL,
+    Register::CL,
+    Register::DL,
+    Register::BL,
+    Register::SPL,
+    Register::BPL,
+    Register::SIL,
+    Register::DIL,
+    Register::R8L,
+    Register::R9L,
+    Register::R10L,
+    Register::R11L,
+    Register::R12L,
+    Register::R13L,
+    Register::R14L,
+    Register::R15L,
+];
+
+/// The 32, 16 or 8-bit register overlapping a 64-bit general-purpose
+/// register; the high byte registers are never returned.
+#[must_use]
+pub fn register_of_width(register: Register, width: u32) -> Register {
+    let index = GPRS.iter().position(|&r| r == register.full_register()).unwrap_or(0);
+    match width {
+        32 => register.full_register32(),
+        16 => GPRS16[index],
+        8 => GPRS8[index],
+        _ => GPRS[index],
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LiveSet {
+    gprs: u16,
+    flags: u32,
+}
+
+impl LiveSet {
+    pub const EMPTY: Self = Self { gprs: 0, flags: 0 };
+
+    pub const ALL: Self = Self {
+        gprs: ALL_GPRS,
+        flags: TRACKED_FLAGS,
+    };
+
+    #[must_use]
+    pub fn is_register_live(&self, register: Register) -> bool {
+        gpr_bit(register).is_some_and(|bit| self.gprs & bit != 0)
+    }
+
+    #[must_use]
+    pub const fn is_flag_live(&self, flags: u32) -> bool {
+        self.flags & flags != 0
+    }
+
+    #[must_use]
+    pub const fn live_flags(&self) -> u32 {
+        self.flags
+    }
+
+    pub fn live_registers(&self) -> impl Iterator<Item = Register> + '_ {
+        GPRS.iter()
+            .copied()
+            .filter(|&register| self.is_register_live(register))
+    }
+
+    pub fn dead_registers(&self) -> impl Iterator<Item = Register> + '_ {
+        GPRS.iter()
+            .copied()
+            .filter(|&register| register != Register::RSP && !self.is_register_live(register))
+    }
+
+    #[must_use]
+    pub const fn union(&self, other: &Self) -> Self {
+        Self {
+            gprs: self.gprs | other.gprs,
+            flags: self.flags | other.flags,
+        }
+    }
+
+    fn add_register(&mut self, register: Register) {
+        if let Some(bit) = gpr_bit(register) {
+            self.gprs |= bit;
+        }
+    }
+}
+
+fn gpr_bit(register: Register) -> Option<u16> {
+    if !register.is_gpr() {
+        return None;
+    }
+    Some(1 << register.full_register().number())
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Effects {
+    uses: LiveSet,
+    kills: LiveSet,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LivenessAnalysis {
+    live_in: Vec<LiveSet>,
+    live_out: Vec<LiveSet>,
+    touched: Vec<u16>,
+}
+
+impl LivenessAnalysis {
+    #[must_use]
+    pub fn compute(instructions: &[InstructionWithId], cfg: &ControlFlowGraph) -> Self {
+        if instructions.is_empty() || cfg.is_empty() {
+            return Self::default();
+        }
+
+        let mut factory = InstructionInfoFactory::new();
+        let effects: Vec<Effects> = instructions
+            .iter()
+            .map(|inst| Self::effects(&mut factory, inst))
+            .collect();
+
+        let boundaries: Vec<LiveSet> = cfg
+            .blocks
+            .iter()
+            .map(|block| Self::boundary_live_out(&instructions[block.last_index()], block))
+            .collect();
+
+        let mut block_live_in = vec![LiveSet::EMPTY; cfg.blocks.len()];
+        let mut block_live_out = vec![LiveSet::EMPTY; cfg.blocks.len()];
+
+        let mut order = cfg.reverse_postorder();
+        order.reverse();
+        order.extend(cfg.unreachable_blocks());
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block_id in &order {
+                let block = &cfg.blocks[block_id];
+                let mut live = block
+                    .successors
+                    .iter()
+                    .fold(boundaries[block_id], |acc, edge| {
+                        acc.union(&block_live_in[edge.target])
+                    });
+                block_live_out[block_id] = live;
+
+                for index in block.range.clone().rev() {
+                    live = Self::transfer(live, &effects[index]);
+                }
+
+                if live != block_live_in[block_id] {
+                    block_live_in[block_id] = live;
+                    changed = true;
+                }
+            }
+        }
+
+        // Exception landing pads and other entry points are reached with the
+        // register state of whatever instruction faulted, so anything they
+        // read has to survive everywhere in the function.
+        let pinned = cfg.entry_points.iter().fold(LiveSet::EMPTY, |acc, &block| {
+            acc.union(&LiveSet {
+                gprs: block_live_in[block].gprs,
+                flags: 0,
+            })
+        });
+
+        let mut live_in = vec![LiveSet::EMPTY; instructions.len()];
+        let mut live_out = vec![LiveSet::EMPTY; instructions.len()];
+        for block in &cfg.blocks {
+            let mut live = block_live_out[block.id];
+            for index in block.range.clone().rev() {
+                live_out[index] = live.union(&pinned);
+                live = Self::transfer(live, &effects[index]);
+                live_in[index] = live.union(&pinned);
+            }
+        }
+
+        let touched = effects
+            .iter()
+            .map(|effect| effect.uses.gprs | effect.kills.gprs)
+            .collect();
+
+        Self {
+            live_in,
+            live_out,
+            touched,
+        }
+    }
+
+    const fn transfer(live_out: LiveSet, effects: &Effects) -> LiveSet {
+        // Uses are applied after kills, so a register that is both read and
+        // written stays live before the instruction.
+        LiveSet {
+            gprs: (live_out.gprs & !effects.kills.gprs) | effects.uses.gprs,
+            flags: (live_out.flags & !effects.kills.flags) | effects.uses.flags,
+        }
+    }
+
+    fn effects(factory: &mut InstructionInfoFactory, inst: &InstructionWithId) -> Effects {
+        let instruction = &inst.instruction;
+        let mut effects = Effects::default();
+
+        match instruction.flow_control() {
+            // Calls may read any register as an argument, and no ABI keeps
+            // status flags alive across them.
+            FlowControl::Call | FlowControl::IndirectCall => {
+                effects.uses = LiveSet {
+                    gprs: ALL_GPRS,
+                    flags: RflagsBits::DF,
+                };
+                effects.kills.flags = STATUS_FLAGS;
+                return effects;
+            }
+            FlowControl::Interrupt | FlowControl::Exception => {
+                effects.uses = LiveSet::ALL;
+                return effects;
+            }
+            _ => {}
+        }
+
+        if Self::is_zero_idiom(instruction) {
+            effects.kills.add_register(instruction.op0_register());
+            effects.kills.flags = instruction.rflags_modified() & TRACKED_FLAGS;
+            return effects;
+        }
+
+        let info = factory.info(instruction);
+        for used in info.used_registers() {
+            let register = used.register();
+            match used.access() {
+                OpAccess::Read
+                | OpAccess::CondRead
+                | OpAccess::ReadWrite
+                | OpAccess::ReadCondWrite
+                | OpAccess::CondWrite => effects.uses.add_register(register),
+                OpAccess::Write => {
+                    // Only 32- and 64-bit writes replace the whole register;
+                    // 8/16-bit writes merge with the bits that were already there.
+                    if register.size() >= 4 {
+                        effects.kills.add_register(register);
+                    } else {
+                        effects.uses.add_register(register);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        effects.uses.flags = instruction.rflags_read() & TRACKED_FLAGS;
+        if !Self::has_conditional_flag_write(instruction) {
+            effects.kills.flags = instruction.rflags_modified() & TRACKED_FLAGS;
+        }
+
+        effects
+    }
+
+    fn boundary_live_out(last: &InstructionWithId, block: &BasicBlock) -> LiveSet {
+        let has_edge = |kind: EdgeKind| block.successors.iter().any(|edge| edge.kind == kind);
+
+        match last.instruction.flow_control() {
+            // Every register may carry a return value or a callee-saved value
+            // back to the caller; flags never do.
+            FlowControl::Return => LiveSet {
+                gprs: ALL_GPRS,
+                flags: RflagsBits::DF,
+            },
+            FlowControl::ConditionalBranch if !has_edge(EdgeKind::Conditional) => LiveSet::ALL,
+            FlowControl::UnconditionalBranch if !has_edge(EdgeKind::Unconditional) => {
+                LiveSet::ALL
+            }
+            FlowControl::IndirectBranch if has_edge(EdgeKind::JumpTable) => LiveSet::EMPTY,
+            FlowControl::IndirectBranch | FlowControl::Interrupt | FlowControl::Exception => {
+                LiveSet::ALL
+            }
+            _ if block.successors.is_empty() => LiveSet::ALL,
+            _ => LiveSet::EMPTY,
+        }
+    }
+
+    fn is_zero_idiom(instruction: &Instruction) -> bool {
+        matches!(
+            instruction.code(),
+            Code::Xor_r32_rm32
+                | Code::Xor_rm32_r32
+                | Code::Xor_r64_rm64
+                | Code::Xor_rm64_r64
+                | Code::Sub_r32_rm32
+                | Code::Sub_rm32_r32
+                | Code::Sub_r64_rm64
+                | Code::Sub_rm64_r64
+        ) && instruction.op0_kind() == OpKind::Register
+            && instruction.op1_kind() == OpKind::Register
+            && instruction.op0_register() == instruction.op1_register()
+    }
+
+    fn has_conditional_flag_write(instruction: &Instruction) -> bool {
+        // Shifts and rotates by CL leave the flags untouched when the count is
+        // zero, so they cannot be treated as killing the previous values.
+        matches!(
+            instruction.mnemonic(),
+            Mnemonic::Shl
+                | Mnemonic::Shr
+                | Mnemonic::Sar
+                | Mnemonic::Sal
+                | Mnemonic::Rol
+                | Mnemonic::Ror
+                | Mnemonic::Rcl
+                | Mnemonic::Rcr
+                | Mnemonic::Shld
+                | Mnemonic::Shrd
+        ) && (0..instruction.op_count()).any(|op| {
+            instruction.op_kind(op) == OpKind::Register
+                && instruction.op_register(op) == Register::CL
+        })
+    }
+
+    #[must_use]
+    pub fn live_in(&self, index: usize) -> LiveSet {
+        self.live_in.get(index).copied().unwrap_or(LiveSet::ALL)
+    }
+
+    #[must_use]
+    pub fn live_out(&self, index: usize) -> LiveSet {
+        self.live_out.get(index).copied().unwrap_or(LiveSet::ALL)
+    }
+
+    #[must_use]
+    pub fn are_flags_dead_after(&self, index: usize, flags: u32) -> bool {
+        !self.live_out(index).is_flag_live(flags)
+    }
+
+    /// Registers that can be freely clobbered by code replacing the
+    /// instruction at `index`: dead on both sides of it, not referenced by
+    /// the instruction itself and never RSP.
+    #[must_use]
+    pub fn free_registers(&self, index: usize) -> Vec<Register> {
+        let Some(&touched) = self.touched.get(index) else {
+            return Vec::new();
+        };
+        let mut busy = self.live_in(index).union(&self.live_out(index));
+        busy.gprs |= touched;
+        busy.dead_registers().collect()
+    }
+}
+
+impl ObfuscatorFunction {
+    pub fn analyze_liveness(&self) -> LivenessAnalysis {
+        LivenessAnalysis::compute(&self.instructions, &self.cfg)
+    }
+}
@@ -1,21 +1,98 @@
+use crate::config::ObfuscatorConfig;
 use crate::function::ObfuscatorFunction;
 use crate::passes::PassManager;
+use crate::selection::{FunctionPattern, matches_any, parse_patterns};
+use crate::verify::Verifier;
+use common::debug;
+use rand::Rng;
 
-pub struct Obfuscator {
+struct Pipeline {
     pass_manager: PassManager,
+    iterations: usize,
+}
+
+struct PipelineOverride {
+    functions: Vec<FunctionPattern>,
+    pipeline: Pipeline,
+}
+
+pub struct Obfuscator {
+    pipeline: Pipeline,
+    overrides: Vec<PipelineOverride>,
+    seed: u64,
 }
 
 impl Obfuscator {
+    /// # Panics
+    ///
+    /// Only if the default configuration stopped validating.
+    #[must_use]
     pub fn new() -> Self {
-        Self {
-            pass_manager: PassManager::default(),
-        }
+        Self::with_config(&ObfuscatorConfig::default()).expect("default config is valid")
     }
 
-    pub fn obfuscate(&self, functions: &mut [ObfuscatorFunction]) -> Result<(), String> {
-        functions.iter_mut().for_each(|function| {
-            self.pass_manager.run_passes(function, 2);
-        });
+    /// # Errors
+    ///
+    /// Fails when a pass or a selection pattern of the configuration is
+    /// invalid.
+    pub fn with_config(config: &ObfuscatorConfig) -> Result<Self, String> {
+        let overrides = config
+            .overrides
+            .iter()
+            .map(|function_override| {
+                Ok(PipelineOverride {
+                    functions: parse_patterns(&function_override.functions)?,
+                    pipeline: Pipeline {
+                        pass_manager: PassManager::from_passes(
+                            function_override.passes.as_ref().unwrap_or(&config.passes),
+                        ),
+                        iterations: function_override.iterations.unwrap_or(config.iterations),
+                    },
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Self {
+            pipeline: Pipeline {
+                pass_manager: PassManager::from_passes(&config.passes),
+                iterations: config.iterations,
+            },
+            overrides,
+            seed: config.seed.unwrap_or_else(|| rand::rng().random()),
+        })
+    }
+
+    #[must_use]
+    pub const fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    fn pipeline_for(&self, function: &ObfuscatorFunction) -> &Pipeline {
+        self.overrides
+            .iter()
+            .find(|candidate| {
+                matches_any(&candidate.functions, &function.name, function.rva, function.size)
+            })
+            .map_or(&self.pipeline, |candidate| {
+                debug!("Using pipeline override for function {}", function.name);
+                &candidate.pipeline
+            })
+    }
+
+    /// # Errors
+    ///
+    /// Fails when the passes cannot be run on the functions.
+    pub fn obfuscate(
+        &self,
+        functions: &mut [ObfuscatorFunction],
+        verifier: Option<&Verifier>,
+    ) -> Result<(), String> {
+        for function in functions.iter_mut() {
+            let pipeline = self.pipeline_for(function);
+            pipeline
+                .pass_manager
+                .run_passes(function, pipeline.iterations, self.seed, verifier);
+        }
         Ok(())
     }
 }
@@ -0,0 +1,352 @@
+use super::{Pass, PassRng, take_over_id};
+use crate::config::ConstantEncryptionConfig;
+use crate::function::ObfuscatorFunction;
+use crate::instruction::{InstructionContext, InstructionWithId};
+use crate::liveness::{LivenessAnalysis, STATUS_FLAGS, register_of_width};
+use iced_x86::{Code, Instruction, Mnemonic, OpKind, Register};
+use rand::Rng;
+
+/// One step of the chain that turns the encrypted value back into the
+/// original immediate.
+#[derive(Clone, Copy, Debug)]
+enum Step {
+    Xor(u64),
+    Add(u64),
+    Sub(u64),
+    Rol(u32),
+    Ror(u32),
+    Not,
+    Neg,
+    Bswap,
+}
+
+impl Step {
+    fn random(width: u32, rng: &mut PassRng) -> Self {
+        // Keys are sign-extended imm32 operands for 64-bit registers.
+        let key = |rng: &mut PassRng| truncate(i64::from(rng.random::<i32>()).cast_unsigned(), width);
+        // BSWAP is undefined on 16-bit registers and does not exist for 8.
+        let choices = if width >= 32 { 8 } else { 7 };
+        match rng.random_range(0..choices) {
+            0 => Self::Xor(key(rng)),
+            1 => Self::Add(key(rng)),
+            2 => Self::Sub(key(rng)),
+            3 => Self::Rol(rng.random_range(1..width)),
+            4 => Self::Ror(rng.random_range(1..width)),
+            5 => Self::Not,
+            6 => Self::Neg,
+            _ => Self::Bswap,
+        }
+    }
+
+    fn apply(self, value: u64, width: u32) -> u64 {
+        let value = match self {
+            Self::Xor(key) => value ^ key,
+            Self::Add(key) => value.wrapping_add(key),
+            Self::Sub(key) => value.wrapping_sub(key),
+            Self::Rol(count) => rotate_left(value, count, width),
+            Self::Ror(count) => rotate_left(value, width - count, width),
+            Self::Not => !value,
+            Self::Neg => value.wrapping_neg(),
+            Self::Bswap => bswap(value, width),
+        };
+        truncate(value, width)
+    }
+
+    fn invert(self, value: u64, width: u32) -> u64 {
+        let value = match self {
+            Self::Xor(key) => value ^ key,
+            Self::Add(key) => value.wrapping_sub(key),
+            Self::Sub(key) => value.wrapping_add(key),
+            Self::Rol(count) => rotate_left(value, width - count, width),
+            Self::Ror(count) => rotate_left(value, count, width),
+            Self::Not => !value,
+            Self::Neg => value.wrapping_neg(),
+            Self::Bswap => bswap(value, width),
+        };
+        truncate(value, width)
+    }
+
+    #[allow(clippy::cast_possible_truncation, reason = "the immediates are sign-extended from at most 32 bits")]
+    fn instruction(self, register: Register, width: u32) -> Result<Instruction, String> {
+        let code = |codes: [Code; 4]| codes[width_index(width)];
+        // The keys are already truncated to the operand width; the encoder
+        // wants them back in the signed range of the immediate.
+        let immediate = |key: u64| sign_extend(key, width.min(32)) as i32;
+        let instruction = match self {
+            Self::Xor(key) => Instruction::with2(
+                code([Code::Xor_rm8_imm8, Code::Xor_rm16_imm16, Code::Xor_rm32_imm32, Code::Xor_rm64_imm32]),
+                register,
+                immediate(key),
+            ),
+            Self::Add(key) => Instruction::with2(
+                code([Code::Add_rm8_imm8, Code::Add_rm16_imm16, Code::Add_rm32_imm32, Code::Add_rm64_imm32]),
+                register,
+                immediate(key),
+            ),
+            Self::Sub(key) => Instruction::with2(
+                code([Code::Sub_rm8_imm8, Code::Sub_rm16_imm16, Code::Sub_rm32_imm32, Code::Sub_rm64_imm32]),
+                register,
+                immediate(key),
+            ),
+            Self::Rol(count) => Instruction::with2(
+                code([Code::Rol_rm8_imm8, Code::Rol_rm16_imm8, Code::Rol_rm32_imm8, Code::Rol_rm64_imm8]),
+                register,
+                count,
+            ),
+            Self::Ror(count) => Instruction::with2(
+                code([Code::Ror_rm8_imm8, Code::Ror_rm16_imm8, Code::Ror_rm32_imm8, Code::Ror_rm64_imm8]),
+                register,
+                count,
+            ),
+            Self::Not => Instruction::with1(
+                code([Code::Not_rm8, Code::Not_rm16, Code::Not_rm32, Code::Not_rm64]),
+                register,
+            ),
+            Self::Neg => Instruction::with1(
+                code([Code::Neg_rm8, Code::Neg_rm16, Code::Neg_rm32, Code::Neg_rm64]),
+                register,
+            ),
+            Self::Bswap => Instruction::with1(
+                if width == 64 { Code::Bswap_r64 } else { Code::Bswap_r32 },
+                register,
+            ),
+        };
+        instruction.map_err(|e| e.to_string())
+    }
+}
+
+/// Replaces immediate operands with a chain of XOR, ADD, SUB, rotates, NOT,
+/// NEG and BSWAP with random keys that computes the value from an encrypted
+/// one, so constants no longer appear in the output.
+///
+/// A `mov reg, imm` computes the value in its destination; ALU instructions
+/// and `mov mem, imm` load it into a free register first and then use the
+/// register form. The chain clobbers the status flags, which only matters
+/// for MOV as every other supported instruction rewrites them.
+pub struct ConstantEncryptionPass {
+    config: ConstantEncryptionConfig,
+}
+
+impl ConstantEncryptionPass {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_config(ConstantEncryptionConfig::default())
+    }
+
+    #[must_use]
+    pub const fn with_config(config: ConstantEncryptionConfig) -> Self {
+        Self { config }
+    }
+
+    /// The immediate of a supported instruction, sign-extended to the
+    /// operand width, and that width in bits.
+    #[allow(clippy::cast_possible_truncation, reason = "general purpose operands are at most 8 bytes wide")]
+    fn immediate(&self, instruction: &Instruction) -> Option<(u64, u32)> {
+        if !matches!(
+            instruction.mnemonic(),
+            Mnemonic::Mov
+                | Mnemonic::Add
+                | Mnemonic::Sub
+                | Mnemonic::And
+                | Mnemonic::Or
+                | Mnemonic::Xor
+                | Mnemonic::Cmp
+                | Mnemonic::Test
+        ) || instruction.op_count() != 2
+            || instruction.is_ip_rel_memory_operand()
+            || instruction.has_segment_prefix()
+        {
+            return None;
+        }
+
+        let width = match instruction.op0_kind() {
+            OpKind::Register => {
+                let register = instruction.op0_register();
+                if !register.is_gpr()
+                    || register.full_register() == Register::RSP
+                    || matches!(register, Register::AH | Register::CH | Register::DH | Register::BH)
+                {
+                    return None;
+                }
+                register.size() as u32 * 8
+            }
+            OpKind::Memory => instruction.memory_size().size() as u32 * 8,
+            _ => return None,
+        };
+        let value = match instruction.op1_kind() {
+            OpKind::Immediate8 | OpKind::Immediate16 | OpKind::Immediate32 | OpKind::Immediate64
+            | OpKind::Immediate8to16 | OpKind::Immediate8to32 | OpKind::Immediate8to64
+            | OpKind::Immediate32to64 => instruction.immediate(1),
+            _ => return None,
+        };
+        if !matches!(width, 8 | 16 | 32 | 64) {
+            return None;
+        }
+
+        let magnitude = sign_extend(truncate(value, width), width).unsigned_abs();
+        (magnitude >= self.config.min_magnitude).then_some((value, width))
+    }
+
+    fn encrypt(&self, value: u64, width: u32, rng: &mut PassRng) -> (u64, Vec<Step>) {
+        let steps: Vec<Step> = (0..self.config.rounds).map(|_| Step::random(width, rng)).collect();
+        let encrypted = steps
+            .iter()
+            .rev()
+            .fold(truncate(value, width), |value, step| step.invert(value, width));
+        debug_assert_eq!(
+            steps.iter().fold(encrypted, |value, step| step.apply(value, width)),
+            truncate(value, width)
+        );
+        (encrypted, steps)
+    }
+
+    fn rewrite(
+        &self,
+        instruction: &InstructionWithId,
+        liveness: &LivenessAnalysis,
+        index: usize,
+        context: &InstructionContext,
+        rng: &mut PassRng,
+    ) -> Result<Option<Vec<InstructionWithId>>, String> {
+        let original = &instruction.instruction;
+        let Some((value, width)) = self.immediate(original) else {
+            return Ok(None);
+        };
+        let is_mov = original.mnemonic() == Mnemonic::Mov;
+        if is_mov && !liveness.are_flags_dead_after(index, STATUS_FLAGS) {
+            return Ok(None);
+        }
+
+        let in_place = is_mov && original.op0_kind() == OpKind::Register;
+        let register = if in_place {
+            original.op0_register()
+        } else {
+            let free = liveness.free_registers(index);
+            if free.is_empty() {
+                return Ok(None);
+            }
+            register_of_width(free[rng.random_range(0..free.len())], width)
+        };
+
+        let (encrypted, steps) = self.encrypt(value, width, rng);
+        let load = match width {
+            64 => Instruction::with2(Code::Mov_r64_imm64, register, encrypted),
+            // Encrypted values are truncated to the operand width.
+            32 => Instruction::with2(Code::Mov_r32_imm32, register, low_dword(encrypted)),
+            16 => Instruction::with2(Code::Mov_r16_imm16, register, low_dword(encrypted)),
+            _ => Instruction::with2(Code::Mov_r8_imm8, register, low_dword(encrypted)),
+        }
+        .map_err(|e| e.to_string())?;
+
+        let mut code = vec![context.create_encoded(load)?];
+        for step in &steps {
+            code.push(context.create_encoded(step.instruction(register, width)?)?);
+        }
+
+        if !in_place {
+            let mut rewritten = *original;
+            let register_form = register_form(original.mnemonic(), width)
+                .ok_or_else(|| format!("No register form for {original}"))?;
+            rewritten.set_code(register_form);
+            rewritten.set_op1_kind(OpKind::Register);
+            rewritten.set_op1_register(register);
+            code.push(context.create_encoded(rewritten)?);
+        }
+        Ok(Some(code))
+    }
+}
+
+impl Pass for ConstantEncryptionPass {
+    fn name(&self) -> &'static str {
+        "ConstantEncryption"
+    }
+
+    fn apply(&self, function: &mut ObfuscatorFunction, rng: &mut PassRng) -> Result<(), String> {
+        let liveness = function.analyze_liveness();
+        let context = &function.instruction_context;
+        let mut result = Vec::with_capacity(function.instructions.len() * 2);
+
+        for (index, instruction) in function.instructions.iter().enumerate() {
+            if function.unwind.is_frame_instruction(instruction.get_id())
+                || function.references_own_code(instruction.get_id())
+                || function.has_absolute_reference(instruction.get_id())
+                || self.immediate(&instruction.instruction).is_none()
+                || !rng.random_bool(self.config.probability)
+            {
+                result.push(instruction.clone());
+                continue;
+            }
+
+            match self.rewrite(instruction, &liveness, index, context, rng)? {
+                Some(mut rewritten) => {
+                    take_over_id(&mut rewritten, instruction);
+                    result.append(&mut rewritten);
+                }
+                None => result.push(instruction.clone()),
+            }
+        }
+
+        function.instructions = result;
+        Ok(())
+    }
+}
+
+impl Default for ConstantEncryptionPass {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const fn width_index(width: u32) -> usize {
+    match width {
+        8 => 0,
+        16 => 1,
+        32 => 2,
+        _ => 3,
+    }
+}
+
+const fn truncate(value: u64, width: u32) -> u64 {
+    if width == 64 { value } else { value & ((1 << width) - 1) }
+}
+
+const fn sign_extend(value: u64, width: u32) -> i64 {
+    let shift = 64 - width;
+    (value << shift).cast_signed() >> shift
+}
+
+const fn rotate_left(value: u64, count: u32, width: u32) -> u64 {
+    let value = truncate(value, width);
+    let count = count % width;
+    if count == 0 {
+        return value;
+    }
+    truncate((value << count) | (value >> (width - count)), width)
+}
+
+fn bswap(value: u64, width: u32) -> u64 {
+    match width {
+        64 => value.swap_bytes(),
+        _ => u64::from(low_dword(value).swap_bytes()),
+    }
+}
+
+const fn low_dword(value: u64) -> u32 {
+    let bytes = value.to_le_bytes();
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+const fn register_form(mnemonic: Mnemonic, width: u32) -> Option<Code> {
+    let codes = match mnemonic {
+        Mnemonic::Mov => [Code::Mov_rm8_r8, Code::Mov_rm16_r16, Code::Mov_rm32_r32, Code::Mov_rm64_r64],
+        Mnemonic::Add => [Code::Add_rm8_r8, Code::Add_rm16_r16, Code::Add_rm32_r32, Code::Add_rm64_r64],
+        Mnemonic::Sub => [Code::Sub_rm8_r8, Code::Sub_rm16_r16, Code::Sub_rm32_r32, Code::Sub_rm64_r64],
+        Mnemonic::And => [Code::And_rm8_r8, Code::And_rm16_r16, Code::And_rm32_r32, Code::And_rm64_r64],
+        Mnemonic::Or => [Code::Or_rm8_r8, Code::Or_rm16_r16, Code::Or_rm32_r32, Code::Or_rm64_r64],
+        Mnemonic::Xor => [Code::Xor_rm8_r8, Code::Xor_rm16_r16, Code::Xor_rm32_r32, Code::Xor_rm64_r64],
+        Mnemonic::Cmp => [Code::Cmp_rm8_r8, Code::Cmp_rm16_r16, Code::Cmp_rm32_r32, Code::Cmp_rm64_r64],
+        Mnemonic::Test => [Code::Test_rm8_r8, Code::Test_rm16_r16, Code::Test_rm32_r32, Code::Test_rm64_r64],
+        _ => return None,
+    };
+    Some(codes[width_index(width)])
+}
@@ -0,0 +1,334 @@
+use super::{Pass, PassRng, falls_off_end};
+use crate::branches::BranchInfo;
+use crate::config::FlatteningConfig;
+use crate::function::ObfuscatorFunction;
+use crate::instruction::{InstructionContext, InstructionWithId};
+use crate::liveness::{LivenessAnalysis, STATUS_FLAGS};
+use iced_x86::{Code, FlowControl, Instruction, Register};
+use rand::Rng;
+use rand::seq::SliceRandom;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// How an edge into a flattened block is sent through the dispatcher.
+#[derive(Clone, Copy, Debug)]
+enum Route {
+    /// The block ends in `jmp target`, which is replaced.
+    Replace { index: usize, target: usize },
+    /// The block ends in `jcc target`, which is pointed at a stub.
+    Stub { index: usize, target: usize },
+    /// The block falls through into `target`; code is inserted after it.
+    After { index: usize, target: usize },
+}
+
+impl Route {
+    const fn target(&self) -> usize {
+        match *self {
+            Self::Replace { target, .. } | Self::Stub { target, .. } | Self::After { target, .. } => target,
+        }
+    }
+}
+
+/// Sends edges between basic blocks through a dispatcher appended to the
+/// function.
+///
+/// The source loads the state of its target into a register and jumps to
+/// the dispatcher, which compares it against every state.
+///
+/// The state register has to be dead and the status flags unused at the
+/// entry of every flattened block. Blocks starting an epilogue and
+/// fallthroughs after calls, whose return address the exception runtime
+/// maps to a state, are left alone.
+pub struct ControlFlowFlatteningPass {
+    config: FlatteningConfig,
+}
+
+impl ControlFlowFlatteningPass {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_config(FlatteningConfig::default())
+    }
+
+    #[must_use]
+    pub const fn with_config(config: FlatteningConfig) -> Self {
+        Self { config }
+    }
+
+    /// Gives every flattened block a distinct random state.
+    fn assign_states(targets: &[usize], rng: &mut PassRng) -> BTreeMap<usize, u32> {
+        let mut states = BTreeMap::new();
+        let mut used = HashSet::new();
+        for &target in targets {
+            let state = loop {
+                let state = rng.random::<u32>();
+                if used.insert(state) {
+                    break state;
+                }
+            };
+            states.insert(target, state);
+        }
+        states
+    }
+
+    /// Compares the state register against the state of every block in
+    /// `order` and jumps to the matching one. The last block is the default.
+    fn dispatcher(
+        function: &ObfuscatorFunction,
+        context: &InstructionContext,
+        order: &[usize],
+        states: &BTreeMap<usize, u32>,
+        state_register: Register,
+        dispatcher_id: usize,
+        new_branches: &mut Vec<BranchInfo>,
+    ) -> Result<Vec<InstructionWithId>, String> {
+        let target_id = |block: usize| function.instructions[function.cfg.blocks[block].first_index()].id;
+        let mut dispatcher = Vec::with_capacity(order.len() * 2);
+        let (&default_target, compared) = order.split_last().ok_or("No flattened blocks")?;
+        for (position, &target) in compared.iter().enumerate() {
+            let compare_id = if position == 0 { dispatcher_id } else { context.next_id() };
+            dispatcher.push(InstructionWithId::encoded(
+                compare_id,
+                Instruction::with2(Code::Cmp_rm32_imm32, state_register, states[&target])
+                    .map_err(|e| e.to_string())?,
+            )?);
+            let branch_id = context.next_id();
+            new_branches.push(BranchInfo {
+                source_id: branch_id,
+                target_id: target_id(target),
+                original_target: 0,
+            });
+            dispatcher.push(InstructionWithId::encoded(branch_id, Instruction::with_branch(Code::Je_rel32_64, 0).map_err(|e| e.to_string())?)?);
+        }
+        let default_id = if compared.is_empty() { dispatcher_id } else { context.next_id() };
+        new_branches.push(BranchInfo {
+            source_id: default_id,
+            target_id: target_id(default_target),
+            original_target: 0,
+        });
+        dispatcher.push(InstructionWithId::encoded(default_id, Instruction::with_branch(Code::Jmp_rel32_64, 0).map_err(|e| e.to_string())?)?);
+        Ok(dispatcher)
+    }
+
+    fn candidate_blocks(function: &ObfuscatorFunction, liveness: &LivenessAnalysis) -> Vec<usize> {
+        let cfg = &function.cfg;
+        cfg.blocks
+            .iter()
+            .filter(|block| block.id != cfg.entry && !block.is_empty())
+            .filter(|block| {
+                let first = block.first_index();
+                !function.unwind.is_frame_instruction(function.instructions[first].id)
+                    && !liveness.live_in(first).is_flag_live(STATUS_FLAGS)
+            })
+            .map(|block| block.id)
+            .collect()
+    }
+
+    /// Picks the register dead at the entry of most candidate blocks.
+    fn pick_state_register(
+        function: &ObfuscatorFunction,
+        liveness: &LivenessAnalysis,
+        candidates: &[usize],
+        rng: &mut PassRng,
+    ) -> Option<(Register, Vec<usize>)> {
+        let mut by_register: HashMap<Register, Vec<usize>> = HashMap::new();
+        let mut registers = Vec::new();
+        for &block in candidates {
+            let first = function.cfg.blocks[block].first_index();
+            for register in liveness.live_in(first).dead_registers() {
+                if !registers.contains(&register) {
+                    registers.push(register);
+                }
+                by_register.entry(register).or_default().push(block);
+            }
+        }
+
+        let best = registers
+            .iter()
+            .map(|register| by_register[register].len())
+            .max()?;
+        let tied: Vec<Register> = registers
+            .into_iter()
+            .filter(|register| by_register[register].len() == best)
+            .collect();
+        let register = tied[rng.random_range(0..tied.len())];
+        Some((register, by_register.remove(&register).unwrap_or_default()))
+    }
+
+    fn collect_routes(function: &ObfuscatorFunction, targets: &HashSet<usize>) -> Vec<Route> {
+        let cfg = &function.cfg;
+        let mut routes = Vec::new();
+        for block in &cfg.blocks {
+            if block.is_empty() {
+                continue;
+            }
+            let index = block.last_index();
+            let last = &function.instructions[index];
+            let taken = function
+                .branch_map
+                .iter()
+                .find(|branch| branch.source_id == last.id)
+                .and_then(|branch| {
+                    function
+                        .instructions
+                        .iter()
+                        .position(|inst| inst.id == branch.target_id)
+                })
+                .and_then(|position| cfg.block_containing(position))
+                .filter(|target| targets.contains(&target.id))
+                .map(|target| target.id);
+            let fallthrough = block.fallthrough().filter(|target| targets.contains(target));
+
+            match last.instruction.flow_control() {
+                FlowControl::UnconditionalBranch => {
+                    if let Some(target) = taken {
+                        routes.push(Route::Replace { index, target });
+                    }
+                }
+                FlowControl::ConditionalBranch => {
+                    if let Some(target) = taken {
+                        routes.push(Route::Stub { index, target });
+                    }
+                    if let Some(target) = fallthrough {
+                        routes.push(Route::After { index, target });
+                    }
+                }
+                FlowControl::Next => {
+                    if let Some(target) = fallthrough
+                        && !function.unwind.is_frame_instruction(last.id)
+                    {
+                        routes.push(Route::After { index, target });
+                    }
+                }
+                _ => {}
+            }
+        }
+        routes
+    }
+}
+
+impl Pass for ControlFlowFlatteningPass {
+    fn name(&self) -> &'static str {
+        "ControlFlowFlattening"
+    }
+
+    fn apply(&self, function: &mut ObfuscatorFunction, rng: &mut PassRng) -> Result<(), String> {
+        if function.cfg.blocks.len() < 2 {
+            return Ok(());
+        }
+
+        let liveness = function.analyze_liveness();
+        let candidates = Self::candidate_blocks(function, &liveness);
+        let Some((register, blocks)) = Self::pick_state_register(function, &liveness, &candidates, rng) else {
+            return Ok(());
+        };
+        let routes = Self::collect_routes(function, &blocks.into_iter().collect());
+
+        let mut targets: Vec<usize> = routes.iter().map(Route::target).collect();
+        targets.sort_unstable();
+        targets.dedup();
+        if targets.is_empty() || targets.len() < self.config.min_blocks {
+            return Ok(());
+        }
+
+        let states = Self::assign_states(&targets, rng);
+
+        let context = function.instruction_context.clone();
+        let state_register = register.full_register32();
+        let dispatcher_id = context.next_id();
+
+        let mut new_branches: Vec<BranchInfo> = Vec::new();
+        let mut removed_sources: HashSet<usize> = HashSet::new();
+        let mut retargeted: Vec<(usize, usize)> = Vec::new();
+
+        // Loads the state of `target` and jumps to the dispatcher.
+        let mut transfer = |first_id: usize, target: usize| -> Result<Vec<InstructionWithId>, String> {
+            let jump_id = context.next_id();
+            new_branches.push(BranchInfo {
+                source_id: jump_id,
+                target_id: dispatcher_id,
+                original_target: 0,
+            });
+            Ok(vec![
+                InstructionWithId::encoded(
+                    first_id,
+                    Instruction::with2(Code::Mov_r32_imm32, state_register, states[&target])
+                        .map_err(|e| e.to_string())?,
+                )?,
+                InstructionWithId::encoded(jump_id, Instruction::with_branch(Code::Jmp_rel32_64, 0).map_err(|e| e.to_string())?)?,
+            ])
+        };
+
+        let mut result = Vec::with_capacity(function.instructions.len() + routes.len() * 3);
+        let mut stubs = Vec::new();
+        let mut routes_at: BTreeMap<usize, Vec<Route>> = BTreeMap::new();
+        for route in &routes {
+            let index = match *route {
+                Route::Replace { index, .. } | Route::Stub { index, .. } | Route::After { index, .. } => index,
+            };
+            routes_at.entry(index).or_default().push(*route);
+        }
+
+        for (index, instruction) in function.instructions.iter().enumerate() {
+            let Some(routes) = routes_at.get(&index) else {
+                result.push(instruction.clone());
+                continue;
+            };
+
+            if let Some(Route::Replace { target, .. }) = routes.iter().find(|route| matches!(route, Route::Replace { .. })) {
+                // Branches into the jump land on the state load instead.
+                removed_sources.insert(instruction.id);
+                result.extend(transfer(instruction.id, *target)?);
+                continue;
+            }
+
+            result.push(instruction.clone());
+            for route in routes {
+                match *route {
+                    Route::Stub { target, .. } => {
+                        let stub_id = context.next_id();
+                        retargeted.push((instruction.id, stub_id));
+                        stubs.extend(transfer(stub_id, target)?);
+                    }
+                    Route::After { target, .. } => {
+                        result.extend(transfer(context.next_id(), target)?);
+                    }
+                    Route::Replace { .. } => {}
+                }
+            }
+        }
+
+        // The original code must not run into the dispatcher.
+        if falls_off_end(&function.instructions) {
+            result.push(context.create_encoded(Instruction::with(Code::Int3))?);
+        }
+        result.extend(stubs);
+
+        let mut order = targets;
+        order.shuffle(rng);
+        result.extend(Self::dispatcher(
+            function,
+            &context,
+            &order,
+            &states,
+            state_register,
+            dispatcher_id,
+            &mut new_branches,
+        )?);
+
+        function.branch_map.retain(|branch| !removed_sources.contains(&branch.source_id));
+        for (source_id, stub_id) in retargeted {
+            if let Some(branch) = function.branch_map.iter_mut().find(|branch| branch.source_id == source_id) {
+                branch.target_id = stub_id;
+            }
+        }
+        function.branch_map.extend(new_branches);
+        function.instructions = result;
+        function.instruction_context = context;
+        Ok(())
+    }
+}
+
+impl Default for ControlFlowFlatteningPass {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,226 @@
+use super::{Pass, PassRng};
+use crate::config::JunkCodeConfig;
+use crate::function::ObfuscatorFunction;
+use crate::liveness::{GPRS, LiveSet, STATUS_FLAGS, register_of_width};
+use iced_x86::{Code, FlowControl, Instruction, MemoryOperand, Register};
+use rand::Rng;
+use std::ops::RangeInclusive;
+
+/// Registers junk may read: every general-purpose register but RSP.
+fn sources() -> Vec<Register> {
+    GPRS.iter().copied().filter(|&register| register != Register::RSP).collect()
+}
+
+/// Inserts instruction sequences without any effect between the real
+/// instructions: NOP equivalents, writes to registers dead at that point
+/// and stack traffic that the sequence itself balances.
+///
+/// Only registers dead before the next real instruction are written, and
+/// the status flags only when they are dead as well. Prologues up to the
+/// first instruction past them, epilogues and the instruction after a call,
+/// whose return address the exception tables may refer to, get no junk in
+/// front of them.
+pub struct JunkCodePass {
+    config: JunkCodeConfig,
+}
+
+impl JunkCodePass {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_config(JunkCodeConfig::default())
+    }
+
+    #[must_use]
+    pub const fn with_config(config: JunkCodeConfig) -> Self {
+        Self { config }
+    }
+
+    /// Positions from the start of each unwind region up to the first
+    /// instruction past its prolog. Junk there would count towards the
+    /// prolog, which has to stay within 255 bytes.
+    fn prolog_spans(function: &ObfuscatorFunction) -> Vec<RangeInclusive<usize>> {
+        let position = |id: usize| function.instructions.iter().position(|inst| inst.id == id);
+        function
+            .unwind
+            .regions
+            .iter()
+            .filter_map(|region| {
+                let begin = position(region.begin_id)?;
+                let end = match region.prolog_end {
+                    Some(id) => position(id)?,
+                    None => function.instructions.len(),
+                };
+                Some(begin..=end)
+            })
+            .collect()
+    }
+
+    fn is_insertion_point(function: &ObfuscatorFunction, prologs: &[RangeInclusive<usize>], index: usize) -> bool {
+        !function.unwind.is_frame_instruction(function.instructions[index].id)
+            && !prologs.iter().any(|span| span.contains(&index))
+            && (index == 0
+                || !matches!(
+                    function.instructions[index - 1].instruction.flow_control(),
+                    FlowControl::Call | FlowControl::IndirectCall
+                ))
+    }
+
+    fn pick<T: Copy>(items: &[T], rng: &mut PassRng) -> T {
+        items[rng.random_range(0..items.len())]
+    }
+
+    /// An instruction that changes neither registers nor flags.
+    fn nop(rng: &mut PassRng) -> Result<Instruction, String> {
+        let e = |e: iced_x86::IcedError| e.to_string();
+        let register = Self::pick(&sources(), rng);
+        match rng.random_range(0..5) {
+            0 => Ok(Instruction::with(Code::Nopd)),
+            1 => Instruction::with1(
+                Code::Nop_rm32,
+                MemoryOperand::with_base_displ(register, rng.random_range(0..0x80i64)),
+            )
+            .map_err(e),
+            2 => Instruction::with2(Code::Mov_r64_rm64, register, register).map_err(e),
+            3 => Instruction::with2(Code::Xchg_rm64_r64, register, register).map_err(e),
+            _ => Instruction::with2(Code::Lea_r64_m, register, MemoryOperand::with_base(register)).map_err(e),
+        }
+    }
+
+    /// Writes `destination`, which must be dead. With `flags_dead` the
+    /// write may also go through an instruction that sets the flags.
+    fn dead_write(destination: Register, flags_dead: bool, rng: &mut PassRng) -> Result<Instruction, String> {
+        let e = |e: iced_x86::IcedError| e.to_string();
+        let source = Self::pick(&sources(), rng);
+        let immediate = rng.random_range(-0x1000..0x1000i32);
+        let stack_slot = MemoryOperand::with_base_displ(Register::RSP, rng.random_range(0..4i64) * 8);
+        let choice = rng.random_range(0..if flags_dead { 12 } else { 7 });
+        match choice {
+            0 => Instruction::with2(Code::Mov_rm64_imm32, destination, immediate),
+            1 => Instruction::with2(Code::Mov_r64_rm64, destination, source),
+            2 => Instruction::with2(
+                Code::Lea_r64_m,
+                destination,
+                MemoryOperand::with_base_index_scale_displ_size(
+                    source,
+                    Self::pick(&sources(), rng),
+                    Self::pick(&[1, 2, 4, 8], rng),
+                    i64::from(immediate),
+                    1,
+                ),
+            ),
+            3 => Instruction::with2(
+                Code::Movzx_r32_rm16,
+                destination.full_register32(),
+                register_of_width(source, 16),
+            ),
+            // Above RSP lies at least the return address and the frame of
+            // the caller, so these reads never fault.
+            4 => Instruction::with2(Code::Mov_r64_rm64, destination, stack_slot),
+            5 => Instruction::with1(Code::Not_rm64, destination),
+            6 => Instruction::with1(Code::Bswap_r64, destination),
+            7 => Instruction::with2(Code::Add_rm64_imm32, destination, immediate),
+            8 => Instruction::with2(Code::Xor_r64_rm64, destination, source),
+            9 => Instruction::with3(Code::Imul_r64_rm64_imm32, destination, source, immediate),
+            10 => Instruction::with2(Code::Rol_rm64_imm8, destination, rng.random_range(1..64u32)),
+            _ => Instruction::with2(Code::Cmp_rm64_imm32, source, immediate),
+        }
+        .map_err(e)
+    }
+
+    /// Pushes a register or reserves stack slots, touches them and restores
+    /// RSP, stepping over the red zone first. Flags are left alone, so `lea`
+    /// adjusts RSP.
+    fn stack_traffic(dead: &[Register], red_zone: u16, rng: &mut PassRng) -> Result<Vec<Instruction>, String> {
+        let e = |e: iced_x86::IcedError| e.to_string();
+        let adjust = |bytes: i64| {
+            Instruction::with2(Code::Lea_r64_m, Register::RSP, MemoryOperand::with_base_displ(Register::RSP, bytes))
+                .map_err(e)
+        };
+        let red_zone = i64::from(red_zone);
+        let source = Self::pick(&sources(), rng);
+        if rng.random_bool(0.5) {
+            let mut code = Vec::new();
+            if red_zone > 0 {
+                code.push(adjust(-red_zone)?);
+            }
+            code.push(Instruction::with1(Code::Push_r64, source).map_err(e)?);
+            if !dead.is_empty() && rng.random_bool(0.5) {
+                let destination = Self::pick(dead, rng);
+                code.push(
+                    Instruction::with2(Code::Mov_r64_rm64, destination, MemoryOperand::with_base(Register::RSP))
+                        .map_err(e)?,
+                );
+            }
+            code.push(Instruction::with1(Code::Pop_r64, source).map_err(e)?);
+            if red_zone > 0 {
+                code.push(adjust(red_zone)?);
+            }
+            return Ok(code);
+        }
+
+        let slots = rng.random_range(1..=4i64);
+        let slot = MemoryOperand::with_base_displ(Register::RSP, rng.random_range(0..slots) * 8);
+        let mut code = vec![
+            adjust(-slots * 8 - red_zone)?,
+            Instruction::with2(Code::Mov_rm64_r64, slot, source).map_err(e)?,
+        ];
+        if !dead.is_empty() && rng.random_bool(0.5) {
+            code.push(Instruction::with2(Code::Mov_r64_rm64, Self::pick(dead, rng), slot).map_err(e)?);
+        }
+        code.push(adjust(slots * 8 + red_zone)?);
+        Ok(code)
+    }
+
+    fn junk(&self, live: LiveSet, red_zone: u16, rng: &mut PassRng) -> Result<Vec<Instruction>, String> {
+        let dead: Vec<Register> = live.dead_registers().collect();
+        let flags_dead = !live.is_flag_live(STATUS_FLAGS);
+        let count = rng.random_range(1..=self.config.max_instructions);
+
+        let mut code = Vec::with_capacity(count + 3);
+        while code.len() < count {
+            match rng.random_range(0..3) {
+                0 if !dead.is_empty() => {
+                    code.push(Self::dead_write(Self::pick(&dead, rng), flags_dead, rng)?);
+                }
+                1 if count - code.len() >= 2 => code.extend(Self::stack_traffic(&dead, red_zone, rng)?),
+                _ => code.push(Self::nop(rng)?),
+            }
+        }
+        Ok(code)
+    }
+}
+
+impl Pass for JunkCodePass {
+    fn name(&self) -> &'static str {
+        "JunkCode"
+    }
+
+    fn apply(&self, function: &mut ObfuscatorFunction, rng: &mut PassRng) -> Result<(), String> {
+        if function.instructions.is_empty() {
+            return Ok(());
+        }
+
+        let liveness = function.analyze_liveness();
+        let prologs = Self::prolog_spans(function);
+        let context = function.instruction_context.clone();
+        let mut result = Vec::with_capacity(function.instructions.len());
+        for (index, instruction) in function.instructions.iter().enumerate() {
+            if Self::is_insertion_point(function, &prologs, index) && rng.random_bool(self.config.density) {
+                for junk in self.junk(liveness.live_in(index), function.red_zone, rng)? {
+                    result.push(context.create_encoded(junk)?);
+                }
+            }
+            result.push(instruction.clone());
+        }
+
+        function.instructions = result;
+        function.instruction_context = context;
+        Ok(())
+    }
+}
+
+impl Default for JunkCodePass {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,528 @@
+use super::{Pass, PassRng, take_over_id};
+use crate::config::MbaConfig;
+use crate::function::ObfuscatorFunction;
+use crate::instruction::{InstructionContext, InstructionWithId};
+use crate::liveness::{GPRS, LivenessAnalysis, STATUS_FLAGS, register_of_width};
+use common::warn;
+use iced_x86::{Code, Instruction, Mnemonic, OpKind, Register};
+use rand::Rng;
+use rand::seq::SliceRandom;
+
+/// Number of random input pairs every expression is checked against, on top
+/// of the edge cases.
+const VERIFY_SAMPLES: usize = 64;
+
+/// Bitwise functions of two variables are identified by their truth table:
+/// bit `2 * x + y` holds the result for the bit pair `(x, y)`.
+const TABLE_X: u8 = 0b1100;
+const TABLE_Y: u8 = 0b1010;
+const TABLE_ONES: u8 = 0b1111;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operation {
+    Add,
+    Sub,
+    Xor,
+    And,
+    Or,
+    Not,
+}
+
+impl Operation {
+    const fn from_mnemonic(mnemonic: Mnemonic) -> Option<Self> {
+        match mnemonic {
+            Mnemonic::Add => Some(Self::Add),
+            Mnemonic::Sub => Some(Self::Sub),
+            Mnemonic::Xor => Some(Self::Xor),
+            Mnemonic::And => Some(Self::And),
+            Mnemonic::Or => Some(Self::Or),
+            Mnemonic::Not => Some(Self::Not),
+            _ => None,
+        }
+    }
+
+    const fn evaluate(self, x: u64, y: u64) -> u64 {
+        match self {
+            Self::Add => x.wrapping_add(y),
+            Self::Sub => x.wrapping_sub(y),
+            Self::Xor => x ^ y,
+            Self::And => x & y,
+            Self::Or => x | y,
+            Self::Not => !x,
+        }
+    }
+
+    /// The operation as coefficients over the bitwise functions.
+    const fn coefficients(self) -> [i64; 16] {
+        let mut coefficients = [0; 16];
+        match self {
+            Self::Add => {
+                coefficients[TABLE_X as usize] = 1;
+                coefficients[TABLE_Y as usize] = 1;
+            }
+            Self::Sub => {
+                coefficients[TABLE_X as usize] = 1;
+                coefficients[TABLE_Y as usize] = -1;
+            }
+            Self::Xor => coefficients[0b0110] = 1,
+            Self::And => coefficients[0b1000] = 1,
+            Self::Or => coefficients[0b1110] = 1,
+            Self::Not => coefficients[0b0011] = 1,
+        }
+        coefficients
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operand {
+    Register(Register),
+    Immediate(i32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Slot {
+    Accumulator,
+    Temporary,
+}
+
+/// The straight-line code an expression is lowered to. It is evaluated by the
+/// verifier before being turned into instructions.
+#[derive(Clone, Copy, Debug)]
+enum Step {
+    Load(Slot, Operand),
+    Not(Slot),
+    Neg(Slot),
+    And(Slot, Operand),
+    Or(Slot, Operand),
+    Xor(Slot, Operand),
+    Add(Slot, Slot),
+    Sub(Slot, Slot),
+    Mul(Slot, i32),
+    Shl(Slot, u32),
+    AddImmediate(Slot, i32),
+}
+
+/// Rewrites ADD, SUB, XOR, AND, OR and NOT on registers into linear
+/// mixed boolean-arithmetic expressions.
+///
+/// The operation is written as a sum of bitwise functions of its operands
+/// with integer coefficients, and `depth` random identities that sum to
+/// zero are folded in. An identity of this
+/// kind holds on words iff it holds on single bits, which is what the
+/// coefficients are solved over; the emitted code is still evaluated on
+/// random inputs before it replaces the instruction.
+///
+/// The expression is computed in two free registers and needs the status
+/// flags to be dead after the instruction.
+pub struct MbaPass {
+    config: MbaConfig,
+}
+
+impl MbaPass {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_config(MbaConfig::default())
+    }
+
+    #[must_use]
+    pub const fn with_config(config: MbaConfig) -> Self {
+        Self { config }
+    }
+
+    /// Operation, destination, second operand and width in bits of a
+    /// supported instruction.
+    #[allow(clippy::cast_possible_truncation, reason = "general purpose registers are at most 8 bytes wide")]
+    fn decompose(instruction: &Instruction) -> Option<(Operation, Register, Option<Operand>, u32)> {
+        let operation = Operation::from_mnemonic(instruction.mnemonic())?;
+        if instruction.op0_kind() != OpKind::Register {
+            return None;
+        }
+        let destination = instruction.op0_register();
+        let width = destination.size() as u32 * 8;
+        if is_high_byte(destination) || destination.full_register() == Register::RSP || !destination.is_gpr() {
+            return None;
+        }
+
+        if operation == Operation::Not {
+            return Some((operation, destination, None, width));
+        }
+        let source = match instruction.op1_kind() {
+            OpKind::Register => {
+                let register = instruction.op1_register();
+                if is_high_byte(register) || !register.is_gpr() {
+                    return None;
+                }
+                Operand::Register(register.full_register())
+            }
+            OpKind::Immediate8 | OpKind::Immediate16 | OpKind::Immediate32 | OpKind::Immediate8to16
+            | OpKind::Immediate8to32 | OpKind::Immediate8to64 | OpKind::Immediate32to64 => {
+                Operand::Immediate(i32::try_from(sign_extend(instruction.immediate(1), width)).ok()?)
+            }
+            _ => return None,
+        };
+        Some((operation, destination, Some(source), width))
+    }
+
+    /// Coefficients of `operation` plus `depth` random zero identities.
+    fn expression(&self, operation: Operation, rng: &mut PassRng) -> [i64; 16] {
+        let mut coefficients = operation.coefficients();
+        for _ in 0..self.config.depth {
+            let basis = random_basis(rng);
+            let table = loop {
+                let table = rng.random_range(1..16u8);
+                if !basis.contains(&table) {
+                    break table;
+                }
+            };
+            let scale = loop {
+                let scale = rng.random_range(-3..=3i64);
+                if scale != 0 {
+                    break scale;
+                }
+            };
+            // table - sum(a_i * basis_i) == 0
+            coefficients[table as usize] += scale;
+            for (basis_table, a) in basis.iter().zip(solve(basis, table)) {
+                coefficients[*basis_table as usize] -= scale * a;
+            }
+        }
+        coefficients
+    }
+
+    fn lower(coefficients: &[i64; 16], x: Operand, y: Operand, rng: &mut PassRng) -> Option<Vec<Step>> {
+        let mut terms: Vec<(u8, i64)> = (1..16u8)
+            .filter(|&table| table != TABLE_ONES && coefficients[table as usize] != 0)
+            .map(|table| (table, coefficients[table as usize]))
+            .collect();
+        terms.shuffle(rng);
+
+        let mut steps = Vec::new();
+        for (position, &(table, coefficient)) in terms.iter().enumerate() {
+            let slot = if position == 0 { Slot::Accumulator } else { Slot::Temporary };
+            steps.extend(table_steps(table, slot, x, y)?);
+            let magnitude = coefficient.unsigned_abs();
+            if magnitude.is_power_of_two() {
+                if magnitude > 1 {
+                    steps.push(Step::Shl(slot, magnitude.trailing_zeros()));
+                }
+                if coefficient < 0 && position == 0 {
+                    steps.push(Step::Neg(slot));
+                }
+            } else {
+                // The sign of later terms is applied by subtracting them.
+                let factor = if position == 0 { coefficient } else { magnitude.cast_signed() };
+                steps.push(Step::Mul(slot, i32::try_from(factor).ok()?));
+            }
+            if position > 0 {
+                steps.push(if coefficient < 0 {
+                    Step::Sub(Slot::Accumulator, Slot::Temporary)
+                } else {
+                    Step::Add(Slot::Accumulator, Slot::Temporary)
+                });
+            }
+        }
+        if steps.is_empty() {
+            return None;
+        }
+
+        // The all-ones word is -1.
+        let constant = coefficients[TABLE_ONES as usize];
+        if constant != 0 {
+            steps.push(Step::AddImmediate(Slot::Accumulator, i32::try_from(-constant).ok()?));
+        }
+        Some(steps)
+    }
+
+    fn verify(steps: &[Step], operation: Operation, x: Operand, y: Operand, width: u32, rng: &mut PassRng) -> bool {
+        let mask = width_mask(width);
+        let edges = [0, 1, u64::MAX, 1 << (width - 1), mask >> 1, mask];
+        let edge_samples = edges.iter().flat_map(|&a| edges.iter().map(move |&b| (a, b)));
+        let random_samples = (0..VERIFY_SAMPLES).map(|_| (rng.random::<u64>(), rng.random::<u64>()));
+
+        edge_samples.chain(random_samples).all(|(x_value, y_value)| {
+            // The same register may be both operands.
+            let y_value = if x == y { x_value } else { y_value };
+            let value = |operand: Operand| match operand {
+                Operand::Immediate(immediate) => i64::from(immediate).cast_unsigned(),
+                Operand::Register(_) if operand == x => x_value,
+                Operand::Register(_) => y_value,
+            };
+            let expected = operation.evaluate(value(x), value(y));
+            evaluate(steps, value) & mask == expected & mask
+        })
+    }
+
+    fn emit(
+        steps: &[Step],
+        accumulator: Register,
+        temporary: Register,
+        destination: Register,
+        context: &InstructionContext,
+    ) -> Result<Vec<InstructionWithId>, String> {
+        let e = |e: iced_x86::IcedError| e.to_string();
+        let register = |slot: Slot| match slot {
+            Slot::Accumulator => accumulator,
+            Slot::Temporary => temporary,
+        };
+        let mut code = Vec::with_capacity(steps.len() + 1);
+        for step in steps {
+            let instruction = match *step {
+                Step::Load(slot, Operand::Register(source)) => {
+                    Instruction::with2(Code::Mov_r64_rm64, register(slot), source)
+                }
+                Step::Load(slot, Operand::Immediate(value)) => {
+                    Instruction::with2(Code::Mov_rm64_imm32, register(slot), value)
+                }
+                Step::Not(slot) => Instruction::with1(Code::Not_rm64, register(slot)),
+                Step::Neg(slot) => Instruction::with1(Code::Neg_rm64, register(slot)),
+                Step::And(slot, operand) => bitwise(Code::And_r64_rm64, Code::And_rm64_imm32, register(slot), operand),
+                Step::Or(slot, operand) => bitwise(Code::Or_r64_rm64, Code::Or_rm64_imm32, register(slot), operand),
+                Step::Xor(slot, operand) => bitwise(Code::Xor_r64_rm64, Code::Xor_rm64_imm32, register(slot), operand),
+                Step::Add(slot, other) => Instruction::with2(Code::Add_r64_rm64, register(slot), register(other)),
+                Step::Sub(slot, other) => Instruction::with2(Code::Sub_r64_rm64, register(slot), register(other)),
+                Step::Mul(slot, factor) => {
+                    Instruction::with3(Code::Imul_r64_rm64_imm32, register(slot), register(slot), factor)
+                }
+                Step::Shl(slot, count) => Instruction::with2(Code::Shl_rm64_imm8, register(slot), count),
+                Step::AddImmediate(slot, value) => Instruction::with2(Code::Add_rm64_imm32, register(slot), value),
+            }
+            .map_err(e)?;
+            code.push(context.create_encoded(instruction)?);
+        }
+
+        let (mov, result) = match destination.size() {
+            8 => (Code::Mov_r64_rm64, accumulator),
+            4 => (Code::Mov_r32_rm32, accumulator.full_register32()),
+            2 => (Code::Mov_r16_rm16, register_of_width(accumulator, 16)),
+            _ => (Code::Mov_r8_rm8, register_of_width(accumulator, 8)),
+        };
+        code.push(context.create_encoded(
+            Instruction::with2(mov, destination, result).map_err(e)?,
+        )?);
+        Ok(code)
+    }
+
+    fn rewrite(
+        &self,
+        instruction: &InstructionWithId,
+        liveness: &LivenessAnalysis,
+        index: usize,
+        context: &InstructionContext,
+        rng: &mut PassRng,
+    ) -> Option<Vec<InstructionWithId>> {
+        let (operation, destination, source, width) = Self::decompose(&instruction.instruction)?;
+        if !liveness.are_flags_dead_after(index, STATUS_FLAGS) {
+            return None;
+        }
+        let mut free = liveness.free_registers(index);
+        if free.len() < 2 {
+            return None;
+        }
+        free.shuffle(rng);
+        let (accumulator, temporary) = (free[0], free[1]);
+
+        let x = Operand::Register(destination.full_register());
+        // NOT has a single operand; the second variable is any other register,
+        // whose value cancels out.
+        let y = source.unwrap_or_else(|| {
+            let others: Vec<Register> = GPRS
+                .iter()
+                .copied()
+                .filter(|&r| r != Register::RSP && r != accumulator && r != temporary)
+                .collect();
+            Operand::Register(others[rng.random_range(0..others.len())])
+        });
+
+        let coefficients = self.expression(operation, rng);
+        let steps = Self::lower(&coefficients, x, y, rng)?;
+        if !Self::verify(&steps, operation, x, y, width, rng) {
+            warn!("Discarding MBA expression that failed verification for {}", instruction.instruction);
+            return None;
+        }
+        Self::emit(&steps, accumulator, temporary, destination, context).ok()
+    }
+}
+
+impl Pass for MbaPass {
+    fn name(&self) -> &'static str {
+        "MBA"
+    }
+
+    fn apply(&self, function: &mut ObfuscatorFunction, rng: &mut PassRng) -> Result<(), String> {
+        let liveness = function.analyze_liveness();
+        let context = &function.instruction_context;
+        let mut result = Vec::with_capacity(function.instructions.len() * 2);
+
+        for (index, instruction) in function.instructions.iter().enumerate() {
+            if function.unwind.is_frame_instruction(instruction.get_id())
+                || function.references_own_code(instruction.get_id())
+                || function.has_absolute_reference(instruction.get_id())
+                || Self::decompose(&instruction.instruction).is_none()
+                || !rng.random_bool(self.config.probability)
+            {
+                result.push(instruction.clone());
+                continue;
+            }
+
+            match self.rewrite(instruction, &liveness, index, context, rng) {
+                Some(mut rewritten) => {
+                    take_over_id(&mut rewritten, instruction);
+                    result.append(&mut rewritten);
+                }
+                None => result.push(instruction.clone()),
+            }
+        }
+
+        function.instructions = result;
+        Ok(())
+    }
+}
+
+impl Default for MbaPass {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const fn is_high_byte(register: Register) -> bool {
+    matches!(register, Register::AH | Register::CH | Register::DH | Register::BH)
+}
+
+const fn width_mask(width: u32) -> u64 {
+    if width == 64 { u64::MAX } else { (1 << width) - 1 }
+}
+
+const fn sign_extend(value: u64, width: u32) -> i64 {
+    let shift = 64 - width;
+    (value << shift).cast_signed() >> shift
+}
+
+fn bitwise(register_code: Code, immediate_code: Code, slot: Register, operand: Operand) -> Result<Instruction, iced_x86::IcedError> {
+    match operand {
+        Operand::Register(source) => Instruction::with2(register_code, slot, source),
+        Operand::Immediate(value) => Instruction::with2(immediate_code, slot, value),
+    }
+}
+
+/// Computes the bitwise function `table` of `x` and `y` into `slot`.
+fn table_steps(table: u8, slot: Slot, x: Operand, y: Operand) -> Option<Vec<Step>> {
+    let not_y = match y {
+        Operand::Immediate(value) => Some(Operand::Immediate(!value)),
+        Operand::Register(_) => None,
+    };
+    let steps = match (table, not_y) {
+        (0b1100, _) => vec![Step::Load(slot, x)],
+        (0b1010, _) => vec![Step::Load(slot, y)],
+        (0b0011, _) => vec![Step::Load(slot, x), Step::Not(slot)],
+        (0b0101, _) => vec![Step::Load(slot, y), Step::Not(slot)],
+        (0b1000, _) => vec![Step::Load(slot, x), Step::And(slot, y)],
+        (0b1110, _) => vec![Step::Load(slot, x), Step::Or(slot, y)],
+        (0b0110, _) => vec![Step::Load(slot, x), Step::Xor(slot, y)],
+        (0b0111, _) => vec![Step::Load(slot, x), Step::And(slot, y), Step::Not(slot)],
+        (0b0001, _) => vec![Step::Load(slot, x), Step::Or(slot, y), Step::Not(slot)],
+        (0b1001, _) => vec![Step::Load(slot, x), Step::Xor(slot, y), Step::Not(slot)],
+        (0b0100, Some(not_y)) => vec![Step::Load(slot, x), Step::And(slot, not_y)],
+        (0b0100, None) => vec![Step::Load(slot, y), Step::Not(slot), Step::And(slot, x)],
+        (0b0010, _) => vec![Step::Load(slot, x), Step::Not(slot), Step::And(slot, y)],
+        (0b1101, Some(not_y)) => vec![Step::Load(slot, x), Step::Or(slot, not_y)],
+        (0b1101, None) => vec![Step::Load(slot, y), Step::Not(slot), Step::Or(slot, x)],
+        (0b1011, _) => vec![Step::Load(slot, x), Step::Not(slot), Step::Or(slot, y)],
+        _ => return None,
+    };
+    Some(steps)
+}
+
+fn evaluate(steps: &[Step], value: impl Fn(Operand) -> u64) -> u64 {
+    let mut slots = [0u64; 2];
+    for step in steps {
+        match *step {
+            Step::Load(slot, source) => slots[slot as usize] = value(source),
+            Step::Not(slot) => slots[slot as usize] = !slots[slot as usize],
+            Step::Neg(slot) => slots[slot as usize] = slots[slot as usize].wrapping_neg(),
+            Step::And(slot, source) => slots[slot as usize] &= value(source),
+            Step::Or(slot, source) => slots[slot as usize] |= value(source),
+            Step::Xor(slot, source) => slots[slot as usize] ^= value(source),
+            Step::Add(slot, other) => {
+                slots[slot as usize] = slots[slot as usize].wrapping_add(slots[other as usize]);
+            }
+            Step::Sub(slot, other) => {
+                slots[slot as usize] = slots[slot as usize].wrapping_sub(slots[other as usize]);
+            }
+            Step::Mul(slot, factor) => {
+                slots[slot as usize] = slots[slot as usize].wrapping_mul(i64::from(factor).cast_unsigned());
+            }
+            Step::Shl(slot, count) => slots[slot as usize] <<= count,
+            Step::AddImmediate(slot, immediate) => {
+                slots[slot as usize] = slots[slot as usize].wrapping_add(i64::from(immediate).cast_unsigned());
+            }
+        }
+    }
+    slots[Slot::Accumulator as usize]
+}
+
+/// Picks four truth tables forming a unimodular matrix, so that every other
+/// table is an integer combination of them.
+fn random_basis(rng: &mut PassRng) -> [u8; 4] {
+    loop {
+        let mut tables: Vec<u8> = (1..16).collect();
+        tables.shuffle(rng);
+        let basis = [tables[0], tables[1], tables[2], tables[3]];
+        if determinant(basis).abs() == 1 {
+            return basis;
+        }
+    }
+}
+
+fn matrix(columns: [u8; 4]) -> [[i64; 4]; 4] {
+    let mut matrix = [[0; 4]; 4];
+    for (column, table) in columns.iter().enumerate() {
+        for (row, cells) in matrix.iter_mut().enumerate() {
+            cells[column] = i64::from((table >> row) & 1);
+        }
+    }
+    matrix
+}
+
+fn determinant(columns: [u8; 4]) -> i64 {
+    determinant_of(&matrix(columns))
+}
+
+fn determinant_of(matrix: &[[i64; 4]; 4]) -> i64 {
+    fn minor(matrix: &[Vec<i64>]) -> i64 {
+        if matrix.len() == 1 {
+            return matrix[0][0];
+        }
+        (0..matrix.len())
+            .map(|column| {
+                let rest: Vec<Vec<i64>> = matrix[1..]
+                    .iter()
+                    .map(|row| {
+                        row.iter()
+                            .enumerate()
+                            .filter(|&(c, _)| c != column)
+                            .map(|(_, &value)| value)
+                            .collect()
+                    })
+                    .collect();
+                let sign = if column % 2 == 0 { 1 } else { -1 };
+                sign * matrix[0][column] * minor(&rest)
+            })
+            .sum()
+    }
+    minor(&matrix.iter().map(|row| row.to_vec()).collect::<Vec<_>>())
+}
+
+/// Coefficients expressing `table` in the unimodular `basis` (Cramer's rule).
+fn solve(basis: [u8; 4], table: u8) -> [i64; 4] {
+    let base = matrix(basis);
+    let det = determinant_of(&base);
+    let mut solution = [0; 4];
+    for (column, value) in solution.iter_mut().enumerate() {
+        let mut replaced = base;
+        for (row, cells) in replaced.iter_mut().enumerate() {
+            cells[column] = i64::from((table >> row) & 1);
+        }
+        *value = determinant_of(&replaced) / det;
+    }
+    solution
+}
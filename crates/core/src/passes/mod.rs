@@ -1,10 +1,70 @@
-use crate::function::ObfuscatorFunction;
+use crate::config::{ObfuscatorConfig, PassConfig};
+use crate::function::{ObfuscatorFunction, StateManaged};
+use crate::instruction::InstructionWithId;
+use crate::verify::Verifier;
 use common::{debug, error};
+use iced_x86::FlowControl;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+pub mod constants;
+pub mod flattening;
+pub mod junk;
+pub mod mba;
 pub mod mutation;
+pub mod opaque;
+pub mod reordering;
+pub mod virtualization;
+
+/// Random source handed to passes. `ChaCha8` is used explicitly because its
+/// output, unlike `StdRng`, is stable across `rand` releases.
+pub type PassRng = ChaCha8Rng;
+
+/// Derives the seed of one pass application from the run seed, so the output
+/// only depends on the seed and not on the order functions are processed in.
+fn derive_seed(seed: u64, function_rva: u32, iteration: usize, pass_index: usize) -> u64 {
+    [u64::from(function_rva), iteration as u64, pass_index as u64]
+        .into_iter()
+        .fold(seed, |state, value| splitmix64(state ^ splitmix64(value)))
+}
+
+const fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Whether execution can run past the last instruction, e.g. after a call
+/// that does not return. Passes appending code then have to guard it.
+pub(crate) fn falls_off_end(instructions: &[InstructionWithId]) -> bool {
+    instructions.last().is_some_and(|last| {
+        !matches!(
+            last.instruction.flow_control(),
+            FlowControl::Return
+                | FlowControl::UnconditionalBranch
+                | FlowControl::IndirectBranch
+                | FlowControl::Interrupt
+                | FlowControl::Exception
+        )
+    })
+}
+
+/// Hands the id of `original` to the first instruction of `replacement`.
+/// Branches into the original instruction must land on the first
+/// instruction of its replacement.
+pub(crate) const fn take_over_id(replacement: &mut [InstructionWithId], original: &InstructionWithId) {
+    if let Some(first) = replacement.first_mut() {
+        first.set_id(original.get_id());
+    }
+}
 
 pub trait Pass {
     fn name(&self) -> &'static str;
-    fn apply(&self, function: &mut ObfuscatorFunction) -> Result<(), String>;
+    /// # Errors
+    ///
+    /// Fails when the pass leaves the function in a state that cannot be
+    /// encoded.
+    fn apply(&self, function: &mut ObfuscatorFunction, rng: &mut PassRng) -> Result<(), String>;
     fn enabled_by_default(&self) -> bool {
         true
     }
@@ -15,21 +75,70 @@ pub struct PassManager {
 }
 
 impl PassManager {
+    #[must_use]
     pub fn new() -> Self {
         Self { passes: Vec::new() }
     }
 
+    #[must_use]
+    pub fn from_config(config: &ObfuscatorConfig) -> Self {
+        Self::from_passes(&config.passes)
+    }
+
+    #[must_use]
+    pub fn from_passes(passes: &[PassConfig]) -> Self {
+        let mut manager = Self::new();
+        for pass in passes {
+            match pass {
+                PassConfig::Mutation(config) => {
+                    manager.add_pass(Box::new(mutation::MutationPass::with_config(config.clone())));
+                }
+                PassConfig::ControlFlowFlattening(config) => manager.add_pass(Box::new(
+                    flattening::ControlFlowFlatteningPass::with_config(config.clone()),
+                )),
+                PassConfig::OpaquePredicate(config) => manager.add_pass(Box::new(
+                    opaque::OpaquePredicatePass::with_config(config.clone()),
+                )),
+                PassConfig::Mba(config) => {
+                    manager.add_pass(Box::new(mba::MbaPass::with_config(config.clone())));
+                }
+                PassConfig::ConstantEncryption(config) => manager.add_pass(Box::new(
+                    constants::ConstantEncryptionPass::with_config(config.clone()),
+                )),
+                PassConfig::Virtualization(config) => manager.add_pass(Box::new(
+                    virtualization::VirtualizationPass::with_config(config.clone()),
+                )),
+                PassConfig::BlockReordering(config) => manager.add_pass(Box::new(
+                    reordering::BlockReorderingPass::with_config(config.clone()),
+                )),
+                PassConfig::JunkCode(config) => {
+                    manager.add_pass(Box::new(junk::JunkCodePass::with_config(config.clone())));
+                }
+            }
+        }
+        manager
+    }
+
     pub fn add_pass(&mut self, pass: Box<dyn Pass>) {
         self.passes.push(pass);
     }
 
-    pub fn run_passes(&self, function: &mut ObfuscatorFunction, count: usize) {
+    /// With a verifier every pass is checked to keep the function's
+    /// behavior.
+    pub fn run_passes(
+        &self,
+        function: &mut ObfuscatorFunction,
+        count: usize,
+        seed: u64,
+        verifier: Option<&Verifier>,
+    ) {
         debug!(
             "Running {} passes {} times on function {}",
             self.passes.len(),
             count,
             function.name
         );
+        let mut reference = verifier.and_then(|verifier| verifier.reference(function));
 
         for iteration in 0..count {
             debug!(
@@ -38,30 +147,40 @@ impl PassManager {
                 function.name
             );
 
-            for pass in &self.passes {
+            for (pass_index, pass) in self.passes.iter().enumerate() {
                 debug!(
                     "Applying pass '{}' to function {}",
                     pass.name(),
                     function.name
                 );
                 let pre_instruction_count = function.instructions.len();
+                let mut rng = PassRng::seed_from_u64(derive_seed(
+                    seed,
+                    function.get_original_rva(),
+                    iteration,
+                    pass_index,
+                ));
 
-                match pass.apply(function) {
-                    Ok(_) => {
+                match pass.apply(function, &mut rng) {
+                    Ok(()) => {
+                        function.build_cfg();
+                        if let (Some(verifier), Some(reference)) = (verifier, reference.as_mut()) {
+                            verifier.check(function, reference, pass.name(), iteration);
+                        }
                         let post_instruction_count = function.instructions.len();
-                        if pre_instruction_count != post_instruction_count {
+                        if pre_instruction_count == post_instruction_count {
                             debug!(
-                                "Pass '{}' modified function {}: {} -> {} instructions",
+                                "Pass '{}' completed on function {} (no changes)",
                                 pass.name(),
-                                function.name,
-                                pre_instruction_count,
-                                post_instruction_count
+                                function.name
                             );
                         } else {
                             debug!(
-                                "Pass '{}' completed on function {} (no changes)",
+                                "Pass '{}' modified function {}: {} -> {} instructions",
                                 pass.name(),
-                                function.name
+                                function.name,
+                                pre_instruction_count,
+                                post_instruction_count
                             );
                         }
                     }
@@ -77,6 +196,9 @@ impl PassManager {
             }
         }
 
+        if let (Some(verifier), Some(reference)) = (verifier, reference.as_ref()) {
+            verifier.finish(reference);
+        }
         debug!(
             "Completed all pass iterations for function {}",
             function.name
@@ -86,8 +208,6 @@ impl PassManager {
 
 impl Default for PassManager {
     fn default() -> Self {
-        let mut manager = Self::new();
-        manager.add_pass(Box::new(mutation::MutationPass::new()));
-        manager
+        Self::from_config(&ObfuscatorConfig::default())
     }
 }
@@ -1,76 +1,162 @@
-use super::Pass;
+use super::{Pass, PassRng, take_over_id};
+use crate::config::MutationConfig;
 use crate::function::ObfuscatorFunction;
-use crate::instruction::InstructionWithId;
-use iced_x86::{Code, Instruction, OpKind};
+use crate::imports::{ImportCall, ImportThunk};
+use crate::instruction::{InstructionContext, InstructionWithId};
+use crate::liveness::{LivenessAnalysis, STATUS_FLAGS};
+use iced_x86::{Code, Instruction, MemoryOperand, OpKind, Register, RflagsBits};
 use rand::Rng;
+use rand::seq::SliceRandom;
 
-pub struct MutationPass;
+pub struct MutationPass {
+    config: MutationConfig,
+}
 
 impl MutationPass {
+    #[must_use]
     pub fn new() -> Self {
-        Self
+        Self::with_config(MutationConfig::default())
+    }
+
+    #[must_use]
+    pub const fn with_config(config: MutationConfig) -> Self {
+        Self { config }
     }
 
-    fn create_instruction(&self, context: &crate::instruction::InstructionContext, instruction: Instruction) -> Option<InstructionWithId> {
-        let instruction = InstructionWithId {
-            id: context.next_id(),
-            instruction,
+    fn should_mutate(&self, code: Code, rng: &mut PassRng) -> bool {
+        let probability = &self.config.probability;
+        let chance = match code {
+            Code::Lea_r64_m => probability.lea,
+            Code::Call_rm64 => probability.call,
+            Code::Add_r64_rm64 | Code::Add_rm64_r64 => probability.add,
+            Code::Or_r64_rm64 | Code::Or_rm64_r64 => probability.or,
+            Code::Inc_rm64 => probability.inc,
+            Code::Dec_rm64 => probability.dec,
+            Code::Push_r64 => probability.push,
+            _ => return false,
         };
+        chance >= 1.0 || rng.random_bool(chance)
+    }
 
-        instruction.re_encode(0).ok().map(|instruction| InstructionWithId {
-            id: context.next_id(),
-            instruction,
-        })
+    fn pick_scratch_register(liveness: &LivenessAnalysis, index: usize, rng: &mut PassRng) -> Option<Register> {
+        let free = liveness.free_registers(index);
+        if free.is_empty() {
+            return None;
+        }
+        Some(free[rng.random_range(0..free.len())])
     }
 
-    fn mutate_lea(&self, instruction: &InstructionWithId, context: &crate::instruction::InstructionContext) -> Vec<InstructionWithId> {
+    fn mutate_lea(instruction: &InstructionWithId, context: &InstructionContext, liveness: &LivenessAnalysis, index: usize, red_zone: u16, rng: &mut PassRng) -> Vec<InstructionWithId> {
         let mut result = Vec::new();
+        let dest_reg = instruction.instruction.op0_register();
+
+        // LEA leaves the flags alone but the compensating SUB does not, so
+        // they are saved around it only when something still reads them.
+        let preserve_flags = !liveness.are_flags_dead_after(index, STATUS_FLAGS);
+        // PUSHFQ must not land in the red zone, which LEA steps over
+        // without touching the flags.
+        let step_over = |bytes: i64| {
+            Instruction::with2(Code::Lea_r64_m, Register::RSP, MemoryOperand::with_base_displ(Register::RSP, bytes))
+                .ok()
+                .and_then(|lea| context.create_encoded(lea).ok())
+        };
+        let red_zone = i64::from(red_zone);
 
-        if instruction.instruction.memory_displ_size() != 0 {
-            let dest_reg = instruction.instruction.op0_register();
-            let random_value = rand::rng().random_range(0..=i16::MAX) as i32;
+        // Splitting an RSP adjustment would leave RSP above live stack data
+        // between the two halves, where any later stack traffic overwrites it.
+        if instruction.instruction.memory_displ_size() == 0 || dest_reg == Register::RSP {
+            result.push(instruction.clone());
+            return result;
+        }
+
+        let random_value = i32::from(rng.random_range(0..=i16::MAX));
 
-            let displacement = instruction.instruction.memory_displacement64();
-            let mut new_instruction = instruction.clone();
-            new_instruction.instruction.set_memory_displacement64(displacement + random_value as u64);
-            result.push(new_instruction);
+        let displacement = instruction.instruction.memory_displacement64();
+        let mut new_instruction = instruction.clone();
+        new_instruction.instruction.set_memory_displacement64(displacement.wrapping_add(i64::from(random_value).cast_unsigned()));
+        result.push(new_instruction);
+
+        if preserve_flags && red_zone > 0 {
+            result.extend(step_over(-red_zone));
+        }
 
-            if let Some(pushf_instr) = self.create_instruction(
-                context,
+        if preserve_flags
+            && let Ok(pushf_instr) = context.create_encoded(
                 Instruction::with(Code::Pushfq),
-            ) {
-                result.push(pushf_instr);
-            }
+            )
+        {
+            result.push(pushf_instr);
+        }
 
-            if let Some(sub_instr) = self.create_instruction(
-                context,
-                Instruction::with2(Code::Sub_rm64_imm32, dest_reg, random_value).unwrap(),
-            ) {
-                result.push(sub_instr);
-            }
+        if let Ok(sub_instr) = context.create_encoded(
+            Instruction::with2(Code::Sub_rm64_imm32, dest_reg, random_value).unwrap(),
+        ) {
+            result.push(sub_instr);
+        }
 
-            if let Some(popfq_instr) = self.create_instruction(
-                context,
+        if preserve_flags
+            && let Ok(popfq_instr) = context.create_encoded(
                 Instruction::with(Code::Popfq),
-            ) {
-                result.push(popfq_instr);
-            }
-        } else {
-            result.push(instruction.clone());
+            )
+        {
+            result.push(popfq_instr);
+        }
+
+        if preserve_flags && red_zone > 0 {
+            result.extend(step_over(red_zone));
         }
 
         result
     }
 
-    fn mutate_call(&self, instruction: &InstructionWithId, _context: &crate::instruction::InstructionContext) -> Vec<InstructionWithId> {
-        let mut result = Vec::new();
+    /// Replaces `call [rip+slot]` through the IAT with a call through an
+    /// address computed from an encoded entry of the import table:
+    ///
+    /// ```text
+    /// lea  a, [rip+entry]
+    /// mov  b, [a]
+    /// xor  b, key
+    /// call [a+b]
+    /// ```
+    ///
+    /// The `lea` points at the slot until the compiler has built the table.
+    /// `a` and `b` come from `scratch`, the registers the calling convention
+    /// of the image passes nothing in and lets the callee clobber. Without
+    /// two of them the call stays as it is.
+    fn mutate_call(instruction: &InstructionWithId, context: &InstructionContext, import: Option<&ImportCall>, scratch: &[Register], thunks: &mut Vec<ImportThunk>, rng: &mut PassRng) -> Vec<InstructionWithId> {
+        let Some(import) = import.filter(|_| scratch.len() >= 2) else {
+            return vec![instruction.clone()];
+        };
 
-        result.push(instruction.clone());
+        let mut scratch = scratch.to_vec();
+        scratch.shuffle(rng);
+        let (address, offset) = (scratch[0], scratch[1]);
+        let key = rng.random::<i32>();
+
+        let code = [
+            Instruction::with2(Code::Lea_r64_m, address, MemoryOperand::with_base_displ(Register::RIP, i64::from(import.slot_rva))),
+            Instruction::with2(Code::Mov_r64_rm64, offset, MemoryOperand::with_base(address)),
+            Instruction::with2(Code::Xor_rm64_imm32, offset, key),
+            Instruction::with1(Code::Call_rm64, MemoryOperand::with_base_index(address, offset)),
+        ];
+        let mut result = Vec::with_capacity(code.len());
+        for replacement in code {
+            match replacement.ok().and_then(|replacement| context.create_encoded(replacement).ok()) {
+                Some(replacement) => result.push(replacement),
+                None => return vec![instruction.clone()],
+            }
+        }
 
+        // The `lea` takes over the ID of the call and with it the reference.
+        thunks.push(ImportThunk {
+            instruction_id: instruction.get_id(),
+            slot_rva: import.slot_rva,
+            key,
+        });
         result
     }
 
-    fn mutate_add(&self, instruction: &InstructionWithId, context: &crate::instruction::InstructionContext) -> Vec<InstructionWithId> {
+    fn mutate_add(instruction: &InstructionWithId, context: &InstructionContext) -> Vec<InstructionWithId> {
         let mut result = Vec::new();
         let op_kinds: Vec<OpKind> = instruction.instruction.op_kinds().collect();
 
@@ -79,34 +165,19 @@ impl MutationPass {
                 let dest_reg = instruction.instruction.op0_register();
                 let src_reg = instruction.instruction.op1_register();
 
-                if let Some(pushf_inst) = self.create_instruction(
-                    context,
-                    Instruction::with(Code::Pushfq),
-                ) {
-                    result.push(pushf_inst);
-                }
-
-                if let Some(clc_inst) = self.create_instruction(
-                    context,
+                // ADC with a cleared carry produces exactly the flags ADD
+                // would, so no flag state needs to be saved around it.
+                if let Ok(clc_inst) = context.create_encoded(
                     Instruction::with(Code::Clc),
                 ) {
                     result.push(clc_inst);
                 }
 
-                if let Some(mut adc_inst) = self.create_instruction(
-                    context,
+                if let Ok(adc_inst) = context.create_encoded(
                     Instruction::with2(Code::Adc_r64_rm64, dest_reg, src_reg).unwrap(),
                 ) {
-                    adc_inst.set_id(instruction.get_id());
                     result.push(adc_inst);
                 }
-
-                if let Some(popf_inst) = self.create_instruction(
-                    context,
-                    Instruction::with(Code::Popfq),
-                ) {
-                    result.push(popf_inst);
-                }
             }
             _ => {
                 result.push(instruction.clone());
@@ -115,52 +186,40 @@ impl MutationPass {
 
         result
     }
-    
-    fn mutate_or(&self, instruction: &InstructionWithId, context: &crate::instruction::InstructionContext) -> Vec<InstructionWithId> {
+
+    fn mutate_or(instruction: &InstructionWithId, context: &InstructionContext, liveness: &LivenessAnalysis, index: usize, rng: &mut PassRng) -> Vec<InstructionWithId> {
         let mut result = Vec::new();
         let op_kinds: Vec<OpKind> = instruction.instruction.op_kinds().collect();
 
-        match (op_kinds[0], op_kinds[1]) {
-            (OpKind::Register, OpKind::Register) => {
+        match (op_kinds[0], op_kinds[1], Self::pick_scratch_register(liveness, index, rng)) {
+            (OpKind::Register, OpKind::Register, Some(scratch_reg)) => {
                 let dest_reg = instruction.instruction.op0_register();
                 let src_reg = instruction.instruction.op1_register();
 
-                if let Some(pushf_inst) = self.create_instruction(
-                    context,
-                    Instruction::with(Code::Pushfq),
+                // a | b == (a ^ b) + (a & b). The two terms share no set bits,
+                // so the final ADD never carries and leaves the same flags as OR.
+                if let Ok(mov_inst) = context.create_encoded(
+                    Instruction::with2(Code::Mov_r64_rm64, scratch_reg, dest_reg).unwrap(),
                 ) {
-                    result.push(pushf_inst);
-                }
-
-                if let Some(mut andn_inst) = self.create_instruction(
-                    context,
-                    Instruction::with3(Code::VEX_Andn_r64_r64_rm64, dest_reg, dest_reg, src_reg).unwrap(),
-                ) {
-                    andn_inst.set_id(instruction.get_id());
-                    result.push(andn_inst);
+                    result.push(mov_inst);
                 }
 
-                if let Some(mut blsi_inst) = self.create_instruction(
-                    context,
-                    Instruction::with2(Code::VEX_Blsi_r64_rm64, dest_reg, src_reg).unwrap(),
+                if let Ok(and_inst) = context.create_encoded(
+                    Instruction::with2(Code::And_r64_rm64, scratch_reg, src_reg).unwrap(),
                 ) {
-                    blsi_inst.set_id(instruction.get_id());
-                    result.push(blsi_inst);
+                    result.push(and_inst);
                 }
 
-                if let Some(mut tzcnt_inst) = self.create_instruction(
-                    context,
-                    Instruction::with2(Code::Tzcnt_r64_rm64, dest_reg, src_reg).unwrap(),
+                if let Ok(xor_inst) = context.create_encoded(
+                    Instruction::with2(Code::Xor_r64_rm64, dest_reg, src_reg).unwrap(),
                 ) {
-                    tzcnt_inst.set_id(instruction.get_id());
-                    result.push(tzcnt_inst);
+                    result.push(xor_inst);
                 }
 
-                if let Some(popf_inst) = self.create_instruction(
-                    context,
-                    Instruction::with(Code::Popfq),
+                if let Ok(add_inst) = context.create_encoded(
+                    Instruction::with2(Code::Add_r64_rm64, dest_reg, scratch_reg).unwrap(),
                 ) {
-                    result.push(popf_inst);
+                    result.push(add_inst);
                 }
             }
             _ => {
@@ -171,42 +230,26 @@ impl MutationPass {
         result
     }
 
-    fn mutate_inc(&self, instruction: &InstructionWithId, context: &crate::instruction::InstructionContext) -> Vec<InstructionWithId> {
+    fn mutate_inc(instruction: &InstructionWithId, context: &InstructionContext, liveness: &LivenessAnalysis, index: usize) -> Vec<InstructionWithId> {
         let mut result = Vec::new();
         let op_kinds: Vec<OpKind> = instruction.instruction.op_kinds().collect();
 
-        match op_kinds[0] {
-            OpKind::Register => {
+        // INC keeps CF while ADC overwrites it; every other flag matches.
+        match (op_kinds[0], liveness.are_flags_dead_after(index, RflagsBits::CF)) {
+            (OpKind::Register, true) => {
                 let reg = instruction.instruction.op0_register();
 
-                if let Some(pushf_inst) = self.create_instruction(
-                    context,
-                    Instruction::with(Code::Pushfq),
-                ) {
-                    result.push(pushf_inst);
-                }
-
-                if let Some(clc_inst) = self.create_instruction(
-                    context,
+                if let Ok(clc_inst) = context.create_encoded(
                     Instruction::with(Code::Clc),
                 ) {
                     result.push(clc_inst);
                 }
 
-                if let Some(mut adc_inst) = self.create_instruction(
-                    context,
+                if let Ok(adc_inst) = context.create_encoded(
                     Instruction::with2(Code::Adc_rm64_imm8, reg, 1).unwrap(),
                 ) {
-                    adc_inst.set_id(instruction.get_id());
                     result.push(adc_inst);
                 }
-
-                if let Some(popf_inst) = self.create_instruction(
-                    context,
-                    Instruction::with(Code::Popfq),
-                ) {
-                    result.push(popf_inst);
-                }
             }
             _ => {
                 result.push(instruction.clone());
@@ -216,42 +259,26 @@ impl MutationPass {
         result
     }
 
-    fn mutate_dec(&self, instruction: &InstructionWithId, context: &crate::instruction::InstructionContext) -> Vec<InstructionWithId> {
+    fn mutate_dec(instruction: &InstructionWithId, context: &InstructionContext, liveness: &LivenessAnalysis, index: usize) -> Vec<InstructionWithId> {
         let mut result = Vec::new();
         let op_kinds: Vec<OpKind> = instruction.instruction.op_kinds().collect();
 
-        match op_kinds[0] {
-            OpKind::Register => {
+        // DEC keeps CF while SBB overwrites it; every other flag matches.
+        match (op_kinds[0], liveness.are_flags_dead_after(index, RflagsBits::CF)) {
+            (OpKind::Register, true) => {
                 let reg = instruction.instruction.op0_register();
 
-                if let Some(pushf_inst) = self.create_instruction(
-                    context,
-                    Instruction::with(Code::Pushfq),
-                ) {
-                    result.push(pushf_inst);
-                }
-
-                if let Some(clc_inst) = self.create_instruction(
-                    context,
+                if let Ok(clc_inst) = context.create_encoded(
                     Instruction::with(Code::Clc),
                 ) {
                     result.push(clc_inst);
                 }
 
-                if let Some(mut sbb_inst) = self.create_instruction(
-                    context,
+                if let Ok(sbb_inst) = context.create_encoded(
                     Instruction::with2(Code::Sbb_rm64_imm8, reg, 1).unwrap(),
                 ) {
-                    sbb_inst.set_id(instruction.get_id());
                     result.push(sbb_inst);
                 }
-
-                if let Some(popf_inst) = self.create_instruction(
-                    context,
-                    Instruction::with(Code::Popfq),
-                ) {
-                    result.push(popf_inst);
-                }
             }
             _ => {
                 result.push(instruction.clone());
@@ -261,28 +288,29 @@ impl MutationPass {
         result
     }
 
-    fn mutate_push(&self, instruction: &InstructionWithId, context: &crate::instruction::InstructionContext) -> Vec<InstructionWithId> {
+    fn mutate_push(instruction: &InstructionWithId, context: &InstructionContext, liveness: &LivenessAnalysis, index: usize) -> Vec<InstructionWithId> {
         let mut result = Vec::new();
         let op_kinds: Vec<OpKind> = instruction.instruction.op_kinds().collect();
+        let reg = instruction.instruction.op0_register();
 
         match op_kinds[0] {
-            OpKind::Register => {
-                let reg = instruction.instruction.op0_register();
-
-                if let Some(mut mov_inst) = self.create_instruction(
-                    context,
-                    Instruction::with2(Code::Mov_rm64_r64, iced_x86::MemoryOperand::new(iced_x86::Register::RSP, iced_x86::Register::None, 1, -8, 8, false, iced_x86::Register::None), reg).unwrap(),
-                ) {
-                    mov_inst.set_id(instruction.get_id());
-                    result.push(mov_inst);
+            OpKind::Register if reg != Register::RSP => {
+                // Reserve the slot before writing it so nothing is ever stored
+                // below RSP. SUB is only usable when the flags are dead.
+                let reserve = if liveness.are_flags_dead_after(index, STATUS_FLAGS) {
+                    Instruction::with2(Code::Sub_rm64_imm8, Register::RSP, 8).unwrap()
+                } else {
+                    Instruction::with2(Code::Lea_r64_m, Register::RSP, MemoryOperand::with_base_displ(Register::RSP, -8)).unwrap()
+                };
+
+                if let Ok(sub_inst) = context.create_encoded(reserve) {
+                    result.push(sub_inst);
                 }
 
-                if let Some(mut sub_inst) = self.create_instruction(
-                    context,
-                    Instruction::with2(Code::Sub_rm64_imm8, iced_x86::Register::RSP, 8).unwrap(),
+                if let Ok(mov_inst) = context.create_encoded(
+                    Instruction::with2(Code::Mov_rm64_r64, MemoryOperand::with_base(Register::RSP), reg).unwrap(),
                 ) {
-                    sub_inst.set_id(instruction.get_id());
-                    result.push(sub_inst);
+                    result.push(mov_inst);
                 }
             }
             _ => {
@@ -299,46 +327,65 @@ impl Pass for MutationPass {
         "Mutation"
     }
 
-    fn apply(&self, function: &mut ObfuscatorFunction) -> Result<(), String> {
+    fn apply(&self, function: &mut ObfuscatorFunction, rng: &mut PassRng) -> Result<(), String> {
+        let liveness = function.analyze_liveness();
+        let context = &function.instruction_context;
         let mut result = Vec::with_capacity(function.instructions.len() * 3);
+        let mut rip_shifts = Vec::new();
+        let mut import_thunks = Vec::new();
+
+        for (index, instruction) in function.instructions.iter().enumerate() {
+            // Prologue and epilogue instructions are described by the unwind
+            // info, and references to the function's own code are relocated
+            // by the encoder only while they point exactly at an instruction.
+            // Relocated absolute addresses have to stay in a single operand.
+            if function.unwind.is_frame_instruction(instruction.get_id())
+                || function.references_own_code(instruction.get_id())
+                || function.has_absolute_reference(instruction.get_id())
+                || !self.should_mutate(instruction.instruction.code(), rng)
+            {
+                result.push(instruction.clone());
+                continue;
+            }
 
-        for instruction in function.instructions.iter() {
-            match instruction.instruction.code() {
-                Code::Lea_r64_m => {
-                    let mut mutated = self.mutate_lea(instruction, &function.instruction_context);
-                    result.append(&mut mutated);
-                }
-                Code::Call_rm64 => {
-                    let mut mutated = self.mutate_call(instruction, &function.instruction_context);
-                    result.append(&mut mutated);
-                }
-                Code::Add_r64_rm64 | Code::Add_rm64_r64 => {
-                    let mut mutated = self.mutate_add(instruction, &function.instruction_context);
-                    result.append(&mut mutated);
-                }
-                Code::Or_r64_rm64 | Code::Or_rm64_r64 => {
-                    let mut mutated = self.mutate_or(instruction, &function.instruction_context);
-                    result.append(&mut mutated);
-                }
-                Code::Inc_rm64 => {
-                    let mut mutated = self.mutate_inc(instruction, &function.instruction_context);
-                    result.append(&mut mutated);
-                }
-                Code::Dec_rm64 => {
-                    let mut mutated = self.mutate_dec(instruction, &function.instruction_context);
-                    result.append(&mut mutated);
-                }
-                Code::Push_r64 => {
-                    let mut mutated = self.mutate_push(instruction, &function.instruction_context);
-                    result.append(&mut mutated);
-                }
-                _ => {
-                    result.push(instruction.clone());
+            let mut mutated = match instruction.instruction.code() {
+                Code::Lea_r64_m => Self::mutate_lea(instruction, context, &liveness, index, function.red_zone, rng),
+                Code::Call_rm64 => Self::mutate_call(
+                    instruction,
+                    context,
+                    function.import_call(instruction.get_id()),
+                    function.call_scratch,
+                    &mut import_thunks,
+                    rng,
+                ),
+                Code::Add_r64_rm64 | Code::Add_rm64_r64 => Self::mutate_add(instruction, context),
+                Code::Or_r64_rm64 | Code::Or_rm64_r64 => Self::mutate_or(instruction, context, &liveness, index, rng),
+                Code::Inc_rm64 => Self::mutate_inc(instruction, context, &liveness, index),
+                Code::Dec_rm64 => Self::mutate_dec(instruction, context, &liveness, index),
+                Code::Push_r64 => Self::mutate_push(instruction, context, &liveness, index),
+                _ => vec![instruction.clone()],
+            };
+
+            take_over_id(&mut mutated, instruction);
+            if let Some(first) = mutated.first() {
+                // The LEA mutation moves the operand and subtracts the same
+                // amount afterwards, which is expected for RIP-relative ones too.
+                if instruction.instruction.is_ip_rel_memory_operand() {
+                    let delta = first.instruction.memory_displacement64().wrapping_sub(instruction.instruction.memory_displacement64());
+                    if delta != 0 {
+                        rip_shifts.push((instruction.get_id(), delta.cast_signed()));
+                    }
                 }
             }
+
+            result.append(&mut mutated);
         }
 
         function.instructions = result;
+        function.import_thunks.extend(import_thunks);
+        for (instruction_id, delta) in rip_shifts {
+            function.shift_rip_reference(instruction_id, delta);
+        }
         Ok(())
     }
 }
@@ -0,0 +1,250 @@
+use super::{Pass, PassRng, falls_off_end};
+use crate::branches::BranchInfo;
+use crate::config::OpaquePredicateConfig;
+use crate::function::ObfuscatorFunction;
+use crate::instruction::{InstructionContext, InstructionWithId};
+use crate::liveness::{GPRS, LivenessAnalysis, STATUS_FLAGS};
+use iced_x86::{Code, FlowControl, Instruction, MemoryOperand, Register};
+use rand::Rng;
+
+/// Identities that leave ZF set for every value of `x`.
+#[derive(Clone, Copy, Debug)]
+enum Identity {
+    /// `x * (x + 1)` is even.
+    ConsecutiveProduct,
+    /// A square is 0 or 1 modulo 4, so bit 1 is clear.
+    SquareModFour,
+    /// `x^3 - x = (x - 1) * x * (x + 1)` is even.
+    CubeMinusSelf,
+}
+
+const IDENTITIES: [Identity; 3] = [
+    Identity::ConsecutiveProduct,
+    Identity::SquareModFour,
+    Identity::CubeMinusSelf,
+];
+
+/// Inserts conditional branches whose outcome is fixed but computed from the
+/// live registers at that point.
+///
+/// Always-true predicates jump over an inline bogus block, always-false
+/// ones branch to a bogus block appended to the function; both kinds of
+/// block end in a jump back into the function, so disassemblers see edges
+/// that never execute.
+///
+/// Predicates go where a scratch register is free and the status flags are
+/// dead, outside the prolog and epilogues and not right after a call whose
+/// return address the exception tables may refer to.
+pub struct OpaquePredicatePass {
+    config: OpaquePredicateConfig,
+}
+
+impl OpaquePredicatePass {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_config(OpaquePredicateConfig::default())
+    }
+
+    #[must_use]
+    pub const fn with_config(config: OpaquePredicateConfig) -> Self {
+        Self { config }
+    }
+
+    fn is_insertion_point(function: &ObfuscatorFunction, liveness: &LivenessAnalysis, index: usize) -> bool {
+        let instruction = &function.instructions[index];
+        if function.unwind.is_frame_instruction(instruction.id)
+            || liveness.live_in(index).is_flag_live(STATUS_FLAGS)
+        {
+            return false;
+        }
+        index == 0
+            || !matches!(
+                function.instructions[index - 1].instruction.flow_control(),
+                FlowControl::Call | FlowControl::IndirectCall
+            )
+    }
+
+    /// Computes the identity of `input` into `scratch` and sets ZF.
+    fn predicate(identity: Identity, scratch: Register, input: Register) -> Result<Vec<Instruction>, String> {
+        let e = |e: iced_x86::IcedError| e.to_string();
+        let mut code = vec![
+            Instruction::with2(Code::Mov_r64_rm64, scratch, input).map_err(e)?,
+            Instruction::with2(Code::Imul_r64_rm64, scratch, scratch).map_err(e)?,
+        ];
+        let mask = match identity {
+            Identity::ConsecutiveProduct => {
+                code.push(Instruction::with2(Code::Add_r64_rm64, scratch, input).map_err(e)?);
+                1
+            }
+            Identity::SquareModFour => 2,
+            Identity::CubeMinusSelf => {
+                code.push(Instruction::with2(Code::Imul_r64_rm64, scratch, input).map_err(e)?);
+                code.push(Instruction::with2(Code::Sub_r64_rm64, scratch, input).map_err(e)?);
+                1
+            }
+        };
+        code.push(Instruction::with2(Code::Test_rm64_imm32, scratch, mask).map_err(e)?);
+        Ok(code)
+    }
+
+    /// Register and stack traffic that looks like compiled code. It is never
+    /// executed, so it may clobber anything but must not reference the image.
+    fn junk(&self, rng: &mut PassRng) -> Result<Vec<Instruction>, String> {
+        let registers: Vec<Register> = GPRS.iter().copied().filter(|&r| r != Register::RSP).collect();
+        let e = |e: iced_x86::IcedError| e.to_string();
+        let count = rng.random_range(1..=self.config.junk_instructions);
+        let mut code = Vec::with_capacity(count);
+        for _ in 0..count {
+            let dst = registers[rng.random_range(0..registers.len())];
+            let src = registers[rng.random_range(0..registers.len())];
+            let slot = MemoryOperand::with_base_displ(Register::RSP, rng.random_range(1..16i64) * 8);
+            let immediate = rng.random_range(-0x1000..0x1000i32);
+            code.push(match rng.random_range(0..10) {
+                0 => Instruction::with2(Code::Mov_rm64_imm32, dst, immediate),
+                1 => Instruction::with2(Code::Add_rm64_imm32, dst, immediate),
+                2 => Instruction::with2(Code::Xor_r64_rm64, dst, src),
+                3 => Instruction::with2(Code::Mov_r64_rm64, dst, src),
+                4 => Instruction::with2(
+                    Code::Lea_r64_m,
+                    dst,
+                    MemoryOperand::with_base_displ(src, i64::from(immediate)),
+                ),
+                5 => Instruction::with2(Code::Mov_r64_rm64, dst, slot),
+                6 => Instruction::with2(Code::Mov_rm64_r64, slot, src),
+                7 => Instruction::with2(Code::Imul_r64_rm64, dst, src),
+                8 => Instruction::with2(Code::Shl_rm64_imm8, dst, rng.random_range(1..8u32)),
+                _ => Instruction::with2(Code::Cmp_rm64_imm32, dst, immediate),
+            }
+            .map_err(e)?);
+        }
+        Ok(code)
+    }
+
+    /// Builds a bogus block ending in a jump to `target_id`.
+    fn bogus_block(
+        &self,
+        context: &InstructionContext,
+        target_id: usize,
+        branches: &mut Vec<BranchInfo>,
+        rng: &mut PassRng,
+    ) -> Result<Vec<InstructionWithId>, String> {
+        let mut block = self
+            .junk(rng)?
+            .into_iter()
+            .map(|instruction| context.create_encoded(instruction))
+            .collect::<Result<Vec<_>, _>>()?;
+        let jump = context.create_encoded(
+            Instruction::with_branch(Code::Jmp_rel32_64, 0).map_err(|e| e.to_string())?,
+        )?;
+        branches.push(BranchInfo {
+            source_id: jump.id,
+            target_id,
+            original_target: 0,
+        });
+        block.push(jump);
+        Ok(block)
+    }
+}
+
+impl Pass for OpaquePredicatePass {
+    fn name(&self) -> &'static str {
+        "OpaquePredicate"
+    }
+
+    fn apply(&self, function: &mut ObfuscatorFunction, rng: &mut PassRng) -> Result<(), String> {
+        if function.instructions.is_empty() {
+            return Ok(());
+        }
+
+        let liveness = function.analyze_liveness();
+        let context = function.instruction_context.clone();
+        let block_starts: Vec<usize> = function
+            .cfg
+            .blocks
+            .iter()
+            .map(|block| function.instructions[block.first_index()].id)
+            .collect();
+
+        let mut result = Vec::with_capacity(function.instructions.len());
+        let mut appended = Vec::new();
+        let mut branches = Vec::new();
+
+        for (index, instruction) in function.instructions.iter().enumerate() {
+            if Self::is_insertion_point(function, &liveness, index)
+                && rng.random_bool(self.config.probability)
+            {
+                let live = liveness.live_in(index);
+                let scratch: Vec<Register> = live.dead_registers().collect();
+                if !scratch.is_empty() {
+                    let scratch = scratch[rng.random_range(0..scratch.len())];
+                    let mut inputs: Vec<Register> = live
+                        .live_registers()
+                        .filter(|&register| register != Register::RSP)
+                        .collect();
+                    if inputs.is_empty() {
+                        inputs = GPRS
+                            .iter()
+                            .copied()
+                            .filter(|&register| register != Register::RSP && register != scratch)
+                            .collect();
+                    }
+                    let input = inputs[rng.random_range(0..inputs.len())];
+                    let identity = IDENTITIES[rng.random_range(0..IDENTITIES.len())];
+                    for code in Self::predicate(identity, scratch, input)? {
+                        result.push(context.create_encoded(code)?);
+                    }
+
+                    let bogus_target = block_starts[rng.random_range(0..block_starts.len())];
+                    let bogus = self.bogus_block(&context, bogus_target, &mut branches, rng)?;
+                    if rng.random_bool(0.5) {
+                        // je <instruction>; <bogus block>
+                        let branch = context.create_encoded(
+                            Instruction::with_branch(Code::Je_rel32_64, 0).map_err(|e| e.to_string())?,
+                        )?;
+                        branches.push(BranchInfo {
+                            source_id: branch.id,
+                            target_id: instruction.id,
+                            original_target: 0,
+                        });
+                        result.push(branch);
+                        result.extend(bogus);
+                    } else {
+                        // jne <bogus block at the end>
+                        let branch = context.create_encoded(
+                            Instruction::with_branch(Code::Jne_rel32_64, 0).map_err(|e| e.to_string())?,
+                        )?;
+                        branches.push(BranchInfo {
+                            source_id: branch.id,
+                            target_id: bogus[0].id,
+                            original_target: 0,
+                        });
+                        result.push(branch);
+                        appended.extend(bogus);
+                    }
+                }
+            }
+            result.push(instruction.clone());
+        }
+
+        if result.len() == function.instructions.len() {
+            return Ok(());
+        }
+        if !appended.is_empty() {
+            if falls_off_end(&function.instructions) {
+                result.push(context.create_encoded(Instruction::with(Code::Int3))?);
+            }
+            result.extend(appended);
+        }
+
+        function.branch_map.extend(branches);
+        function.instructions = result;
+        function.instruction_context = context;
+        Ok(())
+    }
+}
+
+impl Default for OpaquePredicatePass {
+    fn default() -> Self {
+        Self::new()
+    }
+}
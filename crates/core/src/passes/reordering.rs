@@ -0,0 +1,137 @@
+use super::{Pass, PassRng, falls_off_end};
+use crate::branches::BranchInfo;
+use crate::config::BlockReorderingConfig;
+use crate::function::ObfuscatorFunction;
+use crate::instruction::{InstructionContext, InstructionWithId};
+use iced_x86::{Code, FlowControl, Instruction};
+use rand::seq::SliceRandom;
+use std::ops::Range;
+
+/// Consecutive blocks that are moved together.
+#[derive(Clone, Debug)]
+struct Unit {
+    range: Range<usize>,
+    /// Index of the instruction the last block falls through into.
+    fallthrough: Option<usize>,
+}
+
+/// Shuffles the basic blocks of a function and links them with jumps
+/// wherever a block used to fall through into the next one.
+///
+/// The entry block stays first. A block stays attached to its fallthrough
+/// successor when it ends in a prologue or epilogue instruction, where a
+/// jump would confuse the unwinder, or in a call, whose return address must
+/// keep pointing at the original next instruction. Functions whose unwind
+/// information pins the layout (see
+/// [`crate::unwind::FunctionUnwind::movable_from`]) are left alone.
+pub struct BlockReorderingPass {
+    config: BlockReorderingConfig,
+}
+
+impl BlockReorderingPass {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_config(BlockReorderingConfig::default())
+    }
+
+    #[must_use]
+    pub const fn with_config(config: BlockReorderingConfig) -> Self {
+        Self { config }
+    }
+
+    fn create_jump(context: &InstructionContext) -> Result<InstructionWithId, String> {
+        context.create_encoded(Instruction::with_branch(Code::Jmp_rel32_64, 0).map_err(|e| e.to_string())?)
+    }
+
+    fn is_attached(function: &ObfuscatorFunction, last: &InstructionWithId) -> bool {
+        function.unwind.is_frame_instruction(last.id)
+            || matches!(
+                last.instruction.flow_control(),
+                FlowControl::Call | FlowControl::IndirectCall
+            )
+    }
+
+    fn collect_units(function: &ObfuscatorFunction, movable_from: usize) -> Vec<Unit> {
+        let cfg = &function.cfg;
+        let mut units: Vec<Unit> = Vec::new();
+        for block in &cfg.blocks {
+            let fallthrough = block
+                .fallthrough()
+                .map(|target| cfg.blocks[target].first_index());
+            let starts_unit = units.last().is_none_or(|previous| {
+                let last = &function.instructions[previous.range.end - 1];
+                block.first_index() >= movable_from
+                    && !(previous.fallthrough.is_some() && Self::is_attached(function, last))
+            });
+
+            if starts_unit {
+                units.push(Unit {
+                    range: block.range.clone(),
+                    fallthrough,
+                });
+            } else if let Some(unit) = units.last_mut() {
+                unit.range.end = block.range.end;
+                unit.fallthrough = fallthrough;
+            }
+        }
+        units
+    }
+}
+
+impl Pass for BlockReorderingPass {
+    fn name(&self) -> &'static str {
+        "BlockReordering"
+    }
+
+    fn apply(&self, function: &mut ObfuscatorFunction, rng: &mut PassRng) -> Result<(), String> {
+        let Some(movable_from) = function.unwind.movable_from(&function.instructions) else {
+            return Ok(());
+        };
+        let units = Self::collect_units(function, movable_from);
+
+        // Code running off the end has to keep doing so.
+        let pinned_tail = falls_off_end(&function.instructions).then(|| units.len() - 1);
+        let mut order: Vec<usize> = (1..units.len())
+            .filter(|&unit| Some(unit) != pinned_tail)
+            .collect();
+        if order.is_empty() || order.len() < self.config.min_blocks {
+            return Ok(());
+        }
+        order.shuffle(rng);
+        order.insert(0, 0);
+        order.extend(pinned_tail);
+
+        let context = function.instruction_context.clone();
+        let mut result = Vec::with_capacity(function.instructions.len() + units.len());
+        let mut new_branches = Vec::new();
+        for (position, &unit) in order.iter().enumerate() {
+            let unit = &units[unit];
+            result.extend_from_slice(&function.instructions[unit.range.clone()]);
+
+            let Some(target) = unit.fallthrough else {
+                continue;
+            };
+            let next = order.get(position + 1).map(|&next| units[next].range.start);
+            if next != Some(target) {
+                let jump = Self::create_jump(&context)?;
+                new_branches.push(BranchInfo {
+                    source_id: jump.id,
+                    target_id: function.instructions[target].id,
+                    original_target: 0,
+                });
+                result.push(jump);
+            }
+        }
+
+        function.branch_map.extend(new_branches);
+        function.instructions = result;
+        function.instruction_context = context;
+        Ok(())
+    }
+}
+
+impl Default for BlockReorderingPass {
+    fn default() -> Self {
+        Self::new()
+    }
+}
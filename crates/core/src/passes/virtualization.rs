@@ -0,0 +1,479 @@
+use super::{Pass, PassRng};
+use crate::config::VirtualizationConfig;
+use crate::function::ObfuscatorFunction;
+use crate::instruction::InstructionWithId;
+use crate::vm::{AluOp, ExitTarget, ShiftOp, UnaryOp, VmLayout, VmOp, VmProgram, VmSegment, Width};
+use common::debug;
+use iced_x86::{Code, Instruction, MemoryOperand, Mnemonic, OpKind, Register};
+use std::collections::HashSet;
+
+/// Translates one instruction into VM instructions, or gives up on the
+/// ones the VM cannot run so that they stay native.
+struct Lifter<'a> {
+    function: &'a ObfuscatorFunction,
+    thunks: HashSet<usize>,
+}
+
+impl<'a> Lifter<'a> {
+    fn new(function: &'a ObfuscatorFunction) -> Self {
+        Self {
+            function,
+            thunks: function.import_thunks.iter().map(|thunk| thunk.instruction_id).collect(),
+        }
+    }
+
+    fn lift(&self, index: usize) -> Option<Vec<VmOp>> {
+        let inst = &self.function.instructions[index];
+        let instruction = &inst.instruction;
+        if self.stays_native(inst) {
+            return None;
+        }
+        let next = || {
+            self.function
+                .instructions
+                .get(index + 1)
+                .map(|next| ExitTarget::Instruction(next.id))
+        };
+
+        let mut ops = Vec::new();
+        match instruction.mnemonic() {
+            Mnemonic::Nop => {}
+            Mnemonic::Mov => {
+                let width = Self::width(instruction, 0)?;
+                Self::read(instruction, 1, &mut ops)?;
+                Self::write(instruction, 0, width, &mut ops)?;
+            }
+            Mnemonic::Movzx | Mnemonic::Movsx | Mnemonic::Movsxd => {
+                let width = Self::width(instruction, 0)?;
+                let from = Self::width(instruction, 1)?;
+                let signed = instruction.mnemonic() != Mnemonic::Movzx;
+                Self::read(instruction, 1, &mut ops)?;
+                // Loads from memory are already zero-extended.
+                if signed || instruction.op1_kind() == OpKind::Register {
+                    ops.push(VmOp::Extend { from, signed });
+                }
+                Self::write(instruction, 0, width, &mut ops)?;
+            }
+            Mnemonic::Lea => {
+                let width = Self::width(instruction, 0)?;
+                Self::address(instruction, &mut ops)?;
+                Self::write(instruction, 0, width, &mut ops)?;
+            }
+            Mnemonic::Add
+            | Mnemonic::Sub
+            | Mnemonic::And
+            | Mnemonic::Or
+            | Mnemonic::Xor
+            | Mnemonic::Adc
+            | Mnemonic::Sbb
+            | Mnemonic::Cmp
+            | Mnemonic::Test => {
+                let op = Self::alu_op(instruction.mnemonic());
+                let width = Self::width(instruction, 0)?;
+                Self::read(instruction, 0, &mut ops)?;
+                Self::read(instruction, 1, &mut ops)?;
+                ops.push(VmOp::Alu(op, width));
+                if op.keeps_result() {
+                    Self::write(instruction, 0, width, &mut ops)?;
+                }
+            }
+            Mnemonic::Imul => Self::imul(instruction, &mut ops)?,
+            Mnemonic::Inc | Mnemonic::Dec | Mnemonic::Neg | Mnemonic::Not => {
+                let op = Self::unary_op(instruction.mnemonic());
+                let width = Self::width(instruction, 0)?;
+                Self::read(instruction, 0, &mut ops)?;
+                ops.push(VmOp::Unary(op, width));
+                Self::write(instruction, 0, width, &mut ops)?;
+            }
+            Mnemonic::Shl | Mnemonic::Sal | Mnemonic::Shr | Mnemonic::Sar | Mnemonic::Rol | Mnemonic::Ror => {
+                let op = Self::shift_op(instruction.mnemonic());
+                let width = Self::width(instruction, 0)?;
+                Self::read(instruction, 0, &mut ops)?;
+                Self::read(instruction, 1, &mut ops)?;
+                ops.push(VmOp::Shift(op, width));
+                Self::write(instruction, 0, width, &mut ops)?;
+            }
+            Mnemonic::Jmp if instruction.is_jmp_short_or_near() => {
+                ops.push(VmOp::Exit(self.branch_target(inst)?));
+            }
+            _ if instruction.is_jcc_short_or_near() => {
+                ops.push(VmOp::Branch(instruction.condition_code(), self.branch_target(inst)?));
+                ops.push(VmOp::Exit(next()?));
+            }
+            Mnemonic::Call => {
+                match instruction.code() {
+                    // Calls into the function itself are left to the branch
+                    // fixups of the native code.
+                    Code::Call_rel32_64 if self.branch_source(inst.id) => return None,
+                    Code::Call_rel32_64 => {
+                        ops.push(VmOp::LoadAddress(u32::try_from(instruction.near_branch_target()).ok()?));
+                    }
+                    Code::Call_rm64 => Self::read(instruction, 0, &mut ops)?,
+                    _ => return None,
+                }
+                ops.push(VmOp::Call(next()?));
+            }
+            _ => return None,
+        }
+        Some(ops)
+    }
+
+    /// The two- and three-operand forms; the one-operand form writes RDX.
+    fn imul(instruction: &Instruction, ops: &mut Vec<VmOp>) -> Option<()> {
+        let width = Self::width(instruction, 0)?;
+        let sources = match instruction.op_count() {
+            2 => [0, 1],
+            3 => [1, 2],
+            _ => return None,
+        };
+        if width == Width::Byte {
+            return None;
+        }
+        for operand in sources {
+            Self::read(instruction, operand, ops)?;
+        }
+        ops.push(VmOp::Alu(AluOp::Imul, width));
+        Self::write(instruction, 0, width, ops)
+    }
+
+    /// Instructions whose address, prefixes or unwind role the VM cannot
+    /// reproduce.
+    fn stays_native(&self, inst: &InstructionWithId) -> bool {
+        let instruction = &inst.instruction;
+        self.function.unwind.is_frame_instruction(inst.id)
+            || self.function.references_own_code(inst.id)
+            || self.function.has_absolute_reference(inst.id)
+            || self.thunks.contains(&inst.id)
+            || instruction.has_lock_prefix()
+            || instruction.has_rep_prefix()
+            || instruction.has_repne_prefix()
+            || instruction.segment_prefix() != Register::None
+    }
+
+    const fn alu_op(mnemonic: Mnemonic) -> AluOp {
+        match mnemonic {
+            Mnemonic::Add => AluOp::Add,
+            Mnemonic::Sub => AluOp::Sub,
+            Mnemonic::And => AluOp::And,
+            Mnemonic::Or => AluOp::Or,
+            Mnemonic::Xor => AluOp::Xor,
+            Mnemonic::Adc => AluOp::Adc,
+            Mnemonic::Sbb => AluOp::Sbb,
+            Mnemonic::Cmp => AluOp::Cmp,
+            _ => AluOp::Test,
+        }
+    }
+
+    const fn unary_op(mnemonic: Mnemonic) -> UnaryOp {
+        match mnemonic {
+            Mnemonic::Inc => UnaryOp::Inc,
+            Mnemonic::Dec => UnaryOp::Dec,
+            Mnemonic::Neg => UnaryOp::Neg,
+            _ => UnaryOp::Not,
+        }
+    }
+
+    const fn shift_op(mnemonic: Mnemonic) -> ShiftOp {
+        match mnemonic {
+            Mnemonic::Shl | Mnemonic::Sal => ShiftOp::Shl,
+            Mnemonic::Shr => ShiftOp::Shr,
+            Mnemonic::Sar => ShiftOp::Sar,
+            Mnemonic::Rol => ShiftOp::Rol,
+            _ => ShiftOp::Ror,
+        }
+    }
+
+    fn branch_source(&self, instruction_id: usize) -> bool {
+        self.function
+            .branch_map
+            .iter()
+            .any(|branch| branch.source_id == instruction_id)
+    }
+
+    fn branch_target(&self, inst: &InstructionWithId) -> Option<ExitTarget> {
+        self.function
+            .branch_map
+            .iter()
+            .find(|branch| branch.source_id == inst.id)
+            .map(|branch| ExitTarget::Instruction(branch.target_id))
+            .or_else(|| {
+                u32::try_from(inst.instruction.near_branch_target())
+                    .ok()
+                    .map(ExitTarget::Address)
+            })
+    }
+
+    /// General-purpose registers other than AH, CH, DH and BH, which do not
+    /// live in the low bits of their register.
+    fn register(register: Register) -> Option<Register> {
+        let high_byte = matches!(register, Register::AH | Register::CH | Register::DH | Register::BH);
+        (register.is_gpr() && !high_byte).then(|| register.full_register())
+    }
+
+    fn width(instruction: &Instruction, operand: u32) -> Option<Width> {
+        match instruction.op_kind(operand) {
+            OpKind::Register => Width::from_size(instruction.op_register(operand).size()),
+            OpKind::Memory => Width::from_size(instruction.memory_size().size()),
+            _ => None,
+        }
+    }
+
+    fn read(instruction: &Instruction, operand: u32, ops: &mut Vec<VmOp>) -> Option<()> {
+        match instruction.op_kind(operand) {
+            OpKind::Register => {
+                ops.push(VmOp::LoadRegister(Self::register(instruction.op_register(operand))?));
+            }
+            OpKind::Memory => {
+                let width = Self::width(instruction, operand)?;
+                Self::address(instruction, ops)?;
+                ops.push(VmOp::Load(width));
+            }
+            OpKind::Immediate8
+            | OpKind::Immediate16
+            | OpKind::Immediate32
+            | OpKind::Immediate64
+            | OpKind::Immediate8to16
+            | OpKind::Immediate8to32
+            | OpKind::Immediate8to64
+            | OpKind::Immediate32to64 => ops.push(VmOp::LoadImmediate(instruction.immediate(operand))),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Expects the value on the stack. RSP is never written: the VM context
+    /// lives right below the guest stack.
+    fn write(instruction: &Instruction, operand: u32, width: Width, ops: &mut Vec<VmOp>) -> Option<()> {
+        match instruction.op_kind(operand) {
+            OpKind::Register => {
+                let register = Self::register(instruction.op_register(operand))?;
+                if register == Register::RSP {
+                    return None;
+                }
+                ops.push(VmOp::StoreRegister(register, width));
+            }
+            OpKind::Memory => {
+                Self::address(instruction, ops)?;
+                ops.push(VmOp::Store(width));
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn address(instruction: &Instruction, ops: &mut Vec<VmOp>) -> Option<()> {
+        let base = instruction.memory_base();
+        let index = instruction.memory_index();
+        if base == Register::RIP {
+            ops.push(VmOp::LoadAddress(u32::try_from(instruction.memory_displacement64()).ok()?));
+            return Some(());
+        }
+
+        let mut terms = 0;
+        if base != Register::None {
+            if !base.is_gpr64() {
+                return None;
+            }
+            ops.push(VmOp::LoadRegister(base));
+            terms += 1;
+        }
+        if index != Register::None {
+            if !index.is_gpr64() {
+                return None;
+            }
+            ops.push(VmOp::LoadRegister(index));
+            let scale = instruction.memory_index_scale();
+            if scale > 1 {
+                ops.extend([VmOp::LoadImmediate(u64::from(scale)), VmOp::Mul]);
+            }
+            if terms > 0 {
+                ops.push(VmOp::Add);
+            }
+            terms += 1;
+        }
+        let displacement = instruction.memory_displacement64();
+        if displacement != 0 || terms == 0 {
+            ops.push(VmOp::LoadImmediate(displacement));
+            if terms > 0 {
+                ops.push(VmOp::Add);
+            }
+        }
+        Some(())
+    }
+}
+
+/// Runs of instructions turned into bytecode, by instruction index.
+struct Run {
+    start: usize,
+    end: usize,
+    ops: Vec<VmOp>,
+}
+
+/// Moves runs of GPR arithmetic, loads and stores, branches and calls into
+/// bytecode for a stack machine whose encoding is randomized per function.
+///
+/// Each run is replaced by `push token; jmp vm_enter`, which first steps
+/// over the red zone where the ABI has one, and the compiler emits the
+/// function's interpreter and bytecode into the `.vmp` section.
+///
+/// Runs end where the VM meets an instruction it cannot run, which is left
+/// native: the VM exits to it and the code after it enters the VM again.
+/// Instructions other code, the exception tables or data refer to start a
+/// new run, whose stub keeps their id. Calls leave the VM into the callee
+/// with the next instruction as return address. Functions with exception
+/// handlers are skipped, as are prologues and epilogues.
+pub struct VirtualizationPass {
+    config: VirtualizationConfig,
+}
+
+impl VirtualizationPass {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_config(VirtualizationConfig::default())
+    }
+
+    #[must_use]
+    pub const fn with_config(config: VirtualizationConfig) -> Self {
+        Self { config }
+    }
+
+    /// Instructions reached other than by falling into them, or whose
+    /// position is recorded somewhere, which must keep their own id.
+    fn anchored_ids(function: &ObfuscatorFunction) -> HashSet<usize> {
+        let mut ids: HashSet<usize> = function.branch_map.iter().map(|branch| branch.target_id).collect();
+        ids.extend(function.jump_tables.iter().flat_map(|table| table.target_ids.iter().copied()));
+        ids.extend(function.rip_references.iter().filter_map(|reference| reference.target_id));
+        ids.extend(function.absolute_references.iter().filter_map(|reference| reference.target_id));
+        ids.extend(function.unwind.entry_points());
+        for region in &function.unwind.regions {
+            ids.insert(region.begin_id);
+            ids.extend(region.end);
+            ids.extend(region.prolog_end);
+            ids.extend(region.code_anchors.iter().flatten());
+        }
+        ids
+    }
+
+    fn collect_runs(&self, function: &ObfuscatorFunction) -> Vec<Run> {
+        let anchored = Self::anchored_ids(function);
+        let lifter = Lifter::new(function);
+        let mut runs = Vec::new();
+        let mut current: Option<Run> = None;
+
+        for (index, inst) in function.instructions.iter().enumerate() {
+            if anchored.contains(&inst.id)
+                && let Some(mut run) = current.take()
+            {
+                run.ops.push(VmOp::Exit(ExitTarget::Instruction(inst.id)));
+                runs.push(run);
+            }
+
+            let Some(ops) = lifter.lift(index) else {
+                if let Some(mut run) = current.take() {
+                    run.ops.push(VmOp::Exit(ExitTarget::Instruction(inst.id)));
+                    runs.push(run);
+                }
+                continue;
+            };
+            let run = current.get_or_insert(Run {
+                start: index,
+                end: index,
+                ops: Vec::new(),
+            });
+            run.end = index + 1;
+            run.ops.extend(ops);
+            if run.ops.last().is_some_and(VmOp::ends_segment) {
+                runs.extend(current.take());
+            }
+        }
+        // A run still open here falls off the end of the function and has
+        // nowhere to exit to.
+
+        runs.retain(|run| run.end - run.start >= self.config.min_instructions);
+        runs
+    }
+}
+
+impl Pass for VirtualizationPass {
+    fn name(&self) -> &'static str {
+        "Virtualization"
+    }
+
+    fn apply(&self, function: &mut ObfuscatorFunction, rng: &mut PassRng) -> Result<(), String> {
+        if function.vm.is_some() || function.unwind.has_handlers() {
+            return Ok(());
+        }
+
+        let runs = self.collect_runs(function);
+        if runs.is_empty() {
+            return Ok(());
+        }
+
+        let mut virtualized = HashSet::new();
+        let mut segments = Vec::with_capacity(runs.len());
+        let mut result = Vec::with_capacity(function.instructions.len());
+        let mut runs = runs.into_iter().peekable();
+        let mut index = 0;
+        while index < function.instructions.len() {
+            let Some(run) = runs.next_if(|run| run.start == index) else {
+                result.push(function.instructions[index].clone());
+                index += 1;
+                continue;
+            };
+
+            virtualized.extend(function.instructions[run.start..run.end].iter().map(|inst| inst.id));
+            let entry_id = function.instructions[run.start].id;
+            let token_id = if function.red_zone > 0 {
+                let red_zone = MemoryOperand::with_base_displ(Register::RSP, -i64::from(function.red_zone));
+                let lea = Instruction::with2(Code::Lea_r64_m, Register::RSP, red_zone).map_err(|e| e.to_string())?;
+                result.push(InstructionWithId::encoded(entry_id, lea)?);
+                function.instruction_context.next_id()
+            } else {
+                entry_id
+            };
+            let jump_id = function.instruction_context.next_id();
+            let push = Instruction::with1(Code::Pushq_imm32, 0).map_err(|e| e.to_string())?;
+            let jump = Instruction::with_branch(Code::Jmp_rel32_64, 0).map_err(|e| e.to_string())?;
+            result.push(InstructionWithId::encoded(token_id, push)?);
+            result.push(InstructionWithId::encoded(jump_id, jump)?);
+            segments.push(VmSegment {
+                entry_id,
+                token_id,
+                jump_id,
+                ops: run.ops,
+            });
+            index = run.end;
+        }
+
+        debug!(
+            "Virtualized {} instructions of {} in {} segments",
+            virtualized.len(),
+            function.name,
+            segments.len()
+        );
+
+        function.instructions = result;
+        function
+            .branch_map
+            .retain(|branch| !virtualized.contains(&branch.source_id));
+        function
+            .rip_references
+            .retain(|reference| !virtualized.contains(&reference.instruction_id));
+        function
+            .import_calls
+            .retain(|call| !virtualized.contains(&call.instruction_id));
+        function.vm = Some(VmProgram {
+            layout: VmLayout::random(function.red_zone, rng),
+            segments,
+            interpreter_rva: 0,
+            bytecode_rva: 0,
+        });
+        Ok(())
+    }
+}
+
+impl Default for VirtualizationPass {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -8,7 +8,21 @@ pub struct PDBFunction {
     pub size: u32,
 }
 
+/// Code of a source line, from the PDB's line program.
+#[derive(Clone, Debug)]
+pub struct SourceLine {
+    pub rva: u32,
+    pub size: u32,
+    pub file: String,
+    pub line: u32,
+}
+
 #[derive(Clone)]
 pub struct PDBContext {
     pdb_data: Vec<u8>,
+    /// Functions known up front, for images whose symbols do not come
+    /// from a PDB.
+    functions: Option<Vec<PDBFunction>>,
+    /// Source lines known up front, sorted by RVA.
+    lines: Vec<SourceLine>,
 }
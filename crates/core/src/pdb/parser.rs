@@ -1,22 +1,79 @@
-use crate::pdb::{PDBContext, PDBFunction};
+use crate::pdb::{PDBContext, PDBFunction, SourceLine};
 use symbolic::common::Name;
 use symbolic::debuginfo::pdb::PdbObject;
 use symbolic::demangle::{Demangle, DemangleOptions};
 
 impl PDBContext {
-    pub fn new(pdb_data: Vec<u8>) -> Self {
-        Self { pdb_data }
+    #[must_use]
+    pub const fn new(pdb_data: Vec<u8>) -> Self {
+        Self {
+            pdb_data,
+            functions: None,
+            lines: Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn from_functions(mut functions: Vec<PDBFunction>) -> Self {
+        functions.sort_by_key(|f| f.rva);
+        functions.dedup_by(|a, b| a.rva == b.rva);
+        Self {
+            pdb_data: Vec::new(),
+            functions: Some(functions),
+            lines: Vec::new(),
+        }
+    }
+
+    /// Source lines for functions given by [`PDBContext::from_functions`].
+    #[must_use]
+    pub fn with_lines(mut self, lines: Vec<SourceLine>) -> Self {
+        self.lines = lines;
+        self
     }
 
-    pub fn is_supported(&self) -> bool {
+    #[must_use]
+    pub const fn is_supported(&self) -> bool {
         true
     }
 
+    /// # Errors
+    ///
+    /// Fails when the PDB cannot be parsed.
     pub fn get_functions(&self) -> Result<Vec<PDBFunction>, String> {
+        if let Some(functions) = &self.functions {
+            return Ok(functions.clone());
+        }
         match self.parse() {
             Ok(functions) => Ok(functions),
-            Err(e) => Err(e.to_string()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Source lines of every function, sorted by RVA. Contexts without a
+    /// PDB have the ones given by [`PDBContext::with_lines`].
+    ///
+    /// # Errors
+    ///
+    /// Fails when the PDB cannot be parsed.
+    pub fn get_lines(&self) -> Result<Vec<SourceLine>, String> {
+        if self.pdb_data.is_empty() {
+            return Ok(self.lines.clone());
+        }
+        let pdb_object = PdbObject::parse(&self.pdb_data).map_err(|e| e.to_string())?;
+        let session = pdb_object.debug_session().map_err(|e| e.to_string())?;
+        let mut lines = Vec::new();
+        for func in session.functions().flatten() {
+            for line in &func.lines {
+                lines.push(SourceLine {
+                    rva: u32::try_from(line.address).map_err(|_| "Line address does not fit an RVA".to_string())?,
+                    size: u32::try_from(line.size.unwrap_or(0)).map_err(|_| "Line size does not fit an RVA".to_string())?,
+                    file: line.file.path_str(),
+                    line: u32::try_from(line.line).unwrap_or(u32::MAX),
+                });
+            }
         }
+        lines.sort_by_key(|line| line.rva);
+        Ok(lines)
     }
 
     fn parse(&self) -> Result<Vec<PDBFunction>, String> {
@@ -26,9 +83,11 @@ impl PDBContext {
         if let Ok(session) = pdb_object.debug_session() {
             for func in session.functions().flatten() {
                 functions.push(PDBFunction {
-                    name: self.demangle_name(func.name.as_ref()),
-                    rva: func.address as u32,
-                    size: func.size as u32,
+                    name: Self::demangle_name(func.name.as_ref()),
+                    rva: u32::try_from(func.address)
+                        .map_err(|_| format!("Function address {:#x} does not fit an RVA", func.address))?,
+                    size: u32::try_from(func.size)
+                        .map_err(|_| format!("Function size {:#x} does not fit an RVA", func.size))?,
                 });
             }
         }
@@ -39,7 +98,7 @@ impl PDBContext {
         Ok(functions)
     }
 
-    fn demangle_name(&self, name: &str) -> String {
+    fn demangle_name(name: &str) -> String {
         let name = Name::from(name);
         let demangled = name.try_demangle(DemangleOptions::complete());
         demangled.to_string()
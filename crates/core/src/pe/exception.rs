@@ -0,0 +1,341 @@
+use crate::pe::PEContext;
+use crate::unwind::RelocatedUnwind;
+use common::debug;
+use goblin::pe::exception::RuntimeFunction;
+
+pub const UNW_FLAG_EHANDLER: u8 = 0x1;
+pub const UNW_FLAG_UHANDLER: u8 = 0x2;
+pub const UNW_FLAG_CHAININFO: u8 = 0x4;
+
+pub const UWOP_PUSH_NONVOL: u8 = 0;
+pub const UWOP_ALLOC_LARGE: u8 = 1;
+pub const UWOP_ALLOC_SMALL: u8 = 2;
+pub const UWOP_SET_FPREG: u8 = 3;
+pub const UWOP_SAVE_NONVOL: u8 = 4;
+pub const UWOP_SAVE_NONVOL_FAR: u8 = 5;
+pub const UWOP_EPILOG: u8 = 6;
+pub const UWOP_SPARE_CODE: u8 = 7;
+pub const UWOP_SAVE_XMM128: u8 = 8;
+pub const UWOP_SAVE_XMM128_FAR: u8 = 9;
+pub const UWOP_PUSH_MACHFRAME: u8 = 10;
+
+const IMAGE_DIRECTORY_ENTRY_EXCEPTION: usize = 3;
+const RUNTIME_FUNCTION_SIZE: usize = 12;
+
+#[derive(Clone, Debug)]
+pub struct UnwindCode {
+    pub code_offset: u8,
+    pub op: u8,
+    pub info: u8,
+    pub operands: Vec<u16>,
+}
+
+impl UnwindCode {
+    fn operand_slots(op: u8, info: u8) -> Result<usize, String> {
+        match op {
+            UWOP_PUSH_NONVOL | UWOP_ALLOC_SMALL | UWOP_SET_FPREG | UWOP_EPILOG
+            | UWOP_PUSH_MACHFRAME => Ok(0),
+            UWOP_ALLOC_LARGE if info == 0 => Ok(1),
+            UWOP_ALLOC_LARGE if info == 1 => Ok(2),
+            UWOP_SAVE_NONVOL | UWOP_SPARE_CODE | UWOP_SAVE_XMM128 => Ok(1),
+            UWOP_SAVE_NONVOL_FAR | UWOP_SAVE_XMM128_FAR => Ok(2),
+            _ => Err(format!("Unknown unwind operation {op} (info {info})")),
+        }
+    }
+
+    #[must_use]
+    pub const fn slot_count(&self) -> usize {
+        1 + self.operands.len()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct UnwindInfo {
+    pub version: u8,
+    pub flags: u8,
+    pub prolog_size: u8,
+    pub frame_register: u8,
+    pub frame_offset: u8,
+    pub codes: Vec<UnwindCode>,
+    pub handler: Option<u32>,
+    pub handler_data: Vec<u8>,
+    pub chained: Option<RuntimeFunction>,
+}
+
+impl UnwindInfo {
+    /// Parses the `UNWIND_INFO` at `rva`. Language specific handler data has no
+    /// self-describing length, so it is left empty and has to be filled in by
+    /// whoever understands the handler.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the unwind info is truncated or lies outside the file.
+    pub fn parse(pe_context: &PEContext, rva: u32) -> Result<Self, String> {
+        let header = pe_context.read_data_at_rva(rva, 4)?;
+        let version = header[0] & 0x7;
+        let flags = header[0] >> 3;
+        if version != 1 && version != 2 {
+            return Err(format!("Unsupported unwind info version {version} at {rva:#x}"));
+        }
+
+        let slot_count = header[2] as usize;
+        let slot_bytes = pe_context.read_data_at_rva(rva + 4, slot_count * 2)?;
+        let slots: Vec<u16> = slot_bytes
+            .chunks_exact(2)
+            .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+            .collect();
+
+        let mut codes = Vec::new();
+        let mut index = 0;
+        while index < slots.len() {
+            let slot = slots[index];
+            let op = ((slot >> 8) & 0xF) as u8;
+            let info = (slot >> 12) as u8;
+            let operand_count = UnwindCode::operand_slots(op, info)?;
+            let operands = slots
+                .get(index + 1..index + 1 + operand_count)
+                .ok_or_else(|| format!("Truncated unwind code at {rva:#x}"))?
+                .to_vec();
+            codes.push(UnwindCode {
+                code_offset: slot.to_le_bytes()[0],
+                op,
+                info,
+                operands,
+            });
+            index += 1 + operand_count;
+        }
+
+        let trailer_rva = rva + 4 + u32::from(header[2]).next_multiple_of(2) * 2;
+        let mut handler = None;
+        let mut chained = None;
+        if flags & UNW_FLAG_CHAININFO != 0 {
+            let bytes = pe_context.read_data_at_rva(trailer_rva, RUNTIME_FUNCTION_SIZE)?;
+            chained = Some(RuntimeFunction {
+                begin_address: read_u32(&bytes, 0),
+                end_address: read_u32(&bytes, 4),
+                unwind_info_address: read_u32(&bytes, 8),
+            });
+        } else if flags & (UNW_FLAG_EHANDLER | UNW_FLAG_UHANDLER) != 0 {
+            let bytes = pe_context.read_data_at_rva(trailer_rva, 4)?;
+            handler = Some(read_u32(&bytes, 0));
+        }
+
+        Ok(Self {
+            version,
+            flags,
+            prolog_size: header[1],
+            frame_register: header[3] & 0xF,
+            frame_offset: header[3] >> 4,
+            codes,
+            handler,
+            handler_data: Vec::new(),
+            chained,
+        })
+    }
+
+    /// Offset of the language specific handler data from the start of the
+    /// `UNWIND_INFO`.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, reason = "an encodable UNWIND_INFO has at most 255 slots")]
+    pub fn handler_data_offset(&self) -> u32 {
+        (4 + self.slot_count().next_multiple_of(2) * 2 + 4) as u32
+    }
+
+    pub fn slot_count(&self) -> usize {
+        self.codes.iter().map(UnwindCode::slot_count).sum()
+    }
+
+    #[must_use]
+    pub fn byte_size(&self) -> usize {
+        let mut size = 4 + self.slot_count().next_multiple_of(2) * 2;
+        if self.chained.is_some() {
+            size += RUNTIME_FUNCTION_SIZE;
+        } else if self.handler.is_some() {
+            size += 4 + self.handler_data.len();
+        }
+        size
+    }
+
+    /// # Errors
+    ///
+    /// Fails when the unwind codes do not fit the format.
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        let slot_count = u8::try_from(self.slot_count()).map_err(|_| "Too many unwind codes".to_string())?;
+
+        let mut bytes = Vec::with_capacity(self.byte_size());
+        bytes.push(self.version | (self.flags << 3));
+        bytes.push(self.prolog_size);
+        bytes.push(slot_count);
+        bytes.push(self.frame_register | (self.frame_offset << 4));
+
+        for code in &self.codes {
+            let slot = u16::from(code.code_offset) | (u16::from(code.op) << 8) | (u16::from(code.info) << 12);
+            bytes.extend_from_slice(&slot.to_le_bytes());
+            for operand in &code.operands {
+                bytes.extend_from_slice(&operand.to_le_bytes());
+            }
+        }
+        if !slot_count.is_multiple_of(2) {
+            bytes.extend_from_slice(&[0, 0]);
+        }
+
+        if let Some(chained) = &self.chained {
+            bytes.extend_from_slice(&encode_runtime_function(chained));
+        } else if let Some(handler) = self.handler {
+            bytes.extend_from_slice(&handler.to_le_bytes());
+            bytes.extend_from_slice(&self.handler_data);
+        }
+
+        Ok(bytes)
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+#[must_use]
+pub fn encode_runtime_function(function: &RuntimeFunction) -> [u8; RUNTIME_FUNCTION_SIZE] {
+    let mut bytes = [0u8; RUNTIME_FUNCTION_SIZE];
+    bytes[0..4].copy_from_slice(&function.begin_address.to_le_bytes());
+    bytes[4..8].copy_from_slice(&function.end_address.to_le_bytes());
+    bytes[8..12].copy_from_slice(&function.unwind_info_address.to_le_bytes());
+    bytes
+}
+
+impl PEContext {
+    /// Every `RUNTIME_FUNCTION` of the image, including the ones without a
+    /// handler. Images without an exception directory have none.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed.
+    pub fn get_runtime_functions(&self) -> Result<Vec<RuntimeFunction>, String> {
+        let pe = self.parse()?;
+        let Some(exception_data) = pe.exception_data else {
+            return Ok(Vec::new());
+        };
+
+        exception_data
+            .functions()
+            .map(|function| function.map_err(|e| e.to_string()))
+            .collect()
+    }
+
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed or written.
+    pub fn set_exception_directory(&mut self, rva: u32, size: u32) -> Result<(), String> {
+        self.set_data_directory(IMAGE_DIRECTORY_ENTRY_EXCEPTION, rva, size)
+    }
+
+    /// Writes a new exception directory holding the original `RUNTIME_FUNCTION`
+    /// entries plus those of the relocated bodies, followed by their
+    /// `UNWIND_INFO` and rebuilt handler data. The stubs left at the original
+    /// locations keep their old entries, which stay valid for the single jmp
+    /// they contain.
+    ///
+    /// # Errors
+    ///
+    /// Fails when an unwind entry cannot be encoded or the section cannot be
+    /// added.
+    pub fn emit_exception_directory(&mut self, entries: &[RelocatedUnwind]) -> Result<(), String> {
+        let mut table = self.get_runtime_functions()?;
+        let section_rva = self.get_next_section_rva()?;
+
+        let table_size = (table.len() + entries.len()) * 12;
+        let mut section = vec![0u8; table_size];
+        let rva_at = |offset: usize| {
+            u32::try_from(offset)
+                .map(|offset| section_rva + offset)
+                .map_err(|_| "Exception section grew past 4 GiB".to_string())
+        };
+
+        let unwind_offsets: Vec<usize> = entries
+            .iter()
+            .map(|entry| append_aligned(&mut section, &vec![0; entry.info.byte_size()]))
+            .collect();
+
+        let mut blob_rvas: Vec<Vec<u32>> = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let offsets: Vec<usize> = entry
+                .blobs
+                .iter()
+                .map(|blob| append_aligned(&mut section, &blob.bytes))
+                .collect();
+            let rvas = offsets
+                .iter()
+                .map(|&offset| rva_at(offset))
+                .collect::<Result<Vec<u32>, _>>()?;
+            for (blob, &offset) in entry.blobs.iter().zip(&offsets) {
+                for pointer in &blob.pointers {
+                    let at = offset + pointer.offset;
+                    section[at..at + 4].copy_from_slice(&rvas[pointer.blob].to_le_bytes());
+                }
+            }
+            blob_rvas.push(rvas);
+        }
+
+        for (index, entry) in entries.iter().enumerate() {
+            let mut info = entry.info.clone();
+            if let Some(parent) = entry.chained_parent {
+                info.chained = Some(RuntimeFunction {
+                    begin_address: entries[parent].begin_address,
+                    end_address: entries[parent].end_address,
+                    unwind_info_address: rva_at(unwind_offsets[parent])?,
+                });
+            }
+            for pointer in &entry.handler_pointers {
+                let rva = blob_rvas[index][pointer.blob];
+                info.handler_data[pointer.offset..pointer.offset + 4]
+                    .copy_from_slice(&rva.to_le_bytes());
+            }
+
+            let bytes = info.encode()?;
+            let offset = unwind_offsets[index];
+            section[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        }
+
+        for (entry, &offset) in entries.iter().zip(&unwind_offsets) {
+            table.push(RuntimeFunction {
+                begin_address: entry.begin_address,
+                end_address: entry.end_address,
+                unwind_info_address: rva_at(offset)?,
+            });
+        }
+        table.sort_by_key(|function| function.begin_address);
+        for (index, function) in table.iter().enumerate() {
+            section[index * 12..index * 12 + 12].copy_from_slice(&encode_runtime_function(function));
+        }
+
+        let (rva, _) = self
+            .create_data_section(".vpdata", &section)
+            .map_err(|e| format!("Failed to create exception section: {e}"))?;
+        if rva != section_rva {
+            return Err(format!(
+                "Exception section placed at {rva:#x} instead of {section_rva:#x}"
+            ));
+        }
+        let table_size = u32::try_from(table_size).map_err(|_| "Exception directory grew past 4 GiB".to_string())?;
+        self.set_exception_directory(rva, table_size)?;
+
+        debug!(
+            "Wrote exception directory at {rva:#x} with {} entries ({} relocated)",
+            table.len(),
+            entries.len()
+        );
+        Ok(())
+    }
+}
+
+fn append_aligned(section: &mut Vec<u8>, bytes: &[u8]) -> usize {
+    section.resize(section.len().next_multiple_of(4), 0);
+    let offset = section.len();
+    section.extend_from_slice(bytes);
+    offset
+}
@@ -0,0 +1,166 @@
+use crate::format::{BinaryFormat, BinaryImage, ImageRegion, SectionAccess, UnwindCollector};
+use crate::pe::PEContext;
+use crate::pe::relocation::{BaseRelocation, IMAGE_REL_BASED_DIR64, IMAGE_REL_BASED_HIGHLOW, encode_base_relocations};
+use crate::unwind::{RelocatedFrame, RelocatedUnwind};
+use common::debug;
+use std::collections::HashMap;
+use std::ops::Range;
+
+const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x0000_0040;
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+
+const CODE_CHARACTERISTICS: u32 = 0x6000_0020; // IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_MEM_READ
+const DATA_CHARACTERISTICS: u32 = 0x4000_0040; // IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ
+const WRITABLE_DATA_CHARACTERISTICS: u32 = 0xC000_0040; // IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE
+
+impl BinaryImage for PEContext {
+    fn format(&self) -> BinaryFormat {
+        BinaryFormat::Pe
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.pe_data
+    }
+
+    fn image_base(&self) -> Result<u64, String> {
+        Ok(self.parse()?.image_base)
+    }
+
+    fn read_data_at_rva(&self, rva: u32, size: usize) -> Result<Vec<u8>, String> {
+        Self::read_data_at_rva(self, rva, size)
+    }
+
+    fn write_data_at_rva(&mut self, rva: u32, data: &[u8]) -> Result<(), String> {
+        Self::write_data_at_rva(self, rva, data)
+    }
+
+    /// The headers, then every section.
+    fn regions(&self) -> Result<Vec<ImageRegion>, String> {
+        let pe = self.parse()?;
+        let headers = pe
+            .header
+            .optional_header
+            .ok_or("Missing optional header")?
+            .windows_fields
+            .size_of_headers;
+        let mut regions = vec![ImageRegion {
+            name: "headers".to_string(),
+            rva: 0,
+            size: headers,
+            file: 0..(headers as usize).min(self.pe_data.len()),
+            executable: false,
+            writable: false,
+            constant_data: false,
+        }];
+        for section in &pe.sections {
+            let characteristics = section.characteristics;
+            let offset = section.pointer_to_raw_data as usize;
+            regions.push(ImageRegion {
+                name: section.name().unwrap_or("?").to_string(),
+                rva: section.virtual_address,
+                size: section.virtual_size,
+                file: offset..offset + section.size_of_raw_data as usize,
+                executable: characteristics & IMAGE_SCN_MEM_EXECUTE != 0,
+                writable: characteristics & IMAGE_SCN_MEM_WRITE != 0,
+                constant_data: characteristics & IMAGE_SCN_CNT_INITIALIZED_DATA != 0
+                    && characteristics & (IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_MEM_WRITE) == 0,
+            });
+        }
+        Ok(regions)
+    }
+
+    /// The data directories.
+    fn loader_data(&self) -> Result<Vec<Range<u32>>, String> {
+        let pe = self.parse()?;
+        Ok(pe
+            .header
+            .optional_header
+            .map(|header| {
+                header
+                    .data_directories
+                    .dirs()
+                    .map(|(_, directory)| directory.virtual_address..directory.virtual_address + directory.size)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn function_ranges(&self) -> Result<Vec<Range<u32>>, String> {
+        Ok(self
+            .get_runtime_functions()?
+            .iter()
+            .map(|function| function.begin_address..function.end_address)
+            .collect())
+    }
+
+    /// Targets of the base relocations, whose addresses sit in the image.
+    fn pointer_targets(&self) -> Result<Vec<u32>, String> {
+        let image_base = self.parse()?.image_base;
+        let mut targets = Vec::new();
+        for relocation in self.get_base_relocations()? {
+            let value = match relocation.kind {
+                IMAGE_REL_BASED_DIR64 => {
+                    let bytes = self.read_data_at_rva(relocation.rva, 8)?;
+                    u64::from_le_bytes(bytes.try_into().map_err(|_| "Short relocation target")?)
+                }
+                IMAGE_REL_BASED_HIGHLOW => {
+                    let bytes = self.read_data_at_rva(relocation.rva, 4)?;
+                    u64::from(u32::from_le_bytes(bytes.try_into().map_err(|_| "Short relocation target")?))
+                }
+                _ => continue,
+            };
+            // Values outside the image point at nothing it holds.
+            if let Ok(target) = u32::try_from(value.wrapping_sub(image_base)) {
+                targets.push(target);
+            }
+        }
+        Ok(targets)
+    }
+
+    fn get_import_slots(&self) -> Result<HashMap<u32, String>, String> {
+        Self::get_import_slots(self)
+    }
+
+    fn get_base_relocations(&self) -> Result<Vec<BaseRelocation>, String> {
+        Self::get_base_relocations(self)
+    }
+
+    fn set_base_relocations(&mut self, relocations: &[BaseRelocation]) -> Result<(), String> {
+        let bytes = encode_base_relocations(relocations);
+        let (rva, _) = self
+            .create_data_section(".vreloc", &bytes)
+            .map_err(|e| format!("Failed to create relocation section: {e}"))?;
+        let size = u32::try_from(bytes.len()).map_err(|_| "Relocation table grew past 4 GiB".to_string())?;
+        self.set_base_relocation_directory(rva, size)?;
+        debug!("Wrote base relocation table at {rva:#x}");
+        Ok(())
+    }
+
+    fn next_section_rva(&self) -> Result<u32, String> {
+        self.get_next_section_rva()
+    }
+
+    fn add_section(&mut self, name: &str, size: u32, access: SectionAccess) -> Result<(u32, u32), String> {
+        let characteristics = match access {
+            SectionAccess::Execute => CODE_CHARACTERISTICS,
+            SectionAccess::Read => DATA_CHARACTERISTICS,
+            SectionAccess::ReadWrite => WRITABLE_DATA_CHARACTERISTICS,
+        };
+        self.create_section(name, size, characteristics)
+    }
+
+    fn unwind_collector<'a>(&'a self, handler_symbols: &'a HashMap<u32, String>) -> Result<UnwindCollector<'a>, String> {
+        let runtime_functions = self.get_runtime_functions()?;
+        Ok(Box::new(move |function| {
+            function.collect_unwind_info(self, &runtime_functions, handler_symbols)
+        }))
+    }
+
+    fn emit_unwind_info(&mut self, entries: &[RelocatedUnwind], _frames: &[RelocatedFrame]) -> Result<(), String> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.emit_exception_directory(entries)
+    }
+}
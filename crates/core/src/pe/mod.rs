@@ -1,4 +1,7 @@
+pub mod exception;
+pub mod image;
 pub mod parser;
+pub mod relocation;
 pub mod sections;
 
 pub enum PEType {
@@ -1,5 +1,7 @@
 use crate::pe::{PEContext, PEType};
 use goblin::pe::PE;
+use iced_x86::{Code, Decoder, DecoderOptions};
+use std::collections::HashMap;
 
 #[derive(Debug, Clone)]
 pub struct UnwindFunction {
@@ -8,11 +10,15 @@ pub struct UnwindFunction {
 }
 
 impl PEContext {
-    pub fn new(pe_data: Vec<u8>) -> Self {
+    #[must_use]
+    pub const fn new(pe_data: Vec<u8>) -> Self {
         Self { pe_data }
     }
 
-    pub fn parse(&self) -> Result<PE, String> {
+    /// # Errors
+    ///
+    /// Fails when the data is not a PE image goblin can parse.
+    pub fn parse(&self) -> Result<PE<'_>, String> {
         PE::parse(&self.pe_data).map_err(|e| e.to_string())
     }
 
@@ -20,7 +26,7 @@ impl PEContext {
         let pe = match self.parse() {
             Ok(pe) => pe,
             Err(e) => {
-                return Err(e.to_string());
+                return Err(e);
             }
         };
         let characteristics = pe.header.coff_header.characteristics;
@@ -42,17 +48,16 @@ impl PEContext {
     }
 
     fn get_pe_machine(&self) -> u16 {
-        let pe = match self.parse() {
-            Ok(pe) => pe,
-            Err(_) => return 0,
+        let Ok(pe) = self.parse() else {
+            return 0;
         };
         pe.header.coff_header.machine
     }
 
+    #[must_use]
     pub fn is_supported(&self) -> bool {
-        let pe_type = match self.get_pe_type() {
-            Ok(pe_type) => pe_type,
-            Err(_) => return false,
+        let Ok(pe_type) = self.get_pe_type() else {
+            return false;
         };
 
         let pe_machine = self.get_pe_machine();
@@ -64,6 +69,9 @@ impl PEContext {
         matches!(pe_type, PEType::EXE | PEType::DLL)
     }
 
+    /// # Errors
+    ///
+    /// Fails when the range exceeds the file.
     pub fn read_data(&self, offset: usize, size: usize) -> Result<Vec<u8>, String> {
         if offset + size > self.pe_data.len() {
             return Err("Read would exceed file bounds".to_string());
@@ -71,6 +79,9 @@ impl PEContext {
         Ok(self.pe_data[offset..offset + size].to_vec())
     }
 
+    /// # Errors
+    ///
+    /// Fails when the range exceeds the file.
     pub fn write_data(&mut self, offset: usize, data: &[u8]) -> Result<(), String> {
         if offset + data.len() > self.pe_data.len() {
             return Err("Write would exceed file bounds".to_string());
@@ -79,13 +90,16 @@ impl PEContext {
         Ok(())
     }
 
+    /// # Errors
+    ///
+    /// Fails when no section holds `rva` in the file.
     pub fn rva_to_file_offset(&self, rva: u32) -> Result<usize, String> {
         let pe = self.parse()?;
 
         if rva
             < pe.header
                 .optional_header
-                .ok_or("Optional header not found".to_string())?
+                .ok_or_else(|| "Optional header not found".to_string())?
                 .windows_fields
                 .size_of_headers
         {
@@ -105,14 +119,18 @@ impl PEContext {
         Err(format!("RVA {rva:#x} not found in any section"))
     }
 
+    /// # Errors
+    ///
+    /// Fails when no section holds `file_offset`.
     pub fn file_offset_to_rva(&self, file_offset: usize) -> Result<u32, String> {
         let pe = self.parse()?;
-        let file_offset = file_offset as u32;
+        let file_offset =
+            u32::try_from(file_offset).map_err(|_| format!("File offset {file_offset:#x} is out of range"))?;
 
         if file_offset
             < pe.header
                 .optional_header
-                .ok_or("Optional header not found".to_string())?
+                .ok_or_else(|| "Optional header not found".to_string())?
                 .windows_fields
                 .size_of_headers
         {
@@ -134,30 +152,68 @@ impl PEContext {
         ))
     }
 
+    /// # Errors
+    ///
+    /// Fails when the range is not backed by the file.
     pub fn read_data_at_rva(&self, rva: u32, size: usize) -> Result<Vec<u8>, String> {
         let file_offset = self.rva_to_file_offset(rva)?;
         self.read_data(file_offset, size)
     }
 
-    pub fn write_data_at_rva(&mut self, rva: u32, data: &[u8]) -> Result<(), String> {
+    /// The image bytes from `rva` to the end of the file, for parsing
+    /// structures whose size is only known while reading them.
+    ///
+    /// # Errors
+    ///
+    /// Fails when `rva` is not backed by the file.
+    pub fn data_at_rva(&self, rva: u32) -> Result<&[u8], String> {
         let file_offset = self.rva_to_file_offset(rva)?;
-        self.write_data(file_offset, data)
+        self.pe_data
+            .get(file_offset..)
+            .ok_or_else(|| format!("RVA {rva:#x} is outside the file"))
+    }
+
+    /// Name of the import a `jmp [rip+slot]` thunk at `rva` forwards to.
+    #[must_use]
+    pub fn resolve_import_thunk(&self, rva: u32) -> Option<String> {
+        let bytes = self.read_data_at_rva(rva, 16).ok()?;
+        let mut decoder = Decoder::with_ip(64, &bytes, u64::from(rva), DecoderOptions::NONE);
+        let instruction = decoder.decode();
+        if instruction.code() != Code::Jmp_rm64 || !instruction.is_ip_rel_memory_operand() {
+            return None;
+        }
+
+        let slot = usize::try_from(instruction.ip_rel_memory_address()).ok()?;
+        let pe = self.parse().ok()?;
+        pe.imports
+            .iter()
+            .find(|import| import.offset == slot)
+            .map(|import| import.name.to_string())
     }
 
-    pub fn get_exception_functions(
-        &self,
-    ) -> Result<Vec<goblin::pe::exception::RuntimeFunction>, String> {
+    /// Every IAT slot of the import directory with the `dll!name` of the
+    /// function the loader stores there.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed.
+    pub fn get_import_slots(&self) -> Result<HashMap<u32, String>, String> {
         let pe = self.parse()?;
-        let exception_data = pe.exception_data.ok_or("Exception data not found")?;
-
-        Ok(exception_data
-            .functions()
-            .filter_map(|f| f.as_ref().ok().cloned())
-            .filter(|function| {
-                exception_data
-                    .get_unwind_info(*function, &pe.sections)
-                    .is_ok_and(|info| info.handler.is_some())
+        pe.imports
+            .iter()
+            .map(|import| {
+                let slot = u32::try_from(import.offset)
+                    .map_err(|_| format!("Import slot {:#x} is outside the image", import.offset))?;
+                Ok((slot, format!("{}!{}", import.dll, import.name)))
             })
-            .collect())
+            .collect()
+    }
+
+    /// # Errors
+    ///
+    /// Fails when the range is not backed by the file.
+    pub fn write_data_at_rva(&mut self, rva: u32, data: &[u8]) -> Result<(), String> {
+        let file_offset = self.rva_to_file_offset(rva)?;
+        self.write_data(file_offset, data)
     }
 }
@@ -0,0 +1,92 @@
+use crate::pe::PEContext;
+
+pub const IMAGE_REL_BASED_ABSOLUTE: u8 = 0;
+pub const IMAGE_REL_BASED_HIGHLOW: u8 = 3;
+pub const IMAGE_REL_BASED_DIR64: u8 = 10;
+
+const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+const RELOCATION_PAGE_SIZE: u32 = 0x1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BaseRelocation {
+    pub rva: u32,
+    pub kind: u8,
+}
+
+impl BaseRelocation {
+    #[must_use]
+    pub const fn width(&self) -> u32 {
+        match self.kind {
+            IMAGE_REL_BASED_DIR64 => 8,
+            _ => 4,
+        }
+    }
+}
+
+/// Encodes relocations as page blocks. Blocks are padded with an absolute
+/// entry so that each one stays 32-bit aligned.
+#[must_use]
+#[allow(clippy::cast_possible_truncation, reason = "a block covers one page, so its offsets and entry count are small")]
+pub fn encode_base_relocations(relocations: &[BaseRelocation]) -> Vec<u8> {
+    let mut sorted = relocations.to_vec();
+    sorted.sort_by_key(|relocation| relocation.rva);
+    sorted.dedup();
+
+    let mut bytes = Vec::new();
+    for page in sorted.chunk_by(|a, b| a.rva / RELOCATION_PAGE_SIZE == b.rva / RELOCATION_PAGE_SIZE) {
+        let page_rva = page[0].rva & !(RELOCATION_PAGE_SIZE - 1);
+        let mut words: Vec<u16> = page
+            .iter()
+            .map(|relocation| (u16::from(relocation.kind) << 12) | (relocation.rva - page_rva) as u16)
+            .collect();
+        if !words.len().is_multiple_of(2) {
+            words.push(u16::from(IMAGE_REL_BASED_ABSOLUTE) << 12);
+        }
+
+        bytes.extend_from_slice(&page_rva.to_le_bytes());
+        bytes.extend_from_slice(&(8 + words.len() as u32 * 2).to_le_bytes());
+        for word in words {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+    bytes
+}
+
+impl PEContext {
+    /// Every base relocation of the image, without the absolute padding
+    /// entries. Images without a relocation directory have none.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed or the relocation blocks are
+    /// malformed.
+    pub fn get_base_relocations(&self) -> Result<Vec<BaseRelocation>, String> {
+        let pe = self.parse()?;
+        let Some(relocation_data) = pe.relocation_data else {
+            return Ok(Vec::new());
+        };
+
+        let mut relocations = Vec::new();
+        for block in relocation_data.blocks() {
+            let block = block.map_err(|e| e.to_string())?;
+            for word in block.words() {
+                let word = word.map_err(|e| e.to_string())?;
+                if word.reloc_type() == IMAGE_REL_BASED_ABSOLUTE {
+                    continue;
+                }
+                relocations.push(BaseRelocation {
+                    rva: block.rva + u32::from(word.offset()),
+                    kind: word.reloc_type(),
+                });
+            }
+        }
+        Ok(relocations)
+    }
+
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed or written.
+    pub fn set_base_relocation_directory(&mut self, rva: u32, size: u32) -> Result<(), String> {
+        self.set_data_directory(IMAGE_DIRECTORY_ENTRY_BASERELOC, rva, size)
+    }
+}
@@ -1,19 +1,45 @@
 use crate::pe::PEContext;
 
 impl PEContext {
-    pub fn create_executable_section(
-        &mut self,
-        name: &str,
-        bytes: &[u8],
-    ) -> Result<(u32, u32), String> {
-        const EXECUTABLE_CHARACTERISTICS: u32 = 0x60000020; // IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_MEM_READ
-        self.create_section(name, bytes.len() as u32, EXECUTABLE_CHARACTERISTICS)
+    /// # Errors
+    ///
+    /// Fails when the section cannot be added to the image.
+    pub fn create_data_section(&mut self, name: &str, bytes: &[u8]) -> Result<(u32, u32), String> {
+        const DATA_CHARACTERISTICS: u32 = 0x4000_0040; // IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ
+        let size = u32::try_from(bytes.len()).map_err(|_| format!("Section {name} is too large"))?;
+        self.create_section(name, size, DATA_CHARACTERISTICS)
             .and_then(|(virtual_address, virtual_size)| {
-                self.write_data_at_rva(virtual_address as u32, bytes)
-                    .map(|_| (virtual_address, virtual_size))
+                self.write_data_at_rva(virtual_address, bytes)
+                    .map(|()| (virtual_address, virtual_size))
             })
     }
 
+    /// # Errors
+    ///
+    /// Fails when the image has no such data directory.
+    pub fn set_data_directory(&mut self, index: usize, rva: u32, size: u32) -> Result<(), String> {
+        let number_of_directories = {
+            let pe = self.parse()?;
+            pe.header
+                .optional_header
+                .ok_or("Missing optional header")?
+                .windows_fields
+                .number_of_rva_and_sizes as usize
+        };
+        if index >= number_of_directories {
+            return Err(format!("Data directory {index} does not exist"));
+        }
+
+        let nt_headers_offset = self.get_nt_headers_offset()?;
+        let directory_offset = nt_headers_offset + 4 + 20 + 112 + index * 8; // NT + COFF + PE32+ offset to data directories
+        self.pe_data[directory_offset..directory_offset + 4].copy_from_slice(&rva.to_le_bytes());
+        self.pe_data[directory_offset + 4..directory_offset + 8].copy_from_slice(&size.to_le_bytes());
+        Ok(())
+    }
+
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed or has no sections.
     pub fn get_next_section_rva(&self) -> Result<u32, String> {
         let pe = self.parse()?;
 
@@ -28,9 +54,8 @@ impl PEContext {
             .windows_fields
             .section_alignment;
 
-        let last_section = match pe.sections.last() {
-            Some(section) => section,
-            None => return Err("PE file has no sections".to_string()),
+        let Some(last_section) = pe.sections.last() else {
+            return Err("PE file has no sections".to_string());
         };
 
         let last_section_end = last_section.virtual_address + last_section.virtual_size;
@@ -40,7 +65,15 @@ impl PEContext {
         Ok(next_rva)
     }
 
-    fn create_section(
+    /// Appends an empty section of `size` bytes and returns its RVA and
+    /// virtual size. The raw data is zeroed and can be filled with
+    /// `write_data_at_rva`.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed or its headers have no room for
+    /// another section.
+    pub fn create_section(
         &mut self,
         name: &str,
         size: u32,
@@ -69,9 +102,8 @@ impl PEContext {
             let section_alignment = optional_header.windows_fields.section_alignment;
             let file_alignment = optional_header.windows_fields.file_alignment;
 
-            let last_section = match pe.sections.last() {
-                Some(section) => section,
-                None => return Err("PE file has no sections".to_string()),
+            let Some(last_section) = pe.sections.last() else {
+                return Err("PE file has no sections".to_string());
             };
 
             let last_section_info = (
@@ -139,7 +171,7 @@ impl PEContext {
 
         // Update number of sections in COFF header
         let num_sections_offset = nt_headers_offset + 4 + 2; // NT signature + machine field
-        let new_num_sections = (num_sections + 1) as u16;
+        let new_num_sections = u16::try_from(num_sections + 1).map_err(|_| "Too many sections".to_string())?;
         self.pe_data[num_sections_offset..num_sections_offset + 2]
             .copy_from_slice(&new_num_sections.to_le_bytes());
 
@@ -215,6 +247,6 @@ impl PEContext {
     }
 }
 
-fn align_up(value: u32, alignment: u32) -> u32 {
+const fn align_up(value: u32, alignment: u32) -> u32 {
     (value + alignment - 1) & !(alignment - 1)
 }
@@ -0,0 +1,237 @@
+use crate::function::ObfuscatorFunction;
+use crate::instruction::InstructionWithId;
+use common::debug;
+use iced_x86::{Decoder, DecoderOptions};
+
+#[derive(Clone, Debug)]
+pub struct RipReference {
+    pub instruction_id: usize,
+    pub original_ip: u64,
+    pub original_target: u64,
+    pub expected_target: u64,
+    pub target_id: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct UnresolvedReference {
+    pub function: String,
+    pub original_ip: u64,
+    pub expected_target: u64,
+    pub actual_target: Option<u64>,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ReferenceReport {
+    pub checked: usize,
+    pub unresolved: Vec<UnresolvedReference>,
+    pub skipped_functions: Vec<String>,
+}
+
+impl ReferenceReport {
+    #[must_use]
+    pub const fn is_clean(&self) -> bool {
+        self.unresolved.is_empty() && self.skipped_functions.is_empty()
+    }
+
+    pub fn merge(&mut self, other: Self) {
+        self.checked += other.checked;
+        self.unresolved.extend(other.unresolved);
+        self.skipped_functions.extend(other.skipped_functions);
+    }
+}
+
+pub struct ReferenceTracker;
+
+impl ReferenceTracker {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self
+    }
+
+    #[must_use]
+    pub fn build_rip_references(&self, instructions: &[InstructionWithId]) -> Vec<RipReference> {
+        instructions
+            .iter()
+            .filter(|inst| inst.instruction.is_ip_rel_memory_operand())
+            .map(|inst| {
+                let target = inst.instruction.ip_rel_memory_address();
+                // Addresses of the function's own instructions (e.g. catch
+                // continuations) have to follow the code when it moves.
+                let target_id = instructions
+                    .iter()
+                    .find(|other| other.instruction.ip() == target)
+                    .map(|other| other.id);
+                debug!(
+                    "RIP-relative reference at {:#x} to {target:#x}{}",
+                    inst.instruction.ip(),
+                    if target_id.is_some() { " (own code)" } else { "" }
+                );
+                RipReference {
+                    instruction_id: inst.id,
+                    original_ip: inst.instruction.ip(),
+                    original_target: target,
+                    expected_target: target,
+                    target_id,
+                }
+            })
+            .collect()
+    }
+
+    /// Decodes every tracked instruction from the encoded bytes and checks
+    /// that its RIP-relative operand still resolves to the expected RVA.
+    #[must_use]
+    pub fn validate(
+        &self,
+        function_name: &str,
+        instructions: &[InstructionWithId],
+        references: &[RipReference],
+        encoded: &[u8],
+        rva: u32,
+    ) -> ReferenceReport {
+        let mut report = ReferenceReport::default();
+
+        for reference in references {
+            report.checked += 1;
+
+            let expected_target = match reference.target_id {
+                Some(target_id) => {
+                    if let Some(target) = instructions.iter().find(|inst| inst.id == target_id) {
+                        target.instruction.ip()
+                    } else {
+                        report.unresolved.push(UnresolvedReference {
+                            function: function_name.to_string(),
+                            original_ip: reference.original_ip,
+                            expected_target: reference.expected_target,
+                            actual_target: None,
+                            reason: "referenced instruction no longer exists".to_string(),
+                        });
+                        continue;
+                    }
+                }
+                None => reference.expected_target,
+            };
+
+            let unresolved = |actual_target: Option<u64>, reason: &str| UnresolvedReference {
+                function: function_name.to_string(),
+                original_ip: reference.original_ip,
+                expected_target,
+                actual_target,
+                reason: reason.to_string(),
+            };
+
+            let Some(inst) = instructions
+                .iter()
+                .find(|inst| inst.id == reference.instruction_id)
+            else {
+                report
+                    .unresolved
+                    .push(unresolved(None, "instruction no longer exists"));
+                continue;
+            };
+
+            let ip = inst.instruction.ip();
+            let Some(offset) = ip
+                .checked_sub(u64::from(rva))
+                .and_then(|offset| usize::try_from(offset).ok())
+                .filter(|&offset| offset < encoded.len())
+            else {
+                report
+                    .unresolved
+                    .push(unresolved(None, "instruction outside encoded block"));
+                continue;
+            };
+
+            let mut decoder = Decoder::with_ip(
+                64,
+                &encoded[offset..],
+                ip,
+                DecoderOptions::NONE,
+            );
+            let instruction = decoder.decode();
+
+            if !instruction.is_ip_rel_memory_operand() {
+                report
+                    .unresolved
+                    .push(unresolved(None, "operand is no longer RIP-relative"));
+                continue;
+            }
+
+            let actual = instruction.ip_rel_memory_address();
+            if actual != expected_target {
+                report
+                    .unresolved
+                    .push(unresolved(Some(actual), "operand resolves to a different RVA"));
+            }
+        }
+
+        report
+    }
+}
+
+impl Default for ReferenceTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ObfuscatorFunction {
+    pub fn build_rip_references(&mut self) {
+        let tracker = ReferenceTracker::new();
+        self.rip_references = tracker.build_rip_references(&self.instructions);
+    }
+
+    /// Records that a pass deliberately moved the RIP-relative operand of an
+    /// instruction by `delta` bytes and compensates for it in code.
+    pub fn shift_rip_reference(&mut self, instruction_id: usize, delta: i64) {
+        if let Some(reference) = self
+            .rip_references
+            .iter_mut()
+            .find(|reference| reference.instruction_id == instruction_id)
+        {
+            reference.expected_target = reference.expected_target.wrapping_add(delta.cast_unsigned());
+        }
+    }
+
+    pub fn references_own_code(&self, instruction_id: usize) -> bool {
+        self.rip_references
+            .iter()
+            .any(|reference| reference.instruction_id == instruction_id && reference.target_id.is_some())
+    }
+
+    /// Points references to the function's own code at the current address
+    /// of their target, which the block encoder then relocates with it.
+    pub fn retarget_code_references(&mut self) {
+        for reference in &self.rip_references {
+            let Some(target_id) = reference.target_id else {
+                continue;
+            };
+            let Some(address) = self
+                .instructions
+                .iter()
+                .find(|inst| inst.id == target_id)
+                .map(|inst| inst.instruction.ip())
+            else {
+                continue;
+            };
+            if let Some(inst) = self
+                .instructions
+                .iter_mut()
+                .find(|inst| inst.id == reference.instruction_id)
+            {
+                inst.instruction.set_memory_displacement64(address);
+            }
+        }
+    }
+
+    pub fn validate_rip_references(&self, encoded: &[u8], rva: u32) -> ReferenceReport {
+        let tracker = ReferenceTracker::new();
+        tracker.validate(
+            &self.name,
+            &self.instructions,
+            &self.rip_references,
+            encoded,
+            rva,
+        )
+    }
+}
@@ -0,0 +1,244 @@
+use crate::format::BinaryImage;
+use crate::function::ObfuscatorFunction;
+use crate::instruction::InstructionWithId;
+use crate::pe::relocation::{BaseRelocation, IMAGE_REL_BASED_DIR64, IMAGE_REL_BASED_HIGHLOW};
+use common::debug;
+use iced_x86::{Decoder, DecoderOptions, Instruction};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelocatedField {
+    Immediate,
+    Displacement,
+}
+
+/// An absolute address embedded in an instruction and covered by a base
+/// relocation. Addresses of the function's own instructions follow them.
+#[derive(Clone, Debug)]
+pub struct AbsoluteReference {
+    pub instruction_id: usize,
+    pub kind: u8,
+    pub field: RelocatedField,
+    pub original_rva: u32,
+    pub value: u64,
+    pub target_id: Option<usize>,
+}
+
+pub struct RelocationTracker {
+    image_base: u64,
+}
+
+impl RelocationTracker {
+    #[must_use]
+    pub const fn new(image_base: u64) -> Self {
+        Self { image_base }
+    }
+
+    /// # Errors
+    ///
+    /// Fails when a base relocation lies in code that is not decoded or covers
+    /// no operand.
+    pub fn collect(
+        &self,
+        image: &dyn BinaryImage,
+        relocations: &[BaseRelocation],
+        instructions: &[InstructionWithId],
+        rva: u32,
+        size: u32,
+    ) -> Result<Vec<AbsoluteReference>, String> {
+        let mut references = Vec::new();
+        for relocation in relocations {
+            if relocation.rva < rva || relocation.rva >= rva + size {
+                continue;
+            }
+            if !matches!(relocation.kind, IMAGE_REL_BASED_DIR64 | IMAGE_REL_BASED_HIGHLOW) {
+                return Err(format!(
+                    "Unsupported base relocation type {} at {:#x}",
+                    relocation.kind, relocation.rva
+                ));
+            }
+
+            let inst = instructions
+                .iter()
+                .find(|inst| {
+                    let ip = inst.instruction.ip();
+                    (ip..inst.instruction.next_ip()).contains(&u64::from(relocation.rva))
+                })
+                .ok_or_else(|| format!(
+                    "Base relocation at {:#x} is outside the decoded code",
+                    relocation.rva
+                ))?;
+
+            let ip = inst.rva();
+            let bytes = image.read_data_at_rva(ip, inst.instruction.len())?;
+            let (field, value) = Self::locate_field(&bytes, ip, *relocation)?;
+
+            let target_rva = value.wrapping_sub(self.image_base);
+            let target_id = instructions
+                .iter()
+                .find(|other| other.instruction.ip() == target_rva)
+                .map(|other| other.id);
+            debug!(
+                "Absolute reference at {:#x} to {value:#x}{}",
+                relocation.rva,
+                if target_id.is_some() { " (own code)" } else { "" }
+            );
+
+            references.push(AbsoluteReference {
+                instruction_id: inst.id,
+                kind: relocation.kind,
+                field,
+                original_rva: relocation.rva,
+                value,
+                target_id,
+            });
+        }
+        Ok(references)
+    }
+
+    /// Finds which operand of the instruction at `ip` the relocation covers.
+    fn locate_field(
+        bytes: &[u8],
+        ip: u32,
+        relocation: BaseRelocation,
+    ) -> Result<(RelocatedField, u64), String> {
+        let mut decoder = Decoder::with_ip(64, bytes, u64::from(ip), DecoderOptions::NONE);
+        let instruction = decoder.decode();
+        let offsets = decoder.get_constant_offsets(&instruction);
+        let offset = (relocation.rva - ip) as usize;
+        let width = relocation.width() as usize;
+
+        let field = if offsets.has_immediate()
+            && offsets.immediate_offset() == offset
+            && offsets.immediate_size() == width
+        {
+            RelocatedField::Immediate
+        } else if offsets.has_displacement()
+            && offsets.displacement_offset() == offset
+            && offsets.displacement_size() == width
+        {
+            RelocatedField::Displacement
+        } else {
+            return Err(format!(
+                "Base relocation at {:#x} does not cover an operand of `{instruction}`",
+                relocation.rva
+            ));
+        };
+
+        let mut value = [0u8; 8];
+        value[..width].copy_from_slice(&bytes[offset..offset + width]);
+        Ok((field, u64::from_le_bytes(value)))
+    }
+
+    /// Locates the relocated operands in the encoded function, points the
+    /// ones naming the function's own code at their new address and returns
+    /// the relocations for the new layout. Instructions must already carry
+    /// their final addresses.
+    ///
+    /// # Errors
+    ///
+    /// Fails when a relocated instruction no longer exists.
+    pub fn relocate(
+        &self,
+        references: &[AbsoluteReference],
+        instructions: &[InstructionWithId],
+        encoded: &mut [u8],
+        rva: u32,
+    ) -> Result<Vec<BaseRelocation>, String> {
+        let find = |id: usize| -> Result<&Instruction, String> {
+            instructions
+                .iter()
+                .find(|inst| inst.id == id)
+                .map(|inst| &inst.instruction)
+                .ok_or_else(|| format!("Relocated instruction {id} no longer exists"))
+        };
+
+        references
+            .iter()
+            .map(|reference| {
+                let ip = find(reference.instruction_id)?.ip();
+                let start = usize::try_from(ip - u64::from(rva))
+                    .map_err(|_| format!("Relocated instruction at {ip:#x} is outside its function"))?;
+                let mut decoder =
+                    Decoder::with_ip(64, &encoded[start..], ip, DecoderOptions::NONE);
+                let instruction = decoder.decode();
+                let offsets = decoder.get_constant_offsets(&instruction);
+                let width = match reference.kind {
+                    IMAGE_REL_BASED_DIR64 => 8,
+                    _ => 4,
+                };
+                let (present, offset, size) = match reference.field {
+                    RelocatedField::Immediate => (
+                        offsets.has_immediate(),
+                        offsets.immediate_offset(),
+                        offsets.immediate_size(),
+                    ),
+                    RelocatedField::Displacement => (
+                        offsets.has_displacement(),
+                        offsets.displacement_offset(),
+                        offsets.displacement_size(),
+                    ),
+                };
+                if !present || size != width {
+                    return Err(format!(
+                        "Operand covered by the relocation at {:#x} changed size",
+                        reference.original_rva
+                    ));
+                }
+
+                let value = match reference.target_id {
+                    Some(target_id) => self.image_base + find(target_id)?.ip(),
+                    None => reference.value,
+                };
+                let at = start + offset;
+                encoded[at..at + width].copy_from_slice(&value.to_le_bytes()[..width]);
+
+                Ok(BaseRelocation {
+                    rva: u32::try_from(at)
+                        .ok()
+                        .and_then(|at| rva.checked_add(at))
+                        .ok_or_else(|| format!("Relocated operand at {ip:#x} is outside the image"))?,
+                    kind: reference.kind,
+                })
+            })
+            .collect()
+    }
+}
+
+impl ObfuscatorFunction {
+    /// # Errors
+    ///
+    /// Fails when the relocations of a function cannot be collected.
+    pub fn collect_base_relocations(
+        &mut self,
+        image: &dyn BinaryImage,
+        relocations: &[BaseRelocation],
+    ) -> Result<(), String> {
+        let tracker = RelocationTracker::new(image.image_base()?);
+        self.absolute_references =
+            tracker.collect(image, relocations, &self.instructions, self.rva, self.size)?;
+        if self.absolute_references.iter().any(|reference| reference.target_id.is_some()) {
+            self.build_cfg();
+        }
+        Ok(())
+    }
+
+    /// Instructions holding a relocated address must keep their operand.
+    pub fn has_absolute_reference(&self, instruction_id: usize) -> bool {
+        self.absolute_references
+            .iter()
+            .any(|reference| reference.instruction_id == instruction_id)
+    }
+
+    /// # Errors
+    ///
+    /// Fails when the relocations cannot be rewritten into the image.
+    pub fn relocate_base_relocations(
+        &self,
+        image_base: u64,
+        encoded: &mut [u8],
+        rva: u32,
+    ) -> Result<Vec<BaseRelocation>, String> {
+        let tracker = RelocationTracker::new(image_base);
+        tracker.relocate(&self.absolute_references, &self.instructions, encoded, rva)
+    }
+}
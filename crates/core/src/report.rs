@@ -0,0 +1,48 @@
+use crate::references::ReferenceReport;
+use crate::symbol_map::SymbolMap;
+use crate::verify::VerificationReport;
+use serde::Serialize;
+
+/// Summary of a run. The seed is enough to reproduce the output from the
+/// same input and configuration.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ObfuscationReport {
+    pub seed: u64,
+    pub functions: usize,
+    pub functions_left_in_place: Vec<String>,
+    pub references_checked: usize,
+    pub references_unresolved: usize,
+    pub strings_encrypted: usize,
+    pub strings_erased: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verification: Option<VerificationReport>,
+}
+
+impl ObfuscationReport {
+    #[must_use]
+    pub fn new(seed: u64, functions: usize, references: &ReferenceReport) -> Self {
+        Self {
+            seed,
+            functions,
+            functions_left_in_place: references.skipped_functions.clone(),
+            references_checked: references.checked,
+            references_unresolved: references.unresolved.len(),
+            strings_encrypted: 0,
+            strings_erased: 0,
+            verification: None,
+        }
+    }
+
+    /// # Errors
+    ///
+    /// Fails when the report cannot be serialized.
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|e| e.to_string())
+    }
+}
+
+pub struct ObfuscationOutput {
+    pub binary: Vec<u8>,
+    pub report: ObfuscationReport,
+    pub symbol_map: SymbolMap,
+}
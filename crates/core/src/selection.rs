@@ -0,0 +1,111 @@
+use crate::config::SelectionConfig;
+use regex::Regex;
+
+/// A function pattern as written in the config or on the command line:
+///
+/// - `rva:0x1000` matches the function containing that RVA
+/// - `rva:0x1000-0x2000` matches functions starting inside the range
+/// - `re:<regex>` matches the demangled name against a regular expression
+/// - anything else is a glob over the whole demangled name (`*` and `?`)
+#[derive(Clone, Debug)]
+pub enum FunctionPattern {
+    Name(Regex),
+    Address(u32),
+    Range { start: u32, end: u32 },
+}
+
+impl FunctionPattern {
+    /// # Errors
+    ///
+    /// Fails when the pattern is not a valid RVA, range or regular expression.
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        if let Some(rva) = pattern.strip_prefix("rva:") {
+            return match rva.split_once('-') {
+                Some((start, end)) => {
+                    let (start, end) = (parse_rva(start)?, parse_rva(end)?);
+                    if start >= end {
+                        return Err(format!("Empty RVA range in pattern '{pattern}'"));
+                    }
+                    Ok(Self::Range { start, end })
+                }
+                None => Ok(Self::Address(parse_rva(rva)?)),
+            };
+        }
+
+        let expression = pattern
+            .strip_prefix("re:")
+            .map_or_else(|| glob_to_regex(pattern), str::to_string);
+        Regex::new(&expression)
+            .map(Self::Name)
+            .map_err(|e| format!("Invalid function pattern '{pattern}': {e}"))
+    }
+
+    #[must_use]
+    pub fn matches(&self, name: &str, rva: u32, size: u32) -> bool {
+        match self {
+            Self::Name(regex) => regex.is_match(name),
+            Self::Address(address) => (rva..rva.saturating_add(size)).contains(address),
+            Self::Range { start, end } => (*start..*end).contains(&rva),
+        }
+    }
+}
+
+fn parse_rva(text: &str) -> Result<u32, String> {
+    let text = text.trim();
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .unwrap_or(text);
+    u32::from_str_radix(digits, 16).map_err(|e| format!("Invalid RVA '{text}': {e}"))
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut expression = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => expression.push_str(".*"),
+            '?' => expression.push('.'),
+            c => expression.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    expression.push('$');
+    expression
+}
+
+/// # Errors
+///
+/// Fails on the first pattern that does not parse.
+pub fn parse_patterns(patterns: &[String]) -> Result<Vec<FunctionPattern>, String> {
+    patterns.iter().map(|pattern| FunctionPattern::parse(pattern)).collect()
+}
+
+#[must_use]
+pub fn matches_any(patterns: &[FunctionPattern], name: &str, rva: u32, size: u32) -> bool {
+    patterns.iter().any(|pattern| pattern.matches(name, rva, size))
+}
+
+/// Functions picked for obfuscation: those matching an include pattern (or
+/// all of them when there is none) and no exclude pattern.
+#[derive(Clone, Debug, Default)]
+pub struct FunctionSelection {
+    include: Vec<FunctionPattern>,
+    exclude: Vec<FunctionPattern>,
+}
+
+impl FunctionSelection {
+    /// # Errors
+    ///
+    /// Fails when a pattern does not parse.
+    pub fn new(config: &SelectionConfig) -> Result<Self, String> {
+        Ok(Self {
+            include: parse_patterns(&config.include)?,
+            exclude: parse_patterns(&config.exclude)?,
+        })
+    }
+
+    #[must_use]
+    pub fn is_selected(&self, name: &str, rva: u32, size: u32) -> bool {
+        (self.include.is_empty() || matches_any(&self.include, name, rva, size))
+            && !matches_any(&self.exclude, name, rva, size)
+    }
+}
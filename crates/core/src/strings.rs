@@ -0,0 +1,689 @@
+use crate::branches::BranchInfo;
+use crate::config::StringEncryptionConfig;
+use crate::function::{ObfuscatorFunction, StateManaged};
+use crate::instruction::{InstructionContext, InstructionWithId};
+use crate::liveness::{GPRS, LiveSet, STATUS_FLAGS, register_of_width};
+use crate::passes::PassRng;
+use crate::format::{BinaryImage, SectionAccess};
+use crate::references::RipReference;
+use common::{debug, info};
+use iced_x86::{Code, Decoder, DecoderOptions, Instruction, MemoryOperand, OpKind, Register};
+use rand::Rng;
+use rand::seq::SliceRandom;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::rc::Rc;
+
+const SECTION_NAME: &str = ".vstr";
+
+const MAX_STRING_SIZE: usize = 0x1000;
+const RECORD_ALIGNMENT: u32 = 8;
+
+/// A string moved to the string section. Its record holds the encrypted
+/// bytes, a buffer of the same size receiving the plaintext and a flag set
+/// once the buffer has been filled.
+struct EncryptedString {
+    rva: u32,
+    size: u32,
+    record_offset: u32,
+    key: u32,
+    multiplier: u32,
+    increment: u32,
+    rotation: u32,
+    /// Original RVAs of the functions referencing the string.
+    functions: Vec<u32>,
+    /// Nothing but the rewritten instructions reads the original bytes.
+    erasable: bool,
+}
+
+impl EncryptedString {
+    const fn cipher_rva(&self, section_rva: u32) -> u32 {
+        section_rva + self.record_offset
+    }
+
+    const fn plaintext_rva(&self, section_rva: u32) -> u32 {
+        self.cipher_rva(section_rva) + self.size
+    }
+
+    const fn flag_rva(&self, section_rva: u32) -> u32 {
+        self.plaintext_rva(section_rva) + self.size
+    }
+
+    const fn record_size(&self) -> u32 {
+        (self.size * 2 + 1).next_multiple_of(RECORD_ALIGNMENT)
+    }
+
+    /// XORs every byte with the low byte of a key that is stepped through
+    /// `rol(key * multiplier + increment, rotation)`, as the stub does.
+    fn apply_keystream(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut key = self.key;
+        bytes
+            .iter()
+            .map(|&byte| {
+                let encrypted = byte ^ key.to_le_bytes()[0];
+                key = key
+                    .wrapping_mul(self.multiplier)
+                    .wrapping_add(self.increment)
+                    .rotate_left(self.rotation);
+                encrypted
+            })
+            .collect()
+    }
+}
+
+/// A `lea reg, [rip+string]` in an analyzed function.
+struct StringReference {
+    function: usize,
+    instruction_id: usize,
+    target: u32,
+}
+
+/// Moves the strings loaded by the analyzed functions into an encrypted,
+/// writable section.
+///
+/// A stub in front of every load decrypts the string into its buffer on
+/// first use. Filling the buffer is idempotent, so threads racing through
+/// the stub only repeat the work.
+pub struct StringEncryptionContext {
+    image: Rc<RefCell<dyn BinaryImage>>,
+    config: StringEncryptionConfig,
+    strings: Vec<EncryptedString>,
+}
+
+impl StringEncryptionContext {
+    pub fn new(image: Rc<RefCell<dyn BinaryImage>>, config: StringEncryptionConfig) -> Self {
+        Self {
+            image,
+            config,
+            strings: Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub const fn encrypted_count(&self) -> usize {
+        self.strings.len()
+    }
+
+    /// # Errors
+    ///
+    /// Fails when a string cannot be read or its section cannot be added to the
+    /// image.
+    pub fn encrypt(&mut self, functions: &mut [ObfuscatorFunction], rng: &mut PassRng) -> Result<(), String> {
+        let (references, sizes) = self.collect_references(functions)?;
+        if references.is_empty() {
+            info!("String encryption: no string references found");
+            return Ok(());
+        }
+
+        let rewritten: HashSet<u64> = references
+            .iter()
+            .filter_map(|reference| {
+                functions[reference.function]
+                    .instructions
+                    .iter()
+                    .find(|inst| inst.id == reference.instruction_id)
+                    .map(|inst| inst.instruction.ip())
+            })
+            .collect();
+        let erasable = if self.config.erase_plaintext {
+            self.erasable_strings(&sizes, &rewritten)?
+        } else {
+            HashSet::new()
+        };
+
+        let mut record_offset = 0;
+        for (&rva, &size) in &sizes {
+            let mut referencing: Vec<u32> = references
+                .iter()
+                .filter(|reference| reference.target == rva)
+                .map(|reference| functions[reference.function].get_original_rva())
+                .collect();
+            referencing.dedup();
+            let string = EncryptedString {
+                rva,
+                size,
+                record_offset,
+                key: rng.random(),
+                multiplier: rng.random::<u32>() | 1,
+                increment: rng.random(),
+                rotation: rng.random_range(1..32),
+                functions: referencing,
+                erasable: erasable.contains(&rva),
+            };
+            record_offset += string.record_size();
+            self.strings.push(string);
+        }
+
+        let mut section = vec![0u8; record_offset as usize];
+        {
+            let image = self.image.borrow();
+            for string in &self.strings {
+                let plaintext = image.read_data_at_rva(string.rva, string.size as usize)?;
+                let start = string.record_offset as usize;
+                section[start..start + string.size as usize]
+                    .copy_from_slice(&string.apply_keystream(&plaintext));
+            }
+        }
+
+        let (section_rva, _) = self
+            .image
+            .borrow_mut()
+            .add_section_with(SECTION_NAME, &section, SectionAccess::ReadWrite)
+            .map_err(|e| format!("Failed to create string section: {e}"))?;
+
+        for (index, function) in functions.iter_mut().enumerate() {
+            let targets: BTreeMap<usize, u32> = references
+                .iter()
+                .filter(|reference| reference.function == index)
+                .map(|reference| (reference.instruction_id, reference.target))
+                .collect();
+            if !targets.is_empty() {
+                self.insert_stubs(function, &targets, section_rva, rng)?;
+            }
+        }
+
+        info!(
+            "String encryption: {} strings moved to {SECTION_NAME} at {section_rva:#x}, {} loads rewritten, {} can be erased",
+            self.strings.len(),
+            references.len(),
+            self.strings.iter().filter(|string| string.erasable).count()
+        );
+        Ok(())
+    }
+
+    /// Clears the original bytes of strings whose readers have all been
+    /// relocated. Must run after compilation, which decides which functions
+    /// are left in place with their original code.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the original strings cannot be overwritten.
+    pub fn erase_plaintext(&self, functions: &[ObfuscatorFunction]) -> Result<usize, String> {
+        if self.strings.is_empty() {
+            return Ok(0);
+        }
+        let relocated: HashSet<u32> = functions
+            .iter()
+            .filter(|function| function.is_relocated())
+            .map(StateManaged::get_original_rva)
+            .collect();
+
+        // Merged strings share bytes, so an overlapping string that has to
+        // stay keeps the others in place as well.
+        let kept: Vec<&EncryptedString> = self
+            .strings
+            .iter()
+            .filter(|string| {
+                !string.erasable || !string.functions.iter().all(|rva| relocated.contains(rva))
+            })
+            .collect();
+
+        let mut image = self.image.borrow_mut();
+        let mut erased = 0;
+        for string in &self.strings {
+            let end = string.rva + string.size;
+            if kept
+                .iter()
+                .any(|other| other.rva < end && string.rva < other.rva + other.size)
+            {
+                continue;
+            }
+            image.write_data_at_rva(string.rva, &vec![0; string.size as usize])?;
+            erased += 1;
+        }
+        info!("String encryption: erased {erased} of {} original strings", self.strings.len());
+        Ok(erased)
+    }
+
+    fn collect_references(
+        &self,
+        functions: &[ObfuscatorFunction],
+    ) -> Result<(Vec<StringReference>, BTreeMap<u32, u32>), String> {
+        let image = self.image.borrow();
+        let scanner = StringScanner::new(&*image, self.config.min_length)?;
+        let mut references = Vec::new();
+        let mut sizes = BTreeMap::new();
+        for (index, function) in functions.iter().enumerate() {
+            for inst in &function.instructions {
+                let instruction = &inst.instruction;
+                if instruction.code() != Code::Lea_r64_m
+                    || !instruction.is_ip_rel_memory_operand()
+                    || function.unwind.is_frame_instruction(inst.id)
+                    || function.references_own_code(inst.id)
+                {
+                    continue;
+                }
+                let Ok(target) = u32::try_from(instruction.ip_rel_memory_address()) else {
+                    continue;
+                };
+                let Some(size) = scanner.string_size(&*image, target) else {
+                    continue;
+                };
+                debug!(
+                    "String reference at {:#x} in {} to {target:#x} ({size} bytes)",
+                    instruction.ip(),
+                    function.name
+                );
+                sizes.insert(target, size);
+                references.push(StringReference {
+                    function: index,
+                    instruction_id: inst.id,
+                    target,
+                });
+            }
+        }
+        Ok((references, sizes))
+    }
+
+    /// Strings that no code besides the rewritten loads and no pointer of
+    /// the image points into. Code is found by sweeping the executable
+    /// regions and every function of the unwind information, which may only
+    /// add references that do not exist. Absolute operands count as well,
+    /// as executables without relocations load addresses as constants.
+    fn erasable_strings(&self, sizes: &BTreeMap<u32, u32>, rewritten: &HashSet<u64>) -> Result<HashSet<u32>, String> {
+        let image = self.image.borrow();
+        let image_base = image.image_base()?;
+        let mut targets: Vec<u64> = Vec::new();
+
+        let mut sweep = |rva: u32, size: u32| -> Result<(), String> {
+            let bytes = image.read_data_at_rva(rva, size as usize)?;
+            let mut decoder = Decoder::with_ip(64, &bytes, u64::from(rva), DecoderOptions::NONE);
+            for instruction in &mut decoder {
+                if instruction.is_ip_rel_memory_operand() {
+                    if !rewritten.contains(&instruction.ip()) {
+                        targets.push(instruction.ip_rel_memory_address());
+                    }
+                    continue;
+                }
+                for operand in 0..instruction.op_count() {
+                    let value = match instruction.op_kind(operand) {
+                        OpKind::Immediate32 | OpKind::Immediate32to64 | OpKind::Immediate64 => {
+                            instruction.immediate(operand)
+                        }
+                        OpKind::Memory if instruction.memory_base() == Register::None => {
+                            instruction.memory_displacement64()
+                        }
+                        _ => continue,
+                    };
+                    targets.push(value.wrapping_sub(image_base));
+                }
+            }
+            Ok(())
+        };
+        for region in image.regions()?.iter().filter(|region| region.executable) {
+            sweep(region.rva, region.loaded_size())?;
+        }
+        for function in image.function_ranges()? {
+            sweep(function.start, function.end - function.start)?;
+        }
+
+        targets.extend(image.pointer_targets()?.into_iter().map(u64::from));
+
+        // Merged strings are erased together, so a reference into any of
+        // them keeps all overlapping strings.
+        let mut clusters: Vec<(u32, u32, Vec<u32>)> = Vec::new();
+        for (&rva, &size) in sizes {
+            match clusters.last_mut() {
+                Some((_, end, members)) if rva < *end => {
+                    *end = (*end).max(rva + size);
+                    members.push(rva);
+                }
+                _ => clusters.push((rva, rva + size, vec![rva])),
+            }
+        }
+        Ok(clusters
+            .into_iter()
+            .filter(|(start, end, _)| {
+                !targets
+                    .iter()
+                    .any(|&target| target >= u64::from(*start) && target < u64::from(*end))
+            })
+            .flat_map(|(_, _, members)| members)
+            .collect())
+    }
+
+    fn insert_stubs(
+        &self,
+        function: &mut ObfuscatorFunction,
+        targets: &BTreeMap<usize, u32>,
+        section_rva: u32,
+        rng: &mut PassRng,
+    ) -> Result<(), String> {
+        let liveness = function.analyze_liveness();
+        let context = function.instruction_context.clone();
+        let mut result = Vec::with_capacity(function.instructions.len());
+        let mut branches = Vec::new();
+        let mut references = Vec::new();
+        let mut moved = Vec::new();
+
+        for (index, instruction) in function.instructions.iter().enumerate() {
+            let Some(&target) = targets.get(&instruction.id) else {
+                result.push(instruction.clone());
+                continue;
+            };
+            let string = self
+                .strings
+                .iter()
+                .find(|string| string.rva == target)
+                .ok_or("String record not found")?;
+
+            // The stub takes over the ID so branches into the load run it.
+            let load_id = context.next_id();
+            let stub = Self::decryption_stub(
+                string,
+                section_rva,
+                liveness.live_in(index),
+                function.red_zone,
+                &context,
+                load_id,
+                rng,
+            )?;
+            let mut stub_instructions = stub.instructions;
+            stub_instructions[0].set_id(instruction.id);
+            for (id, target) in stub.references {
+                let id = if id == stub.first_id { instruction.id } else { id };
+                references.push(RipReference {
+                    instruction_id: id,
+                    original_ip: instruction.instruction.ip(),
+                    original_target: u64::from(target),
+                    expected_target: u64::from(target),
+                    target_id: None,
+                });
+            }
+            for mut branch in stub.branches {
+                if branch.source_id == stub.first_id {
+                    branch.source_id = instruction.id;
+                }
+                branches.push(branch);
+            }
+            result.extend(stub_instructions);
+
+            let mut load = instruction.clone();
+            load.set_id(load_id);
+            load.instruction
+                .set_memory_displacement64(u64::from(string.plaintext_rva(section_rva)));
+            moved.push((instruction.id, load_id, i64::from(string.plaintext_rva(section_rva)) - i64::from(target)));
+            result.push(load);
+        }
+
+        for (old_id, new_id, delta) in moved {
+            function.shift_rip_reference(old_id, delta);
+            if let Some(reference) = function
+                .rip_references
+                .iter_mut()
+                .find(|reference| reference.instruction_id == old_id)
+            {
+                reference.instruction_id = new_id;
+            }
+        }
+        function.rip_references.extend(references);
+        function.branch_map.extend(branches);
+        function.instructions = result;
+        function.instruction_context = context;
+        // The passes' liveness analysis walks the CFG, which must cover the stubs.
+        function.build_cfg();
+        Ok(())
+    }
+
+    /// Saves `saved` and, with `save_flags`, the flags on the stack below
+    /// the red zone, and restores them.
+    fn save_state(
+        saved: &[Register],
+        save_flags: bool,
+        red_zone: u16,
+    ) -> Result<(Vec<Instruction>, Vec<Instruction>), String> {
+        let e = |e: iced_x86::IcedError| e.to_string();
+        let step_over = |bytes: i64| {
+            Instruction::with2(Code::Lea_r64_m, Register::RSP, MemoryOperand::with_base_displ(Register::RSP, bytes))
+                .map_err(e)
+        };
+        let step = if save_flags || !saved.is_empty() { i64::from(red_zone) } else { 0 };
+        let mut prologue = Vec::new();
+        if step > 0 {
+            prologue.push(step_over(-step)?);
+        }
+        if save_flags {
+            prologue.push(Instruction::with(Code::Pushfq));
+        }
+        for &register in saved {
+            prologue.push(Instruction::with1(Code::Push_r64, register).map_err(e)?);
+        }
+        let mut epilogue: Vec<Instruction> = saved
+            .iter()
+            .rev()
+            .map(|&register| Instruction::with1(Code::Pop_r64, register))
+            .collect::<Result<_, _>>()
+            .map_err(e)?;
+        if save_flags {
+            epilogue.push(Instruction::with(Code::Popfq));
+        }
+        if step > 0 {
+            epilogue.push(step_over(step)?);
+        }
+        Ok((prologue, epilogue))
+    }
+
+    /// Builds the code run before a load of `string`:
+    ///
+    /// ```text
+    ///     [lea rsp, [rsp-red zone] / pushfq / push saved registers]
+    ///     cmp byte [rip+flag], 0
+    ///     jne restore
+    ///     lea source, [rip+cipher]
+    ///     xor index, index
+    ///     mov key, <key>
+    /// next:
+    ///     movzx byte, byte [source+index]
+    ///     xor byte, key
+    ///     mov byte [source+index+size], byte
+    ///     imul key, key, <multiplier>
+    ///     add key, <increment>
+    ///     rol key, <rotation>
+    ///     inc index
+    ///     cmp index, <size>
+    ///     jb next
+    ///     mov byte [rip+flag], 1
+    /// restore:
+    ///     [pop saved registers / popfq / lea rsp, [rsp+red zone]]
+    /// ```
+    ///
+    /// Registers dead before the load are used first, the others and the
+    /// status flags, if live, are saved on the stack below the red zone.
+    fn decryption_stub(
+        string: &EncryptedString,
+        section_rva: u32,
+        live: LiveSet,
+        red_zone: u16,
+        context: &InstructionContext,
+        continuation_id: usize,
+        rng: &mut PassRng,
+    ) -> Result<Stub, String> {
+        let e = |e: iced_x86::IcedError| e.to_string();
+        let mut dead: Vec<Register> = live.dead_registers().collect();
+        dead.shuffle(rng);
+        let mut others: Vec<Register> = GPRS
+            .iter()
+            .copied()
+            .filter(|&register| register != Register::RSP && !dead.contains(&register))
+            .collect();
+        others.shuffle(rng);
+        let registers: Vec<Register> = dead.iter().chain(others.iter()).copied().take(4).collect();
+        let saved: Vec<Register> = registers
+            .iter()
+            .copied()
+            .filter(|register| !dead.contains(register))
+            .collect();
+        let save_flags = live.is_flag_live(STATUS_FLAGS);
+
+        let (source, index, key, byte) = (registers[0], registers[1], registers[2], registers[3]);
+        let index32 = register_of_width(index, 32);
+        let key32 = register_of_width(key, 32);
+        let flag = MemoryOperand::with_base_displ(Register::RIP, i64::from(string.flag_rva(section_rva)));
+        let element = MemoryOperand::with_base_index(source, index);
+        let plaintext = MemoryOperand::with_base_index_scale_displ_size(source, index, 1, i64::from(string.size), 1);
+
+        let (prologue, epilogue) = Self::save_state(&saved, save_flags, red_zone)?;
+
+        let mut stub = Stub::default();
+        for instruction in prologue {
+            stub.push(context, instruction)?;
+        }
+        stub.push_referencing(
+            context,
+            Instruction::with2(Code::Cmp_rm8_imm8, flag, 0).map_err(e)?,
+            string.flag_rva(section_rva),
+        )?;
+        let skip = stub.push(context, Instruction::with_branch(Code::Jne_rel32_64, 0).map_err(e)?)?;
+        stub.push_referencing(
+            context,
+            Instruction::with2(
+                Code::Lea_r64_m,
+                source,
+                MemoryOperand::with_base_displ(Register::RIP, i64::from(string.cipher_rva(section_rva))),
+            )
+            .map_err(e)?,
+            string.cipher_rva(section_rva),
+        )?;
+        stub.push(context, Instruction::with2(Code::Xor_r32_rm32, index32, index32).map_err(e)?)?;
+        stub.push(context, Instruction::with2(Code::Mov_r32_imm32, key32, string.key).map_err(e)?)?;
+        let next = stub.push(
+            context,
+            Instruction::with2(Code::Movzx_r32_rm8, register_of_width(byte, 32), element).map_err(e)?,
+        )?;
+        stub.push(
+            context,
+            Instruction::with2(Code::Xor_r8_rm8, register_of_width(byte, 8), register_of_width(key, 8)).map_err(e)?,
+        )?;
+        stub.push(
+            context,
+            Instruction::with2(Code::Mov_rm8_r8, plaintext, register_of_width(byte, 8)).map_err(e)?,
+        )?;
+        stub.push(
+            context,
+            Instruction::with3(Code::Imul_r32_rm32_imm32, key32, key32, string.multiplier).map_err(e)?,
+        )?;
+        stub.push(context, Instruction::with2(Code::Add_rm32_imm32, key32, string.increment).map_err(e)?)?;
+        stub.push(context, Instruction::with2(Code::Rol_rm32_imm8, key32, string.rotation).map_err(e)?)?;
+        stub.push(context, Instruction::with1(Code::Inc_rm32, index32).map_err(e)?)?;
+        stub.push(context, Instruction::with2(Code::Cmp_rm32_imm32, index32, string.size).map_err(e)?)?;
+        let repeat = stub.push(context, Instruction::with_branch(Code::Jb_rel32_64, 0).map_err(e)?)?;
+        stub.push_referencing(
+            context,
+            Instruction::with2(Code::Mov_rm8_imm8, flag, 1).map_err(e)?,
+            string.flag_rva(section_rva),
+        )?;
+        let mut restore = continuation_id;
+        for (position, instruction) in epilogue.into_iter().enumerate() {
+            let id = stub.push(context, instruction)?;
+            if position == 0 {
+                restore = id;
+            }
+        }
+
+        stub.branch(skip, restore);
+        stub.branch(repeat, next);
+        Ok(stub)
+    }
+}
+
+/// Finds strings in the read-only data of the image, leaving out anything
+/// the loader reads.
+struct StringScanner {
+    sections: Vec<(u32, u32)>,
+    directories: Vec<(u32, u32)>,
+    min_length: usize,
+}
+
+impl StringScanner {
+    fn new(image: &dyn BinaryImage, min_length: usize) -> Result<Self, String> {
+        let sections = image
+            .regions()?
+            .iter()
+            .filter(|region| region.constant_data)
+            .map(|region| (region.rva, region.rva + region.loaded_size()))
+            .collect();
+        let directories = image
+            .loader_data()?
+            .into_iter()
+            .map(|range| (range.start, range.end))
+            .collect();
+        Ok(Self {
+            sections,
+            directories,
+            min_length,
+        })
+    }
+
+    /// Size including the terminator of the NUL-terminated ASCII or UTF-16
+    /// string at `rva` with at least `min_length` printable characters.
+    #[allow(clippy::cast_possible_truncation, reason = "strings are at most MAX_STRING_SIZE bytes long")]
+    fn string_size(&self, image: &dyn BinaryImage, rva: u32) -> Option<u32> {
+        let &(_, end) = self
+            .sections
+            .iter()
+            .find(|&&(start, end)| rva >= start && rva < end)?;
+        if self
+            .directories
+            .iter()
+            .any(|&(start, end)| rva >= start && rva < end)
+        {
+            return None;
+        }
+
+        let available = ((end - rva) as usize).min(MAX_STRING_SIZE);
+        let bytes = image.read_data_at_rva(rva, available).ok()?;
+        let printable = |c: u16| (0x20..0x7f).contains(&c) || matches!(c, 0x09 | 0x0a | 0x0d);
+
+        let ascii = bytes.iter().position(|&byte| byte == 0).filter(|&length| {
+            length >= self.min_length && bytes[..length].iter().all(|&byte| printable(u16::from(byte)))
+        });
+        if let Some(length) = ascii {
+            return Some(length as u32 + 1);
+        }
+
+        if !rva.is_multiple_of(2) {
+            return None;
+        }
+        let units: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .collect();
+        let length = units.iter().position(|&unit| unit == 0)?;
+        (length >= self.min_length && units[..length].iter().all(|&unit| printable(unit)))
+            .then_some((length as u32 + 1) * 2)
+    }
+}
+
+#[derive(Default)]
+struct Stub {
+    instructions: Vec<InstructionWithId>,
+    branches: Vec<BranchInfo>,
+    references: Vec<(usize, u32)>,
+    first_id: usize,
+}
+
+impl Stub {
+    fn push(&mut self, context: &InstructionContext, instruction: Instruction) -> Result<usize, String> {
+        let instruction = context.create_encoded(instruction)?;
+        let id = instruction.id;
+        if self.instructions.is_empty() {
+            self.first_id = id;
+        }
+        self.instructions.push(instruction);
+        Ok(id)
+    }
+
+    fn push_referencing(&mut self, context: &InstructionContext, instruction: Instruction, target: u32) -> Result<usize, String> {
+        let id = self.push(context, instruction)?;
+        self.references.push((id, target));
+        Ok(id)
+    }
+
+    fn branch(&mut self, source_id: usize, target_id: usize) {
+        self.branches.push(BranchInfo {
+            source_id,
+            target_id,
+            original_target: 0,
+        });
+    }
+}
@@ -0,0 +1,215 @@
+use crate::function::{ObfuscatorFunction, StateManaged};
+use crate::pdb::SourceLine;
+use crate::format::BinaryImage;
+use iced_x86::{Decoder, DecoderOptions};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Where the code of the obfuscated image came from, for symbolicating
+/// addresses in the relocated functions.
+///
+/// It is written next to the image rather than into it. Addresses outside
+/// of it did not move, so the original PDB still describes them.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SymbolMap {
+    pub image_base: u64,
+    /// Source files, referred to by index from the ranges.
+    pub files: Vec<String>,
+    pub functions: Vec<MappedFunction>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MappedFunction {
+    pub name: String,
+    pub original_rva: u32,
+    pub original_size: u32,
+    /// Relocated code, sorted by RVA.
+    pub ranges: Vec<MappedRange>,
+    /// Interpreter and bytecode of the function's virtual machine.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vm: Option<MappedRegion>,
+}
+
+/// Relocated instructions that all stem from the original instruction at
+/// `original_rva`. Instructions added by the passes belong to the original
+/// instruction they follow.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MappedRange {
+    pub rva: u32,
+    pub size: u32,
+    pub original_rva: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MappedRegion {
+    pub rva: u32,
+    pub size: u32,
+}
+
+/// An address of the obfuscated image in terms of the original one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location<'a> {
+    pub function: &'a str,
+    pub original_rva: u32,
+    pub file: Option<&'a str>,
+    pub line: Option<u32>,
+}
+
+impl SymbolMap {
+    /// Maps the relocated functions of the compiled `image`.
+    /// `lines` are the original source lines, sorted by RVA.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed or a relocated instruction lies
+    /// outside it.
+    pub fn new(
+        image: &dyn BinaryImage,
+        functions: &[ObfuscatorFunction],
+        lines: &[SourceLine],
+    ) -> Result<Self, String> {
+        let mut map = Self {
+            image_base: image.image_base()?,
+            ..Self::default()
+        };
+        let regions = image.regions()?;
+        let mut sections = Vec::with_capacity(regions.len());
+        for region in &regions {
+            let bytes = image
+                .data()
+                .get(region.file.clone())
+                .ok_or_else(|| format!("Section {} is outside the file", region.name))?;
+            sections.push((region.rva, bytes));
+        }
+        let mut file_indices = HashMap::new();
+        for func in functions.iter().filter(|f| f.is_relocated()) {
+            let mut ranges = map_instructions(&sections, func)?;
+            for range in &mut ranges {
+                if let Some(line) = line_at(lines, range.original_rva) {
+                    let next = map.files.len();
+                    let index = *file_indices.entry(line.file.as_str()).or_insert(next);
+                    if index == next {
+                        map.files.push(line.file.clone());
+                    }
+                    range.file = Some(index);
+                    range.line = Some(line.line);
+                }
+            }
+            let vm = func
+                .vm
+                .as_ref()
+                .map(|program| -> Result<MappedRegion, String> {
+                    let bytecode_size = u32::try_from(program.bytecode_size())
+                        .map_err(|_| format!("VM bytecode of {} is too large", func.name))?;
+                    Ok(MappedRegion {
+                        rva: program.interpreter_rva,
+                        size: program.bytecode_rva - program.interpreter_rva + bytecode_size,
+                    })
+                })
+                .transpose()?;
+            map.functions.push(MappedFunction {
+                name: func.name.clone(),
+                original_rva: func.get_original_rva(),
+                original_size: func.get_original_size(),
+                ranges,
+                vm,
+            });
+        }
+        Ok(map)
+    }
+
+    pub fn resolve(&self, rva: u32) -> Option<Location<'_>> {
+        for func in &self.functions {
+            let index = func.ranges.partition_point(|range| range.rva <= rva);
+            if let Some(range) = index.checked_sub(1).map(|index| &func.ranges[index])
+                && rva - range.rva < range.size
+            {
+                return Some(Location {
+                    function: &func.name,
+                    original_rva: range.original_rva,
+                    file: range.file.and_then(|file| self.files.get(file)).map(String::as_str),
+                    line: range.line,
+                });
+            }
+            if let Some(vm) = &func.vm
+                && rva.wrapping_sub(vm.rva) < vm.size
+            {
+                return Some(Location {
+                    function: &func.name,
+                    original_rva: func.original_rva,
+                    file: None,
+                    line: None,
+                });
+            }
+        }
+        None
+    }
+
+    /// # Errors
+    ///
+    /// Fails when the map cannot be serialized.
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|e| e.to_string())
+    }
+
+    /// # Errors
+    ///
+    /// Fails when the text is not a valid symbol map.
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| e.to_string())
+    }
+}
+
+/// Attributes every relocated instruction of `func` to an original one,
+/// merging neighbours with the same origin. The compiler leaves the final
+/// address of each instruction in its IP; the lengths are decoded from the
+/// image, as the encoder may have picked other branch sizes.
+#[allow(clippy::cast_possible_truncation, reason = "instructions are decoded at their RVAs and at most 15 bytes long")]
+fn map_instructions(sections: &[(u32, &[u8])], func: &ObfuscatorFunction) -> Result<Vec<MappedRange>, String> {
+    let original = func.get_original_instructions()?;
+    let mut origin = func.get_original_rva();
+    let mut placed = Vec::with_capacity(func.instructions.len());
+    for inst in &func.instructions {
+        // Decoding numbered the original instructions in order.
+        if let Some(original) = original.get(inst.id) {
+            origin = original.ip() as u32;
+        }
+        placed.push((inst.rva(), origin));
+    }
+    placed.sort_by_key(|&(rva, _)| rva);
+
+    let mut ranges: Vec<MappedRange> = Vec::new();
+    for (rva, original_rva) in placed {
+        let bytes = sections
+            .iter()
+            .find(|(start, bytes)| (rva.wrapping_sub(*start) as usize) < bytes.len())
+            .map(|(start, bytes)| &bytes[(rva - start) as usize..])
+            .ok_or_else(|| format!("Instruction of {} at {rva:#x} is outside the image", func.name))?;
+        let length = Decoder::with_ip(64, bytes, u64::from(rva), DecoderOptions::NONE)
+            .decode()
+            .len() as u32;
+        match ranges.last_mut() {
+            Some(last) if last.original_rva == original_rva && last.rva + last.size == rva => {
+                last.size += length;
+            }
+            _ => ranges.push(MappedRange {
+                rva,
+                size: length,
+                original_rva,
+                file: None,
+                line: None,
+            }),
+        }
+    }
+    Ok(ranges)
+}
+
+fn line_at(lines: &[SourceLine], rva: u32) -> Option<&SourceLine> {
+    let index = lines.partition_point(|line| line.rva <= rva).checked_sub(1)?;
+    let line = &lines[index];
+    (line.size == 0 || rva - line.rva < line.size).then_some(line)
+}
@@ -0,0 +1,624 @@
+use crate::exceptions::{BlobPointer, CodeAddress, DataBlob, ExceptionHandler, HandlerKind};
+use crate::function::ObfuscatorFunction;
+use crate::instruction::InstructionWithId;
+use crate::elf::cfi::{FrameState, encode_advance};
+use crate::elf::eh_frame::FrameEntry;
+use crate::pe::PEContext;
+use crate::pe::exception::{UNW_FLAG_CHAININFO, UWOP_EPILOG, UnwindInfo};
+use common::debug;
+use goblin::pe::exception::RuntimeFunction;
+use iced_x86::{Code, FlowControl, Mnemonic, OpKind, Register};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+/// A position inside the function that follows the instruction it names
+/// through mutation and re-encoding. `None` stands for the function end.
+pub type Anchor = Option<usize>;
+
+#[derive(Clone, Debug)]
+pub struct UnwindRegion {
+    pub original: RuntimeFunction,
+    pub info: UnwindInfo,
+    pub begin_id: usize,
+    pub end: Anchor,
+    pub prolog_end: Anchor,
+    pub code_anchors: Vec<Anchor>,
+    pub chained_parent: Option<usize>,
+    pub handler: Option<ExceptionHandler>,
+}
+
+/// The call frame table of an ELF function, by instruction.
+#[derive(Clone, Debug)]
+pub struct CallFrames {
+    states: Vec<FrameState>,
+    /// Index into `states` of the state at each original instruction.
+    original_states: Vec<usize>,
+}
+
+impl CallFrames {
+    /// The state after the original instruction `id` has run.
+    fn state_after(&self, id: usize) -> usize {
+        self.original_states
+            .get(id + 1)
+            .or_else(|| self.original_states.get(id))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FunctionUnwind {
+    pub regions: Vec<UnwindRegion>,
+    pub frames: Option<CallFrames>,
+    frame_instructions: HashSet<usize>,
+}
+
+impl FunctionUnwind {
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.regions.is_empty() && self.frames.is_none()
+    }
+
+    /// Unwind entries the function is described by.
+    #[must_use]
+    pub const fn region_count(&self) -> usize {
+        self.regions.len() + self.frames.is_some() as usize
+    }
+
+    /// Prologue and epilogue instructions are described by the unwind codes
+    /// (or recognised by the OS unwinder) and must be kept as they are.
+    #[must_use]
+    pub fn is_frame_instruction(&self, instruction_id: usize) -> bool {
+        self.frame_instructions.contains(&instruction_id)
+    }
+
+    #[must_use]
+    pub fn has_handlers(&self) -> bool {
+        self.regions.iter().any(|region| region.handler.is_some())
+    }
+
+    /// Index of the first instruction that can be moved away from the entry
+    /// without invalidating the unwind information, i.e. the first one past
+    /// the prolog and every unwind code. `None` when the function has
+    /// several regions or handler data, whose ranges must stay contiguous.
+    #[must_use]
+    pub fn movable_from(&self, instructions: &[InstructionWithId]) -> Option<usize> {
+        let [region] = self.regions.as_slice() else {
+            return self.regions.is_empty().then_some(1);
+        };
+        if region.handler.is_some()
+            || region.info.chained.is_some()
+            || region.end.is_some()
+            || instructions.first().map(|inst| inst.id) != Some(region.begin_id)
+        {
+            return None;
+        }
+
+        region
+            .code_anchors
+            .iter()
+            .chain([&region.prolog_end])
+            .try_fold(1, |limit, anchor| {
+                let id = (*anchor)?;
+                let position = instructions.iter().position(|inst| inst.id == id)?;
+                Some(limit.max(position + 1))
+            })
+    }
+
+    /// Instructions reached from the exception runtime rather than through
+    /// ordinary control flow: landing pads, catch funclets and continuations.
+    pub fn entry_points(&self) -> Vec<usize> {
+        self.regions
+            .iter()
+            .filter_map(|region| region.handler.as_ref())
+            .flat_map(ExceptionHandler::entry_points)
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RelocatedUnwind {
+    pub begin_address: u32,
+    pub end_address: u32,
+    pub info: UnwindInfo,
+    pub chained_parent: Option<usize>,
+    pub handler_pointers: Vec<BlobPointer>,
+    pub blobs: Vec<DataBlob>,
+}
+
+/// A frame description entry for a piece of relocated code.
+#[derive(Clone, Debug)]
+pub struct RelocatedFrame {
+    pub begin_address: u32,
+    pub end_address: u32,
+    /// Call frame instructions from the usual x86-64 entry state.
+    pub program: Vec<u8>,
+}
+
+pub struct UnwindManager;
+
+impl UnwindManager {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self
+    }
+
+    /// Names a language-specific handler by its import or, for handlers
+    /// linked into the image, by its symbol.
+    fn handler_name(pe_context: &PEContext, handler_symbols: &HashMap<u32, String>, handler_rva: u32) -> String {
+        pe_context
+            .resolve_import_thunk(handler_rva)
+            .or_else(|| handler_symbols.get(&handler_rva).cloned())
+            .unwrap_or_else(|| format!("{handler_rva:#x}"))
+    }
+
+    /// # Errors
+    ///
+    /// Fails when an unwind code does not fall on an instruction boundary.
+    pub fn collect(
+        &self,
+        pe_context: &PEContext,
+        runtime_functions: &[RuntimeFunction],
+        handler_symbols: &HashMap<u32, String>,
+        instructions: &[InstructionWithId],
+        rva: u32,
+        size: u32,
+    ) -> Result<FunctionUnwind, String> {
+        let end = rva + size;
+        let anchor_at = |address: u32| -> Result<Anchor, String> {
+            if address == end {
+                return Ok(None);
+            }
+            instructions
+                .iter()
+                .find(|inst| inst.instruction.ip() == u64::from(address))
+                .map(|inst| Some(inst.id))
+                .ok_or_else(|| format!("Unwind address {address:#x} is not an instruction boundary"))
+        };
+        let code_address = |address: u32| -> Result<CodeAddress, String> {
+            if !(rva..=end).contains(&address) {
+                return Ok(CodeAddress::Outside(address));
+            }
+            anchor_at(address)
+                .map(|anchor| CodeAddress::Inside { anchor, bias: 0 })
+                .or_else(|e| {
+                    anchor_at(address - 1)
+                        .map(|anchor| CodeAddress::Inside { anchor, bias: 1 })
+                        .map_err(|_| e)
+                })
+        };
+
+        let mut regions = Vec::new();
+        for function in runtime_functions {
+            if function.begin_address < rva && function.end_address > rva {
+                return Err(format!(
+                    "Function starts inside the unwind region at {:#x}",
+                    function.begin_address
+                ));
+            }
+            if function.begin_address < rva || function.begin_address >= end {
+                continue;
+            }
+            if function.end_address > end {
+                return Err(format!(
+                    "Unwind region at {:#x} extends past the function",
+                    function.begin_address
+                ));
+            }
+
+            let info = UnwindInfo::parse(pe_context, function.unwind_info_address)?;
+            if info.codes.iter().any(|code| code.op == UWOP_EPILOG) {
+                return Err("Version 2 epilog unwind codes are not supported".to_string());
+            }
+
+            let handler = info
+                .handler
+                .map(|handler_rva| {
+                    let name = Self::handler_name(pe_context, handler_symbols, handler_rva);
+                    let kind = HandlerKind::from_name(&name)
+                        .ok_or_else(|| format!("Unsupported exception handler {name}"))?;
+                    ExceptionHandler::parse(
+                        kind,
+                        pe_context,
+                        function.unwind_info_address + info.handler_data_offset(),
+                        function.begin_address,
+                        &code_address,
+                    )
+                })
+                .transpose()?;
+
+            let code_anchors = info
+                .codes
+                .iter()
+                .map(|code| anchor_at(function.begin_address + u32::from(code.code_offset)))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            regions.push(UnwindRegion {
+                original: *function,
+                begin_id: anchor_at(function.begin_address)?
+                    .ok_or_else(|| "Empty unwind region".to_string())?,
+                end: anchor_at(function.end_address)?,
+                prolog_end: anchor_at(function.begin_address + u32::from(info.prolog_size))?,
+                code_anchors,
+                info,
+                chained_parent: None,
+                handler,
+            });
+        }
+        regions.sort_by_key(|region| region.original.begin_address);
+
+        for index in 0..regions.len() {
+            let Some(parent) = regions[index].info.chained else {
+                continue;
+            };
+            if parent.begin_address < rva || parent.begin_address >= end {
+                // The parent stays where it is, so the chain record remains valid.
+                continue;
+            }
+            let parent_index = regions
+                .iter()
+                .position(|region| region.original.begin_address == parent.begin_address)
+                .ok_or_else(|| format!(
+                    "Chained unwind parent {:#x} not found",
+                    parent.begin_address
+                ))?;
+            regions[index].chained_parent = Some(parent_index);
+        }
+
+        let frame_instructions = Self::frame_instructions(&regions, instructions);
+        Ok(FunctionUnwind {
+            regions,
+            frames: None,
+            frame_instructions,
+        })
+    }
+
+    fn frame_instructions(
+        regions: &[UnwindRegion],
+        instructions: &[InstructionWithId],
+    ) -> HashSet<usize> {
+        let mut frame = HashSet::new();
+        if regions.is_empty() {
+            return frame;
+        }
+
+        for region in regions {
+            let begin = u64::from(region.original.begin_address);
+            let prolog_end = begin + u64::from(region.info.prolog_size);
+            frame.extend(
+                instructions
+                    .iter()
+                    .filter(|inst| (begin..prolog_end).contains(&inst.instruction.ip()))
+                    .map(|inst| inst.id),
+            );
+        }
+
+        // The unwinder recognises epilogues by their shape: an optional stack
+        // deallocation, pops of non-volatile registers and a ret or jmp.
+        for (index, inst) in instructions.iter().enumerate() {
+            if !matches!(
+                inst.instruction.flow_control(),
+                FlowControl::Return | FlowControl::UnconditionalBranch | FlowControl::IndirectBranch
+            ) {
+                continue;
+            }
+            frame.insert(inst.id);
+
+            let mut cursor = index;
+            while cursor > 0 && Self::is_epilog_pop(&instructions[cursor - 1]) {
+                cursor -= 1;
+                frame.insert(instructions[cursor].id);
+            }
+            if cursor > 0 && Self::is_epilog_deallocation(&instructions[cursor - 1]) {
+                frame.insert(instructions[cursor - 1].id);
+            }
+        }
+
+        frame
+    }
+
+    fn is_epilog_pop(inst: &InstructionWithId) -> bool {
+        inst.instruction.mnemonic() == Mnemonic::Pop
+            && inst.instruction.op0_kind() == OpKind::Register
+    }
+
+    fn is_epilog_deallocation(inst: &InstructionWithId) -> bool {
+        let instruction = &inst.instruction;
+        instruction.op0_kind() == OpKind::Register
+            && instruction.op0_register() == Register::RSP
+            && matches!(
+                instruction.code(),
+                Code::Add_rm64_imm8 | Code::Add_rm64_imm32 | Code::Lea_r64_m
+            )
+    }
+
+    /// Builds the unwind entries for the encoded function. Instructions must
+    /// already carry their final addresses.
+    ///
+    /// # Errors
+    ///
+    /// Fails when an instruction the unwind info refers to no longer exists.
+    pub fn relocate(
+        &self,
+        unwind: &FunctionUnwind,
+        instructions: &[InstructionWithId],
+        rva: u32,
+        size: u32,
+    ) -> Result<Vec<RelocatedUnwind>, String> {
+        let address_of = |anchor: Anchor| -> Result<u32, String> {
+            let Some(id) = anchor else {
+                return Ok(rva + size);
+            };
+            instructions
+                .iter()
+                .find(|inst| inst.id == id)
+                .map(InstructionWithId::rva)
+                .ok_or_else(|| format!("Unwind anchor instruction {id} no longer exists"))
+        };
+        let resolve = |address: CodeAddress| -> Result<u32, String> {
+            match address {
+                CodeAddress::Inside { anchor, bias } => Ok(address_of(anchor)? + bias),
+                CodeAddress::Outside(address) => Ok(address),
+            }
+        };
+
+        unwind
+            .regions
+            .iter()
+            .map(|region| {
+                let begin_address = address_of(Some(region.begin_id))?;
+                let end_address = address_of(region.end)?;
+                let offset_of = |anchor: Anchor| -> Result<u8, String> {
+                    let offset = address_of(anchor)?
+                        .checked_sub(begin_address)
+                        .ok_or_else(|| "Unwind code moved before its region".to_string())?;
+                    u8::try_from(offset).map_err(|_| {
+                        format!("Prologue of region at {begin_address:#x} grew past 255 bytes")
+                    })
+                };
+
+                let mut info = region.info.clone();
+                info.prolog_size = offset_of(region.prolog_end)?;
+                for (code, &anchor) in info.codes.iter_mut().zip(&region.code_anchors) {
+                    code.code_offset = offset_of(anchor)?;
+                }
+
+                let (handler_pointers, blobs) = match &region.handler {
+                    Some(handler) => {
+                        let (inline, blobs) = handler.relocate(&resolve, begin_address)?;
+                        info.handler_data = inline.bytes;
+                        (inline.pointers, blobs)
+                    }
+                    None => (Vec::new(), Vec::new()),
+                };
+
+                debug!(
+                    "Relocated unwind region {:#x}-{:#x} to {begin_address:#x}-{end_address:#x}",
+                    region.original.begin_address, region.original.end_address
+                );
+
+                Ok(RelocatedUnwind {
+                    begin_address,
+                    end_address,
+                    info,
+                    chained_parent: region.chained_parent,
+                    handler_pointers,
+                    blobs,
+                })
+            })
+            .collect()
+    }
+
+    /// Entry for a piece of a function placed away from its entry. It has
+    /// no codes of its own and is chained to `parent`, whose codes all apply
+    /// since the piece starts past the prolog.
+    #[must_use]
+    pub fn chain(&self, parent: usize, entries: &[RelocatedUnwind], chunk: &Range<u32>) -> RelocatedUnwind {
+        let parent_entry = &entries[parent];
+        RelocatedUnwind {
+            begin_address: chunk.start,
+            end_address: chunk.end,
+            info: UnwindInfo {
+                version: parent_entry.info.version,
+                flags: UNW_FLAG_CHAININFO,
+                prolog_size: 0,
+                frame_register: parent_entry.info.frame_register,
+                frame_offset: parent_entry.info.frame_offset,
+                codes: Vec::new(),
+                handler: None,
+                handler_data: Vec::new(),
+                // Filled in with the final address of the parent's info.
+                chained: Some(RuntimeFunction {
+                    begin_address: parent_entry.begin_address,
+                    end_address: parent_entry.end_address,
+                    unwind_info_address: 0,
+                }),
+            },
+            chained_parent: Some(parent),
+            handler_pointers: Vec::new(),
+            blobs: Vec::new(),
+        }
+    }
+}
+
+impl Default for UnwindManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ObfuscatorFunction {
+    /// # Errors
+    ///
+    /// Fails when the unwind info of the function cannot be parsed.
+    pub fn collect_unwind_info(
+        &mut self,
+        pe_context: &PEContext,
+        runtime_functions: &[RuntimeFunction],
+        handler_symbols: &HashMap<u32, String>,
+    ) -> Result<(), String> {
+        let manager = UnwindManager::new();
+        self.unwind = manager.collect(
+            pe_context,
+            runtime_functions,
+            handler_symbols,
+            &self.instructions,
+            self.rva,
+            self.size,
+        )?;
+        self.build_cfg();
+        Ok(())
+    }
+
+    /// Builds the unwind entries of the function placed in `chunks`, the
+    /// one holding its entry first. Only functions whose code may leave the
+    /// entry (see [`FunctionUnwind::movable_from`]) are split.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the unwind info cannot be rebuilt for the relocated code.
+    pub fn relocate_unwind_info(&self, chunks: &[Range<u32>]) -> Result<Vec<RelocatedUnwind>, String> {
+        let manager = UnwindManager::new();
+        let entry = chunks.first().ok_or_else(|| "Function has no code".to_string())?;
+        let mut entries =
+            manager.relocate(&self.unwind, &self.instructions, entry.start, entry.end - entry.start)?;
+        if chunks.len() > 1 && entries.len() > 1 {
+            return Err("Function with several unwind regions was split".to_string());
+        }
+        if !entries.is_empty() {
+            let chained: Vec<RelocatedUnwind> = chunks[1..]
+                .iter()
+                .map(|chunk| manager.chain(0, &entries, chunk))
+                .collect();
+            entries.extend(chained);
+        }
+        Ok(entries)
+    }
+
+    /// Follows the call frame table of the `.eh_frame` entries overlapping
+    /// the function. Instructions that change the frame are kept as they
+    /// are, as on PE.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the function is not described by a single entry starting
+    /// at it, the entry has language specific data or describes a signal
+    /// handler, or its rows are not on instruction boundaries.
+    pub fn collect_call_frames(&mut self, entries: &[&FrameEntry]) -> Result<(), String> {
+        let end = self.rva + self.size;
+        let entry = match entries {
+            [] => {
+                self.unwind = FunctionUnwind::default();
+                self.build_cfg();
+                return Ok(());
+            }
+            [entry] => *entry,
+            _ => return Err("Function is described by several frame descriptions".to_string()),
+        };
+        let description = &entry.description;
+        if description.begin_address != self.rva || description.end_address > end {
+            return Err(format!(
+                "Frame description {:#x}-{:#x} does not match the function",
+                description.begin_address, description.end_address
+            ));
+        }
+        if description.has_lsda {
+            return Err("Functions with language specific data are not supported".to_string());
+        }
+        if entry.signal_frame {
+            return Err("Signal frames are not supported".to_string());
+        }
+        let rows = entry.rows.as_ref().map_err(Clone::clone)?;
+        let boundaries: HashSet<u32> = self.instructions.iter().map(InstructionWithId::rva).collect();
+        if let Some((rva, _)) = rows
+            .iter()
+            .find(|(rva, _)| *rva < description.end_address && !boundaries.contains(rva))
+        {
+            return Err(format!("Call frame row at {rva:#x} is not an instruction boundary"));
+        }
+
+        let mut states: Vec<FrameState> = Vec::new();
+        let mut original_states = Vec::with_capacity(self.instructions.len());
+        for inst in &self.instructions {
+            let ip = inst.rva();
+            let row = rows.partition_point(|(rva, _)| *rva <= ip).saturating_sub(1);
+            let state = &rows[row].1;
+            let index = states.iter().position(|known| known == state).unwrap_or_else(|| {
+                states.push(state.clone());
+                states.len() - 1
+            });
+            original_states.push(index);
+        }
+        let entry_state = FrameState::entry();
+        for state in &states {
+            entry_state.encode_transition(state, &mut Vec::new())?;
+        }
+
+        let frame_instructions = original_states
+            .windows(2)
+            .enumerate()
+            .filter(|(_, pair)| pair[0] != pair[1])
+            .map(|(id, _)| id)
+            .collect();
+        self.unwind = FunctionUnwind {
+            regions: Vec::new(),
+            frames: Some(CallFrames {
+                states,
+                original_states,
+            }),
+            frame_instructions,
+        };
+        self.build_cfg();
+        Ok(())
+    }
+
+    /// Builds a frame description entry for each of `chunks`, the pieces the
+    /// function was placed in. Instructions keep the state they had; the
+    /// ones the passes added take the state after the original instruction
+    /// they follow. Instructions must already carry their final addresses.
+    ///
+    /// # Errors
+    ///
+    /// Fails when a state cannot be encoded.
+    pub fn relocate_call_frames(&self, chunks: &[Range<u32>]) -> Result<Vec<RelocatedFrame>, String> {
+        let Some(frames) = &self.unwind.frames else {
+            return Ok(Vec::new());
+        };
+        let mut placed = Vec::with_capacity(self.instructions.len());
+        let mut current = frames.original_states.first().copied().unwrap_or(0);
+        for inst in &self.instructions {
+            let state = match frames.original_states.get(inst.id) {
+                Some(&state) => {
+                    current = frames.state_after(inst.id);
+                    state
+                }
+                None => current,
+            };
+            placed.push((inst.rva(), state));
+        }
+        placed.sort_by_key(|&(rva, _)| rva);
+
+        let entry_state = FrameState::entry();
+        let mut relocated = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            let mut program = Vec::new();
+            let mut state = &entry_state;
+            let mut location = chunk.start;
+            for &(rva, index) in placed.iter().filter(|(rva, _)| chunk.contains(rva)) {
+                let target = &frames.states[index];
+                if target != state {
+                    encode_advance(rva - location, &mut program);
+                    state.encode_transition(target, &mut program)?;
+                    state = target;
+                    location = rva;
+                }
+            }
+            relocated.push(RelocatedFrame {
+                begin_address: chunk.start,
+                end_address: chunk.end,
+                program,
+            });
+        }
+        Ok(relocated)
+    }
+}
@@ -0,0 +1,999 @@
+use iced_x86::{Code, ConditionCode, Decoder, DecoderOptions, Instruction, Mnemonic, OpKind, Register};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::rc::Rc;
+
+pub const CF: u64 = 1 << 0;
+pub const PF: u64 = 1 << 2;
+pub const AF: u64 = 1 << 4;
+pub const ZF: u64 = 1 << 6;
+pub const SF: u64 = 1 << 7;
+pub const DF: u64 = 1 << 10;
+pub const OF: u64 = 1 << 11;
+const STATUS: u64 = CF | PF | AF | ZF | SF | OF;
+/// Bits a user-mode POPFQ can change besides the status flags: TF, IF,
+/// AC and ID. Bit 1 always reads as set.
+const POPF_MASK: u64 = STATUS | DF | (1 << 8) | (1 << 9) | (1 << 18) | (1 << 21);
+
+pub const PAGE_BYTES: usize = 0x1000;
+pub const PAGE_SIZE: u64 = PAGE_BYTES as u64;
+
+pub type Page = [u8; PAGE_BYTES];
+
+/// Hashes addresses with a single multiplication; the default hasher
+/// dominated the time spent per emulated instruction.
+#[derive(Default)]
+pub struct AddressHasher(u64);
+
+impl Hasher for AddressHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 << 8 | u64::from(byte)).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.0 = value.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+pub type AddressMap<T> = HashMap<u64, T, BuildHasherDefault<AddressHasher>>;
+
+/// Why execution stopped before the function returned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// `int3`, `int` or `ud2`, which is how panics and fast-fail requests
+    /// end a function.
+    Trap,
+    DivideError,
+    /// Access to an unmapped address.
+    Memory(u64),
+    Unsupported(String),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Trap => write!(f, "trap"),
+            Self::DivideError => write!(f, "divide error"),
+            Self::Memory(address) => write!(f, "access to unmapped address {address:#x}"),
+            Self::Unsupported(what) => write!(f, "unsupported {what}"),
+        }
+    }
+}
+
+/// Paged memory on top of a shared, read-only base. Pages are copied out
+/// of the base when first written, so many runs can start from one image.
+#[derive(Clone, Default)]
+pub struct Memory {
+    base: Rc<AddressMap<Box<Page>>>,
+    pages: AddressMap<Box<Page>>,
+}
+
+impl Memory {
+    #[must_use]
+    pub fn new(base: Rc<AddressMap<Box<Page>>>) -> Self {
+        Self {
+            base,
+            pages: AddressMap::default(),
+        }
+    }
+
+    /// Collects the pages of the memory into a base for others.
+    #[must_use]
+    pub fn into_base(mut self) -> Rc<AddressMap<Box<Page>>> {
+        let mut base = Rc::try_unwrap(self.base).unwrap_or_else(|shared| (*shared).clone());
+        base.extend(self.pages.drain());
+        Rc::new(base)
+    }
+
+    /// Maps zeroed pages over `address..address + size` that are not
+    /// mapped yet.
+    pub fn map(&mut self, address: u64, size: u64) {
+        let mut page = address & !(PAGE_SIZE - 1);
+        while page < address + size {
+            if !self.base.contains_key(&page) {
+                self.pages
+                    .entry(page)
+                    .or_insert_with(|| Box::new([0; PAGE_BYTES]));
+            }
+            page += PAGE_SIZE;
+        }
+    }
+
+    fn page(&self, page: u64) -> Option<&Page> {
+        self.pages
+            .get(&page)
+            .or_else(|| self.base.get(&page))
+            .map(|page| &**page)
+    }
+
+    fn page_mut(&mut self, page: u64) -> Option<&mut Page> {
+        if !self.pages.contains_key(&page) {
+            let copy = self.base.get(&page)?.clone();
+            self.pages.insert(page, copy);
+        }
+        self.pages.get_mut(&page).map(|page| &mut **page)
+    }
+
+    /// Pages written since the memory was created from its base.
+    pub fn written_pages(&self) -> impl Iterator<Item = u64> + '_ {
+        self.pages.keys().copied()
+    }
+
+    /// # Errors
+    ///
+    /// Faults when the range is not mapped.
+    pub fn read_bytes(&self, address: u64, out: &mut [u8]) -> Result<(), Fault> {
+        let mut done = 0;
+        while done < out.len() {
+            let address = address.wrapping_add(done as u64);
+            let offset = page_offset(address);
+            let page = self
+                .page(address & !(PAGE_SIZE - 1))
+                .ok_or(Fault::Memory(address))?;
+            let length = (out.len() - done).min(PAGE_BYTES - offset);
+            out[done..done + length].copy_from_slice(&page[offset..offset + length]);
+            done += length;
+        }
+        Ok(())
+    }
+
+    /// # Errors
+    ///
+    /// Faults when the range is not mapped.
+    pub fn write_bytes(&mut self, address: u64, bytes: &[u8]) -> Result<(), Fault> {
+        let mut done = 0;
+        while done < bytes.len() {
+            let address = address.wrapping_add(done as u64);
+            let offset = page_offset(address);
+            let page = self
+                .page_mut(address & !(PAGE_SIZE - 1))
+                .ok_or(Fault::Memory(address))?;
+            let length = (bytes.len() - done).min(PAGE_BYTES - offset);
+            page[offset..offset + length].copy_from_slice(&bytes[done..done + length]);
+            done += length;
+        }
+        Ok(())
+    }
+
+    /// # Errors
+    ///
+    /// Faults when the range is not mapped.
+    pub fn read(&self, address: u64, size: usize) -> Result<u64, Fault> {
+        let mut bytes = [0u8; 8];
+        self.read_bytes(address, &mut bytes[..size])?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// # Errors
+    ///
+    /// Faults when the range is not mapped.
+    pub fn write(&mut self, address: u64, size: usize, value: u64) -> Result<(), Fault> {
+        self.write_bytes(address, &value.to_le_bytes()[..size])
+    }
+
+    fn read_vector(&self, address: u64, size: usize) -> Result<u128, Fault> {
+        let mut bytes = [0u8; 16];
+        self.read_bytes(address, &mut bytes[..size])?;
+        Ok(u128::from_le_bytes(bytes))
+    }
+
+    fn write_vector(&mut self, address: u64, size: usize, value: u128) -> Result<(), Fault> {
+        self.write_bytes(address, &value.to_le_bytes()[..size])
+    }
+}
+
+#[allow(clippy::cast_possible_truncation, reason = "the offset is below PAGE_SIZE")]
+const fn page_offset(address: u64) -> usize {
+    (address & (PAGE_SIZE - 1)) as usize
+}
+
+const fn mask(bits: u32) -> u64 {
+    if bits == 64 { u64::MAX } else { (1u64 << bits) - 1 }
+}
+
+const fn sign(value: u64, bits: u32) -> bool {
+    (value >> (bits - 1)) & 1 != 0
+}
+
+const fn sign_extend(value: u64, bits: u32) -> u64 {
+    if bits >= 64 {
+        value
+    } else {
+        ((value << (64 - bits)).cast_signed() >> (64 - bits)).cast_unsigned()
+    }
+}
+
+/// Interprets the user-mode integer subset of x86-64 that compilers emit
+/// for ordinary functions and that the passes produce.
+///
+/// The SSE moves used to copy and clear memory are supported as well.
+/// Anything else stops with [`Fault::Unsupported`].
+#[derive(Clone)]
+pub struct Emulator {
+    pub registers: [u64; 16],
+    pub xmm: [u128; 16],
+    pub rip: u64,
+    pub flags: u64,
+    pub memory: Memory,
+    pub steps: u64,
+    /// Instructions by address. Nothing the passes emit modifies code.
+    decoded: AddressMap<Instruction>,
+}
+
+impl Emulator {
+    #[must_use]
+    pub fn new(memory: Memory) -> Self {
+        Self {
+            registers: [0; 16],
+            xmm: [0; 16],
+            rip: 0,
+            flags: 0x202,
+            memory,
+            steps: 0,
+            decoded: AddressMap::default(),
+        }
+    }
+
+    fn gpr_index(register: Register) -> usize {
+        register.full_register().number() - Register::RAX.number()
+    }
+
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, reason = "registers are at most 64 bytes wide")]
+    pub fn register(&self, register: Register) -> u64 {
+        match register {
+            Register::AH | Register::CH | Register::DH | Register::BH => {
+                let index = register.number() - Register::AH.number();
+                (self.registers[index] >> 8) & 0xff
+            }
+            Register::RIP => self.rip,
+            _ => self.registers[Self::gpr_index(register)] & mask(register.size() as u32 * 8),
+        }
+    }
+
+    pub fn set_register(&mut self, register: Register, value: u64) {
+        match register {
+            Register::AH | Register::CH | Register::DH | Register::BH => {
+                let index = register.number() - Register::AH.number();
+                self.registers[index] = (self.registers[index] & !0xff00) | ((value & 0xff) << 8);
+            }
+            _ => {
+                let index = Self::gpr_index(register);
+                let current = self.registers[index];
+                self.registers[index] = match register.size() {
+                    8 => value,
+                    4 => value & 0xffff_ffff,
+                    2 => (current & !0xffff) | (value & 0xffff),
+                    _ => (current & !0xff) | (value & 0xff),
+                };
+            }
+        }
+    }
+
+    #[must_use]
+    pub const fn rsp(&self) -> u64 {
+        self.registers[4]
+    }
+
+    fn address(&self, instruction: &Instruction) -> Result<u64, Fault> {
+        if matches!(instruction.segment_prefix(), Register::FS | Register::GS) {
+            return Err(Fault::Unsupported("segment override".to_string()));
+        }
+        if instruction.is_ip_rel_memory_operand() {
+            return Ok(instruction.ip_rel_memory_address());
+        }
+        let mut address = instruction.memory_displacement64();
+        if instruction.memory_base() != Register::None {
+            address = address.wrapping_add(self.register(instruction.memory_base()));
+        }
+        if instruction.memory_index() != Register::None {
+            let index = self.register(instruction.memory_index());
+            address = address.wrapping_add(index.wrapping_mul(u64::from(instruction.memory_index_scale())));
+        }
+        if instruction.memory_base().size() == 4 {
+            address &= 0xffff_ffff;
+        }
+        Ok(address)
+    }
+
+    #[allow(clippy::cast_possible_truncation, reason = "operands are at most 64 bytes wide")]
+    fn operand_bits(instruction: &Instruction, operand: u32) -> u32 {
+        match instruction.op_kind(operand) {
+            OpKind::Register => instruction.op_register(operand).size() as u32 * 8,
+            OpKind::Memory => instruction.memory_size().size() as u32 * 8,
+            OpKind::Immediate8 => 8,
+            OpKind::Immediate16 | OpKind::Immediate8to16 => 16,
+            OpKind::Immediate32 | OpKind::Immediate8to32 => 32,
+            _ => 64,
+        }
+    }
+
+    fn read_operand(&self, instruction: &Instruction, operand: u32) -> Result<u64, Fault> {
+        match instruction.op_kind(operand) {
+            OpKind::Register => Ok(self.register(instruction.op_register(operand))),
+            OpKind::Memory => self
+                .memory
+                .read(self.address(instruction)?, instruction.memory_size().size()),
+            OpKind::Immediate8
+            | OpKind::Immediate16
+            | OpKind::Immediate32
+            | OpKind::Immediate64
+            | OpKind::Immediate8to16
+            | OpKind::Immediate8to32
+            | OpKind::Immediate8to64
+            | OpKind::Immediate32to64 => Ok(instruction.immediate(operand)),
+            kind => Err(Fault::Unsupported(format!("operand {kind:?}"))),
+        }
+    }
+
+    fn write_operand(&mut self, instruction: &Instruction, operand: u32, value: u64) -> Result<(), Fault> {
+        match instruction.op_kind(operand) {
+            OpKind::Register => {
+                self.set_register(instruction.op_register(operand), value);
+                Ok(())
+            }
+            OpKind::Memory => {
+                let address = self.address(instruction)?;
+                self.memory.write(address, instruction.memory_size().size(), value)
+            }
+            kind => Err(Fault::Unsupported(format!("operand {kind:?}"))),
+        }
+    }
+
+    fn read_vector_operand(&self, instruction: &Instruction, operand: u32) -> Result<u128, Fault> {
+        match instruction.op_kind(operand) {
+            OpKind::Register if instruction.op_register(operand).is_xmm() => {
+                Ok(self.xmm[instruction.op_register(operand).number() - Register::XMM0.number()])
+            }
+            OpKind::Memory => self
+                .memory
+                .read_vector(self.address(instruction)?, instruction.memory_size().size()),
+            _ => self.read_operand(instruction, operand).map(u128::from),
+        }
+    }
+
+    /// Writes the low `size` bytes of `value` to an XMM register, memory or
+    /// a general-purpose register. The rest of an XMM register is cleared
+    /// unless `merge` is set.
+    #[allow(clippy::cast_possible_truncation, reason = "other destinations take the low 64 bits, as on the CPU")]
+    fn write_vector_operand(
+        &mut self,
+        instruction: &Instruction,
+        operand: u32,
+        value: u128,
+        size: usize,
+        merge: bool,
+    ) -> Result<(), Fault> {
+        match instruction.op_kind(operand) {
+            OpKind::Register if instruction.op_register(operand).is_xmm() => {
+                let index = instruction.op_register(operand).number() - Register::XMM0.number();
+                let low = if size == 16 { u128::MAX } else { (1u128 << (size * 8)) - 1 };
+                let kept = if merge { self.xmm[index] & !low } else { 0 };
+                self.xmm[index] = kept | (value & low);
+                Ok(())
+            }
+            OpKind::Memory => {
+                let address = self.address(instruction)?;
+                self.memory.write_vector(address, size, value)
+            }
+            _ => self.write_operand(instruction, operand, value as u64),
+        }
+    }
+
+    const fn flag(&self, flag: u64) -> bool {
+        self.flags & flag != 0
+    }
+
+    const fn set_flag(&mut self, flag: u64, value: bool) {
+        if value {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
+
+    const fn set_result_flags(&mut self, result: u64, bits: u32) {
+        self.set_flag(ZF, result & mask(bits) == 0);
+        self.set_flag(SF, sign(result, bits));
+        self.set_flag(PF, result.to_le_bytes()[0].count_ones().is_multiple_of(2));
+    }
+
+    const fn condition(&self, condition: ConditionCode) -> bool {
+        let (cf, zf, sf, of, pf) = (
+            self.flag(CF),
+            self.flag(ZF),
+            self.flag(SF),
+            self.flag(OF),
+            self.flag(PF),
+        );
+        match condition {
+            ConditionCode::o => of,
+            ConditionCode::no => !of,
+            ConditionCode::b => cf,
+            ConditionCode::ae => !cf,
+            ConditionCode::e => zf,
+            ConditionCode::ne => !zf,
+            ConditionCode::be => cf || zf,
+            ConditionCode::a => !cf && !zf,
+            ConditionCode::s => sf,
+            ConditionCode::ns => !sf,
+            ConditionCode::p => pf,
+            ConditionCode::np => !pf,
+            ConditionCode::l => sf != of,
+            ConditionCode::ge => sf == of,
+            ConditionCode::le => zf || sf != of,
+            ConditionCode::g => !zf && sf == of,
+            ConditionCode::None => true,
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation, reason = "the sum is kept to the operand width, as on the CPU")]
+    fn add(&mut self, a: u64, b: u64, carry: u64, bits: u32) -> u64 {
+        let m = mask(bits);
+        let (a, b) = (a & m, b & m);
+        let wide = u128::from(a) + u128::from(b) + u128::from(carry);
+        let result = wide as u64 & m;
+        self.flags &= !STATUS;
+        self.set_flag(CF, wide > u128::from(m));
+        self.set_flag(OF, sign((a ^ result) & (b ^ result), bits));
+        self.set_flag(AF, (a ^ b ^ result) & 0x10 != 0);
+        self.set_result_flags(result, bits);
+        result
+    }
+
+    fn sub(&mut self, a: u64, b: u64, borrow: u64, bits: u32) -> u64 {
+        let m = mask(bits);
+        let (a, b) = (a & m, b & m);
+        let result = a.wrapping_sub(b).wrapping_sub(borrow) & m;
+        self.flags &= !STATUS;
+        self.set_flag(CF, u128::from(a) < u128::from(b) + u128::from(borrow));
+        self.set_flag(OF, sign((a ^ b) & (a ^ result), bits));
+        self.set_flag(AF, (a ^ b ^ result) & 0x10 != 0);
+        self.set_result_flags(result, bits);
+        result
+    }
+
+    const fn logic(&mut self, result: u64, bits: u32) -> u64 {
+        self.flags &= !STATUS;
+        self.set_result_flags(result, bits);
+        result & mask(bits)
+    }
+
+    fn push(&mut self, value: u64) -> Result<(), Fault> {
+        let rsp = self.rsp().wrapping_sub(8);
+        self.memory.write(rsp, 8, value)?;
+        self.registers[4] = rsp;
+        Ok(())
+    }
+
+    /// # Errors
+    ///
+    /// Faults when the stack is not mapped.
+    pub fn pop(&mut self) -> Result<u64, Fault> {
+        let value = self.memory.read(self.rsp(), 8)?;
+        self.registers[4] = self.rsp().wrapping_add(8);
+        Ok(value)
+    }
+
+    fn decode(&self) -> Result<Instruction, Fault> {
+        let mut bytes = [0u8; 15];
+        let mut length = bytes.len();
+        // The last instruction of a mapping may end less than 15 bytes
+        // before the next unmapped page.
+        while length > 0 && self.memory.read_bytes(self.rip, &mut bytes[..length]).is_err() {
+            length -= 1;
+        }
+        if length == 0 {
+            return Err(Fault::Memory(self.rip));
+        }
+        let mut decoder = Decoder::with_ip(64, &bytes[..length], self.rip, DecoderOptions::NONE);
+        let instruction = decoder.decode();
+        if instruction.is_invalid() {
+            return Err(Fault::Unsupported(format!("encoding at {:#x}", self.rip)));
+        }
+        Ok(instruction)
+    }
+
+    /// Executes the instruction at RIP.
+    ///
+    /// # Errors
+    ///
+    /// Faults on unmapped memory, division errors, traps and instructions that
+    /// are not supported.
+    pub fn step(&mut self) -> Result<(), Fault> {
+        let instruction = if let Some(instruction) = self.decoded.get(&self.rip) {
+            *instruction
+        } else {
+            let instruction = self.decode()?;
+            self.decoded.insert(self.rip, instruction);
+            instruction
+        };
+        self.steps += 1;
+        self.rip = instruction.next_ip();
+        self.execute(&instruction)
+    }
+
+    fn execute(&mut self, instruction: &Instruction) -> Result<(), Fault> {
+        if instruction.has_lock_prefix() && instruction.mnemonic() != Mnemonic::Cmpxchg {
+            return Err(Fault::Unsupported(format!("lock {:?}", instruction.mnemonic())));
+        }
+        if instruction.has_rep_prefix() || instruction.has_repne_prefix() {
+            return self.execute_string(instruction);
+        }
+
+        let bits = Self::operand_bits(instruction, 0);
+        let handled = match instruction.mnemonic() {
+            Mnemonic::Nop | Mnemonic::Endbr64 | Mnemonic::Pause | Mnemonic::Prefetchnta | Mnemonic::Prefetcht0 => true,
+            Mnemonic::Int3 | Mnemonic::Int | Mnemonic::Ud2 => return Err(Fault::Trap),
+            _ => {
+                self.execute_move(instruction, bits)?
+                    || self.execute_arithmetic(instruction, bits)?
+                    || self.execute_flow(instruction, bits)?
+                    || self.execute_conditional(instruction)?
+            }
+        };
+        if handled {
+            Ok(())
+        } else {
+            Err(Fault::Unsupported(format!("{:?}", instruction.mnemonic())))
+        }
+    }
+
+    /// Moves, conversions, exchanges and bit tests. Like the other
+    /// `execute_*` helpers it returns false for instructions outside its family.
+    fn execute_move(&mut self, instruction: &Instruction, bits: u32) -> Result<bool, Fault> {
+        match instruction.mnemonic() {
+            Mnemonic::Mov | Mnemonic::Movzx => {
+                let value = self.read_operand(instruction, 1)?;
+                self.write_operand(instruction, 0, value)?;
+            }
+            Mnemonic::Movsx | Mnemonic::Movsxd => {
+                let value = sign_extend(
+                    self.read_operand(instruction, 1)?,
+                    Self::operand_bits(instruction, 1),
+                );
+                self.write_operand(instruction, 0, value)?;
+            }
+            Mnemonic::Lea => {
+                let value = self.address(instruction)?;
+                self.write_operand(instruction, 0, value)?;
+            }
+            Mnemonic::Cbw => self.set_register(Register::AX, sign_extend(self.register(Register::AL), 8)),
+            Mnemonic::Cwde => self.set_register(Register::EAX, sign_extend(self.register(Register::AX), 16)),
+            Mnemonic::Cdqe => self.set_register(Register::RAX, sign_extend(self.register(Register::EAX), 32)),
+            Mnemonic::Cwd => {
+                let high = if sign(self.register(Register::AX), 16) { 0xffff } else { 0 };
+                self.set_register(Register::DX, high);
+            }
+            Mnemonic::Cdq => {
+                let high = if sign(self.register(Register::EAX), 32) { 0xffff_ffff } else { 0 };
+                self.set_register(Register::EDX, high);
+            }
+            Mnemonic::Cqo => {
+                let high = if sign(self.register(Register::RAX), 64) { u64::MAX } else { 0 };
+                self.set_register(Register::RDX, high);
+            }
+            Mnemonic::Xchg => {
+                let (a, b) = (self.read_operand(instruction, 0)?, self.read_operand(instruction, 1)?);
+                self.write_operand(instruction, 0, b)?;
+                self.write_operand(instruction, 1, a)?;
+            }
+            Mnemonic::Cmpxchg => {
+                let current = self.read_operand(instruction, 0)?;
+                let expected = self.register(Register::RAX) & mask(bits);
+                self.sub(expected, current, 0, bits);
+                if self.flag(ZF) {
+                    let value = self.read_operand(instruction, 1)?;
+                    self.write_operand(instruction, 0, value)?;
+                } else {
+                    let accumulator = match bits {
+                        8 => Register::AL,
+                        16 => Register::AX,
+                        32 => Register::EAX,
+                        _ => Register::RAX,
+                    };
+                    self.set_register(accumulator, current);
+                }
+            }
+            Mnemonic::Bswap => {
+                let a = self.read_operand(instruction, 0)?;
+                let result = if bits == 64 { a.swap_bytes() } else { a.swap_bytes() >> 32 };
+                self.write_operand(instruction, 0, result)?;
+            }
+            Mnemonic::Bt | Mnemonic::Bts | Mnemonic::Btr | Mnemonic::Btc => {
+                if instruction.op0_kind() == OpKind::Memory && instruction.op1_kind() == OpKind::Register {
+                    return Err(Fault::Unsupported("bit string operand".to_string()));
+                }
+                let (a, b) = (self.read_operand(instruction, 0)?, self.read_operand(instruction, 1)?);
+                let bit = 1u64 << (b % u64::from(bits));
+                self.set_flag(CF, a & bit != 0);
+                let result = match instruction.mnemonic() {
+                    Mnemonic::Bts => a | bit,
+                    Mnemonic::Btr => a & !bit,
+                    Mnemonic::Btc => a ^ bit,
+                    _ => return Ok(true),
+                };
+                self.write_operand(instruction, 0, result)?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Integer arithmetic, logic and shifts.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, reason = "products are kept to the operand width, as on the CPU")]
+    fn execute_arithmetic(&mut self, instruction: &Instruction, bits: u32) -> Result<bool, Fault> {
+        match instruction.mnemonic() {
+            Mnemonic::Add | Mnemonic::Adc | Mnemonic::Sub | Mnemonic::Sbb | Mnemonic::Cmp => {
+                let a = self.read_operand(instruction, 0)?;
+                let b = sign_extend(self.read_operand(instruction, 1)?, Self::operand_bits(instruction, 1));
+                let carry = u64::from(self.flag(CF));
+                let result = match instruction.mnemonic() {
+                    Mnemonic::Add => self.add(a, b, 0, bits),
+                    Mnemonic::Adc => self.add(a, b, carry, bits),
+                    Mnemonic::Sbb => self.sub(a, b, carry, bits),
+                    _ => self.sub(a, b, 0, bits),
+                };
+                if instruction.mnemonic() != Mnemonic::Cmp {
+                    self.write_operand(instruction, 0, result)?;
+                }
+            }
+            Mnemonic::And | Mnemonic::Or | Mnemonic::Xor | Mnemonic::Test => {
+                let a = self.read_operand(instruction, 0)?;
+                let b = sign_extend(self.read_operand(instruction, 1)?, Self::operand_bits(instruction, 1));
+                let result = match instruction.mnemonic() {
+                    Mnemonic::Or => a | b,
+                    Mnemonic::Xor => a ^ b,
+                    _ => a & b,
+                };
+                let result = self.logic(result, bits);
+                if instruction.mnemonic() != Mnemonic::Test {
+                    self.write_operand(instruction, 0, result)?;
+                }
+            }
+            Mnemonic::Inc | Mnemonic::Dec => {
+                let a = self.read_operand(instruction, 0)?;
+                let carry = self.flag(CF);
+                let result = if instruction.mnemonic() == Mnemonic::Inc {
+                    self.add(a, 1, 0, bits)
+                } else {
+                    self.sub(a, 1, 0, bits)
+                };
+                self.set_flag(CF, carry);
+                self.write_operand(instruction, 0, result)?;
+            }
+            Mnemonic::Neg => {
+                let a = self.read_operand(instruction, 0)?;
+                let result = self.sub(0, a, 0, bits);
+                self.write_operand(instruction, 0, result)?;
+            }
+            Mnemonic::Not => {
+                let a = self.read_operand(instruction, 0)?;
+                self.write_operand(instruction, 0, !a)?;
+            }
+            Mnemonic::Shl | Mnemonic::Sal | Mnemonic::Shr | Mnemonic::Sar | Mnemonic::Rol | Mnemonic::Ror => {
+                self.shift(instruction)?;
+            }
+            Mnemonic::Imul if instruction.op_count() >= 2 => {
+                let (a, b) = if instruction.op_count() == 2 {
+                    (self.read_operand(instruction, 0)?, self.read_operand(instruction, 1)?)
+                } else {
+                    (self.read_operand(instruction, 1)?, self.read_operand(instruction, 2)?)
+                };
+                let full = i128::from(sign_extend(a, bits).cast_signed())
+                    * i128::from(sign_extend(b, Self::operand_bits(instruction, instruction.op_count() - 1)).cast_signed());
+                let result = full as u64 & mask(bits);
+                let overflow = i128::from(sign_extend(result, bits).cast_signed()) != full;
+                self.flags &= !STATUS;
+                self.set_flag(CF, overflow);
+                self.set_flag(OF, overflow);
+                self.set_result_flags(result, bits);
+                self.write_operand(instruction, 0, result)?;
+            }
+            Mnemonic::Mul | Mnemonic::Imul => {
+                let a = self.register(Register::RAX) & mask(bits);
+                let b = self.read_operand(instruction, 0)? & mask(bits);
+                let (low, high, overflow) = if instruction.mnemonic() == Mnemonic::Mul {
+                    let full = u128::from(a) * u128::from(b);
+                    let high = (full >> bits) as u64 & mask(bits);
+                    (full as u64 & mask(bits), high, high != 0)
+                } else {
+                    let full = i128::from(sign_extend(a, bits).cast_signed()) * i128::from(sign_extend(b, bits).cast_signed());
+                    let low = full as u64 & mask(bits);
+                    (low, (full >> bits) as u64 & mask(bits), i128::from(sign_extend(low, bits).cast_signed()) != full)
+                };
+                self.flags &= !STATUS;
+                self.set_flag(CF, overflow);
+                self.set_flag(OF, overflow);
+                self.set_result_flags(low, bits);
+                self.store_wide(bits, low, high);
+            }
+            Mnemonic::Div | Mnemonic::Idiv => self.divide(instruction, bits)?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Flag instructions, the stack and control transfers.
+    fn execute_flow(&mut self, instruction: &Instruction, bits: u32) -> Result<bool, Fault> {
+        match instruction.mnemonic() {
+            Mnemonic::Clc => self.set_flag(CF, false),
+            Mnemonic::Stc => self.set_flag(CF, true),
+            Mnemonic::Cmc => self.set_flag(CF, !self.flag(CF)),
+            Mnemonic::Cld => self.set_flag(DF, false),
+            Mnemonic::Std => self.set_flag(DF, true),
+            Mnemonic::Push => {
+                if instruction.op0_kind() == OpKind::Register && instruction.op0_register().size() != 8 {
+                    return Err(Fault::Unsupported("16-bit push".to_string()));
+                }
+                let value = sign_extend(self.read_operand(instruction, 0)?, bits);
+                self.push(value)?;
+            }
+            Mnemonic::Pop => {
+                let value = self.pop()?;
+                self.write_operand(instruction, 0, value)?;
+            }
+            Mnemonic::Pushfq => self.push(self.flags)?,
+            Mnemonic::Popfq => {
+                let value = self.pop()?;
+                self.flags = (value & POPF_MASK) | 2;
+            }
+            Mnemonic::Leave => {
+                self.registers[4] = self.registers[5];
+                self.registers[5] = self.pop()?;
+            }
+            Mnemonic::Call => {
+                let target = self.branch_target(instruction)?;
+                self.push(self.rip)?;
+                self.rip = target;
+            }
+            Mnemonic::Ret => {
+                let target = self.pop()?;
+                if instruction.op_count() == 1 {
+                    self.registers[4] = self.rsp().wrapping_add(u64::from(instruction.immediate16()));
+                }
+                self.rip = target;
+            }
+            Mnemonic::Jmp => self.rip = self.branch_target(instruction)?,
+            _ if instruction.is_jcc_short_or_near() => {
+                if self.condition(instruction.condition_code()) {
+                    self.rip = instruction.near_branch64();
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// `SETcc`, `CMOVcc` and the SSE moves and XORs.
+    fn execute_conditional(&mut self, instruction: &Instruction) -> Result<bool, Fault> {
+        match instruction.mnemonic() {
+            Mnemonic::Seto
+            | Mnemonic::Setno
+            | Mnemonic::Setb
+            | Mnemonic::Setae
+            | Mnemonic::Sete
+            | Mnemonic::Setne
+            | Mnemonic::Setbe
+            | Mnemonic::Seta
+            | Mnemonic::Sets
+            | Mnemonic::Setns
+            | Mnemonic::Setp
+            | Mnemonic::Setnp
+            | Mnemonic::Setl
+            | Mnemonic::Setge
+            | Mnemonic::Setle
+            | Mnemonic::Setg => {
+                let value = u64::from(self.condition(instruction.condition_code()));
+                self.write_operand(instruction, 0, value)?;
+            }
+            Mnemonic::Cmovo
+            | Mnemonic::Cmovno
+            | Mnemonic::Cmovb
+            | Mnemonic::Cmovae
+            | Mnemonic::Cmove
+            | Mnemonic::Cmovne
+            | Mnemonic::Cmovbe
+            | Mnemonic::Cmova
+            | Mnemonic::Cmovs
+            | Mnemonic::Cmovns
+            | Mnemonic::Cmovp
+            | Mnemonic::Cmovnp
+            | Mnemonic::Cmovl
+            | Mnemonic::Cmovge
+            | Mnemonic::Cmovle
+            | Mnemonic::Cmovg => {
+                // A 32-bit CMOV clears the upper half even when it does not move.
+                let value = if self.condition(instruction.condition_code()) {
+                    self.read_operand(instruction, 1)?
+                } else {
+                    self.read_operand(instruction, 0)?
+                };
+                self.write_operand(instruction, 0, value)?;
+            }
+            Mnemonic::Movups | Mnemonic::Movaps | Mnemonic::Movdqu | Mnemonic::Movdqa | Mnemonic::Movupd
+            | Mnemonic::Movapd => {
+                let value = self.read_vector_operand(instruction, 1)?;
+                self.write_vector_operand(instruction, 0, value, 16, false)?;
+            }
+            Mnemonic::Movq | Mnemonic::Movd => {
+                let size = if instruction.mnemonic() == Mnemonic::Movq { 8 } else { 4 };
+                let value = self.read_vector_operand(instruction, 1)?;
+                self.write_vector_operand(instruction, 0, value, size, false)?;
+            }
+            // Loads from memory clear the rest of the register, moves
+            // between registers keep it.
+            Mnemonic::Movss | Mnemonic::Movsd if instruction.code() != Code::Movsd_m32_m32 => {
+                let size = if instruction.mnemonic() == Mnemonic::Movss { 4 } else { 8 };
+                let value = self.read_vector_operand(instruction, 1)?;
+                let merge = instruction.op1_kind() == OpKind::Register;
+                self.write_vector_operand(instruction, 0, value, size, merge)?;
+            }
+            Mnemonic::Xorps | Mnemonic::Xorpd | Mnemonic::Pxor => {
+                let a = self.read_vector_operand(instruction, 0)?;
+                let b = self.read_vector_operand(instruction, 1)?;
+                self.write_vector_operand(instruction, 0, a ^ b, 16, false)?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn branch_target(&self, instruction: &Instruction) -> Result<u64, Fault> {
+        if instruction.op0_kind() == OpKind::NearBranch64 {
+            Ok(instruction.near_branch64())
+        } else {
+            self.read_operand(instruction, 0)
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation, reason = "the count is masked to six bits")]
+    fn shift(&mut self, instruction: &Instruction) -> Result<(), Fault> {
+        let bits = Self::operand_bits(instruction, 0);
+        let a = self.read_operand(instruction, 0)? & mask(bits);
+        let count = if instruction.op_count() > 1 { self.read_operand(instruction, 1)? } else { 1 };
+        let count = (count & if bits == 64 { 0x3f } else { 0x1f }) as u32;
+        if count == 0 {
+            return Ok(());
+        }
+        let m = mask(bits);
+        let result = match instruction.mnemonic() {
+            Mnemonic::Shl | Mnemonic::Sal => {
+                let result = if count >= 64 { 0 } else { (a << count) & m };
+                let carry = count <= bits && (a >> (bits - count)) & 1 != 0;
+                self.flags &= !STATUS;
+                self.set_flag(CF, carry);
+                self.set_flag(OF, sign(result, bits) != carry);
+                self.set_result_flags(result, bits);
+                result
+            }
+            Mnemonic::Shr => {
+                let result = if count >= 64 { 0 } else { a >> count };
+                self.flags &= !STATUS;
+                self.set_flag(CF, (a >> (count - 1)) & 1 != 0);
+                self.set_flag(OF, sign(a, bits));
+                self.set_result_flags(result, bits);
+                result
+            }
+            Mnemonic::Sar => {
+                let signed = sign_extend(a, bits).cast_signed();
+                let result = (signed >> count.min(63)).cast_unsigned() & m;
+                self.flags &= !STATUS;
+                self.set_flag(CF, (signed >> (count - 1).min(63)) & 1 != 0);
+                self.set_result_flags(result, bits);
+                result
+            }
+            _ => {
+                let rotation = count % bits;
+                let left = instruction.mnemonic() == Mnemonic::Rol;
+                let result = match (rotation, left) {
+                    (0, _) => a,
+                    (_, true) => ((a << rotation) | (a >> (bits - rotation))) & m,
+                    (_, false) => ((a >> rotation) | (a << (bits - rotation))) & m,
+                };
+                if left {
+                    let carry = result & 1 != 0;
+                    self.set_flag(CF, carry);
+                    self.set_flag(OF, sign(result, bits) != carry);
+                } else {
+                    self.set_flag(CF, sign(result, bits));
+                    self.set_flag(OF, sign(result, bits) != sign(result << 1, bits));
+                }
+                result
+            }
+        };
+        self.write_operand(instruction, 0, result)
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, reason = "quotients are checked to fit the operand width and remainders are below the divisor")]
+    fn divide(&mut self, instruction: &Instruction, bits: u32) -> Result<(), Fault> {
+        let divisor = self.read_operand(instruction, 0)? & mask(bits);
+        if divisor == 0 {
+            return Err(Fault::DivideError);
+        }
+        let (low, high) = if bits == 8 {
+            (self.register(Register::AX) & 0xff, self.register(Register::AX) >> 8)
+        } else {
+            (
+                self.register(Register::RAX) & mask(bits),
+                self.register(Register::RDX) & mask(bits),
+            )
+        };
+        let dividend = (u128::from(high) << bits) | u128::from(low);
+        let (quotient, remainder) = if instruction.mnemonic() == Mnemonic::Div {
+            let quotient = dividend / u128::from(divisor);
+            if quotient > u128::from(mask(bits)) {
+                return Err(Fault::DivideError);
+            }
+            (quotient as u64, (dividend % u128::from(divisor)) as u64)
+        } else {
+            let shift = 128 - 2 * bits;
+            let dividend = (dividend << shift).cast_signed() >> shift;
+            let divisor = i128::from(sign_extend(divisor, bits).cast_signed());
+            if dividend == i128::MIN && divisor == -1 {
+                return Err(Fault::DivideError);
+            }
+            let quotient = dividend / divisor;
+            if quotient != (quotient << (128 - bits)) >> (128 - bits) {
+                return Err(Fault::DivideError);
+            }
+            (quotient as u64 & mask(bits), (dividend % divisor) as u64 & mask(bits))
+        };
+        self.store_wide(bits, quotient, remainder);
+        Ok(())
+    }
+
+    /// Writes a result split over AL/AH, AX/DX, EAX/EDX or RAX/RDX.
+    fn store_wide(&mut self, bits: u32, low: u64, high: u64) {
+        match bits {
+            8 => self.set_register(Register::AX, (high << 8) | (low & 0xff)),
+            16 => {
+                self.set_register(Register::AX, low);
+                self.set_register(Register::DX, high);
+            }
+            32 => {
+                self.set_register(Register::EAX, low);
+                self.set_register(Register::EDX, high);
+            }
+            _ => {
+                self.set_register(Register::RAX, low);
+                self.set_register(Register::RDX, high);
+            }
+        }
+    }
+
+    /// `rep stos` and `rep movs`, which compilers use for inline memset
+    /// and memcpy.
+    fn execute_string(&mut self, instruction: &Instruction) -> Result<(), Fault> {
+        let size = match instruction.code() {
+            Code::Stosb_m8_AL | Code::Movsb_m8_m8 => 1,
+            Code::Stosw_m16_AX | Code::Movsw_m16_m16 => 2,
+            Code::Stosd_m32_EAX | Code::Movsd_m32_m32 => 4,
+            Code::Stosq_m64_RAX | Code::Movsq_m64_m64 => 8,
+            _ => return Err(Fault::Unsupported(format!("rep {:?}", instruction.mnemonic()))),
+        };
+        let step = if self.flag(DF) { (size as u64).wrapping_neg() } else { size as u64 };
+        let stores = matches!(instruction.mnemonic(), Mnemonic::Stosb | Mnemonic::Stosw | Mnemonic::Stosd | Mnemonic::Stosq);
+        while self.registers[1] != 0 {
+            let value = if stores {
+                self.registers[0]
+            } else {
+                let value = self.memory.read(self.registers[6], size)?;
+                self.registers[6] = self.registers[6].wrapping_add(step);
+                value
+            };
+            self.memory.write(self.registers[7], size, value)?;
+            self.registers[7] = self.registers[7].wrapping_add(step);
+            self.registers[1] -= 1;
+            self.steps += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,343 @@
+//! Runs a function from a random state and compares what it left behind
+//! for its caller. The verifier uses it on single functions, the
+//! differential tests on whole images.
+
+use super::emulator::{DF, Emulator, Fault, Memory, PAGE_BYTES};
+use crate::passes::PassRng;
+use iced_x86::Register;
+use rand::Rng;
+use std::collections::BTreeSet;
+use std::ops::Range;
+
+/// Where the function returns to. Reaching it ends a run.
+pub const RETURN_ADDRESS: u64 = 0xdead_0000_0000;
+pub const STACK: u64 = 0x7ff0_0000_0000;
+pub const STACK_SIZE: u64 = 0x40000;
+/// Memory that registers and stack arguments point into.
+pub const HEAP: u64 = 0x5000_0000_0000;
+pub const HEAP_SIZE: u64 = 0x10000;
+const STACK_ARGUMENTS: u64 = 64;
+pub const MAX_CALLS: usize = 1000;
+/// A transformed function may take this many times the steps of the one
+/// it is compared with.
+///
+/// The VM alone runs dozens of instructions per original one, and entering
+/// and leaving it takes a fixed allowance on top.
+pub const STEP_FACTOR: u64 = 256;
+pub const STEP_ALLOWANCE: u64 = 10_000;
+
+const NON_VOLATILE_REGISTERS: [Register; 9] = [
+    Register::RBX,
+    Register::RSP,
+    Register::RBP,
+    Register::RSI,
+    Register::RDI,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+    Register::R15,
+];
+/// XMM0 for floating-point results and the non-volatile XMM6-XMM15.
+const RESULT_XMM: [usize; 11] = [0, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+const VOLATILE_REGISTERS: [Register; 7] = [
+    Register::RAX,
+    Register::RCX,
+    Register::RDX,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+];
+
+/// Registers, stack and heap a run starts from.
+#[derive(Clone)]
+pub struct State {
+    registers: [u64; 16],
+    xmm: [u128; 16],
+    heap: Vec<u8>,
+    stack: Vec<u64>,
+}
+
+impl State {
+    #[allow(clippy::cast_possible_truncation, reason = "the heap is 64 KiB")]
+    pub fn random(rng: &mut PassRng) -> Self {
+        let pointer_or_value = |rng: &mut PassRng| match rng.random_range(0..3) {
+            0 => HEAP + rng.random_range(0..HEAP_SIZE - 0x100),
+            1 => rng.random_range(0..100),
+            _ => rng.random(),
+        };
+        let mut heap = vec![0u8; HEAP_SIZE as usize];
+        rng.fill(&mut heap[..]);
+        // Pointers at regular intervals keep double indirections mapped.
+        for offset in (0..HEAP_SIZE as usize).step_by(64) {
+            let pointer = HEAP + rng.random_range(0..HEAP_SIZE - 0x100);
+            heap[offset..offset + 8].copy_from_slice(&pointer.to_le_bytes());
+        }
+        Self {
+            registers: std::array::from_fn(|_| pointer_or_value(rng)),
+            xmm: std::array::from_fn(|_| rng.random()),
+            heap,
+            stack: (0..STACK_ARGUMENTS).map(|_| pointer_or_value(rng)).collect(),
+        }
+    }
+}
+
+/// A value the caller or a callee sees.
+///
+/// When the environment records what a pointer points to, the value also
+/// matches another pointer to the same bytes: string encryption hands out
+/// decrypted copies of the originals.
+#[derive(Clone, Debug)]
+pub struct Value {
+    pub raw: u64,
+    pub pointee: Option<Vec<u8>>,
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.raw == other.raw || (self.pointee.is_some() && self.pointee == other.pointee)
+    }
+}
+
+/// What a run sees of the code around the one under test.
+pub trait Environment {
+    /// Whether reaching `address` calls out of the code under test. Such
+    /// calls return at once with values derived from the address and the
+    /// number of calls made so far in the volatile registers.
+    fn is_external(&self, address: u64) -> bool;
+
+    /// Records `value`, an argument or the result, for comparison.
+    fn value(&self, _memory: &Memory, value: u64) -> Value {
+        Value {
+            raw: value,
+            pointee: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum End {
+    Return,
+    Trap,
+    DivideError,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Call {
+    pub target: u64,
+    pub arguments: [Value; 4],
+}
+
+/// What a run left behind for the caller.
+pub struct Outcome {
+    pub end: End,
+    pub result: Value,
+    registers: Vec<u64>,
+    xmm: Vec<u128>,
+    direction_flag: bool,
+    pub calls: Vec<Call>,
+    memory: Memory,
+    pub steps: u64,
+}
+
+/// Why a run ended without an outcome.
+#[derive(Debug)]
+pub enum Stop {
+    Fault(Fault),
+    /// Too many steps or calls, most likely a loop over random data.
+    Limit,
+}
+
+impl From<Fault> for Stop {
+    fn from(fault: Fault) -> Self {
+        Self::Fault(fault)
+    }
+}
+
+const fn initial_rsp() -> u64 {
+    STACK + STACK_SIZE - 0x8000 - 8
+}
+
+/// Runs the code at `entry` from `state` until it returns or traps.
+///
+/// # Errors
+///
+/// Fails when the run faults or takes more than `max_steps` steps or
+/// [`MAX_CALLS`] calls.
+pub fn run(
+    mut memory: Memory,
+    entry: u64,
+    state: &State,
+    max_steps: u64,
+    environment: &impl Environment,
+) -> Result<Outcome, Stop> {
+    memory.write_bytes(HEAP, &state.heap)?;
+    let rsp = initial_rsp();
+    memory.write(rsp, 8, RETURN_ADDRESS)?;
+    for (slot, &value) in state.stack.iter().enumerate() {
+        memory.write(rsp + 8 * (slot as u64 + 1), 8, value)?;
+    }
+
+    let mut emulator = Emulator::new(memory);
+    emulator.registers = state.registers;
+    emulator.registers[4] = rsp;
+    emulator.xmm = state.xmm;
+    emulator.rip = entry;
+
+    let mut calls = Vec::new();
+    let end = loop {
+        let rip = emulator.rip;
+        if rip == RETURN_ADDRESS {
+            break End::Return;
+        }
+        if environment.is_external(rip) {
+            let r = &emulator.registers;
+            let arguments = [r[1], r[2], r[8], r[9]].map(|value| environment.value(&emulator.memory, value));
+            calls.push(Call {
+                target: rip,
+                arguments,
+            });
+            if calls.len() > MAX_CALLS {
+                return Err(Stop::Limit);
+            }
+            let hash = rip.wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ calls.len() as u64;
+            for (index, &register) in (0u32..).zip(VOLATILE_REGISTERS.iter()) {
+                emulator.set_register(register, hash.rotate_left(index * 7) % 1000);
+            }
+            emulator.xmm[0] = u128::from(hash);
+            emulator.rip = emulator.pop()?;
+            continue;
+        }
+        if emulator.steps >= max_steps {
+            return Err(Stop::Limit);
+        }
+        match emulator.step() {
+            Ok(()) => {}
+            Err(Fault::Trap) => break End::Trap,
+            Err(Fault::DivideError) => break End::DivideError,
+            Err(fault) => return Err(fault.into()),
+        }
+    };
+
+    Ok(Outcome {
+        end,
+        result: environment.value(&emulator.memory, emulator.register(Register::RAX)),
+        registers: NON_VOLATILE_REGISTERS
+            .iter()
+            .map(|&register| emulator.register(register))
+            .collect(),
+        xmm: RESULT_XMM.iter().map(|&index| emulator.xmm[index]).collect(),
+        direction_flag: emulator.flags & DF != 0,
+        calls,
+        steps: emulator.steps,
+        memory: emulator.memory,
+    })
+}
+
+/// Compares two runs from the same state, with pointers in memory recorded
+/// as `environment`, the one of the `actual` run, records values.
+///
+/// Registers only matter after a return; a trap leaves them to the
+/// exception handler, which sees the same calls and memory.
+///
+/// # Errors
+///
+/// Fails with what the caller would see differently: the way the run
+/// ended, the calls made, the result and non-volatile registers, or the
+/// heap, caller stack and `writable` memory.
+pub fn compare(
+    expected: &Outcome,
+    actual: &Outcome,
+    writable: &[Range<u64>],
+    environment: &impl Environment,
+) -> Result<(), String> {
+    if expected.end != actual.end {
+        return Err(format!("ended with {:?} instead of {:?}", actual.end, expected.end));
+    }
+    if let Some(index) = (0..expected.calls.len().max(actual.calls.len()))
+        .find(|&index| expected.calls.get(index) != actual.calls.get(index))
+    {
+        return Err(format!(
+            "call {} was {:x?} instead of {:x?}",
+            index + 1,
+            actual.calls.get(index),
+            expected.calls.get(index)
+        ));
+    }
+    if expected.end == End::Return {
+        if expected.result != actual.result {
+            return Err(format!("returned {:x?} instead of {:x?}", actual.result, expected.result));
+        }
+        for (index, register) in NON_VOLATILE_REGISTERS.iter().enumerate() {
+            if expected.registers[index] != actual.registers[index] {
+                return Err(format!(
+                    "{register:?} was {:#x} instead of {:#x}",
+                    actual.registers[index], expected.registers[index]
+                ));
+            }
+        }
+        for (index, register) in RESULT_XMM.iter().enumerate() {
+            if expected.xmm[index] != actual.xmm[index] {
+                return Err(format!(
+                    "XMM{register} was {:#x} instead of {:#x}",
+                    actual.xmm[index], expected.xmm[index]
+                ));
+            }
+        }
+        if expected.direction_flag != actual.direction_flag {
+            return Err("direction flag left set".to_string());
+        }
+    }
+    compare_memory(writable, &expected.memory, &actual.memory, environment)
+        .map_or(Ok(()), |address| Err(format!("memory at {address:#x} differs")))
+}
+
+/// First address the caller could observe that holds different bytes: the
+/// heap, the stack above the return address and the writable sections.
+/// Pointers the environment records as the same value do not count.
+#[allow(clippy::cast_possible_truncation, reason = "offsets stay within a page and the pointer straddling its end")]
+fn compare_memory(
+    writable: &[Range<u64>],
+    expected: &Memory,
+    actual: &Memory,
+    environment: &impl Environment,
+) -> Option<u64> {
+    let caller_stack = initial_rsp() + 8..STACK + STACK_SIZE;
+    let observable = |address: u64| {
+        (HEAP..HEAP + HEAP_SIZE).contains(&address)
+            || caller_stack.contains(&address)
+            || writable.iter().any(|range| range.contains(&address))
+    };
+    let pages: BTreeSet<u64> = expected.written_pages().chain(actual.written_pages()).collect();
+    let mut left = [0u8; PAGE_BYTES];
+    let mut right = [0u8; PAGE_BYTES];
+    for page in pages {
+        if expected.read_bytes(page, &mut left).is_err() || actual.read_bytes(page, &mut right).is_err() {
+            continue;
+        }
+        if left == right {
+            continue;
+        }
+        let mut offset = 0;
+        while offset < PAGE_BYTES {
+            let address = page + offset as u64;
+            if left[offset] == right[offset] || !observable(address) {
+                offset += 1;
+                continue;
+            }
+            // Pointers in objects at random addresses need not be aligned.
+            let pointer = (address.saturating_sub(7)..=address).find(|&start| {
+                expected
+                    .read(start, 8)
+                    .ok()
+                    .zip(actual.read(start, 8).ok())
+                    .is_some_and(|(left, right)| environment.value(expected, left) == environment.value(actual, right))
+            });
+            let Some(start) = pointer else {
+                return Some(address);
+            };
+            offset = (start + 8 - page) as usize;
+        }
+    }
+    None
+}
@@ -0,0 +1,336 @@
+pub mod emulator;
+#[doc(hidden)]
+pub mod harness;
+
+use crate::config::VerificationConfig;
+use crate::function::{Encodable, ObfuscatorFunction, StateManaged};
+use crate::passes::PassRng;
+use crate::format::BinaryImage;
+use crate::vm::interpreter;
+use common::{debug, warn};
+use emulator::{AddressMap, Memory, Page};
+use harness::{
+    Environment, HEAP, HEAP_SIZE, MAX_CALLS, Outcome, STACK, STACK_SIZE, STEP_ALLOWANCE, STEP_FACTOR, State, Stop,
+};
+use rand::SeedableRng;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::ops::Range;
+use std::rc::Rc;
+
+/// A pass after which a function no longer did the same as before it.
+#[derive(Clone, Debug, Serialize)]
+pub struct SemanticMismatch {
+    pub function: String,
+    pub pass: String,
+    pub iteration: usize,
+    pub detail: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct VerificationReport {
+    /// Functions with at least one conclusive trial for every pass.
+    pub functions_verified: usize,
+    /// Functions whose original could not be run to the end from any
+    /// state, e.g. because it uses unsupported instructions.
+    pub functions_inconclusive: Vec<String>,
+    pub trials: usize,
+    pub inconclusive_trials: usize,
+    pub mismatches: Vec<SemanticMismatch>,
+}
+
+/// One version of a function placed in a copy of the image. Control
+/// leaving `code` is taken for a call to another function.
+struct Program {
+    memory: Memory,
+    entry: u64,
+    code: Vec<Range<u64>>,
+}
+
+impl Program {
+    fn run(&self, state: &State, max_steps: u64) -> Result<Outcome, Stop> {
+        harness::run(self.memory.clone(), self.entry, state, max_steps, self)
+    }
+}
+
+impl Environment for Program {
+    fn is_external(&self, address: u64) -> bool {
+        !self.code.iter().any(|range| range.contains(&address))
+    }
+}
+
+/// The version of a function the passes are checked against, with its
+/// outcome from each state.
+pub struct Reference {
+    states: Vec<State>,
+    outcomes: Vec<Option<Outcome>>,
+    /// Steps of each trial after the last pass checked.
+    steps: Vec<u64>,
+}
+
+impl Reference {
+    fn is_conclusive(&self) -> bool {
+        self.outcomes.iter().any(Option::is_some)
+    }
+}
+
+/// Checks that passes keep the behavior of the functions they transform.
+///
+/// Each function is emulated before the passes and after every one of them
+/// from the same random register, stack and heap states, with calls to
+/// other functions answered by deterministic stand-ins. The value in RAX
+/// and the non-volatile registers, the direction flag, the calls made with
+/// their register arguments, and the heap, caller stack and writable image
+/// memory must come out the same.
+pub struct Verifier {
+    config: VerificationConfig,
+    seed: u64,
+    image_base: u64,
+    image: Rc<AddressMap<Box<Page>>>,
+    writable: Vec<Range<u64>>,
+    /// Free addresses above the image where the transformed versions run.
+    scratch_rva: u32,
+    report: RefCell<VerificationReport>,
+}
+
+impl Verifier {
+    /// # Errors
+    ///
+    /// Fails when the image cannot be parsed.
+    pub fn new(image: &dyn BinaryImage, config: VerificationConfig, seed: u64) -> Result<Self, String> {
+        let image_base = image.image_base()?;
+        let mut memory = Memory::default();
+        let mut writable = Vec::new();
+        for region in image.regions()? {
+            let address = image_base + u64::from(region.rva);
+            let size = u64::from(region.size).max(region.file.len() as u64);
+            memory.map(address, size);
+            if let Some(bytes) = image.data().get(region.file.clone())
+                && !bytes.is_empty()
+            {
+                memory.write_bytes(address, bytes).map_err(|e| e.to_string())?;
+            }
+            if region.writable {
+                writable.push(address..address + size);
+            }
+        }
+
+        // Zeroed in the shared base, so a run only copies the pages it writes.
+        memory.map(STACK, STACK_SIZE);
+        memory.map(HEAP, HEAP_SIZE);
+
+        Ok(Self {
+            config,
+            seed,
+            image_base,
+            image: memory.into_base(),
+            writable,
+            scratch_rva: image.next_section_rva()?,
+            report: RefCell::new(VerificationReport::default()),
+        })
+    }
+
+    pub fn report(&self) -> VerificationReport {
+        self.report.borrow().clone()
+    }
+
+    /// Runs the function as the passes receive it. That is its original
+    /// code unless string encryption already rewrote its loads, which
+    /// changes the pointers they produce.
+    pub fn reference(&self, function: &ObfuscatorFunction) -> Option<Reference> {
+        let original = function.get_original()?;
+        let untouched = original.instructions.len() == function.instructions.len()
+            && original
+                .instructions
+                .iter()
+                .zip(&function.instructions)
+                .all(|(original, current)| *original == current.instruction);
+
+        let program = if untouched {
+            Ok(self.original_program(function))
+        } else {
+            self.transformed_program(function)
+        };
+        let program = match program {
+            Ok(program) => program,
+            Err(e) => {
+                debug!("Not verifying function {}: {e}", function.name);
+                return None;
+            }
+        };
+
+        let mut rng = PassRng::seed_from_u64(self.seed ^ (u64::from(function.get_original_rva()) << 32));
+        let states: Vec<State> = (0..self.config.trials).map(|_| State::random(&mut rng)).collect();
+        let outcomes: Vec<Option<Outcome>> = states
+            .iter()
+            .map(|state| program.run(state, self.config.max_steps).ok())
+            .collect();
+        if outcomes.iter().all(Option::is_none) {
+            self.report
+                .borrow_mut()
+                .functions_inconclusive
+                .push(function.name.clone());
+        }
+        let steps = outcomes.iter().map(|outcome| outcome.as_ref().map_or(0, |outcome| outcome.steps)).collect();
+        Some(Reference {
+            states,
+            outcomes,
+            steps,
+        })
+    }
+
+    /// Compares the function after `pass` with `reference`. A mismatch is
+    /// reported and the function as it is now becomes the reference, so
+    /// that later passes are judged on what they were given.
+    pub fn check(&self, function: &ObfuscatorFunction, reference: &mut Reference, pass: &str, iteration: usize) {
+        if !reference.is_conclusive() {
+            return;
+        }
+        let program = match self.transformed_program(function) {
+            Ok(program) => program,
+            Err(e) => {
+                debug!("Cannot verify pass {pass} on function {}: {e}", function.name);
+                return;
+            }
+        };
+
+        let mut report = self.report.borrow_mut();
+        for trial in 0..reference.states.len() {
+            report.trials += 1;
+            let Some(expected) = &reference.outcomes[trial] else {
+                report.inconclusive_trials += 1;
+                continue;
+            };
+            let max_steps = reference.steps[trial] * STEP_FACTOR + STEP_ALLOWANCE;
+            let verdict = match program.run(&reference.states[trial], max_steps) {
+                Ok(actual) => {
+                    reference.steps[trial] = actual.steps;
+                    harness::compare(expected, &actual, &self.writable, &program)
+                }
+                // The reference finished from this state, so running out
+                // of steps or calls is a change of behavior.
+                Err(Stop::Limit) => Err(format!(
+                    "did not finish within {max_steps} steps and {MAX_CALLS} calls"
+                )),
+                Err(Stop::Fault(fault)) => Err(format!("stopped with {fault}")),
+            };
+            match verdict {
+                Ok(()) => {}
+                Err(detail) => {
+                    let detail = format!("trial {trial}: {detail}");
+                    warn!(
+                        "Pass {pass} changed the behavior of function {} in iteration {}: {detail}",
+                        function.name,
+                        iteration + 1
+                    );
+                    report.mismatches.push(SemanticMismatch {
+                        function: function.name.clone(),
+                        pass: pass.to_string(),
+                        iteration: iteration + 1,
+                        detail,
+                    });
+                    drop(report);
+                    if let Some(current) = self.reference(function) {
+                        *reference = current;
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Counts a function whose passes have all been checked.
+    pub fn finish(&self, reference: &Reference) {
+        if reference.is_conclusive() {
+            self.report.borrow_mut().functions_verified += 1;
+        }
+    }
+
+    /// The function as it is in the input image. Its bytes are run as they
+    /// are: re-encoding may shorten instructions and leave stale bytes behind.
+    fn original_program(&self, function: &ObfuscatorFunction) -> Program {
+        let start = self.image_base + u64::from(function.get_original_rva());
+        let end = start + u64::from(function.get_original_size());
+        Program {
+            memory: Memory::new(self.image.clone()),
+            entry: start,
+            code: std::iter::once(start..end).collect(),
+        }
+    }
+
+    /// The current instructions encoded above the image, with the import
+    /// table entries, interpreter and bytecode of the function in front of
+    /// them and a jump to them at the original entry for recursive calls.
+    fn transformed_program(&self, function: &ObfuscatorFunction) -> Result<Program, String> {
+        let mut function = function.clone();
+        let mut memory = Memory::new(self.image.clone());
+        let write = |memory: &mut Memory, rva: u32, bytes: &[u8]| {
+            let address = self.image_base + u64::from(rva);
+            memory.map(address, bytes.len() as u64);
+            memory.write_bytes(address, bytes).map_err(|e| e.to_string())
+        };
+
+        let mut code_rva = self.scratch_rva;
+        let mut table = Vec::new();
+        let mut entry_rva = code_rva;
+        for thunk in std::mem::take(&mut function.import_thunks) {
+            table.extend_from_slice(&thunk.encode_entry(entry_rva).to_le_bytes());
+            function.retarget_import_thunk(&thunk, entry_rva);
+            entry_rva += 8;
+        }
+        if !table.is_empty() {
+            write(&mut memory, code_rva, &table)?;
+            code_rva = entry_rva.next_multiple_of(16);
+        }
+
+        let mut code = Vec::new();
+        let mut interpreter_code = None;
+        if let Some(program) = &function.vm {
+            let interpreter = interpreter::assemble(&program.layout, code_rva)?;
+            let code_size = u32::try_from(interpreter.code.len()).map_err(|_| "VM interpreter too large")?;
+            let bytecode_size = u32::try_from(program.bytecode_size()).map_err(|_| "VM bytecode too large")?;
+            let bytecode_rva = code_rva + code_size;
+            let end = bytecode_rva + bytecode_size;
+            function.bind_vm_stubs(code_rva + interpreter.entry, bytecode_rva)?;
+            code.push(self.image_base + u64::from(code_rva)..self.image_base + u64::from(end));
+            interpreter_code = Some((code_rva, bytecode_rva, interpreter.code));
+            code_rva = end.next_multiple_of(16);
+        }
+
+        let mut bytes = function.encode(code_rva)?;
+        let relocations = function.relocate_base_relocations(self.image_base, &mut bytes, code_rva)?;
+        debug!(
+            "Placed {} for verification at {code_rva:#x} ({} bytes, {} relocations)",
+            function.name,
+            bytes.len(),
+            relocations.len()
+        );
+        write(&mut memory, code_rva, &bytes)?;
+        code.push(self.image_base + u64::from(code_rva)..self.image_base + (code_rva as usize + bytes.len()) as u64);
+
+        if let Some((rva, bytecode_rva, interpreter)) = interpreter_code {
+            write(&mut memory, rva, &interpreter)?;
+            if let Some(bytecode) = function.encode_vm_bytecode()? {
+                write(&mut memory, bytecode_rva, &bytecode)?;
+            }
+        }
+        for (table_rva, entries) in function.encode_jump_tables()? {
+            write(&mut memory, table_rva, &entries)?;
+        }
+
+        let original_rva = function.get_original_rva();
+        let mut redirect = [0xE9u8; 5];
+        let offset = i32::try_from(i64::from(code_rva) - (i64::from(original_rva) + 5))
+            .map_err(|_| format!("Scratch code is out of reach of {}", function.name))?;
+        redirect[1..].copy_from_slice(&offset.to_le_bytes());
+        write(&mut memory, original_rva, &redirect)?;
+        let original = self.image_base + u64::from(original_rva);
+        code.push(original..original + redirect.len() as u64);
+
+        Ok(Program {
+            memory,
+            entry: self.image_base + u64::from(code_rva),
+            code,
+        })
+    }
+}
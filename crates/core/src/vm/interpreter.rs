@@ -0,0 +1,391 @@
+use super::{
+    AluOp, CONTEXT_FLAGS, CONTEXT_RETURN, CONTEXT_SIZE, CONTEXT_TARGET, Handler, ShiftOp, UnaryOp, VmLayout,
+    Width,
+};
+use crate::liveness::GPRS;
+use iced_x86::code_asm::{
+    AsmRegister64, CodeAssembler, CodeLabel, al, ax, byte_ptr, cl, cx, dword_ptr, eax, ecx,
+    get_gpr64, ptr, qword_ptr, rax, rbx, rcx, rdi, rsi, rsp, word_ptr,
+};
+use iced_x86::{BlockEncoderOptions, ConditionCode, IcedError, Register};
+
+/// Machine code of the interpreter of one function, with the offset of
+/// the routine the entry stubs jump to.
+pub struct Interpreter {
+    pub code: Vec<u8>,
+    pub entry: u32,
+}
+
+/// Expands `$body` with `$a` and `$c` bound to AL/CL, AX/CX, EAX/ECX or
+/// RAX/RCX, the operands of the flag-setting handlers.
+macro_rules! sized {
+    ($width:expr, |$a:ident, $c:ident| $body:expr) => {
+        match $width {
+            Width::Byte => {
+                let ($a, $c) = (al, cl);
+                $body
+            }
+            Width::Word => {
+                let ($a, $c) = (ax, cx);
+                $body
+            }
+            Width::Dword => {
+                let ($a, $c) = (eax, ecx);
+                $body
+            }
+            Width::Qword => {
+                let ($a, $c) = (rax, rcx);
+                $body
+            }
+        }
+    };
+}
+
+/// Builds the interpreter for `layout` as it will run at `rva`. The code
+/// only depends on the RVA through one constant, so its size does not.
+///
+/// While the VM runs RDI points to the context, RSI to the next opcode and
+/// RBX to the image base; the native stack below the context is the operand
+/// stack. Every handler ends by dispatching the next opcode through a table
+/// of offsets relative to the table itself.
+///
+/// # Errors
+///
+/// Fails when the interpreter cannot be assembled.
+pub fn assemble(layout: &VmLayout, rva: u32) -> Result<Interpreter, String> {
+    build(layout, rva).map_err(|e| format!("Failed to assemble VM interpreter: {e}"))
+}
+
+#[allow(clippy::cast_possible_truncation, reason = "the interpreter spans a few kilobytes")]
+fn build(layout: &VmLayout, rva: u32) -> Result<Interpreter, IcedError> {
+    let mut a = CodeAssembler::new(64)?;
+    let mut start = a.create_label();
+    let mut table = a.create_label();
+    let mut exit = a.create_label();
+
+    a.set_label(&mut start)?;
+    emit_enter(&mut a, layout, rva, start, table)?;
+
+    let mut handlers: Vec<(Handler, CodeLabel)> = Vec::new();
+    for &handler in &layout.opcodes {
+        if handlers.iter().any(|&(emitted, _)| emitted == handler) {
+            continue;
+        }
+        let mut label = a.create_label();
+        a.set_label(&mut label)?;
+        emit_handler(&mut a, layout, handler, table, exit)?;
+        handlers.push((handler, label));
+    }
+
+    // EAX holds the RVA to continue at.
+    a.set_label(&mut exit)?;
+    a.add(rax, rbx)?;
+    a.mov(qword_ptr(rdi + CONTEXT_RETURN), rax)?;
+    emit_leave(&mut a, layout, false)?;
+
+    a.set_label(&mut table)?;
+    a.db(&[0u8; 256 * 4])?;
+
+    let result = a.assemble_options(u64::from(rva), BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS)?;
+    let table_ip = result.label_ip(&table)?;
+    let mut code = result.inner.code_buffer.clone();
+    let table_offset = (table_ip - u64::from(rva)) as usize;
+    for (opcode, handler) in layout.opcodes.iter().enumerate() {
+        let &(_, label) = handlers
+            .iter()
+            .find(|(emitted, _)| emitted == handler)
+            .expect("every handler of the table is emitted");
+        let delta = (result.label_ip(&label)?.cast_signed() - table_ip.cast_signed()) as i32;
+        let at = table_offset + opcode * 4;
+        code[at..at + 4].copy_from_slice(&delta.to_le_bytes());
+    }
+
+    Ok(Interpreter { code, entry: 0 })
+}
+
+fn gpr(register: Register) -> AsmRegister64 {
+    get_gpr64(register).expect("GPRS holds 64-bit registers")
+}
+
+fn slot_offset(layout: &VmLayout, index: usize) -> i32 {
+    i32::from(layout.slots[index]) * 8
+}
+
+/// Saves the guest state below the token pushed by the stub: an empty exit
+/// target slot, the flags and the registers in their permuted slots. The
+/// guest RSP lies above the token and the red zone the stub stepped over.
+fn emit_enter(
+    a: &mut CodeAssembler,
+    layout: &VmLayout,
+    rva: u32,
+    start: CodeLabel,
+    table: CodeLabel,
+) -> Result<(), IcedError> {
+    a.lea(rsp, ptr(rsp - 8))?;
+    a.pushfq()?;
+    a.lea(rsp, ptr(rsp - CONTEXT_FLAGS))?;
+    for (index, &register) in GPRS.iter().enumerate() {
+        if register != Register::RSP {
+            a.mov(qword_ptr(rsp + slot_offset(layout, index)), gpr(register))?;
+        }
+    }
+    a.lea(rax, ptr(rsp + CONTEXT_SIZE + i32::from(layout.red_zone)))?;
+    a.mov(qword_ptr(rsp + slot_offset(layout, Register::RSP.number())), rax)?;
+    a.mov(rdi, rsp)?;
+
+    a.lea(rbx, ptr(start))?;
+    a.mov(ecx, rva)?;
+    a.sub(rbx, rcx)?;
+    a.mov(eax, dword_ptr(rdi + CONTEXT_RETURN))?;
+    a.xor(eax, layout.token_key.cast_signed())?;
+    a.lea(rsi, ptr(rbx + rax))?;
+    emit_dispatch(a, 0, table)
+}
+
+/// Restores the guest registers and flags and leaves through `ret`, either
+/// to the target stored above the flags (for calls, which leave their
+/// return address in the token slot) or to the one in the token slot.
+/// Calls store both one red zone higher, right below the guest RSP, and
+/// exits pop the red zone along with the target.
+fn emit_leave(a: &mut CodeAssembler, layout: &VmLayout, call: bool) -> Result<(), IcedError> {
+    a.mov(rsp, rdi)?;
+    for (index, &register) in GPRS.iter().enumerate() {
+        if register != Register::RSP {
+            a.mov(gpr(register), qword_ptr(rsp + slot_offset(layout, index)))?;
+        }
+    }
+    a.lea(rsp, ptr(rsp + CONTEXT_FLAGS))?;
+    a.popfq()?;
+    let red_zone = i32::from(layout.red_zone);
+    if call {
+        if red_zone > 0 {
+            a.lea(rsp, ptr(rsp + red_zone))?;
+        }
+        return a.ret();
+    }
+    a.lea(rsp, ptr(rsp + 8))?;
+    if red_zone > 0 { a.ret_1(red_zone) } else { a.ret() }
+}
+
+/// Moves RSI past the current instruction and jumps to the handler of the
+/// next opcode.
+fn emit_dispatch(a: &mut CodeAssembler, advance: i32, table: CodeLabel) -> Result<(), IcedError> {
+    if advance != 0 {
+        a.add(rsi, advance)?;
+    }
+    a.movzx(eax, byte_ptr(rsi))?;
+    a.lea(rcx, ptr(table))?;
+    a.movsxd(rax, dword_ptr(rcx + rax * 4))?;
+    a.add(rax, rcx)?;
+    a.jmp(rax)
+}
+
+fn load_flags(a: &mut CodeAssembler) -> Result<(), IcedError> {
+    a.push(qword_ptr(rdi + CONTEXT_FLAGS))?;
+    a.popfq()
+}
+
+fn store_flags(a: &mut CodeAssembler) -> Result<(), IcedError> {
+    a.pushfq()?;
+    a.pop(qword_ptr(rdi + CONTEXT_FLAGS))
+}
+
+/// Emits the handler of an opcode at RSI, which is left pointing at it
+/// until the handler moves on to the next one.
+fn emit_handler(
+    a: &mut CodeAssembler,
+    layout: &VmLayout,
+    handler: Handler,
+    table: CodeLabel,
+    exit: CodeLabel,
+) -> Result<(), IcedError> {
+    match handler {
+        Handler::LoadRegister => {
+            a.movzx(eax, byte_ptr(rsi + 1))?;
+            a.push(qword_ptr(rdi + rax * 8))?;
+            emit_dispatch(a, 2, table)
+        }
+        Handler::StoreRegister(width) => {
+            a.movzx(eax, byte_ptr(rsi + 1))?;
+            a.pop(rcx)?;
+            match width {
+                Width::Byte => a.mov(byte_ptr(rdi + rax * 8), cl)?,
+                Width::Word => a.mov(word_ptr(rdi + rax * 8), cx)?,
+                Width::Dword => {
+                    a.mov(ecx, ecx)?;
+                    a.mov(qword_ptr(rdi + rax * 8), rcx)?;
+                }
+                Width::Qword => a.mov(qword_ptr(rdi + rax * 8), rcx)?,
+            }
+            emit_dispatch(a, 2, table)
+        }
+        Handler::LoadImmediate => {
+            a.mov(rax, qword_ptr(rsi + 1))?;
+            a.mov(rcx, layout.immediate_key)?;
+            a.xor(rax, rcx)?;
+            a.push(rax)?;
+            emit_dispatch(a, 9, table)
+        }
+        Handler::LoadAddress => {
+            a.mov(eax, dword_ptr(rsi + 1))?;
+            a.add(rax, rbx)?;
+            a.push(rax)?;
+            emit_dispatch(a, 5, table)
+        }
+        Handler::Load(width) => {
+            a.pop(rax)?;
+            match width {
+                Width::Byte => a.movzx(ecx, byte_ptr(rax))?,
+                Width::Word => a.movzx(ecx, word_ptr(rax))?,
+                Width::Dword => a.mov(ecx, dword_ptr(rax))?,
+                Width::Qword => a.mov(rcx, qword_ptr(rax))?,
+            }
+            a.push(rcx)?;
+            emit_dispatch(a, 1, table)
+        }
+        Handler::Store(width) => {
+            a.pop(rax)?;
+            a.pop(rcx)?;
+            match width {
+                Width::Byte => a.mov(byte_ptr(rax), cl)?,
+                Width::Word => a.mov(word_ptr(rax), cx)?,
+                Width::Dword => a.mov(dword_ptr(rax), ecx)?,
+                Width::Qword => a.mov(qword_ptr(rax), rcx)?,
+            }
+            emit_dispatch(a, 1, table)
+        }
+        Handler::Add | Handler::Mul => {
+            a.pop(rcx)?;
+            a.pop(rax)?;
+            if handler == Handler::Add {
+                a.add(rax, rcx)?;
+            } else {
+                a.imul_2(rax, rcx)?;
+            }
+            a.push(rax)?;
+            emit_dispatch(a, 1, table)
+        }
+        Handler::Extend(from, signed) => emit_extend(a, from, signed, table),
+        Handler::Alu(op, width) => emit_alu(a, op, width, table),
+        Handler::Unary(op, width) => emit_unary(a, op, width, table),
+        Handler::Shift(op, width) => emit_shift(a, op, width, table),
+        Handler::Branch(condition) => emit_branch(a, condition, table, exit),
+        Handler::Call => {
+            a.pop(rcx)?;
+            a.mov(eax, dword_ptr(rsi + 1))?;
+            a.add(rax, rbx)?;
+            let red_zone = i32::from(layout.red_zone);
+            a.mov(qword_ptr(rdi + CONTEXT_RETURN + red_zone), rax)?;
+            a.mov(qword_ptr(rdi + CONTEXT_TARGET + red_zone), rcx)?;
+            emit_leave(a, layout, true)
+        }
+        Handler::Exit => {
+            a.mov(eax, dword_ptr(rsi + 1))?;
+            a.jmp(exit)
+        }
+    }
+}
+
+/// Widens the value on top of the stack.
+fn emit_extend(a: &mut CodeAssembler, from: Width, signed: bool, table: CodeLabel) -> Result<(), IcedError> {
+    a.pop(rax)?;
+    match (from, signed) {
+        (Width::Byte, false) => a.movzx(eax, al)?,
+        (Width::Word, false) => a.movzx(eax, ax)?,
+        (Width::Byte, true) => a.movsx(rax, al)?,
+        (Width::Word, true) => a.movsx(rax, ax)?,
+        (_, false) => a.mov(eax, eax)?,
+        (_, true) => a.movsxd(rax, eax)?,
+    }
+    a.push(rax)?;
+    emit_dispatch(a, 1, table)
+}
+
+/// Applies a binary operation to the two values on top of the stack
+/// with the guest flags loaded, and saves the flags it leaves.
+fn emit_alu(a: &mut CodeAssembler, op: AluOp, width: Width, table: CodeLabel) -> Result<(), IcedError> {
+    a.pop(rcx)?;
+    a.pop(rax)?;
+    load_flags(a)?;
+    match (op, width) {
+        (AluOp::Imul, Width::Word) => a.imul_2(ax, cx)?,
+        (AluOp::Imul, Width::Dword) => a.imul_2(eax, ecx)?,
+        (AluOp::Imul, Width::Qword) => a.imul_2(rax, rcx)?,
+        (AluOp::Imul, Width::Byte) => unreachable!("there is no byte IMUL handler"),
+        _ => sized!(width, |x, y| match op {
+            AluOp::Add => a.add(x, y),
+            AluOp::Sub => a.sub(x, y),
+            AluOp::And => a.and(x, y),
+            AluOp::Or => a.or(x, y),
+            AluOp::Xor => a.xor(x, y),
+            AluOp::Adc => a.adc(x, y),
+            AluOp::Sbb => a.sbb(x, y),
+            AluOp::Cmp => a.cmp(x, y),
+            AluOp::Test => a.test(x, y),
+            AluOp::Imul => unreachable!(),
+        })?,
+    }
+    store_flags(a)?;
+    if op.keeps_result() {
+        a.push(rax)?;
+    }
+    emit_dispatch(a, 1, table)
+}
+
+fn emit_unary(a: &mut CodeAssembler, op: UnaryOp, width: Width, table: CodeLabel) -> Result<(), IcedError> {
+    a.pop(rax)?;
+    load_flags(a)?;
+    sized!(width, |x, _y| match op {
+        UnaryOp::Inc => a.inc(x),
+        UnaryOp::Dec => a.dec(x),
+        UnaryOp::Neg => a.neg(x),
+        UnaryOp::Not => a.not(x),
+    })?;
+    store_flags(a)?;
+    a.push(rax)?;
+    emit_dispatch(a, 1, table)
+}
+
+/// Shifts the value below the top of the stack by the count on top.
+fn emit_shift(a: &mut CodeAssembler, op: ShiftOp, width: Width, table: CodeLabel) -> Result<(), IcedError> {
+    a.pop(rcx)?;
+    a.pop(rax)?;
+    load_flags(a)?;
+    sized!(width, |x, _y| match op {
+        ShiftOp::Shl => a.shl(x, cl),
+        ShiftOp::Shr => a.shr(x, cl),
+        ShiftOp::Sar => a.sar(x, cl),
+        ShiftOp::Rol => a.rol(x, cl),
+        ShiftOp::Ror => a.ror(x, cl),
+    })?;
+    store_flags(a)?;
+    a.push(rax)?;
+    emit_dispatch(a, 1, table)
+}
+
+/// Leaves the VM to the exit in the operand when the guest flags meet
+/// the condition.
+fn emit_branch(a: &mut CodeAssembler, condition: ConditionCode, table: CodeLabel, exit: CodeLabel) -> Result<(), IcedError> {
+    a.mov(eax, dword_ptr(rsi + 1))?;
+    load_flags(a)?;
+    match condition {
+        ConditionCode::o => a.jo(exit)?,
+        ConditionCode::no => a.jno(exit)?,
+        ConditionCode::b => a.jb(exit)?,
+        ConditionCode::ae => a.jae(exit)?,
+        ConditionCode::e => a.je(exit)?,
+        ConditionCode::ne => a.jne(exit)?,
+        ConditionCode::be => a.jbe(exit)?,
+        ConditionCode::a => a.ja(exit)?,
+        ConditionCode::s => a.js(exit)?,
+        ConditionCode::ns => a.jns(exit)?,
+        ConditionCode::p => a.jp(exit)?,
+        ConditionCode::np => a.jnp(exit)?,
+        ConditionCode::l => a.jl(exit)?,
+        ConditionCode::ge => a.jge(exit)?,
+        ConditionCode::le => a.jle(exit)?,
+        ConditionCode::g => a.jg(exit)?,
+        ConditionCode::None => unreachable!("branches always have a condition"),
+    }
+    emit_dispatch(a, 5, table)
+}
@@ -0,0 +1,440 @@
+pub mod interpreter;
+
+use crate::function::ObfuscatorFunction;
+use crate::instruction::InstructionWithId;
+use crate::liveness::GPRS;
+use crate::passes::PassRng;
+use iced_x86::{Code, ConditionCode, Register};
+use rand::Rng;
+use rand::seq::SliceRandom;
+
+/// Guest flags, the exit target and the token pushed by the entry stub sit
+/// above the 16 register slots of the context.
+///
+/// The guest stack starts right after them, or after the red zone the stub
+/// stepped over.
+pub const CONTEXT_FLAGS: i32 = 128;
+pub const CONTEXT_TARGET: i32 = 136;
+pub const CONTEXT_RETURN: i32 = 144;
+pub const CONTEXT_SIZE: i32 = 152;
+
+pub const CONDITIONS: [ConditionCode; 16] = [
+    ConditionCode::o,
+    ConditionCode::no,
+    ConditionCode::b,
+    ConditionCode::ae,
+    ConditionCode::e,
+    ConditionCode::ne,
+    ConditionCode::be,
+    ConditionCode::a,
+    ConditionCode::s,
+    ConditionCode::ns,
+    ConditionCode::p,
+    ConditionCode::np,
+    ConditionCode::l,
+    ConditionCode::ge,
+    ConditionCode::le,
+    ConditionCode::g,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Width {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+impl Width {
+    pub const ALL: [Self; 4] = [Self::Byte, Self::Word, Self::Dword, Self::Qword];
+
+    #[must_use]
+    pub const fn from_size(size: usize) -> Option<Self> {
+        match size {
+            1 => Some(Self::Byte),
+            2 => Some(Self::Word),
+            4 => Some(Self::Dword),
+            8 => Some(Self::Qword),
+            _ => None,
+        }
+    }
+}
+
+/// Binary operations that set the guest flags. CMP and TEST only keep the
+/// flags and drop their result.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AluOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Adc,
+    Sbb,
+    Imul,
+    Cmp,
+    Test,
+}
+
+impl AluOp {
+    pub const ALL: [Self; 10] = [
+        Self::Add,
+        Self::Sub,
+        Self::And,
+        Self::Or,
+        Self::Xor,
+        Self::Adc,
+        Self::Sbb,
+        Self::Imul,
+        Self::Cmp,
+        Self::Test,
+    ];
+
+    #[must_use]
+    pub const fn keeps_result(self) -> bool {
+        !matches!(self, Self::Cmp | Self::Test)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum UnaryOp {
+    Inc,
+    Dec,
+    Neg,
+    Not,
+}
+
+impl UnaryOp {
+    pub const ALL: [Self; 4] = [Self::Inc, Self::Dec, Self::Neg, Self::Not];
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ShiftOp {
+    Shl,
+    Shr,
+    Sar,
+    Rol,
+    Ror,
+}
+
+impl ShiftOp {
+    pub const ALL: [Self; 5] = [Self::Shl, Self::Shr, Self::Sar, Self::Rol, Self::Ror];
+}
+
+/// Where the VM continues in native code: an instruction of the function,
+/// resolved once it has been encoded, or an address outside of it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitTarget {
+    Instruction(usize),
+    Address(u32),
+}
+
+/// One instruction of the stack machine. Operands are popped in reverse
+/// order: `Store` pops the address and then the value, binary operations
+/// pop their second operand first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmOp {
+    LoadRegister(Register),
+    /// Writes the popped value like an x86 register write of this width:
+    /// 32-bit writes clear the upper half, narrower ones merge.
+    StoreRegister(Register, Width),
+    LoadImmediate(u64),
+    /// Pushes the image base plus an RVA.
+    LoadAddress(u32),
+    Load(Width),
+    Store(Width),
+    /// Address arithmetic, which leaves the guest flags alone.
+    Add,
+    Mul,
+    Extend { from: Width, signed: bool },
+    Alu(AluOp, Width),
+    Unary(UnaryOp, Width),
+    Shift(ShiftOp, Width),
+    Branch(ConditionCode, ExitTarget),
+    /// Pops the callee and leaves the VM into it with the return address
+    /// on the guest stack.
+    Call(ExitTarget),
+    Exit(ExitTarget),
+}
+
+/// The code of the interpreter implementing a group of VM instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Handler {
+    LoadRegister,
+    StoreRegister(Width),
+    LoadImmediate,
+    LoadAddress,
+    Load(Width),
+    Store(Width),
+    Add,
+    Mul,
+    Extend(Width, bool),
+    Alu(AluOp, Width),
+    Unary(UnaryOp, Width),
+    Shift(ShiftOp, Width),
+    Branch(ConditionCode),
+    Call,
+    Exit,
+}
+
+impl Handler {
+    pub fn all() -> Vec<Self> {
+        let mut handlers = vec![
+            Self::LoadRegister,
+            Self::LoadImmediate,
+            Self::LoadAddress,
+            Self::Add,
+            Self::Mul,
+            Self::Call,
+            Self::Exit,
+        ];
+        for width in Width::ALL {
+            handlers.extend([Self::StoreRegister(width), Self::Load(width), Self::Store(width)]);
+            // There is no two-operand IMUL on bytes.
+            handlers.extend(
+                AluOp::ALL
+                    .into_iter()
+                    .filter(|&op| op != AluOp::Imul || width != Width::Byte)
+                    .map(|op| Self::Alu(op, width)),
+            );
+            handlers.extend(UnaryOp::ALL.into_iter().map(|op| Self::Unary(op, width)));
+            handlers.extend(ShiftOp::ALL.into_iter().map(|op| Self::Shift(op, width)));
+        }
+        for from in [Width::Byte, Width::Word, Width::Dword] {
+            handlers.extend([Self::Extend(from, false), Self::Extend(from, true)]);
+        }
+        handlers.extend(CONDITIONS.into_iter().map(Handler::Branch));
+        handlers
+    }
+}
+
+impl VmOp {
+    #[must_use]
+    pub const fn handler(&self) -> Handler {
+        match *self {
+            Self::LoadRegister(_) => Handler::LoadRegister,
+            Self::StoreRegister(_, width) => Handler::StoreRegister(width),
+            Self::LoadImmediate(_) => Handler::LoadImmediate,
+            Self::LoadAddress(_) => Handler::LoadAddress,
+            Self::Load(width) => Handler::Load(width),
+            Self::Store(width) => Handler::Store(width),
+            Self::Add => Handler::Add,
+            Self::Mul => Handler::Mul,
+            Self::Extend { from, signed } => Handler::Extend(from, signed),
+            Self::Alu(op, width) => Handler::Alu(op, width),
+            Self::Unary(op, width) => Handler::Unary(op, width),
+            Self::Shift(op, width) => Handler::Shift(op, width),
+            Self::Branch(condition, _) => Handler::Branch(condition),
+            Self::Call(_) => Handler::Call,
+            Self::Exit(_) => Handler::Exit,
+        }
+    }
+
+    /// Encoded size: the opcode followed by the operand, if any.
+    #[must_use]
+    pub const fn size(&self) -> usize {
+        match self {
+            Self::LoadRegister(_) | Self::StoreRegister(..) => 2,
+            Self::LoadImmediate(_) => 9,
+            Self::LoadAddress(_) | Self::Branch(..) | Self::Call(_) | Self::Exit(_) => 5,
+            _ => 1,
+        }
+    }
+
+    #[must_use]
+    pub const fn ends_segment(&self) -> bool {
+        matches!(self, Self::Call(_) | Self::Exit(_))
+    }
+}
+
+/// The randomized encoding of one function's bytecode, which its
+/// interpreter is built for.
+///
+/// It holds the handler behind every opcode (each handler has at least
+/// one, the rest are aliases), the context slot of every register and the
+/// keys of the entry tokens and immediates, along with the red zone of the
+/// function, which the entry stubs step over.
+#[derive(Clone, Debug)]
+pub struct VmLayout {
+    pub opcodes: Vec<Handler>,
+    pub slots: [u8; 16],
+    pub token_key: u32,
+    pub immediate_key: u64,
+    pub red_zone: u16,
+}
+
+impl VmLayout {
+    pub fn random(red_zone: u16, rng: &mut PassRng) -> Self {
+        let handlers = Handler::all();
+        let mut opcodes = handlers.clone();
+        while opcodes.len() < 256 {
+            opcodes.push(handlers[rng.random_range(0..handlers.len())]);
+        }
+        opcodes.shuffle(rng);
+
+        let mut slots = [0u8; 16];
+        for (slot, index) in slots.iter_mut().zip(0..) {
+            *slot = index;
+        }
+        slots.shuffle(rng);
+
+        Self {
+            opcodes,
+            slots,
+            token_key: rng.random(),
+            immediate_key: rng.random(),
+            red_zone,
+        }
+    }
+
+    /// Picks one of the opcodes of `handler`, varying with the position so
+    /// that repeated instructions do not repeat their encoding.
+    fn opcode(&self, handler: Handler, offset: usize) -> Result<u8, String> {
+        let aliases: Vec<u8> = (0..=255u8)
+            .filter(|&opcode| self.opcodes[opcode as usize] == handler)
+            .collect();
+        if aliases.is_empty() {
+            return Err(format!("No opcode for VM handler {handler:?}"));
+        }
+        Ok(aliases[offset % aliases.len()])
+    }
+
+    /// # Errors
+    ///
+    /// Fails when `register` is not a general purpose register.
+    pub fn slot(&self, register: Register) -> Result<u8, String> {
+        GPRS.iter()
+            .position(|&gpr| gpr == register)
+            .map(|index| self.slots[index])
+            .ok_or_else(|| format!("Register {register:?} has no VM slot"))
+    }
+
+    #[must_use]
+    pub const fn encode_token(&self, bytecode_rva: u32) -> i32 {
+        (bytecode_rva ^ self.token_key).cast_signed()
+    }
+}
+
+/// A run of instructions replaced by a native `push token; jmp vm_enter`
+/// stub.
+///
+/// Where the ABI has a red zone, a `lea rsp, [rsp-red zone]` comes first.
+/// The first instruction of the stub keeps the id of the run's first so
+/// that branches into the run enter the VM.
+#[derive(Clone, Debug)]
+pub struct VmSegment {
+    pub entry_id: usize,
+    pub token_id: usize,
+    pub jump_id: usize,
+    pub ops: Vec<VmOp>,
+}
+
+impl VmSegment {
+    pub fn size(&self) -> usize {
+        self.ops.iter().map(VmOp::size).sum()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VmProgram {
+    pub layout: VmLayout,
+    pub segments: Vec<VmSegment>,
+    /// Start of the interpreter, whose code is followed by the bytecode.
+    pub interpreter_rva: u32,
+    pub bytecode_rva: u32,
+}
+
+impl VmProgram {
+    pub fn bytecode_size(&self) -> usize {
+        self.segments.iter().map(VmSegment::size).sum()
+    }
+
+    /// Encodes every segment, with exit targets given as RVAs by `resolve`.
+    ///
+    /// # Errors
+    ///
+    /// Fails when an operand does not fit its encoding or an exit cannot be
+    /// resolved.
+    pub fn encode(&self, resolve: impl Fn(ExitTarget) -> Option<u32>) -> Result<Vec<u8>, String> {
+        let exit = |target: ExitTarget| {
+            resolve(target).ok_or_else(|| format!("VM exit target {target:?} no longer exists"))
+        };
+        let mut bytes = Vec::with_capacity(self.bytecode_size());
+        for op in self.segments.iter().flat_map(|segment| &segment.ops) {
+            bytes.push(self.layout.opcode(op.handler(), bytes.len())?);
+            match *op {
+                VmOp::LoadRegister(register) | VmOp::StoreRegister(register, _) => {
+                    bytes.push(self.layout.slot(register)?);
+                }
+                VmOp::LoadImmediate(value) => {
+                    bytes.extend_from_slice(&(value ^ self.layout.immediate_key).to_le_bytes());
+                }
+                VmOp::LoadAddress(rva) => bytes.extend_from_slice(&rva.to_le_bytes()),
+                VmOp::Branch(_, target) | VmOp::Call(target) | VmOp::Exit(target) => {
+                    bytes.extend_from_slice(&exit(target)?.to_le_bytes());
+                }
+                _ => {}
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+impl ObfuscatorFunction {
+    /// Points the entry stubs at the interpreter and gives each the token
+    /// of its segment's bytecode.
+    ///
+    /// # Errors
+    ///
+    /// Fails when a stub cannot be bound to the interpreter.
+    pub fn bind_vm_stubs(&mut self, enter_rva: u32, bytecode_rva: u32) -> Result<(), String> {
+        let Some(program) = self.vm.as_mut() else {
+            return Ok(());
+        };
+        program.bytecode_rva = bytecode_rva;
+
+        let mut offset = 0;
+        for segment in &program.segments {
+            let token = program.layout.encode_token(bytecode_rva + offset);
+            offset += u32::try_from(segment.size()).map_err(|_| format!("VM bytecode of {} is too large", self.name))?;
+
+            let mut bound = 0;
+            for inst in &mut self.instructions {
+                if inst.id == segment.token_id && inst.instruction.code() == Code::Pushq_imm32 {
+                    inst.instruction.set_immediate32(token.cast_unsigned());
+                    bound += 1;
+                } else if inst.id == segment.jump_id && inst.instruction.code() == Code::Jmp_rel32_64 {
+                    inst.instruction.set_near_branch64(u64::from(enter_rva));
+                    bound += 1;
+                }
+            }
+            if bound != 2 {
+                return Err(format!(
+                    "VM entry stub of instruction {} in {} was modified",
+                    segment.entry_id, self.name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Encodes the bytecode once the function has its final layout.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the bytecode cannot be encoded.
+    pub fn encode_vm_bytecode(&self) -> Result<Option<Vec<u8>>, String> {
+        let Some(program) = self.vm.as_ref() else {
+            return Ok(None);
+        };
+        let bytes = program.encode(|target| match target {
+            ExitTarget::Instruction(id) => self
+                .instructions
+                .iter()
+                .find(|inst| inst.id == id)
+                .map(InstructionWithId::rva),
+            ExitTarget::Address(rva) => Some(rva),
+        })?;
+        Ok(Some(bytes))
+    }
+}
@@ -0,0 +1,121 @@
+//! Obfuscates the sample binaries and calls their functions in both the
+//! original and the obfuscated image from the same random states. Every
+//! call the original finishes must come out the same in the obfuscated
+//! image: return value, non-volatile registers, import calls and memory.
+
+mod support;
+
+use core::config::ObfuscatorConfig;
+use core::pdb::{PDBContext, PDBFunction};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use support::full_config;
+use support::sandbox::compare_images;
+
+/// CRT helpers that walk the section headers of their own image, which the
+/// obfuscator extends.
+const READS_HEADERS: [&str; 3] = [
+    "__scrt_is_nonwritable_in_current_image",
+    "_FindPESection",
+    "_IsNonwritableInCurrentImage",
+];
+
+const SEED: u64 = 0x5eed;
+
+fn sample(name: &str) -> Vec<u8> {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "..", "..", "tests", "data", name]
+        .iter()
+        .collect();
+    std::fs::read(&path).unwrap_or_else(|e| panic!("cannot read {}: {e}", path.display()))
+}
+
+/// Runs every function of the sample and returns how many calls were
+/// compared, failing with every call that behaves differently. The PDB
+/// names the functions to run; with `use_pdb` unset the obfuscator does
+/// not get it and finds the functions itself. At most `max_skipped`
+/// functions may finish no call in the original image.
+fn check_sample(binary: &str, pdb: &str, use_pdb: bool, max_skipped: usize) -> usize {
+    let pe_data = sample(binary);
+    let pdb_data = sample(pdb);
+    let output = if use_pdb {
+        core::run_with_config(&pe_data, &pdb_data, &full_config())
+    } else {
+        core::run_without_pdb(&pe_data, &full_config())
+    }
+    .unwrap();
+
+    let functions: Vec<PDBFunction> = PDBContext::new(pdb_data)
+        .get_functions()
+        .unwrap()
+        .into_iter()
+        .filter(|function| !READS_HEADERS.iter().any(|name| function.name.starts_with(name)))
+        .collect();
+
+    let comparison = compare_images(&pe_data, &output.binary, &functions, SEED)
+        .unwrap_or_else(|mismatches| panic!("{binary}: {} calls differ:\n{}", mismatches.len(), mismatches.join("\n")));
+    assert!(
+        comparison.skipped.len() <= max_skipped,
+        "{binary}: {} functions skipped, expected at most {max_skipped}:\n{}",
+        comparison.skipped.len(),
+        comparison.skipped.join("\n")
+    );
+
+    // Calls into functions the obfuscator left alone only test the
+    // emulator, so most compared functions must have been relocated.
+    let relocated: HashSet<u32> = output.symbol_map.functions.iter().map(|f| f.original_rva).collect();
+    let in_place: Vec<&str> = comparison
+        .functions
+        .iter()
+        .filter(|name| {
+            let function = functions.iter().find(|function| &function.name == *name).unwrap();
+            !relocated.contains(&function.rva)
+        })
+        .map(String::as_str)
+        .collect();
+    assert!(
+        in_place.len() * 3 <= comparison.functions.len(),
+        "{binary}: {} of {} compared functions were not relocated:\n{}",
+        in_place.len(),
+        comparison.functions.len(),
+        in_place.join("\n")
+    );
+    comparison.compared
+}
+
+#[test]
+fn login_program() {
+    assert!(check_sample("login-program.exe", "login-program.pdb", true, 44) > 0);
+}
+
+#[test]
+fn seh_test() {
+    assert!(check_sample("seh-test.exe", "seh-test.pdb", true, 21) > 0);
+}
+
+#[test]
+fn rust_test() {
+    assert!(check_sample("rust-test.exe", "rust_test.pdb", true, 272) > 0);
+}
+
+#[test]
+fn login_program_without_pdb() {
+    assert!(check_sample("login-program.exe", "login-program.pdb", false, 44) > 0);
+}
+
+#[test]
+fn login_program_symbol_map() {
+    let pdb_data = sample("login-program.pdb");
+    let output = core::run_with_config(&sample("login-program.exe"), &pdb_data, &ObfuscatorConfig::default()).unwrap();
+    let map = &output.symbol_map;
+    assert_eq!(map.functions.len(), output.report.functions);
+
+    let mut with_lines = 0;
+    for mapped in &map.functions {
+        let location = map.resolve(mapped.ranges[0].rva).unwrap();
+        assert_eq!((location.function, location.original_rva), (mapped.name.as_str(), mapped.original_rva));
+        if location.file.is_some_and(|file| file.ends_with(".cpp")) && location.line.is_some_and(|line| line > 0) {
+            with_lines += 1;
+        }
+    }
+    assert!(with_lines > 0);
+}
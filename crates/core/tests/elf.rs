@@ -0,0 +1,313 @@
+//! Reads small ELF images assembled in memory: format detection, address
+//! mapping, functions from the symbol table and from `.eh_frame`, and the
+//! pipeline rewriting them along with their call frame information.
+
+mod support;
+
+use core::config::{ConfigFormat, ObfuscatorConfig};
+use core::elf::ElfContext;
+use core::elf::cfi::FrameState;
+use core::elf::eh_frame::{FrameDescription, FrameEntry};
+use core::format::BinaryFormat;
+use goblin::elf::program_header::{PF_X, PT_GNU_EH_FRAME, PT_LOAD};
+use iced_x86::code_asm::*;
+use support::elf_builder::{BuiltElf, ElfBuilder, TEXT_OFFSET};
+use support::full_config;
+use support::pe_builder::{Code, PEBuilder, Symbol};
+use support::sandbox::compare_images;
+
+const SEED: u64 = 0x5eed;
+
+/// A caller, a leaf function, a loop and a function saving a register, so
+/// the functions differ in size and in their call frames.
+fn build_sample(builder: ElfBuilder) -> BuiltElf {
+    builder
+        .build(|a| {
+            let mut main = a.create_label();
+            let mut add = a.create_label();
+            let mut sum = a.create_label();
+            let mut again = a.create_label();
+            let mut twice = a.create_label();
+
+            a.set_label(&mut main)?;
+            a.sub(rsp, 8)?;
+            a.mov(edi, 3)?;
+            a.mov(esi, 4)?;
+            a.call(add)?;
+            a.mov(edi, eax)?;
+            a.call(sum)?;
+            a.add(rsp, 8)?;
+            a.ret()?;
+
+            a.set_label(&mut add)?;
+            a.lea(eax, dword_ptr(rdi + rsi))?;
+            a.ret()?;
+
+            a.set_label(&mut sum)?;
+            a.xor(eax, eax)?;
+            a.set_label(&mut again)?;
+            a.add(eax, edi)?;
+            a.dec(edi)?;
+            a.jnz(again)?;
+            a.ret()?;
+
+            a.set_label(&mut twice)?;
+            a.push(rbx)?;
+            a.mov(ebx, edi)?;
+            a.mov(esi, edi)?;
+            a.call(add)?;
+            a.add(eax, ebx)?;
+            a.pop(rbx)?;
+            a.ret()?;
+
+            Ok(vec![("main", main), ("add", add), ("sum", sum), ("twice", twice)])
+        })
+        .unwrap()
+}
+
+/// Junk with stack traffic at every instruction and virtualization of
+/// every run, the rewrites that push below RSP.
+const STACK_CONFIG: &str = r#"
+seed = 7
+iterations = 1
+
+[[passes]]
+type = "junk_code"
+density = 1.0
+
+[[passes]]
+type = "virtualization"
+min_instructions = 1
+"#;
+
+/// A leaf function keeping its arguments in the red zone, below RSP, across
+/// a `lea` the flags stay live through and arithmetic the VM can run, and
+/// a caller so the VM also leaves through a call.
+fn build_red_zone_sample(builder: ElfBuilder) -> BuiltElf {
+    builder
+        .build(|a| {
+            let mut main = a.create_label();
+            let mut leaf = a.create_label();
+
+            a.set_label(&mut main)?;
+            a.sub(rsp, 8)?;
+            a.call(leaf)?;
+            a.add(rax, 1)?;
+            a.add(rsp, 8)?;
+            a.ret()?;
+
+            a.set_label(&mut leaf)?;
+            a.mov(qword_ptr(rsp - 8), rdi)?;
+            a.mov(qword_ptr(rsp - 16), rsi)?;
+            a.cmp(rdi, rsi)?;
+            a.lea(rax, qword_ptr(rdi + rsi * 2 + 0x40))?;
+            a.setb(cl)?;
+            a.movzx(ecx, cl)?;
+            a.add(rax, rcx)?;
+            a.add(rax, qword_ptr(rsp - 8))?;
+            a.xor(rax, qword_ptr(rsp - 16))?;
+            a.ret()?;
+
+            Ok(vec![("main", main), ("leaf", leaf)])
+        })
+        .unwrap()
+}
+
+fn summary(functions: &[core::pdb::PDBFunction]) -> Vec<(String, u32, u32)> {
+    functions.iter().map(|f| (f.name.clone(), f.rva, f.size)).collect()
+}
+
+#[test]
+fn formats_are_detected() {
+    let elf = build_sample(ElfBuilder::new());
+    let pe = PEBuilder::new()
+        .build(|a, _| {
+            let mut main = a.create_label();
+            a.set_label(&mut main)?;
+            a.ret()?;
+            Ok(Code {
+                symbols: vec![Symbol::new("main", main)],
+                absolute: Vec::new(),
+            })
+        })
+        .unwrap();
+
+    assert_eq!(BinaryFormat::detect(&elf.elf_data), Some(BinaryFormat::Elf));
+    assert_eq!(BinaryFormat::detect(&pe.pe_data), Some(BinaryFormat::Pe));
+    assert_eq!(BinaryFormat::detect(b"\0asm"), None);
+    assert_eq!(BinaryFormat::detect(&[]), None);
+}
+
+#[test]
+fn built_elf_parses() {
+    let image = build_sample(ElfBuilder::new());
+    let elf_context = ElfContext::new(image.elf_data.clone());
+    assert!(elf_context.is_supported());
+    assert_eq!(elf_context.image_base().unwrap(), support::elf_builder::ELF_BASE);
+
+    let text_rva = TEXT_OFFSET as u32;
+    let text_end = text_rva + image.text.len() as u32;
+    let ranges = elf_context.executable_ranges().unwrap();
+    assert_eq!(ranges.len(), 1);
+    assert!(ranges[0].start <= text_rva && text_end <= ranges[0].end);
+
+    assert_eq!(elf_context.rva_to_file_offset(text_rva).unwrap(), TEXT_OFFSET);
+    let add = image.function("add");
+    assert_eq!(
+        elf_context.read_data_at_rva(add.rva, add.size as usize).unwrap(),
+        image.text[(add.rva - text_rva) as usize..(add.rva - text_rva + add.size) as usize]
+    );
+    assert!(elf_context.rva_to_file_offset(0x10_0000).is_err());
+}
+
+#[test]
+fn other_machines_are_not_supported() {
+    let mut elf_data = build_sample(ElfBuilder::new()).elf_data;
+    // e_machine: EM_386.
+    elf_data[18..20].copy_from_slice(&3u16.to_le_bytes());
+    assert!(!ElfContext::new(elf_data).is_supported());
+}
+
+#[test]
+fn frame_descriptions_cover_every_function() {
+    let image = build_sample(ElfBuilder::new());
+    let descriptions = ElfContext::new(image.elf_data.clone())
+        .get_frame_descriptions()
+        .unwrap();
+    let expected: Vec<FrameDescription> = image
+        .functions
+        .iter()
+        .map(|function| FrameDescription {
+            begin_address: function.rva,
+            end_address: function.rva + function.size,
+            has_lsda: false,
+        })
+        .collect();
+    assert_eq!(descriptions, expected);
+}
+
+#[test]
+fn functions_come_from_symbols() {
+    let image = build_sample(ElfBuilder::new());
+    let functions = ElfContext::new(image.elf_data.clone()).get_functions().unwrap();
+    assert_eq!(summary(&functions), summary(&image.functions));
+    assert!(ElfContext::new(image.elf_data).get_lines().unwrap().is_empty());
+}
+
+#[test]
+fn stripped_functions_come_from_frame_descriptions() {
+    let image = build_sample(ElfBuilder::new().stripped());
+    let functions = ElfContext::new(image.elf_data.clone()).get_functions().unwrap();
+    let expected: Vec<(String, u32, u32)> = image
+        .functions
+        .iter()
+        .map(|f| (format!("sub_{:x}", f.rva), f.rva, f.size))
+        .collect();
+    assert_eq!(summary(&functions), expected);
+}
+
+/// The row of `entry` in effect at `rva`.
+fn state_at(entry: &FrameEntry, rva: u32) -> &FrameState {
+    let rows = entry.rows.as_ref().unwrap();
+    &rows[rows.partition_point(|&(row, _)| row <= rva) - 1].1
+}
+
+fn entry_at(entries: &[FrameEntry], rva: u32) -> &FrameEntry {
+    entries
+        .iter()
+        .find(|entry| (entry.description.begin_address..entry.description.end_address).contains(&rva))
+        .unwrap_or_else(|| panic!("no frame description covers {rva:#x}"))
+}
+
+#[test]
+fn frame_rows_follow_the_stack() {
+    let image = build_sample(ElfBuilder::new());
+    let entries = ElfContext::new(image.elf_data.clone()).get_frame_entries().unwrap();
+    assert_eq!(entries.len(), image.functions.len());
+
+    let twice = image.function("twice");
+    let rows = entries[3].rows.as_ref().unwrap();
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[0], (twice.rva, FrameState::entry()));
+    // After `push rbx`, which is one byte.
+    assert_eq!(rows[1].0, twice.rva + 1);
+    assert_eq!(rows[1].1.cfa_offset, 16);
+    assert_eq!(rows[1].1.registers.len(), 2);
+    assert_eq!(rows[2].1.cfa_offset, 8);
+}
+
+/// Executables at a fixed address and position independent images, as
+/// PIE executables and shared objects are.
+fn builders() -> [ElfBuilder; 2] {
+    [ElfBuilder::new(), ElfBuilder::new().shared()]
+}
+
+#[test]
+fn pipeline_rewrites_elf() {
+    for builder in builders() {
+        let image = build_sample(builder);
+        let kind = ElfContext::new(image.elf_data.clone()).parse().unwrap().header.e_type;
+        for config in [ObfuscatorConfig::default(), full_config()] {
+            let output = core::run_without_pdb(&image.elf_data, &config).unwrap();
+            // `add` is too small to be worth relocating.
+            assert_eq!(output.report.functions, image.functions.len() - 1);
+            match compare_images(&image.elf_data, &output.binary, &image.functions, SEED) {
+                Ok(comparison) => {
+                    assert!(comparison.compared > 0);
+                    assert_eq!(comparison.skipped, Vec::<String>::new());
+                }
+                Err(mismatches) => panic!("{} calls differ:\n{}", mismatches.len(), mismatches.join("\n")),
+            }
+
+            let elf_context = ElfContext::new(output.binary.clone());
+            assert!(elf_context.is_supported());
+            let elf = elf_context.parse().unwrap();
+            assert_eq!(elf.header.e_type, kind);
+            let loads: Vec<_> = elf.program_headers.iter().filter(|header| header.p_type == PT_LOAD).collect();
+            assert!(loads.windows(2).all(|pair| pair[0].p_vaddr < pair[1].p_vaddr));
+            assert!(loads[1..].iter().any(|header| header.p_flags & PF_X != 0));
+            let eh_frame_hdr = elf
+                .program_headers
+                .iter()
+                .find(|header| header.p_type == PT_GNU_EH_FRAME)
+                .unwrap();
+            assert!(eh_frame_hdr.p_vaddr > loads[0].p_vaddr + loads[0].p_memsz);
+        }
+        let error = core::run(&image.elf_data, &[]).unwrap_err();
+        assert!(error.contains("ELF"), "{error}");
+    }
+}
+
+#[test]
+fn relocated_code_keeps_its_call_frames() {
+    for builder in builders() {
+        let image = build_sample(builder);
+        let original = ElfContext::new(image.elf_data.clone()).get_frame_entries().unwrap();
+        let output = core::run_without_pdb(&image.elf_data, &ObfuscatorConfig::default()).unwrap();
+        let entries = ElfContext::new(output.binary).get_frame_entries().unwrap();
+        assert_eq!(entries.len(), original.len() + output.symbol_map.functions.len());
+
+        for mapped in &output.symbol_map.functions {
+            for range in &mapped.ranges {
+                let expected = state_at(entry_at(&original, range.original_rva), range.original_rva);
+                let entry = entry_at(&entries, range.rva);
+                assert_ne!(entry.description.begin_address, mapped.original_rva);
+                assert_eq!(state_at(entry, range.rva), expected, "{} at {:#x}", mapped.name, range.original_rva);
+            }
+        }
+    }
+}
+
+#[test]
+fn red_zone_survives_the_passes() {
+    let image = build_red_zone_sample(ElfBuilder::new());
+    let stack_config = ObfuscatorConfig::parse(STACK_CONFIG, ConfigFormat::Toml).unwrap();
+    for config in [ObfuscatorConfig::default(), stack_config, full_config()] {
+        let output = core::run_without_pdb(&image.elf_data, &config).unwrap();
+        assert_eq!(output.report.functions, image.functions.len());
+        match compare_images(&image.elf_data, &output.binary, &image.functions, SEED) {
+            Ok(comparison) => assert_eq!(comparison.functions, ["main", "leaf"]),
+            Err(mismatches) => panic!("{} calls differ:\n{}", mismatches.len(), mismatches.join("\n")),
+        }
+    }
+}
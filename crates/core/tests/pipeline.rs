@@ -0,0 +1,337 @@
+//! Runs the whole pipeline on small images assembled in memory, so that
+//! parsing, analysis, the passes and the compiler are checked without any
+//! compiled binary or PDB.
+
+mod support;
+
+use core::config::ObfuscatorConfig;
+use core::discovery::FunctionDiscovery;
+use core::pe::PEContext;
+use core::pe::exception::{UWOP_ALLOC_SMALL, UnwindCode, UnwindInfo};
+use core::symbol_map::SymbolMap;
+use iced_x86::{Decoder, DecoderOptions, Register};
+use iced_x86::code_asm::*;
+use support::full_config;
+use support::pe_builder::{BuiltImage, Code, IMAGE_BASE, PEBuilder, Symbol, call_rip, lea_rip, mov_rip};
+use support::sandbox::compare_images;
+
+const SEED: u64 = 0x5eed;
+
+/// Unwind info of a prolog that is a single `sub rsp, 0x28`.
+fn frame_unwind() -> UnwindInfo {
+    UnwindInfo {
+        version: 1,
+        flags: 0,
+        prolog_size: 4,
+        frame_register: 0,
+        frame_offset: 0,
+        codes: vec![UnwindCode {
+            code_offset: 4,
+            op: UWOP_ALLOC_SMALL,
+            info: 0x28 / 8 - 1,
+            operands: Vec::new(),
+        }],
+        handler: None,
+        handler_data: Vec::new(),
+        chained: None,
+    }
+}
+
+/// A program with leaf and framed functions, a loop over a table, an
+/// indirect call through a relocated function pointer, an absolute address
+/// in the code and calls to imports with a string argument.
+fn build_sample() -> BuiltImage {
+    let table: Vec<u8> = [3u32, 1, 4, 1, 5, 9, 2, 6].iter().flat_map(|value| value.to_le_bytes()).collect();
+    PEBuilder::new()
+        .import("KERNEL32.dll", &["GetTickCount", "Sleep"])
+        .import("USER32.dll", &["MessageBoxA"])
+        .rdata("table", &table)
+        .rdata("greeting", b"Hello from a synthetic image\0")
+        .data("counter", &[0; 8])
+        .data("handlers", &[0; 16])
+        .pointer("handlers", 0, "add_mul")
+        .pointer("handlers", 8, "sum_table")
+        .build(|a, layout| {
+            let mut main = a.create_label();
+            let mut add_mul = a.create_label();
+            let mut sum_table = a.create_label();
+            let mut dispatch = a.create_label();
+            let mut bump = a.create_label();
+            let mut counter_address = a.create_label();
+
+            a.set_label(&mut main)?;
+            a.sub(rsp, 0x28)?;
+            a.call(sum_table)?;
+            a.mov(ecx, eax)?;
+            a.mov(edx, 5)?;
+            a.call(add_mul)?;
+            a.mov(rcx, rax)?;
+            a.call(dispatch)?;
+            a.call(bump)?;
+            a.add(rsp, 0x28)?;
+            a.ret()?;
+
+            a.set_label(&mut add_mul)?;
+            a.lea(rax, rcx + rdx)?;
+            a.imul_2(rax, rcx)?;
+            a.ret()?;
+
+            a.set_label(&mut sum_table)?;
+            let mut sum_loop = a.create_label();
+            let mut sum_done = a.create_label();
+            a.xor(eax, eax)?;
+            a.and(ecx, 7)?;
+            lea_rip(a, Register::RDX, layout.data("table"))?;
+            a.set_label(&mut sum_loop)?;
+            a.test(ecx, ecx)?;
+            a.jz(sum_done)?;
+            a.add(eax, dword_ptr(rdx + rcx * 4))?;
+            a.dec(ecx)?;
+            a.jmp(sum_loop)?;
+            a.set_label(&mut sum_done)?;
+            a.ret()?;
+
+            a.set_label(&mut dispatch)?;
+            a.sub(rsp, 0x28)?;
+            a.and(ecx, 1)?;
+            lea_rip(a, Register::RAX, layout.data("handlers"))?;
+            a.mov(rax, qword_ptr(rax + rcx * 8))?;
+            a.mov(ecx, 7)?;
+            a.mov(edx, 9)?;
+            a.call(rax)?;
+            a.add(rsp, 0x28)?;
+            a.ret()?;
+
+            a.set_label(&mut bump)?;
+            a.sub(rsp, 0x28)?;
+            call_rip(a, layout.import("KERNEL32.dll!GetTickCount"))?;
+            a.set_label(&mut counter_address)?;
+            a.mov(rcx, layout.data("counter"))?;
+            a.add(qword_ptr(rcx), rax)?;
+            a.xor(ecx, ecx)?;
+            lea_rip(a, Register::RDX, layout.data("greeting"))?;
+            a.mov(r8, rdx)?;
+            a.xor(r9d, r9d)?;
+            call_rip(a, layout.import("USER32.dll!MessageBoxA"))?;
+            mov_rip(a, Register::RAX, layout.data("counter"))?;
+            a.add(rsp, 0x28)?;
+            a.ret()?;
+
+            Ok(Code {
+                symbols: vec![
+                    Symbol::new("main", main).with_unwind(frame_unwind()),
+                    Symbol::new("add_mul", add_mul),
+                    Symbol::new("sum_table", sum_table),
+                    Symbol::new("dispatch", dispatch).with_unwind(frame_unwind()),
+                    Symbol::new("bump", bump).with_unwind(frame_unwind()),
+                ],
+                absolute: vec![counter_address],
+            })
+        })
+        .unwrap()
+}
+
+fn discover(pe_data: &[u8]) -> Vec<(String, u32, u32)> {
+    let pe_context = PEContext::new(pe_data.to_vec());
+    let functions = FunctionDiscovery::new(&pe_context).unwrap().discover().unwrap();
+    functions.into_iter().map(|function| (function.name, function.rva, function.size)).collect()
+}
+
+fn obfuscate(image: &BuiltImage, config: &ObfuscatorConfig) -> Vec<u8> {
+    let output = core::run_with_functions(&image.pe_data, image.functions.clone(), config).unwrap();
+    assert_eq!(output.report.functions, image.functions.len());
+    assert!(output.report.functions_left_in_place.is_empty());
+    output.binary
+}
+
+/// Target of the jump left at the original start of `rva`.
+fn redirect_target(pe_context: &PEContext, rva: u32) -> u32 {
+    let jmp = pe_context.read_data_at_rva(rva, 5).unwrap();
+    assert_eq!(jmp[0], 0xE9);
+    (rva + 5).wrapping_add_signed(i32::from_le_bytes(jmp[1..5].try_into().unwrap()))
+}
+
+fn assert_equivalent(image: &BuiltImage, obfuscated: &[u8]) {
+    match compare_images(&image.pe_data, obfuscated, &image.functions, SEED) {
+        Ok(comparison) => {
+            assert!(comparison.compared > 0);
+            assert_eq!(comparison.skipped, Vec::<String>::new());
+        }
+        Err(mismatches) => panic!("{} calls differ:\n{}", mismatches.len(), mismatches.join("\n")),
+    }
+}
+
+#[test]
+fn built_image_parses() {
+    let image = build_sample();
+    let pe_context = PEContext::new(image.pe_data.clone());
+    assert!(pe_context.is_supported());
+
+    let slots = pe_context.get_import_slots().unwrap();
+    assert_eq!(slots.len(), 3);
+    for name in ["KERNEL32.dll!GetTickCount", "KERNEL32.dll!Sleep", "USER32.dll!MessageBoxA"] {
+        let slot_rva = (image.layout.import(name) - IMAGE_BASE) as u32;
+        assert_eq!(slots.get(&slot_rva).map(String::as_str), Some(name));
+    }
+
+    let runtime_functions = pe_context.get_runtime_functions().unwrap();
+    let begins: Vec<u32> = runtime_functions.iter().map(|function| function.begin_address).collect();
+    let expected: Vec<u32> = ["main", "dispatch", "bump"]
+        .iter()
+        .map(|name| image.function(name).rva)
+        .collect();
+    assert_eq!(begins, expected);
+
+    // Two function pointers in `.data` and the `mov rcx, imm64` in `bump`.
+    assert_eq!(pe_context.get_base_relocations().unwrap().len(), 3);
+
+    let main = image.function("main");
+    let offset = pe_context.rva_to_file_offset(main.rva).unwrap();
+    assert_eq!(&image.pe_data[offset..offset + 4], &[0x48, 0x83, 0xEC, 0x28]);
+    assert_eq!(pe_context.file_offset_to_rva(offset).unwrap(), main.rva);
+}
+
+#[test]
+fn building_is_deterministic() {
+    assert_eq!(build_sample().pe_data, build_sample().pe_data);
+}
+
+#[test]
+fn create_section_appends_after_last_section() {
+    let image = build_sample();
+    let mut pe_context = PEContext::new(image.pe_data.clone());
+    let sections = pe_context.parse().unwrap().sections.len();
+    let next_rva = pe_context.get_next_section_rva().unwrap();
+
+    let bytes = b"appended section";
+    let (rva, size) = pe_context.create_data_section(".test", bytes).unwrap();
+    assert_eq!((rva, size), (next_rva, bytes.len() as u32));
+    assert_eq!(pe_context.parse().unwrap().sections.len(), sections + 1);
+    assert_eq!(pe_context.read_data_at_rva(rva, bytes.len()).unwrap(), bytes);
+    assert!(pe_context.get_next_section_rva().unwrap() > rva);
+}
+
+#[test]
+fn default_pipeline_preserves_behavior() {
+    let image = build_sample();
+    let obfuscated = obfuscate(&image, &ObfuscatorConfig::default());
+    assert_equivalent(&image, &obfuscated);
+}
+
+#[test]
+fn full_pipeline_preserves_behavior() {
+    let image = build_sample();
+    let obfuscated = obfuscate(&image, &full_config());
+    assert_equivalent(&image, &obfuscated);
+
+    // Every function now starts with a jump to its relocated code.
+    let pe_context = PEContext::new(obfuscated);
+    for function in &image.functions {
+        assert_eq!(pe_context.read_data_at_rva(function.rva, 1).unwrap(), [0xE9]);
+    }
+}
+
+#[test]
+fn obfuscation_is_deterministic() {
+    let image = build_sample();
+    assert_eq!(obfuscate(&image, &full_config()), obfuscate(&image, &full_config()));
+}
+
+#[test]
+fn discovery_finds_every_function() {
+    let image = build_sample();
+    let named = |name: &str, found: &str| {
+        let function = image.function(name);
+        (found.to_string(), function.rva, function.size)
+    };
+    let sub = |name: &str| named(name, &format!("sub_{:x}", image.function(name).rva));
+    let expected = vec![
+        named("main", "entry"),
+        sub("add_mul"),
+        sub("sum_table"),
+        sub("dispatch"),
+        sub("bump"),
+    ];
+    assert_eq!(discover(&image.pe_data), expected);
+}
+
+#[test]
+fn discovery_uses_exports() {
+    let image = PEBuilder::new()
+        .export("exported")
+        .build(|a, _| {
+            let mut main = a.create_label();
+            let mut exported = a.create_label();
+            a.set_label(&mut main)?;
+            a.xor(eax, eax)?;
+            a.ret()?;
+            a.set_label(&mut exported)?;
+            a.lea(eax, rcx + 1)?;
+            a.ret()?;
+            Ok(Code {
+                symbols: vec![Symbol::new("main", main), Symbol::new("exported", exported)],
+                absolute: Vec::new(),
+            })
+        })
+        .unwrap();
+    let exported = image.function("exported");
+    assert_eq!(
+        discover(&image.pe_data),
+        vec![
+            ("entry".to_string(), image.function("main").rva, image.function("main").size),
+            ("exported".to_string(), exported.rva, exported.size),
+        ]
+    );
+}
+
+#[test]
+fn pipeline_without_pdb_preserves_behavior() {
+    let image = build_sample();
+    let output = core::run_without_pdb(&image.pe_data, &full_config()).unwrap();
+    assert_eq!(output.report.functions, image.functions.len());
+    assert_equivalent(&image, &output.binary);
+}
+
+#[test]
+fn symbol_map_covers_relocated_code() {
+    let image = build_sample();
+    for config in [ObfuscatorConfig::default(), full_config()] {
+        let output = core::run_with_functions(&image.pe_data, image.functions.clone(), &config).unwrap();
+        let map = SymbolMap::from_json(&output.symbol_map.to_json().unwrap()).unwrap();
+        assert_eq!(map.image_base, IMAGE_BASE);
+        assert_eq!(map.functions.len(), image.functions.len());
+
+        let pe_context = PEContext::new(output.binary);
+        let mut covered: Vec<(u32, u32)> = Vec::new();
+        for mapped in &map.functions {
+            let function = image.function(&mapped.name);
+            assert_eq!((mapped.original_rva, mapped.original_size), (function.rva, function.size));
+            for range in &mapped.ranges {
+                assert!((function.rva..function.rva + function.size).contains(&range.original_rva));
+                covered.push((range.rva, range.size));
+            }
+
+            let entry = map.resolve(redirect_target(&pe_context, function.rva)).unwrap();
+            assert_eq!((entry.function, entry.original_rva), (function.name.as_str(), function.rva));
+        }
+        covered.sort();
+        assert!(covered.windows(2).all(|pair| pair[0].0 + pair[0].1 <= pair[1].0));
+    }
+}
+
+#[test]
+fn symbol_map_without_passes_maps_every_instruction() {
+    let image = build_sample();
+    let map = core::run_with_functions(&image.pe_data, image.functions.clone(), &ObfuscatorConfig::default())
+        .unwrap()
+        .symbol_map;
+    let pe_context = PEContext::new(image.pe_data.clone());
+    for function in &image.functions {
+        let bytes = pe_context.read_data_at_rva(function.rva, function.size as usize).unwrap();
+        let mut decoder = Decoder::with_ip(64, &bytes, function.rva as u64, DecoderOptions::NONE);
+        let originals: Vec<u32> = decoder.iter().map(|instruction| instruction.ip() as u32).collect();
+        let mapped = map.functions.iter().find(|mapped| mapped.name == function.name).unwrap();
+        let mapped: Vec<u32> = mapped.ranges.iter().map(|range| range.original_rva).collect();
+        assert_eq!(mapped, originals, "{}", function.name);
+    }
+}
@@ -0,0 +1,464 @@
+//! Assembles minimal x86-64 ELF executables in memory: one loadable
+//! segment holding `.text` from a `code_asm` snippet, an `.eh_frame` with
+//! an entry per function describing its pushes and stack adjustments, and
+//! the `.eh_frame_hdr` lookup table, plus `.symtab` unless the image is
+//! built stripped. Images are executables at a fixed address or position
+//! independent ones linked at 0.
+
+use core::pdb::PDBFunction;
+use iced_x86::code_asm::{CodeAssembler, CodeLabel};
+use iced_x86::{BlockEncoderOptions, Code, Decoder, DecoderOptions, IcedError, Instruction, OpKind, Register};
+
+pub const ELF_BASE: u64 = 0x40_0000;
+pub const TEXT_OFFSET: usize = 0x1000;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_GNU_EH_FRAME: u32 = 0x6474_E550;
+const PF_R: u32 = 4;
+const PF_R_X: u32 = 5;
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const STT_FUNC_GLOBAL: u8 = 0x12;
+/// `DW_EH_PE_pcrel | DW_EH_PE_sdata4`, what compilers emit for x86-64.
+const FDE_ENCODING: u8 = 0x1B;
+/// `DW_EH_PE_datarel | DW_EH_PE_sdata4`, the only table encoding.
+const TABLE_ENCODING: u8 = 0x3B;
+const DW_EH_PE_UDATA4: u8 = 0x03;
+
+pub struct BuiltElf {
+    pub elf_data: Vec<u8>,
+    /// The functions by RVA, relative to the image base.
+    pub functions: Vec<PDBFunction>,
+    pub text: Vec<u8>,
+}
+
+impl BuiltElf {
+    pub fn function(&self, name: &str) -> &PDBFunction {
+        self.functions
+            .iter()
+            .find(|function| function.name == name)
+            .unwrap_or_else(|| panic!("unknown function {name}"))
+    }
+}
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    address: u64,
+    offset: usize,
+    size: usize,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+}
+
+pub struct ElfBuilder {
+    stripped: bool,
+    kind: u16,
+    base: u64,
+}
+
+impl Default for ElfBuilder {
+    fn default() -> Self {
+        Self {
+            stripped: false,
+            kind: ET_EXEC,
+            base: ELF_BASE,
+        }
+    }
+}
+
+impl ElfBuilder {
+    /// An executable loaded at [`ELF_BASE`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds a position independent `ET_DYN` image linked at address 0,
+    /// as PIE executables and shared objects are.
+    pub fn shared(mut self) -> Self {
+        self.kind = ET_DYN;
+        self.base = 0;
+        self
+    }
+
+    /// Leaves out `.symtab`, as `strip` does.
+    pub fn stripped(mut self) -> Self {
+        self.stripped = true;
+        self
+    }
+
+    /// Builds the image from the functions `code` assembles, each running
+    /// from its label to the next one or the end of the code. The first
+    /// function is the entry point.
+    pub fn build(
+        self,
+        code: impl FnOnce(&mut CodeAssembler) -> Result<Vec<(&'static str, CodeLabel)>, IcedError>,
+    ) -> Result<BuiltElf, String> {
+        let e = |e: IcedError| e.to_string();
+        let base = self.base;
+        let text_rva = TEXT_OFFSET as u32;
+        let mut assembler = CodeAssembler::new(64).map_err(e)?;
+        let labels = code(&mut assembler).map_err(e)?;
+        let result = assembler
+            .assemble_options(
+                base + text_rva as u64,
+                BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS,
+            )
+            .map_err(e)?;
+        let text = result.inner.code_buffer.clone();
+        let text_end = text_rva + text.len() as u32;
+
+        let mut starts = Vec::with_capacity(labels.len());
+        for (name, label) in &labels {
+            starts.push((*name, (result.label_ip(label).map_err(e)? - base) as u32));
+        }
+        let entry = starts.first().map_or(text_rva, |&(_, rva)| rva);
+        starts.sort_by_key(|&(_, rva)| rva);
+        let mut functions = Vec::with_capacity(starts.len());
+        for (index, &(name, rva)) in starts.iter().enumerate() {
+            let end = starts.get(index + 1).map_or(text_end, |&(_, next)| next);
+            functions.push(PDBFunction {
+                name: name.to_string(),
+                rva,
+                size: end - rva,
+            });
+        }
+
+        let mut data = vec![0u8; TEXT_OFFSET];
+        data.extend_from_slice(&text);
+        align(&mut data, 8);
+        let eh_frame_offset = data.len();
+        let (eh_frame, records) = build_eh_frame(base, base + eh_frame_offset as u64, &functions, &text);
+        data.extend_from_slice(&eh_frame);
+        align(&mut data, 4);
+        let eh_frame_hdr_offset = data.len();
+        let eh_frame_hdr = build_eh_frame_hdr(
+            base + eh_frame_hdr_offset as u64,
+            base + eh_frame_offset as u64,
+            &records,
+        );
+        data.extend_from_slice(&eh_frame_hdr);
+        let load_end = data.len();
+
+        let mut shstrtab = vec![0u8];
+        let mut headers = vec![SectionHeader {
+            name: 0,
+            kind: 0,
+            flags: 0,
+            address: 0,
+            offset: 0,
+            size: 0,
+            link: 0,
+            info: 0,
+            align: 0,
+            entry_size: 0,
+        }];
+        headers.push(SectionHeader {
+            name: add_string(&mut shstrtab, ".text"),
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC | SHF_EXECINSTR,
+            address: base + TEXT_OFFSET as u64,
+            offset: TEXT_OFFSET,
+            size: text.len(),
+            link: 0,
+            info: 0,
+            align: 16,
+            entry_size: 0,
+        });
+        headers.push(SectionHeader {
+            name: add_string(&mut shstrtab, ".eh_frame"),
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC,
+            address: base + eh_frame_offset as u64,
+            offset: eh_frame_offset,
+            size: eh_frame.len(),
+            link: 0,
+            info: 0,
+            align: 8,
+            entry_size: 0,
+        });
+        headers.push(SectionHeader {
+            name: add_string(&mut shstrtab, ".eh_frame_hdr"),
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC,
+            address: base + eh_frame_hdr_offset as u64,
+            offset: eh_frame_hdr_offset,
+            size: eh_frame_hdr.len(),
+            link: 0,
+            info: 0,
+            align: 4,
+            entry_size: 0,
+        });
+
+        if !self.stripped {
+            let mut strtab = vec![0u8];
+            let mut symtab = vec![0u8; SYM_SIZE];
+            for function in &functions {
+                symtab.extend_from_slice(&add_string(&mut strtab, &function.name).to_le_bytes());
+                symtab.push(STT_FUNC_GLOBAL);
+                symtab.push(0);
+                // Defined in .text.
+                symtab.extend_from_slice(&1u16.to_le_bytes());
+                symtab.extend_from_slice(&(base + function.rva as u64).to_le_bytes());
+                symtab.extend_from_slice(&(function.size as u64).to_le_bytes());
+            }
+            align(&mut data, 8);
+            let symtab_index = headers.len() as u32;
+            headers.push(SectionHeader {
+                name: add_string(&mut shstrtab, ".symtab"),
+                kind: SHT_SYMTAB,
+                flags: 0,
+                address: 0,
+                offset: data.len(),
+                size: symtab.len(),
+                link: symtab_index + 1,
+                info: 1,
+                align: 8,
+                entry_size: SYM_SIZE as u64,
+            });
+            data.extend_from_slice(&symtab);
+            headers.push(SectionHeader {
+                name: add_string(&mut shstrtab, ".strtab"),
+                kind: SHT_STRTAB,
+                flags: 0,
+                address: 0,
+                offset: data.len(),
+                size: strtab.len(),
+                link: 0,
+                info: 0,
+                align: 1,
+                entry_size: 0,
+            });
+            data.extend_from_slice(&strtab);
+        }
+
+        let shstrtab_index = headers.len() as u16;
+        let shstrtab_name = add_string(&mut shstrtab, ".shstrtab");
+        headers.push(SectionHeader {
+            name: shstrtab_name,
+            kind: SHT_STRTAB,
+            flags: 0,
+            address: 0,
+            offset: data.len(),
+            size: shstrtab.len(),
+            link: 0,
+            info: 0,
+            align: 1,
+            entry_size: 0,
+        });
+        data.extend_from_slice(&shstrtab);
+
+        align(&mut data, 8);
+        let section_headers_offset = data.len();
+        for header in &headers {
+            data.extend_from_slice(&header.name.to_le_bytes());
+            data.extend_from_slice(&header.kind.to_le_bytes());
+            data.extend_from_slice(&header.flags.to_le_bytes());
+            data.extend_from_slice(&header.address.to_le_bytes());
+            data.extend_from_slice(&(header.offset as u64).to_le_bytes());
+            data.extend_from_slice(&(header.size as u64).to_le_bytes());
+            data.extend_from_slice(&header.link.to_le_bytes());
+            data.extend_from_slice(&header.info.to_le_bytes());
+            data.extend_from_slice(&header.align.to_le_bytes());
+            data.extend_from_slice(&header.entry_size.to_le_bytes());
+        }
+
+        let mut header = Vec::with_capacity(EHDR_SIZE + 2 * PHDR_SIZE);
+        header.extend_from_slice(b"\x7fELF");
+        // 64-bit, little endian, current version, System V.
+        header.extend_from_slice(&[2, 1, 1, 0]);
+        header.resize(16, 0);
+        header.extend_from_slice(&self.kind.to_le_bytes());
+        header.extend_from_slice(&EM_X86_64.to_le_bytes());
+        header.extend_from_slice(&1u32.to_le_bytes());
+        header.extend_from_slice(&(base + entry as u64).to_le_bytes());
+        header.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        header.extend_from_slice(&(section_headers_offset as u64).to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+        header.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&(SHDR_SIZE as u16).to_le_bytes());
+        header.extend_from_slice(&(headers.len() as u16).to_le_bytes());
+        header.extend_from_slice(&shstrtab_index.to_le_bytes());
+
+        header.extend_from_slice(&PT_LOAD.to_le_bytes());
+        header.extend_from_slice(&PF_R_X.to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes());
+        header.extend_from_slice(&base.to_le_bytes());
+        header.extend_from_slice(&base.to_le_bytes());
+        header.extend_from_slice(&(load_end as u64).to_le_bytes());
+        header.extend_from_slice(&(load_end as u64).to_le_bytes());
+        header.extend_from_slice(&0x1000u64.to_le_bytes());
+
+        let eh_frame_hdr_address = base + eh_frame_hdr_offset as u64;
+        header.extend_from_slice(&PT_GNU_EH_FRAME.to_le_bytes());
+        header.extend_from_slice(&PF_R.to_le_bytes());
+        header.extend_from_slice(&(eh_frame_hdr_offset as u64).to_le_bytes());
+        header.extend_from_slice(&eh_frame_hdr_address.to_le_bytes());
+        header.extend_from_slice(&eh_frame_hdr_address.to_le_bytes());
+        header.extend_from_slice(&(eh_frame_hdr.len() as u64).to_le_bytes());
+        header.extend_from_slice(&(eh_frame_hdr.len() as u64).to_le_bytes());
+        header.extend_from_slice(&4u64.to_le_bytes());
+        data[..header.len()].copy_from_slice(&header);
+
+        Ok(BuiltElf {
+            elf_data: data,
+            functions,
+            text,
+        })
+    }
+}
+
+/// A CIE with the usual x86-64 entry state, then an FDE per function with
+/// PC-relative addresses, then the terminator, for an image loaded at
+/// `base`. Returns the section and the start address and record address of
+/// each function.
+fn build_eh_frame(base: u64, address: u64, functions: &[PDBFunction], text: &[u8]) -> (Vec<u8>, Vec<(u64, u64)>) {
+    let mut cie = vec![0, 0, 0, 0, 1];
+    cie.extend_from_slice(b"zR\0");
+    // Code alignment 1, data alignment -8, return address in r16.
+    cie.extend_from_slice(&[1, 0x78, 16]);
+    cie.extend_from_slice(&[1, FDE_ENCODING]);
+    // DW_CFA_def_cfa rsp+8, DW_CFA_offset r16 at cfa-8.
+    cie.extend_from_slice(&[0x0C, 7, 8, 0x90, 1]);
+    let mut frame = record(cie);
+
+    let mut records = Vec::with_capacity(functions.len());
+    for function in functions {
+        let record_offset = frame.len();
+        records.push((base + function.rva as u64, address + record_offset as u64));
+        let mut fde = Vec::new();
+        fde.extend_from_slice(&(record_offset as u32 + 4).to_le_bytes());
+        let field = address + record_offset as u64 + 8;
+        let begin = (base + function.rva as u64).wrapping_sub(field) as u32;
+        fde.extend_from_slice(&begin.to_le_bytes());
+        fde.extend_from_slice(&function.size.to_le_bytes());
+        fde.push(0);
+        let start = function.rva as usize - TEXT_OFFSET;
+        fde.extend_from_slice(&call_frame_program(
+            base + function.rva as u64,
+            &text[start..start + function.size as usize],
+        ));
+        frame.extend_from_slice(&record(fde));
+    }
+    frame.extend_from_slice(&[0; 4]);
+    (frame, records)
+}
+
+/// The call frame instructions for code that only moves `rsp` by pushes,
+/// pops and immediate adjustments: the CFA offset after each of them, and
+/// the slot of each callee-saved register pushed.
+fn call_frame_program(ip: u64, code: &[u8]) -> Vec<u8> {
+    let mut program = Vec::new();
+    let mut decoder = Decoder::with_ip(64, code, ip, DecoderOptions::NONE);
+    let mut instruction = Instruction::default();
+    let mut cfa_offset = 8i64;
+    let mut location = ip;
+    while decoder.can_decode() {
+        decoder.decode_out(&mut instruction);
+        let immediate = || match instruction.op1_kind() {
+            OpKind::Immediate8to64 | OpKind::Immediate32to64 => instruction.immediate64() as i64,
+            OpKind::Immediate8 => i64::from(instruction.immediate8()),
+            _ => 0,
+        };
+        let rsp = instruction.op0_register() == Register::RSP;
+        let (delta, saved) = match instruction.code() {
+            Code::Push_r64 => (8, dwarf_register(instruction.op0_register())),
+            Code::Pop_r64 => (-8, None),
+            Code::Sub_rm64_imm8 | Code::Sub_rm64_imm32 if rsp => (immediate(), None),
+            Code::Add_rm64_imm8 | Code::Add_rm64_imm32 if rsp => (-immediate(), None),
+            _ => continue,
+        };
+        // The new rule holds from the next instruction on.
+        let next = instruction.next_ip();
+        let advance = (next - location) as u8;
+        match advance {
+            0..0x40 => program.push(0x40 | advance),
+            _ => program.extend_from_slice(&[0x02, advance]),
+        }
+        location = next;
+        cfa_offset += delta;
+        // DW_CFA_def_cfa_offset.
+        program.push(0x0E);
+        push_uleb128(&mut program, cfa_offset as u64);
+        if let Some(register) = saved {
+            // DW_CFA_offset at the slot just pushed, factored by -8.
+            program.push(0x80 | register);
+            push_uleb128(&mut program, (cfa_offset / 8) as u64);
+        }
+    }
+    program
+}
+
+/// DWARF numbers of the registers the System V ABI has callees preserve.
+fn dwarf_register(register: Register) -> Option<u8> {
+    match register {
+        Register::RBX => Some(3),
+        Register::RBP => Some(6),
+        Register::R12 => Some(12),
+        Register::R13 => Some(13),
+        Register::R14 => Some(14),
+        Register::R15 => Some(15),
+        _ => None,
+    }
+}
+
+/// Version 1 with a sorted lookup table of the FDEs, relative to the header.
+fn build_eh_frame_hdr(address: u64, eh_frame: u64, records: &[(u64, u64)]) -> Vec<u8> {
+    let mut header = vec![1, FDE_ENCODING, DW_EH_PE_UDATA4, TABLE_ENCODING];
+    let field = address + 4;
+    header.extend_from_slice(&(eh_frame.wrapping_sub(field) as u32).to_le_bytes());
+    header.extend_from_slice(&(records.len() as u32).to_le_bytes());
+    // The FDEs are in function order, so the table is already sorted.
+    for &(begin, record) in records {
+        header.extend_from_slice(&(begin.wrapping_sub(address) as u32).to_le_bytes());
+        header.extend_from_slice(&(record.wrapping_sub(address) as u32).to_le_bytes());
+    }
+    header
+}
+
+fn push_uleb128(data: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            data.push(byte);
+            return;
+        }
+        data.push(byte | 0x80);
+    }
+}
+
+/// Prefixes `body` with its length, padded with `DW_CFA_nop`.
+fn record(mut body: Vec<u8>) -> Vec<u8> {
+    align(&mut body, 4);
+    let mut record = (body.len() as u32).to_le_bytes().to_vec();
+    record.extend_from_slice(&body);
+    record
+}
+
+fn add_string(table: &mut Vec<u8>, string: &str) -> u32 {
+    let offset = table.len() as u32;
+    table.extend_from_slice(string.as_bytes());
+    table.push(0);
+    offset
+}
+
+fn align(data: &mut Vec<u8>, alignment: usize) {
+    data.resize(data.len().next_multiple_of(alignment), 0);
+}
@@ -0,0 +1,80 @@
+use core::elf::ElfContext;
+use core::format::{BinaryFormat, BinaryImage};
+use core::pe::PEContext;
+use core::verify::emulator::Memory;
+use std::ops::Range;
+
+/// Imports resolve into this range, one stub address per IAT slot, so that
+/// reaching one tells which import was called.
+pub const IMPORT_STUBS: u64 = 0x7ffe_0000_0000;
+const IMPORT_STUB_SIZE: u64 = 0x10;
+
+/// A PE or ELF image mapped at its preferred base, as the loader would map
+/// it when no relocation is needed, with every import slot bound to a stub.
+pub struct LoadedImage {
+    pub image_base: u64,
+    pub size_of_image: u64,
+    pub memory: Memory,
+    pub writable: Vec<Range<u64>>,
+    /// `dll!name` of the import behind each stub.
+    pub imports: Vec<String>,
+}
+
+impl LoadedImage {
+    pub fn load(data: &[u8]) -> Result<Self, String> {
+        let image: Box<dyn BinaryImage> = match BinaryFormat::detect(data) {
+            Some(BinaryFormat::Elf) => Box::new(ElfContext::new(data.to_vec())),
+            _ => Box::new(PEContext::new(data.to_vec())),
+        };
+        let image_base = image.image_base()?;
+
+        let mut memory = Memory::default();
+        let mut writable = Vec::new();
+        let mut size_of_image = 0;
+        for region in image.regions()? {
+            let address = image_base + region.rva as u64;
+            let size = (region.size as u64).max(region.file.len() as u64);
+            memory.map(address, size);
+            let bytes = data.get(region.file.clone()).ok_or("Region outside the file")?;
+            memory.write_bytes(address, bytes).map_err(|e| e.to_string())?;
+            if region.writable {
+                writable.push(address..address + size);
+            }
+            size_of_image = size_of_image.max(region.rva as u64 + size);
+        }
+
+        let mut slots: Vec<(u32, String)> = image.get_import_slots()?.into_iter().collect();
+        slots.sort();
+        let mut imports = Vec::with_capacity(slots.len());
+        for (index, (slot_rva, name)) in slots.into_iter().enumerate() {
+            let stub = IMPORT_STUBS + index as u64 * IMPORT_STUB_SIZE;
+            memory
+                .write(image_base + slot_rva as u64, 8, stub)
+                .map_err(|e| e.to_string())?;
+            imports.push(name);
+        }
+
+        Ok(Self {
+            image_base,
+            size_of_image,
+            memory,
+            writable,
+            imports,
+        })
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        (self.image_base..self.image_base + self.size_of_image).contains(&address)
+    }
+
+    /// The import whose stub starts at `address`.
+    pub fn import_at(&self, address: u64) -> Option<&str> {
+        let offset = address.checked_sub(IMPORT_STUBS)?;
+        if offset % IMPORT_STUB_SIZE != 0 {
+            return None;
+        }
+        self.imports
+            .get((offset / IMPORT_STUB_SIZE) as usize)
+            .map(String::as_str)
+    }
+}
@@ -0,0 +1,52 @@
+// Each test crate uses its own part of the support code.
+#![allow(dead_code)]
+
+pub mod elf_builder;
+pub mod loader;
+pub mod pe_builder;
+pub mod sandbox;
+
+use core::config::{ConfigFormat, ObfuscatorConfig};
+
+/// Every pass, twice over, with string encryption and interleaved chunks.
+const FULL_CONFIG: &str = r#"
+seed = 11
+iterations = 2
+
+[strings]
+enabled = true
+
+[layout]
+interleave = true
+
+[[passes]]
+type = "opaque_predicate"
+probability = 0.2
+
+[[passes]]
+type = "mba"
+
+[[passes]]
+type = "constant_encryption"
+
+[[passes]]
+type = "control_flow_flattening"
+
+[[passes]]
+type = "virtualization"
+min_instructions = 1
+
+[[passes]]
+type = "mutation"
+
+[[passes]]
+type = "block_reordering"
+
+[[passes]]
+type = "junk_code"
+density = 0.5
+"#;
+
+pub fn full_config() -> ObfuscatorConfig {
+    ObfuscatorConfig::parse(FULL_CONFIG, ConfigFormat::Toml).unwrap()
+}
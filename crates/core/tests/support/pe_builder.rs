@@ -0,0 +1,586 @@
+//! Assembles minimal x86-64 PE images in memory: headers, `.rdata` with
+//! the imports, `.data`, `.text` from a `code_asm` snippet, `.pdata`,
+//! `.edata` and `.reloc`, along with the symbol list the pipeline takes in place of a
+//! PDB. The data comes before the code, so its addresses are known while
+//! the code is assembled.
+
+use core::pdb::PDBFunction;
+use core::pe::exception::{UnwindInfo, encode_runtime_function};
+use core::pe::relocation::{BaseRelocation, IMAGE_REL_BASED_DIR64, encode_base_relocations};
+use goblin::pe::exception::RuntimeFunction;
+use iced_x86::code_asm::{CodeAssembler, CodeLabel};
+use iced_x86::{BlockEncoderOptions, Code as OpCode, Decoder, DecoderOptions, IcedError, Instruction, MemoryOperand, Register};
+use std::collections::HashMap;
+
+pub const IMAGE_BASE: u64 = 0x1_4000_0000;
+const SECTION_ALIGNMENT: u32 = 0x1000;
+const FILE_ALIGNMENT: u32 = 0x200;
+const HEADERS_SIZE: u32 = 0x400;
+const NT_HEADERS_OFFSET: usize = 0x40;
+const OPTIONAL_HEADER_SIZE: usize = 240;
+const SECTION_HEADER_SIZE: usize = 40;
+
+const IMAGE_DIRECTORY_ENTRY_EXPORT: usize = 0;
+const IMAGE_DIRECTORY_ENTRY_IMPORT: usize = 1;
+const IMAGE_DIRECTORY_ENTRY_EXCEPTION: usize = 3;
+const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+const IMAGE_DIRECTORY_ENTRY_IAT: usize = 12;
+
+const CODE_CHARACTERISTICS: u32 = 0x6000_0020;
+const READ_ONLY_CHARACTERISTICS: u32 = 0x4000_0040;
+const WRITABLE_CHARACTERISTICS: u32 = 0xC000_0040;
+const RELOCATION_CHARACTERISTICS: u32 = 0x4200_0040;
+
+/// A function of the code, from its label to the label of the next one or
+/// the end of the code. Functions without unwind info are leaf functions.
+pub struct Symbol {
+    pub name: String,
+    pub label: CodeLabel,
+    pub unwind: Option<UnwindInfo>,
+}
+
+impl Symbol {
+    pub fn new(name: &str, label: CodeLabel) -> Self {
+        Self {
+            name: name.to_string(),
+            label,
+            unwind: None,
+        }
+    }
+
+    pub fn with_unwind(mut self, unwind: UnwindInfo) -> Self {
+        self.unwind = Some(unwind);
+        self
+    }
+}
+
+/// What a code snippet hands back to the builder.
+pub struct Code {
+    /// The functions, the entry point first.
+    pub symbols: Vec<Symbol>,
+    /// Labels of `mov r64, imm64` instructions whose immediate is an address
+    /// in the image, which gets a base relocation.
+    pub absolute: Vec<CodeLabel>,
+}
+
+/// Addresses of the imports and data items, for the code to refer to.
+pub struct Layout {
+    imports: HashMap<String, u64>,
+    data: HashMap<String, u64>,
+}
+
+impl Layout {
+    /// Address of the IAT slot of `dll!name`.
+    pub fn import(&self, name: &str) -> u64 {
+        *self
+            .imports
+            .get(name)
+            .unwrap_or_else(|| panic!("unknown import {name}"))
+    }
+
+    pub fn data(&self, name: &str) -> u64 {
+        *self
+            .data
+            .get(name)
+            .unwrap_or_else(|| panic!("unknown data item {name}"))
+    }
+}
+
+struct DataItem {
+    name: String,
+    bytes: Vec<u8>,
+    writable: bool,
+    /// Offsets holding the address of a function or data item.
+    pointers: Vec<(usize, String)>,
+}
+
+struct Section {
+    name: &'static str,
+    rva: u32,
+    bytes: Vec<u8>,
+    characteristics: u32,
+}
+
+pub struct BuiltImage {
+    pub pe_data: Vec<u8>,
+    pub functions: Vec<PDBFunction>,
+    pub layout: Layout,
+}
+
+impl BuiltImage {
+    pub fn function(&self, name: &str) -> &PDBFunction {
+        self.functions
+            .iter()
+            .find(|function| function.name == name)
+            .unwrap_or_else(|| panic!("unknown function {name}"))
+    }
+}
+
+#[derive(Default)]
+pub struct PEBuilder {
+    imports: Vec<(String, Vec<String>)>,
+    data: Vec<DataItem>,
+    exports: Vec<String>,
+}
+
+impl PEBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn import(mut self, dll: &str, functions: &[&str]) -> Self {
+        self.imports
+            .push((dll.to_string(), functions.iter().map(|name| name.to_string()).collect()));
+        self
+    }
+
+    /// Exports the function `name` under its own name.
+    pub fn export(mut self, name: &str) -> Self {
+        self.exports.push(name.to_string());
+        self
+    }
+
+    pub fn rdata(self, name: &str, bytes: &[u8]) -> Self {
+        self.item(name, bytes, false)
+    }
+
+    pub fn data(self, name: &str, bytes: &[u8]) -> Self {
+        self.item(name, bytes, true)
+    }
+
+    fn item(mut self, name: &str, bytes: &[u8], writable: bool) -> Self {
+        self.data.push(DataItem {
+            name: name.to_string(),
+            bytes: bytes.to_vec(),
+            writable,
+            pointers: Vec::new(),
+        });
+        self
+    }
+
+    /// Stores the address of the function or data item `target` at
+    /// `offset` of the data item `name`, with a base relocation.
+    pub fn pointer(mut self, name: &str, offset: usize, target: &str) -> Self {
+        let item = self
+            .data
+            .iter_mut()
+            .find(|item| item.name == name)
+            .unwrap_or_else(|| panic!("unknown data item {name}"));
+        item.pointers.push((offset, target.to_string()));
+        self
+    }
+
+    pub fn build(
+        self,
+        code: impl FnOnce(&mut CodeAssembler, &Layout) -> Result<Code, IcedError>,
+    ) -> Result<BuiltImage, String> {
+        let e = |e: IcedError| e.to_string();
+        let mut layout = Layout {
+            imports: HashMap::new(),
+            data: HashMap::new(),
+        };
+        let mut relocations = Vec::new();
+        let mut directories = [(0u32, 0u32); 16];
+
+        let rdata_rva = SECTION_ALIGNMENT;
+        let mut rdata = self.build_imports(rdata_rva, &mut layout, &mut directories);
+        let mut placed = Vec::new();
+        for item in self.data.iter().filter(|item| !item.writable) {
+            placed.push((item, place(&mut rdata, rdata_rva, &item.bytes, 16)));
+        }
+        let data_rva = next_rva(rdata_rva, rdata.len());
+        let mut data = Vec::new();
+        for item in self.data.iter().filter(|item| item.writable) {
+            placed.push((item, place(&mut data, data_rva, &item.bytes, 16)));
+        }
+        for (item, rva) in &placed {
+            layout.data.insert(item.name.clone(), IMAGE_BASE + *rva as u64);
+        }
+
+        let text_rva = next_rva(data_rva, data.len());
+        let mut assembler = CodeAssembler::new(64).map_err(e)?;
+        let Code { symbols, absolute } = code(&mut assembler, &layout).map_err(e)?;
+        let result = assembler
+            .assemble_options(
+                IMAGE_BASE + text_rva as u64,
+                BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS,
+            )
+            .map_err(e)?;
+        let text = result.inner.code_buffer.clone();
+        let text_end = text_rva + text.len() as u32;
+
+        let mut starts = Vec::with_capacity(symbols.len());
+        for symbol in &symbols {
+            starts.push((result.label_ip(&symbol.label).map_err(e)? - IMAGE_BASE) as u32);
+        }
+        let mut order: Vec<usize> = (0..symbols.len()).collect();
+        order.sort_by_key(|&index| starts[index]);
+        let mut functions = Vec::with_capacity(symbols.len());
+        for (position, &index) in order.iter().enumerate() {
+            let end = order.get(position + 1).map_or(text_end, |&next| starts[next]);
+            functions.push(PDBFunction {
+                name: symbols[index].name.clone(),
+                rva: starts[index],
+                size: end - starts[index],
+            });
+        }
+
+        for label in &absolute {
+            let ip = result.label_ip(label).map_err(e)?;
+            let offset = (ip - IMAGE_BASE) as usize - text_rva as usize;
+            let mut decoder = Decoder::with_ip(64, &text[offset..], ip, DecoderOptions::NONE);
+            let length = decoder.decode().len() as u32;
+            relocations.push(BaseRelocation {
+                rva: (ip - IMAGE_BASE) as u32 + length - 8,
+                kind: IMAGE_REL_BASED_DIR64,
+            });
+        }
+
+        for (item, rva) in &placed {
+            let (section, section_rva) = if item.writable {
+                (&mut data, data_rva)
+            } else {
+                (&mut rdata, rdata_rva)
+            };
+            for (offset, target) in &item.pointers {
+                let address = match functions.iter().find(|function| &function.name == target) {
+                    Some(function) => IMAGE_BASE + function.rva as u64,
+                    None => layout.data(target),
+                };
+                let at = (rva - section_rva) as usize + offset;
+                section[at..at + 8].copy_from_slice(&address.to_le_bytes());
+                relocations.push(BaseRelocation {
+                    rva: rva + *offset as u32,
+                    kind: IMAGE_REL_BASED_DIR64,
+                });
+            }
+        }
+
+        let mut sections = Vec::new();
+        if !rdata.is_empty() {
+            sections.push(Section {
+                name: ".rdata",
+                rva: rdata_rva,
+                bytes: rdata,
+                characteristics: READ_ONLY_CHARACTERISTICS,
+            });
+        }
+        if !data.is_empty() {
+            sections.push(Section {
+                name: ".data",
+                rva: data_rva,
+                bytes: data,
+                characteristics: WRITABLE_CHARACTERISTICS,
+            });
+        }
+        sections.push(Section {
+            name: ".text",
+            rva: text_rva,
+            bytes: text,
+            characteristics: CODE_CHARACTERISTICS,
+        });
+
+        let unwound: Vec<usize> = order
+            .iter()
+            .enumerate()
+            .filter(|&(_, &index)| symbols[index].unwind.is_some())
+            .map(|(position, _)| position)
+            .collect();
+        if !unwound.is_empty() {
+            let pdata_rva = next_rva(text_rva, text_end as usize - text_rva as usize);
+            let table_size = unwound.len() * 12;
+            let mut pdata = vec![0u8; table_size];
+            for (entry, &position) in unwound.iter().enumerate() {
+                let unwind = symbols[order[position]].unwind.as_ref().unwrap().encode()?;
+                let unwind_rva = place(&mut pdata, pdata_rva, &unwind, 4);
+                let function = &functions[position];
+                let runtime_function = RuntimeFunction {
+                    begin_address: function.rva,
+                    end_address: function.rva + function.size,
+                    unwind_info_address: unwind_rva,
+                };
+                pdata[entry * 12..entry * 12 + 12].copy_from_slice(&encode_runtime_function(&runtime_function));
+            }
+            directories[IMAGE_DIRECTORY_ENTRY_EXCEPTION] = (pdata_rva, table_size as u32);
+            sections.push(Section {
+                name: ".pdata",
+                rva: pdata_rva,
+                bytes: pdata,
+                characteristics: READ_ONLY_CHARACTERISTICS,
+            });
+        }
+
+        if !self.exports.is_empty() {
+            let last = sections.last().unwrap();
+            let edata_rva = next_rva(last.rva, last.bytes.len());
+            let bytes = self.build_exports(edata_rva, &functions, &mut directories);
+            sections.push(Section {
+                name: ".edata",
+                rva: edata_rva,
+                bytes,
+                characteristics: READ_ONLY_CHARACTERISTICS,
+            });
+        }
+
+        if !relocations.is_empty() {
+            let last = sections.last().unwrap();
+            let reloc_rva = next_rva(last.rva, last.bytes.len());
+            let bytes = encode_base_relocations(&relocations);
+            directories[IMAGE_DIRECTORY_ENTRY_BASERELOC] = (reloc_rva, bytes.len() as u32);
+            sections.push(Section {
+                name: ".reloc",
+                rva: reloc_rva,
+                bytes,
+                characteristics: RELOCATION_CHARACTERISTICS,
+            });
+        }
+
+        let entry_point = functions
+            .iter()
+            .find(|function| symbols.first().is_some_and(|symbol| symbol.name == function.name))
+            .map_or(text_rva, |function| function.rva);
+        let pe_data = write_image(&sections, &directories, entry_point, text_rva);
+        Ok(BuiltImage {
+            pe_data,
+            functions,
+            layout,
+        })
+    }
+
+    /// Lays out the IAT at the start of `.rdata`, followed by the import
+    /// descriptors, the lookup tables and the names.
+    fn build_imports(
+        &self,
+        rdata_rva: u32,
+        layout: &mut Layout,
+        directories: &mut [(u32, u32); 16],
+    ) -> Vec<u8> {
+        if self.imports.is_empty() {
+            return Vec::new();
+        }
+        let thunk_count: usize = self.imports.iter().map(|(_, functions)| functions.len() + 1).sum();
+        let table_size = thunk_count * 8;
+        let descriptors_offset = table_size;
+        let lookup_offset = descriptors_offset + (self.imports.len() + 1) * 20;
+        let names_offset = lookup_offset + table_size;
+
+        let mut names = Vec::new();
+        let mut thunks = Vec::with_capacity(thunk_count);
+        let mut descriptors = Vec::new();
+        for (dll, functions) in &self.imports {
+            let first_thunk = thunks.len() * 8;
+            for function in functions {
+                layout.imports.insert(
+                    format!("{dll}!{function}"),
+                    IMAGE_BASE + (rdata_rva as usize + thunks.len() * 8) as u64,
+                );
+                let hint_name = rdata_rva as usize + names_offset + names.len();
+                names.extend_from_slice(&[0, 0]);
+                names.extend_from_slice(function.as_bytes());
+                names.push(0);
+                if names.len() % 2 != 0 {
+                    names.push(0);
+                }
+                thunks.push(hint_name as u64);
+            }
+            thunks.push(0);
+            let dll_name = rdata_rva as usize + names_offset + names.len();
+            names.extend_from_slice(dll.as_bytes());
+            names.push(0);
+            if names.len() % 2 != 0 {
+                names.push(0);
+            }
+            descriptors.push((first_thunk, dll_name));
+        }
+
+        let mut bytes = Vec::with_capacity(names_offset + names.len());
+        for thunk in &thunks {
+            bytes.extend_from_slice(&thunk.to_le_bytes());
+        }
+        for (first_thunk, dll_name) in &descriptors {
+            let rva = |offset: usize| (rdata_rva as usize + offset) as u32;
+            bytes.extend_from_slice(&rva(lookup_offset + first_thunk).to_le_bytes());
+            bytes.extend_from_slice(&0u32.to_le_bytes());
+            bytes.extend_from_slice(&0u32.to_le_bytes());
+            bytes.extend_from_slice(&(*dll_name as u32).to_le_bytes());
+            bytes.extend_from_slice(&rva(*first_thunk).to_le_bytes());
+        }
+        bytes.extend_from_slice(&[0; 20]);
+        for thunk in &thunks {
+            bytes.extend_from_slice(&thunk.to_le_bytes());
+        }
+        bytes.extend_from_slice(&names);
+
+        directories[IMAGE_DIRECTORY_ENTRY_IMPORT] = (
+            rdata_rva + descriptors_offset as u32,
+            ((self.imports.len() + 1) * 20) as u32,
+        );
+        directories[IMAGE_DIRECTORY_ENTRY_IAT] = (rdata_rva, table_size as u32);
+        bytes
+    }
+
+    /// Lays out the export directory, followed by the address, name and
+    /// ordinal tables and the names.
+    fn build_exports(
+        &self,
+        edata_rva: u32,
+        functions: &[PDBFunction],
+        directories: &mut [(u32, u32); 16],
+    ) -> Vec<u8> {
+        let count = self.exports.len();
+        let addresses_offset = 40;
+        let names_offset = addresses_offset + count * 4;
+        let ordinals_offset = names_offset + count * 4;
+        let strings_offset = ordinals_offset + count * 2;
+
+        let mut sorted: Vec<usize> = (0..count).collect();
+        sorted.sort_by_key(|&index| &self.exports[index]);
+        let mut bytes = vec![0u8; strings_offset];
+        let mut strings = b"image.exe\0".to_vec();
+        for (position, &index) in sorted.iter().enumerate() {
+            let name_rva = edata_rva + (strings_offset + strings.len()) as u32;
+            strings.extend_from_slice(self.exports[index].as_bytes());
+            strings.push(0);
+            put_u32(&mut bytes, names_offset + position * 4, name_rva);
+            put_u16(&mut bytes, ordinals_offset + position * 2, index as u16);
+        }
+        for (index, name) in self.exports.iter().enumerate() {
+            let function = functions
+                .iter()
+                .find(|function| &function.name == name)
+                .unwrap_or_else(|| panic!("unknown function {name}"));
+            put_u32(&mut bytes, addresses_offset + index * 4, function.rva);
+        }
+
+        let rva = |offset: usize| edata_rva + offset as u32;
+        put_u32(&mut bytes, 12, rva(strings_offset));
+        put_u32(&mut bytes, 16, 1);
+        put_u32(&mut bytes, 20, count as u32);
+        put_u32(&mut bytes, 24, count as u32);
+        put_u32(&mut bytes, 28, rva(addresses_offset));
+        put_u32(&mut bytes, 32, rva(names_offset));
+        put_u32(&mut bytes, 36, rva(ordinals_offset));
+        bytes.extend_from_slice(&strings);
+        directories[IMAGE_DIRECTORY_ENTRY_EXPORT] = (edata_rva, bytes.len() as u32);
+        bytes
+    }
+}
+
+// code_asm only encodes RIP-relative operands that point at labels, so
+// these add the instructions that reach the imports and data themselves.
+
+/// `lea register, [rip+...]` loading `address`.
+pub fn lea_rip(a: &mut CodeAssembler, register: Register, address: u64) -> Result<(), IcedError> {
+    a.add_instruction(Instruction::with2(OpCode::Lea_r64_m, register, rip_relative(address))?)
+}
+
+/// `mov register, qword ptr [rip+...]` reading `address`.
+pub fn mov_rip(a: &mut CodeAssembler, register: Register, address: u64) -> Result<(), IcedError> {
+    a.add_instruction(Instruction::with2(OpCode::Mov_r64_rm64, register, rip_relative(address))?)
+}
+
+/// `call qword ptr [rip+...]` through the pointer at `address`.
+pub fn call_rip(a: &mut CodeAssembler, address: u64) -> Result<(), IcedError> {
+    a.add_instruction(Instruction::with1(OpCode::Call_rm64, rip_relative(address))?)
+}
+
+fn rip_relative(address: u64) -> MemoryOperand {
+    MemoryOperand::with_base_displ(Register::RIP, address as i64)
+}
+
+/// Appends `bytes` to `section` at `alignment` and returns their RVA.
+fn place(section: &mut Vec<u8>, section_rva: u32, bytes: &[u8], alignment: usize) -> u32 {
+    section.resize(section.len().next_multiple_of(alignment), 0);
+    let rva = section_rva + section.len() as u32;
+    section.extend_from_slice(bytes);
+    rva
+}
+
+fn next_rva(rva: u32, size: usize) -> u32 {
+    (rva + (size as u32).max(1)).next_multiple_of(SECTION_ALIGNMENT)
+}
+
+fn put_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(bytes: &mut [u8], offset: usize, value: u64) {
+    bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_image(sections: &[Section], directories: &[(u32, u32); 16], entry_point: u32, text_rva: u32) -> Vec<u8> {
+    let mut image = vec![0u8; HEADERS_SIZE as usize];
+    image[0..2].copy_from_slice(b"MZ");
+    put_u32(&mut image, 0x3c, NT_HEADERS_OFFSET as u32);
+
+    let coff = NT_HEADERS_OFFSET + 4;
+    image[NT_HEADERS_OFFSET..coff].copy_from_slice(b"PE\0\0");
+    put_u16(&mut image, coff, 0x8664);
+    put_u16(&mut image, coff + 2, sections.len() as u16);
+    put_u16(&mut image, coff + 16, OPTIONAL_HEADER_SIZE as u16);
+    // IMAGE_FILE_EXECUTABLE_IMAGE | IMAGE_FILE_LARGE_ADDRESS_AWARE
+    put_u16(&mut image, coff + 18, 0x0022);
+
+    let last = sections.last().unwrap();
+    let size_of_image = next_rva(last.rva, last.bytes.len());
+    let size_of = |characteristics: u32| -> u32 {
+        sections
+            .iter()
+            .filter(|section| section.characteristics == characteristics)
+            .map(|section| (section.bytes.len() as u32).next_multiple_of(FILE_ALIGNMENT))
+            .sum()
+    };
+
+    let optional = coff + 20;
+    put_u16(&mut image, optional, 0x20b);
+    image[optional + 2] = 14;
+    put_u32(&mut image, optional + 4, size_of(CODE_CHARACTERISTICS));
+    put_u32(
+        &mut image,
+        optional + 8,
+        size_of(READ_ONLY_CHARACTERISTICS) + size_of(WRITABLE_CHARACTERISTICS) + size_of(RELOCATION_CHARACTERISTICS),
+    );
+    put_u32(&mut image, optional + 16, entry_point);
+    put_u32(&mut image, optional + 20, text_rva);
+    put_u64(&mut image, optional + 24, IMAGE_BASE);
+    put_u32(&mut image, optional + 32, SECTION_ALIGNMENT);
+    put_u32(&mut image, optional + 36, FILE_ALIGNMENT);
+    put_u16(&mut image, optional + 40, 6);
+    put_u16(&mut image, optional + 48, 6);
+    put_u32(&mut image, optional + 56, size_of_image);
+    put_u32(&mut image, optional + 60, HEADERS_SIZE);
+    // IMAGE_SUBSYSTEM_WINDOWS_CUI
+    put_u16(&mut image, optional + 68, 3);
+    // High entropy VA, dynamic base, NX compatible, terminal server aware
+    put_u16(&mut image, optional + 70, 0x8160);
+    put_u64(&mut image, optional + 72, 0x10_0000);
+    put_u64(&mut image, optional + 80, 0x1000);
+    put_u64(&mut image, optional + 88, 0x10_0000);
+    put_u64(&mut image, optional + 96, 0x1000);
+    put_u32(&mut image, optional + 108, directories.len() as u32);
+    for (index, &(rva, size)) in directories.iter().enumerate() {
+        put_u32(&mut image, optional + 112 + index * 8, rva);
+        put_u32(&mut image, optional + 116 + index * 8, size);
+    }
+
+    let headers = optional + OPTIONAL_HEADER_SIZE;
+    for (index, section) in sections.iter().enumerate() {
+        let pointer_to_raw_data = image.len() as u32;
+        let size_of_raw_data = (section.bytes.len() as u32).next_multiple_of(FILE_ALIGNMENT);
+        image.extend_from_slice(&section.bytes);
+        image.resize((pointer_to_raw_data + size_of_raw_data) as usize, 0);
+
+        let header = headers + index * SECTION_HEADER_SIZE;
+        image[header..header + section.name.len()].copy_from_slice(section.name.as_bytes());
+        put_u32(&mut image, header + 8, section.bytes.len() as u32);
+        put_u32(&mut image, header + 12, section.rva);
+        put_u32(&mut image, header + 16, size_of_raw_data);
+        put_u32(&mut image, header + 20, pointer_to_raw_data);
+        put_u32(&mut image, header + 36, section.characteristics);
+    }
+    image
+}
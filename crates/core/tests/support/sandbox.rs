@@ -0,0 +1,146 @@
+use super::loader::LoadedImage;
+use core::passes::PassRng;
+use core::pdb::PDBFunction;
+use core::verify::emulator::{AddressMap, Memory, Page};
+use core::verify::harness::{
+    self, Environment, HEAP, HEAP_SIZE, Outcome, STACK, STACK_SIZE, STEP_ALLOWANCE, STEP_FACTOR, State, Stop,
+    Value,
+};
+use rand::SeedableRng;
+use std::rc::Rc;
+
+const TRIALS: usize = 4;
+const MAX_STEPS: u64 = 20_000;
+/// The verifier gives each pass [`STEP_FACTOR`] times the steps of the
+/// last and [`STEP_ALLOWANCE`] on top; a call into the obfuscated image
+/// runs the work of every pass and every string decryption at once.
+const PIPELINE_STEP_FACTOR: u64 = 4 * STEP_FACTOR;
+const PIPELINE_STEP_ALLOWANCE: u64 = 10 * STEP_ALLOWANCE;
+/// Bytes of image memory compared in place of a pointer into the image.
+const POINTEE_BYTES: usize = 64;
+
+/// Runs functions of a loaded image. Everything but the imports is
+/// emulated, callees included; imports return at once with values derived
+/// from their stub address and the number of imports called so far.
+pub struct Sandbox {
+    image: LoadedImage,
+    base: Rc<AddressMap<Box<Page>>>,
+}
+
+impl Environment for Sandbox {
+    fn is_external(&self, address: u64) -> bool {
+        self.image.import_at(address).is_some()
+    }
+
+    /// Pointers into the image carry the bytes they point to up to the
+    /// first zero.
+    fn value(&self, memory: &Memory, value: u64) -> Value {
+        if !self.image.contains(value) {
+            return Value {
+                raw: value,
+                pointee: None,
+            };
+        }
+        let mut bytes = Vec::new();
+        let mut byte = [0u8];
+        while bytes.len() < POINTEE_BYTES
+            && memory.read_bytes(value + bytes.len() as u64, &mut byte).is_ok()
+            && byte[0] != 0
+        {
+            bytes.push(byte[0]);
+        }
+        Value {
+            raw: value,
+            pointee: Some(bytes),
+        }
+    }
+}
+
+impl Sandbox {
+    pub fn new(mut image: LoadedImage) -> Self {
+        let mut memory = std::mem::take(&mut image.memory);
+        // Zeroed in the shared base, so a run only copies the pages it writes.
+        memory.map(STACK, STACK_SIZE);
+        memory.map(HEAP, HEAP_SIZE);
+        Self {
+            image,
+            base: memory.into_base(),
+        }
+    }
+
+    /// Calls the function at `rva` from `state` and runs it until it
+    /// returns or traps.
+    pub fn call(&self, rva: u32, state: &State, max_steps: u64) -> Result<Outcome, Stop> {
+        let memory = Memory::new(self.base.clone());
+        harness::run(memory, self.image.image_base + rva as u64, state, max_steps, self)
+    }
+}
+
+/// How the calls of [`compare_images`] went when none differed.
+#[derive(Debug)]
+pub struct Comparison {
+    /// Calls that came out the same in both images.
+    pub compared: usize,
+    /// Functions with at least one call compared.
+    pub functions: Vec<String>,
+    /// Functions the original image finished no call of, from unsupported
+    /// instructions, wild pointers or loops over random data.
+    pub skipped: Vec<String>,
+}
+
+/// Calls each of `functions` in the original and the obfuscated image from
+/// the same random states. Every call the original finishes must come out
+/// the same in the obfuscated image, which must finish it as well. Fails
+/// with every call that came out differently.
+pub fn compare_images(
+    original: &[u8],
+    obfuscated: &[u8],
+    functions: &[PDBFunction],
+    seed: u64,
+) -> Result<Comparison, Vec<String>> {
+    let original = Sandbox::new(LoadedImage::load(original).map_err(|e| vec![e])?);
+    let obfuscated = Sandbox::new(LoadedImage::load(obfuscated).map_err(|e| vec![e])?);
+
+    let mut rng = PassRng::seed_from_u64(seed);
+    let mut compared = 0;
+    let mut compared_functions = Vec::new();
+    let mut skipped = Vec::new();
+    let mut mismatches = Vec::new();
+    for function in functions {
+        let before = compared;
+        let mut finished = false;
+        for trial in 0..TRIALS {
+            let state = State::random(&mut rng);
+            // Calls the original cannot finish say nothing about the
+            // obfuscator.
+            let Ok(expected) = original.call(function.rva, &state, MAX_STEPS) else {
+                continue;
+            };
+            finished = true;
+            let max_steps = expected.steps * PIPELINE_STEP_FACTOR + PIPELINE_STEP_ALLOWANCE;
+            let verdict = match obfuscated.call(function.rva, &state, max_steps) {
+                Ok(actual) => harness::compare(&expected, &actual, &original.image.writable, &obfuscated),
+                Err(Stop::Limit) => Err(format!("did not finish within {max_steps} steps")),
+                Err(Stop::Fault(fault)) => Err(format!("stopped with {fault}")),
+            };
+            match verdict {
+                Ok(()) => compared += 1,
+                Err(detail) => mismatches.push(format!("{} (trial {trial}): {detail}", function.name)),
+            }
+        }
+        if !finished {
+            skipped.push(function.name.clone());
+        } else if compared > before {
+            compared_functions.push(function.name.clone());
+        }
+    }
+    if mismatches.is_empty() {
+        Ok(Comparison {
+            compared,
+            functions: compared_functions,
+            skipped,
+        })
+    } else {
+        Err(mismatches)
+    }
+}